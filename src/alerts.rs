@@ -0,0 +1,287 @@
+//! Threshold-based alert rules over the readings, notifying when a rule starts and stops firing.
+use crate::counters::Timezone;
+use crate::expr::Expr;
+use crate::filter::Pattern;
+use crate::notify::Notifier;
+use crate::{PublishData, Value};
+use chrono::NaiveTime;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertRule {
+    pub name: String,
+    /// Sources (and virtual devices) the rule applies to, all by default
+    #[serde(default)]
+    pub sources: Vec<Pattern>,
+    /// Fires while this expression is not `0`, e.g. `currentPower == 0`
+    #[serde(default)]
+    pub when: Option<Expr>,
+    /// Fires while this field is a non-empty string, a non-zero number or `true`
+    #[serde(default)]
+    pub non_empty: Option<String>,
+    /// Only fires from this time of day, like `"10:00"`
+    #[serde(default)]
+    pub from: Option<NaiveTime>,
+    /// Only fires until this time of day (exclusive)
+    #[serde(default)]
+    pub to: Option<NaiveTime>,
+    /// Time zone of `from` and `to`, defaults to the local time zone
+    #[serde(default)]
+    pub timezone: Option<Timezone>,
+    /// How long the condition has to hold before firing
+    #[serde(default, rename = "for", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub duration: Duration,
+    /// Names of the notifiers to notify, all by default
+    #[serde(default)]
+    pub notify: Vec<String>,
+}
+
+impl AlertRule {
+    fn applies_to(&self, id: &str) -> bool {
+        self.sources.is_empty() || self.sources.iter().any(|p| p.matches(id))
+    }
+
+    fn in_time(&self, now: SystemTime) -> bool {
+        let time = Timezone::time(self.timezone, now);
+        match (self.from, self.to) {
+            (Some(from), Some(to)) if from <= to => from <= time && time < to,
+            (Some(from), Some(to)) => time >= from || time < to,
+            (Some(from), None) => time >= from,
+            (None, Some(to)) => time < to,
+            (None, None) => true,
+        }
+    }
+
+    /// Whether the condition holds for the reading. Readings lacking the fields don't fire.
+    fn holds(&self, data: &PublishData) -> bool {
+        let when = self.when.as_ref().is_none_or(|when| {
+            when.eval(&|field| data.number(field))
+                .is_ok_and(|value| value != 0.0)
+        });
+        let non_empty = self
+            .non_empty
+            .as_ref()
+            .is_none_or(|field| match data.field_value(field) {
+                Some(Value::String(s)) => !s.trim().is_empty(),
+                Some(Value::F64(f)) => *f != 0.0,
+                Some(Value::I64(i)) => *i != 0,
+                Some(Value::Bool(b)) => *b,
+                Some(Value::Timestamp(_)) => true,
+                None => false,
+            });
+        when && non_empty
+    }
+}
+
+/// Alerts once a source failed to be polled several times in a row, and once it recovers.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+pub struct StaleAlert {
+    /// Consecutive failed polls before alerting
+    #[serde(default = "StaleAlert::default_failures")]
+    pub failures: u64,
+    /// Additionally, how long the polls have to fail before alerting
+    #[serde(default, rename = "for", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub duration: Duration,
+    /// Names of the notifiers to notify, all by default
+    #[serde(default)]
+    pub notify: Vec<String>,
+}
+
+impl StaleAlert {
+    fn default_failures() -> u64 {
+        3
+    }
+}
+
+/// Since when a rule holds for a device (or it fails to be polled), and whether it was notified.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default, Clone)]
+pub struct AlertState {
+    since: Option<SystemTime>,
+    fired: bool,
+    /// Consecutive failed polls, for stale alerts
+    #[serde(default)]
+    failures: u64,
+}
+
+/// The alert rules along with their state by `<rule>/<device>`, and `$stale/<device>` for stale
+/// alerts.
+#[derive(Debug, Default)]
+pub struct Alerts {
+    pub rules: Vec<AlertRule>,
+    pub stale: Option<StaleAlert>,
+    pub notifiers: Vec<Notifier>,
+    pub state: BTreeMap<String, AlertState>,
+}
+
+impl Alerts {
+    /// Sends `title` and `message` to the named notifiers, or all of them if `names` is empty.
+    pub fn notify(&self, names: &[String], title: &str, message: &str) {
+        for notifier in &self.notifiers {
+            if names.is_empty() || names.contains(&notifier.name) {
+                if let Err(err) = notifier.send(title, message) {
+                    tracing::error!("Failed to notify '{}': {err}", notifier.name);
+                }
+            }
+        }
+    }
+
+    /// Whether a stale alert was sent for the source, which hasn't recovered yet.
+    pub fn is_stale(&self, id: &str) -> bool {
+        self.state
+            .get(&format!("$stale/{id}"))
+            .is_some_and(|state| state.fired)
+    }
+
+    /// Tracks the consecutive failed polls of a source, returning the stale or recovery
+    /// notification sent (title and message).
+    pub fn polled(
+        &mut self,
+        id: &str,
+        error: Option<&str>,
+        now: SystemTime,
+    ) -> Option<(String, String)> {
+        let stale = self.stale.as_ref()?;
+        let key = format!("$stale/{id}");
+        let notification = match error {
+            Some(error) => {
+                let state = self.state.entry(key).or_default();
+                state.failures += 1;
+                let since = *state.since.get_or_insert(now);
+                let failing = now.duration_since(since).unwrap_or_default();
+                if state.fired || state.failures < stale.failures || failing < stale.duration {
+                    return None;
+                }
+                state.fired = true;
+                (
+                    format!("Stale: {id}"),
+                    format!(
+                        "'{id}' failed to be polled {} times in a row: {error}",
+                        state.failures
+                    ),
+                )
+            }
+            None => {
+                let state = self.state.remove(&key)?;
+                if !state.fired {
+                    return None;
+                }
+                (
+                    format!("Recovered: {id}"),
+                    format!("'{id}' is polled again, after {} failures", state.failures),
+                )
+            }
+        };
+        tracing::warn!("{}", notification.1);
+        self.notify(&stale.notify, &notification.0, &notification.1);
+        Some(notification)
+    }
+
+    /// Evaluates all rules against the readings of a cycle, returning the notifications sent
+    /// (title and message).
+    pub fn check(
+        &mut self,
+        readings: &[(String, PublishData)],
+        now: SystemTime,
+    ) -> Vec<(String, String)> {
+        let mut notifications = vec![];
+        for rule in &self.rules {
+            for (id, data) in readings.iter().filter(|(id, _)| rule.applies_to(id)) {
+                let state = self.state.entry(format!("{}/{id}", rule.name)).or_default();
+                if rule.in_time(now) && rule.holds(data) {
+                    let since = *state.since.get_or_insert(now);
+                    let held = now.duration_since(since).unwrap_or_default();
+                    if !state.fired && held >= rule.duration {
+                        state.fired = true;
+                        notifications.push((
+                            &rule.notify,
+                            format!("{}: {id}", rule.name),
+                            format!("'{}' fired for '{id}'", rule.name),
+                        ));
+                    }
+                } else {
+                    if state.fired {
+                        notifications.push((
+                            &rule.notify,
+                            format!("Resolved {}: {id}", rule.name),
+                            format!("'{}' stopped firing for '{id}'", rule.name),
+                        ));
+                    }
+                    *state = AlertState::default();
+                }
+            }
+        }
+        for (names, title, message) in &notifications {
+            tracing::warn!("{message}");
+            self.notify(names, title, message);
+        }
+        notifications
+            .into_iter()
+            .map(|(_, title, message)| (title, message))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale() {
+        let mut alerts = Alerts {
+            stale: serde_json::from_str(r#"{"failures": 2}"#).unwrap(),
+            ..Default::default()
+        };
+        let now = SystemTime::UNIX_EPOCH;
+        assert_eq!(alerts.polled("plug", Some("down"), now), None);
+        let (title, _) = alerts.polled("plug", Some("down"), now).unwrap();
+        assert_eq!(title, "Stale: plug");
+        assert!(alerts.is_stale("plug"));
+        assert_eq!(alerts.polled("plug", Some("down"), now), None);
+        let (title, message) = alerts.polled("plug", None, now).unwrap();
+        assert_eq!(title, "Recovered: plug");
+        assert_eq!(message, "'plug' is polled again, after 3 failures");
+        assert_eq!(alerts.polled("plug", None, now), None);
+    }
+
+    #[test]
+    fn test_check() {
+        let rule: AlertRule = serde_json::from_str(
+            r#"{
+                "name": "no production",
+                "sources": ["inverter"],
+                "when": "currentPower == 0",
+                "from": "10:00",
+                "to": "16:00",
+                "timezone": "UTC",
+                "for": "30m"
+            }"#,
+        )
+        .unwrap();
+        let mut alerts = Alerts {
+            rules: vec![rule],
+            ..Default::default()
+        };
+        let reading = |power: f64| {
+            let mut data = PublishData::default();
+            data.field("currentPower", power);
+            vec![("inverter".to_string(), data)]
+        };
+        // 2023-06-01 11:00 UTC
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_685_617_200);
+        let minutes = |m: u64| start + Duration::from_secs(m * 60);
+        assert!(alerts.check(&reading(0.0), start).is_empty());
+        assert!(alerts.check(&reading(0.0), minutes(20)).is_empty());
+        let fired = alerts.check(&reading(0.0), minutes(30));
+        assert_eq!(fired[0].0, "no production: inverter");
+        assert!(alerts.check(&reading(0.0), minutes(40)).is_empty());
+        let resolved = alerts.check(&reading(50.0), minutes(50));
+        assert_eq!(resolved[0].0, "Resolved no production: inverter");
+        // Outside of the time window
+        assert!(alerts.check(&reading(0.0), minutes(400)).is_empty());
+        assert!(alerts.check(&reading(0.0), minutes(500)).is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+//! JSON API with the latest readings, for scripts on the local network:
+//! * `/api/devices` - all devices with the status of their last poll
+//! * `/api/devices/<id>/latest` - the last reading of a device
+//! * `/api/devices/<id>/history?since=1h` - the numeric fields of the readings kept in memory,
+//!   optionally only those of the last `since`
+//! * `/api/stream` - every new reading, as Server-Sent Events
+use crate::http::{percent_decode, Request, Response};
+use crate::live::{Device, Live};
+use crate::scheduler::CycleSummary;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+type Values = BTreeMap<String, serde_json::Value>;
+
+fn time(time: Option<SystemTime>) -> serde_json::Value {
+    time.map(|time| chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339())
+        .into()
+}
+
+fn status(id: &str, device: &Device) -> serde_json::Value {
+    json!({
+        "id": id,
+        "success": device.error.is_none(),
+        "error": device.error,
+        "polled": time(device.polled),
+        "updated": time(device.updated),
+    })
+}
+
+fn reading(
+    id: &str,
+    time: Option<SystemTime>,
+    tags: &Values,
+    fields: &Values,
+) -> serde_json::Value {
+    json!({"id": id, "time": self::time(time), "tags": tags, "fields": fields})
+}
+
+/// The readings of a cycle, as sent to `/api/stream`.
+pub fn events(summary: &CycleSummary, time: SystemTime) -> Vec<String> {
+    summary
+        .sources
+        .iter()
+        .filter(|source| source.success)
+        .map(|source| reading(&source.id, Some(time), &source.tags, &source.values).to_string())
+        .collect()
+}
+
+fn json(value: serde_json::Value) -> Response {
+    Response::new("application/json", value.to_string())
+}
+
+fn history(device: &Device, query: Option<&str>) -> anyhow::Result<serde_json::Value> {
+    let mut from = SystemTime::UNIX_EPOCH;
+    for (name, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match name.as_ref() {
+            "since" => from = SystemTime::now() - crate::duration::parse(&value)?,
+            name => anyhow::bail!("Unknown parameter '{name}'"),
+        }
+    }
+    Ok(device
+        .range(from, SystemTime::now())
+        .map(|sample| json!({"time": time(Some(sample.time)), "fields": sample.values}))
+        .collect())
+}
+
+/// Answers requests below `/api/`.
+pub fn respond(live: &Live, request: &Request) -> Response {
+    let Some(path) = request.path.strip_prefix("/api/devices") else {
+        return Response::not_found();
+    };
+    if path.is_empty() || path == "/" {
+        return json(
+            live.devices
+                .iter()
+                .map(|(id, device)| status(id, device))
+                .collect(),
+        );
+    }
+    let Some((id, resource)) = path
+        .strip_prefix('/')
+        .and_then(|path| path.rsplit_once('/'))
+    else {
+        return Response::not_found();
+    };
+    let id = percent_decode(id);
+    let Some(device) = live.devices.get(&id) else {
+        return Response::not_found();
+    };
+    match resource {
+        "latest" if device.updated.is_some() => {
+            json(reading(&id, device.updated, &device.tags, &device.values))
+        }
+        "history" => match history(device, request.query.as_deref()) {
+            Ok(history) => json(history),
+            Err(err) => Response::error(400, &err.to_string()),
+        },
+        _ => Response::not_found(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_respond() {
+        let mut live = Live::default();
+        live.devices.insert(
+            "heat pump".to_string(),
+            Device {
+                tags: [("deviceName".to_string(), "heat pump".into())].into(),
+                values: [("currentPower".to_string(), 344.5.into())].into(),
+                polled: Some(SystemTime::UNIX_EPOCH),
+                updated: Some(SystemTime::UNIX_EPOCH),
+                history: [crate::live::Sample {
+                    time: SystemTime::UNIX_EPOCH,
+                    values: [("currentPower".to_string(), 344.5)].into(),
+                }]
+                .into(),
+                ..Default::default()
+            },
+        );
+        live.devices.insert(
+            "inverter".to_string(),
+            Device {
+                error: Some("Timed out".to_string()),
+                polled: Some(SystemTime::UNIX_EPOCH),
+                ..Default::default()
+            },
+        );
+        let body = |path: &str| {
+            let (path, query) = match path.split_once('?') {
+                Some((path, query)) => (path, Some(query.to_string())),
+                None => (path, None),
+            };
+            let request = Request {
+                method: "GET".to_string(),
+                path: path.to_string(),
+                query,
+                body: vec![],
+            };
+            let response = respond(&live, &request);
+            (response.status, String::from_utf8(response.body).unwrap())
+        };
+        let (status, devices) = body("/api/devices");
+        assert_eq!(status, 200);
+        let devices: serde_json::Value = serde_json::from_str(&devices).unwrap();
+        assert_eq!(devices[1]["error"], "Timed out");
+        assert_eq!(devices[1]["updated"], serde_json::Value::Null);
+        let (status, latest) = body("/api/devices/heat%20pump/latest");
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&latest).unwrap(),
+            json!({
+                "id": "heat pump",
+                "time": "1970-01-01T00:00:00+00:00",
+                "tags": {"deviceName": "heat pump"},
+                "fields": {"currentPower": 344.5},
+            })
+        );
+        assert_eq!(body("/api/devices/inverter/latest").0, 404);
+        assert_eq!(body("/api/devices/bogus/latest").0, 404);
+        let (status, history) = body("/api/devices/heat%20pump/history");
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&history).unwrap(),
+            json!([{"time": "1970-01-01T00:00:00+00:00", "fields": {"currentPower": 344.5}}])
+        );
+        assert_eq!(body("/api/devices/heat%20pump/history?since=1h").1, "[]");
+        assert_eq!(body("/api/devices/heat%20pump/history?until=1h").0, 400);
+
+        let summary = CycleSummary {
+            sources: vec![
+                crate::scheduler::SourceSummary {
+                    id: "heat pump".to_string(),
+                    success: true,
+                    tags: live.devices["heat pump"].tags.clone(),
+                    values: live.devices["heat pump"].values.clone(),
+                    ..Default::default()
+                },
+                crate::scheduler::SourceSummary {
+                    id: "inverter".to_string(),
+                    error: Some("Timed out".to_string()),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(events(&summary, SystemTime::UNIX_EPOCH), [latest]);
+    }
+}
@@ -0,0 +1,79 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const ARP_TABLE: &str = "/proc/net/arp";
+
+/// Looks up the current address of a device by its MAC address in the kernel's ARP cache, or in
+/// its IPv6 neighbor cache (listed by `ip -6 neigh`) on IPv6 only networks. Only devices the host
+/// has recently talked to (or seen broadcasting) are listed there.
+pub fn lookup(mac: &str) -> Option<IpAddr> {
+    let table = std::fs::read_to_string(ARP_TABLE).unwrap_or_default();
+    if let Some(ip) = find_ip(&table, mac) {
+        return Some(ip.into());
+    }
+    let neighbors = std::process::Command::new("ip")
+        .args(["-6", "neigh", "show"])
+        .output()
+        .ok()?;
+    find_ipv6(&String::from_utf8_lossy(&neighbors.stdout), mac).map(IpAddr::V6)
+}
+
+fn find_ip(table: &str, mac: &str) -> Option<Ipv4Addr> {
+    let mac = mac.replace('-', ":");
+    table
+        .lines()
+        // Header: IP address, HW type, Flags, HW address, Mask, Device
+        .skip(1)
+        .filter_map(|line| {
+            let columns: Vec<_> = line.split_whitespace().collect();
+            match columns[..] {
+                [ip, _, _, hw_address, ..] if hw_address.eq_ignore_ascii_case(&mac) => {
+                    ip.parse().ok()
+                }
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Link-local addresses are skipped, they can't be used without the interface in URLs.
+fn find_ipv6(neighbors: &str, mac: &str) -> Option<Ipv6Addr> {
+    let mac = mac.replace('-', ":");
+    neighbors
+        .lines()
+        // e.g. "2001:db8::23 dev eth0 lladdr 24:0a:c4:12:34:56 REACHABLE"
+        .filter_map(|line| {
+            let columns: Vec<_> = line.split_whitespace().collect();
+            let lladdr = columns.iter().position(|c| *c == "lladdr")?;
+            let ip: Ipv6Addr = columns.first()?.parse().ok()?;
+            (columns.get(lladdr + 1)?.eq_ignore_ascii_case(&mac) && !ip.is_unicast_link_local())
+                .then_some(ip)
+        })
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_ip() {
+        let table = r#"IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         a0:b1:c2:d3:e4:f5     *        eth0
+192.168.1.23     0x1         0x2         24:0a:c4:12:34:56     *        eth0
+"#;
+        assert_eq!(
+            find_ip(table, "24-0A-C4-12-34-56"),
+            Some(Ipv4Addr::new(192, 168, 1, 23))
+        );
+        assert_eq!(find_ip(table, "24:0a:c4:00:00:00"), None);
+        let neighbors = "fe80::260a:c4ff:fe12:3456 dev eth0 lladdr 24:0a:c4:12:34:56 STALE
+2001:db8::1 dev eth0 lladdr a0:b1:c2:d3:e4:f5 router REACHABLE
+2001:db8::23 dev eth0 lladdr 24:0a:c4:12:34:56 REACHABLE
+2001:db8::99 dev eth0 FAILED
+";
+        assert_eq!(
+            find_ipv6(neighbors, "24-0A-C4-12-34-56"),
+            Some("2001:db8::23".parse().unwrap())
+        );
+    }
+}
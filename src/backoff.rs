@@ -0,0 +1,98 @@
+//! Backing off from polling failing sources, eventually quarantining them with rare probes.
+use std::time::{Duration, SystemTime};
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Backoff {
+    /// Delay after the first failed poll, doubled with every further failure
+    #[serde(default = "Backoff::default_initial", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub initial: Duration,
+    /// Longest delay in between polls while backing off
+    #[serde(default = "Backoff::default_max", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub max: Duration,
+    /// Consecutive failed polls after which the source is quarantined
+    #[serde(default = "Backoff::default_quarantine_after")]
+    pub quarantine_after: u64,
+    /// Delay in between the probing polls of quarantined sources
+    #[serde(default = "Backoff::default_probe_interval", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub probe_interval: Duration,
+}
+
+/// Consecutive failed polls of a source, and when to poll it again.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default, Clone)]
+pub struct BackoffState {
+    pub failures: u64,
+    pub retry_at: Option<SystemTime>,
+}
+
+impl Backoff {
+    fn default_initial() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_max() -> Duration {
+        Duration::from_secs(30 * 60)
+    }
+
+    fn default_quarantine_after() -> u64 {
+        10
+    }
+
+    fn default_probe_interval() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    /// Delay before polling again after `failures` consecutive failures.
+    pub fn delay(&self, failures: u64) -> Duration {
+        if failures >= self.quarantine_after {
+            return self.probe_interval;
+        }
+        let doublings = failures.saturating_sub(1).min(31) as u32;
+        self.initial.saturating_mul(1 << doublings).min(self.max)
+    }
+
+    pub fn is_quarantined(&self, state: &BackoffState) -> bool {
+        state.failures >= self.quarantine_after
+    }
+
+    /// Records the outcome of a poll.
+    pub fn polled(&self, state: &mut BackoffState, ok: bool, now: SystemTime) {
+        if ok {
+            *state = BackoffState::default();
+        } else {
+            state.failures += 1;
+            state.retry_at = Some(now + self.delay(state.failures));
+        }
+    }
+}
+
+impl BackoffState {
+    pub fn is_due(&self, now: SystemTime) -> bool {
+        self.retry_at.is_none_or(|retry_at| now >= retry_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff: Backoff = serde_json::from_str(r#"{"quarantineAfter": 4}"#).unwrap();
+        let delays: Vec<_> = (1..=5).map(|f| backoff.delay(f).as_secs()).collect();
+        assert_eq!(delays, [60, 120, 240, 3600, 3600]);
+        assert_eq!(backoff.delay(3), Duration::from_secs(240));
+        let capped: Backoff = serde_json::from_str(r#"{"max": "3m"}"#).unwrap();
+        assert_eq!(capped.delay(3), Duration::from_secs(180));
+        let mut state = BackoffState::default();
+        let now = SystemTime::UNIX_EPOCH;
+        backoff.polled(&mut state, false, now);
+        assert!(!state.is_due(now + Duration::from_secs(59)));
+        assert!(state.is_due(now + Duration::from_secs(60)));
+        backoff.polled(&mut state, true, now);
+        assert_eq!(state, BackoffState::default());
+    }
+}
@@ -0,0 +1,131 @@
+//! Latencies of the polling and publishing stages, for sizing poll intervals on slow hardware.
+use crate::{Config, PublishData, Source, Target};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Durations of one stage, like fetching from a device.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Latencies {
+    pub name: String,
+    pub durations: Vec<Duration>,
+    pub errors: usize,
+}
+
+impl Latencies {
+    fn new(name: String) -> Self {
+        Latencies {
+            name,
+            ..Default::default()
+        }
+    }
+
+    /// Times `run`, keeping its result.
+    fn time<T>(&mut self, run: impl FnOnce() -> anyhow::Result<T>) -> Option<T> {
+        let start = Instant::now();
+        let result = run();
+        self.durations.push(start.elapsed());
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                tracing::debug!("{} failed: {err}", self.name);
+                self.errors += 1;
+                None
+            }
+        }
+    }
+
+    /// Nearest-rank percentile, `p` from 0 to 100.
+    pub fn percentile(&self, p: f64) -> Duration {
+        let mut sorted = self.durations.clone();
+        sorted.sort();
+        let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted
+            .get(rank.clamp(1, sorted.len().max(1)) - 1)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Fetches and parses the response of every source `count` times, and (with `publish`) writes
+/// the readings to every target.
+pub fn run(config: &Config, count: usize, publish: bool) -> Vec<Latencies> {
+    let mut stages = vec![];
+    let mut readings: Vec<PublishData> = vec![];
+    for source in &config.sources {
+        let id = source.device.id();
+        let mut fetch = Latencies::new(format!("{id} fetch"));
+        let mut parse = Latencies::new(format!("{id} parse"));
+        for _ in 0..count {
+            let Some(raw) = fetch.time(|| source.device.fetch_raw()) else {
+                continue;
+            };
+            readings.extend(parse.time(|| source.device.parse_raw(&raw)));
+        }
+        stages.extend([fetch, parse]);
+    }
+    if publish {
+        for target in &config.targets {
+            let mut latencies = Latencies::new(format!("{} publish", target.id()));
+            for data in readings.iter().cycle().take(count) {
+                latencies.time(|| target.publish(data));
+            }
+            stages.push(latencies);
+        }
+    }
+    stages
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}", duration.as_secs_f64() * 1000.0)
+}
+
+/// The percentiles of the stages, in milliseconds.
+pub fn table(stages: &[Latencies]) -> String {
+    let width = stages
+        .iter()
+        .map(|stage| stage.name.len())
+        .max()
+        .unwrap_or_default()
+        .max(5);
+    let mut table = format!(
+        "{:width$} {:>6} {:>6} {:>8} {:>8} {:>8} {:>8}\n",
+        "Stage", "Runs", "Errors", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+    for stage in stages {
+        writeln!(
+            table,
+            "{:width$} {:>6} {:>6} {:>8} {:>8} {:>8} {:>8}",
+            stage.name,
+            stage.durations.len(),
+            stage.errors,
+            millis(stage.percentile(50.0)),
+            millis(stage.percentile(90.0)),
+            millis(stage.percentile(99.0)),
+            millis(stage.percentile(100.0)),
+        )
+        .expect("writing to a string");
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let latencies = Latencies {
+            name: "plug fetch".to_string(),
+            durations: (1..=10).rev().map(Duration::from_millis).collect(),
+            errors: 1,
+        };
+        assert_eq!(latencies.percentile(50.0), Duration::from_millis(5));
+        assert_eq!(latencies.percentile(90.0), Duration::from_millis(9));
+        assert_eq!(latencies.percentile(100.0), Duration::from_millis(10));
+        assert_eq!(Latencies::default().percentile(50.0), Duration::ZERO);
+        assert_eq!(
+            table(&[latencies]).lines().nth(1),
+            Some("plug fetch     10      1      5.0      9.0     10.0     10.0")
+        );
+    }
+}
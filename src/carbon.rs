@@ -0,0 +1,196 @@
+//! Grid carbon intensity, for publishing the CO2 emissions avoided by the production.
+use crate::backoff::{Backoff, BackoffState};
+use crate::counters::Weighted;
+use crate::PublishData;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Delay after the first failed fetch, doubled up to the `refresh` of the intensity.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Intensities fetched by any source, and the failures fetching them, by zone and URL.
+static FETCHED: Mutex<BTreeMap<(String, String), Fetched>> = Mutex::new(BTreeMap::new());
+
+#[derive(Default)]
+struct Fetched {
+    intensity: Option<(f64, SystemTime)>,
+    backoff: BackoffState,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Carbon {
+    /// Static carbon intensity of the grid in kg CO2 per kWh, also used if fetching fails
+    #[serde(default)]
+    pub intensity: Option<f64>,
+    /// Fetch the current intensity from electricityMaps
+    #[serde(default)]
+    pub electricity_map: Option<ElectricityMap>,
+    /// How long a fetched intensity is used
+    #[serde(default = "Carbon::default_refresh", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub refresh: Duration,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+pub struct ElectricityMap {
+    /// Zone like `DE`
+    pub zone: String,
+    pub token: String,
+    #[serde(default = "ElectricityMap::default_url")]
+    pub url: String,
+    #[serde(default = "ElectricityMap::default_timeout", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub timeout: Duration,
+}
+
+/// Last fetched intensity and the avoided emissions.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+pub struct CarbonState {
+    fetched: Option<(f64, SystemTime)>,
+    avoided: Weighted,
+}
+
+impl ElectricityMap {
+    fn default_url() -> String {
+        "https://api.electricitymap.org/v3/carbon-intensity/latest".to_string()
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    /// Current intensity in kg CO2 per kWh.
+    fn fetch(&self) -> anyhow::Result<f64> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Response {
+            /// g CO2eq per kWh
+            carbon_intensity: f64,
+        }
+        let body = ureq::get(&self.url)
+            .query("zone", &self.zone)
+            .set("auth-token", &self.token)
+            .timeout(self.timeout)
+            .call()?
+            .into_string()?;
+        let response: Response = serde_json::from_str(&body)?;
+        Ok(response.carbon_intensity / 1000.0)
+    }
+}
+
+impl Carbon {
+    fn default_refresh() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    /// The current intensity, fetched at most every `refresh` for all sources, and less often
+    /// while fetching fails.
+    fn intensity(&self, state: &mut CarbonState, now: SystemTime) -> anyhow::Result<f64> {
+        let Some(electricity_map) = &self.electricity_map else {
+            return self.intensity.context("No carbon intensity configured");
+        };
+        let fresh = |fetched: Option<(f64, SystemTime)>| {
+            fetched.filter(|(_, at)| now.duration_since(*at).unwrap_or_default() < self.refresh)
+        };
+        if let Some((intensity, _)) = fresh(state.fetched) {
+            return Ok(intensity);
+        }
+        // Locked while fetching, so sources polled at the same time wait for the result
+        let mut fetched = FETCHED.lock().expect("not poisoned");
+        let key = (electricity_map.zone.clone(), electricity_map.url.clone());
+        let fetched = fetched.entry(key).or_default();
+        if let Some((intensity, at)) = fresh(fetched.intensity) {
+            state.fetched = Some((intensity, at));
+            return Ok(intensity);
+        }
+        let fallback = |err: String| {
+            self.intensity
+                .or(state.fetched.map(|(intensity, _)| intensity))
+                .with_context(|| format!("Failed to fetch carbon intensity: {err}"))
+        };
+        if !fetched.backoff.is_due(now) {
+            let failures = fetched.backoff.failures;
+            return fallback(format!("Backing off after {failures} failures"));
+        }
+        let result = electricity_map.fetch();
+        self.backoff()
+            .polled(&mut fetched.backoff, result.is_ok(), now);
+        match result {
+            Ok(intensity) => {
+                fetched.intensity = Some((intensity, now));
+                state.fetched = Some((intensity, now));
+                Ok(intensity)
+            }
+            Err(err) => fallback(err.to_string()),
+        }
+    }
+
+    /// Retries failed fetches with a growing delay, up to `refresh`.
+    fn backoff(&self) -> Backoff {
+        Backoff {
+            initial: RETRY_DELAY.min(self.refresh),
+            max: self.refresh,
+            quarantine_after: u64::MAX,
+            probe_interval: self.refresh,
+        }
+    }
+
+    /// Adds `co2AvoidedKg`, the emissions avoided by producing the energy counted in `field` (in
+    /// kWh), weighted with the carbon intensity at the time.
+    pub fn apply(
+        &self,
+        data: &mut PublishData,
+        field: &str,
+        state: &mut CarbonState,
+        now: SystemTime,
+    ) -> anyhow::Result<()> {
+        let Some(energy) = data.number(field) else {
+            return Ok(());
+        };
+        let intensity = self.intensity(state, now)?;
+        let avoided = state.avoided.add(field, energy, intensity);
+        data.field("co2AvoidedKg", avoided);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static_intensity() {
+        let carbon: Carbon = serde_json::from_str(r#"{"intensity": 0.4}"#).unwrap();
+        let mut state = CarbonState::default();
+        let mut data = PublishData::default();
+        data.field("yieldToday", 2.5);
+        carbon
+            .apply(&mut data, "yieldToday", &mut state, SystemTime::now())
+            .unwrap();
+        assert_eq!(data.number("co2AvoidedKg"), Some(1.0));
+    }
+
+    #[test]
+    fn test_fetch_backoff() {
+        let carbon: Carbon = serde_json::from_str(
+            r#"{"intensity": 0.4, "electricityMap": {"zone": "XX", "token": "t",
+                "url": "http://127.0.0.1:1/latest", "timeout": "1s"}}"#,
+        )
+        .unwrap();
+        let mut state = CarbonState::default();
+        let now = SystemTime::now();
+        assert_eq!(carbon.intensity(&mut state, now).unwrap(), 0.4);
+        let key = ("XX".to_string(), "http://127.0.0.1:1/latest".to_string());
+        let retry_at = |fetched: &BTreeMap<_, Fetched>| fetched[&key].backoff.retry_at;
+        let first = retry_at(&FETCHED.lock().unwrap());
+        assert_eq!(first, Some(now + RETRY_DELAY));
+        // Not fetched again while backing off, by any source
+        let mut other = CarbonState::default();
+        let later = now + Duration::from_secs(30);
+        assert_eq!(carbon.intensity(&mut other, later).unwrap(), 0.4);
+        assert_eq!(retry_at(&FETCHED.lock().unwrap()), first);
+    }
+}
@@ -0,0 +1,211 @@
+//! Per-phase or per-channel values, e.g. the voltages of a three-phase meter or the power of each
+//! string of an inverter.
+use crate::{Field, PublishData, Value};
+
+/// How channel values are published.
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    Default,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum ChannelMode {
+    /// As fields of the reading, suffixed with the channel label: `voltageL1`, `voltageL2`, ...
+    #[default]
+    Suffix,
+    /// As separate points, tagged with the channel label: `phase=L1`, `phase=L2`, ...
+    Points,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Channels {
+    /// Tag name identifying the channel of separate points, like `phase` or `channel`
+    pub tag: String,
+    pub labels: Vec<String>,
+    /// Values of each field, by channel. `None` where a channel has no (more) value, e.g. because
+    /// it was filtered.
+    pub fields: Vec<(String, Vec<Option<Value>>)>,
+}
+
+impl PublishData {
+    /// Declares the channels of the device, before adding values with `channel_field`.
+    pub fn channels(
+        &mut self,
+        tag: impl Into<String>,
+        labels: impl IntoIterator<Item = impl Into<String>>,
+    ) {
+        self.channels = Some(Channels {
+            tag: tag.into(),
+            labels: labels.into_iter().map(Into::into).collect(),
+            fields: vec![],
+        });
+    }
+
+    pub fn has_channels(&self) -> bool {
+        self.channels.is_some()
+    }
+
+    /// Adds a field holding one value per channel. Fails if the channels weren't declared, or the
+    /// number of values doesn't match.
+    pub fn channel_field(
+        &mut self,
+        name: impl Into<String>,
+        values: impl IntoIterator<Item = impl Into<Value>>,
+    ) -> anyhow::Result<()> {
+        let name = name.into();
+        let Some(channels) = self.channels.as_mut() else {
+            anyhow::bail!("Channels must be declared before adding '{name}'");
+        };
+        let values: Vec<_> = values.into_iter().map(|v| Some(v.into())).collect();
+        anyhow::ensure!(
+            values.len() == channels.labels.len(),
+            "Expected {} values of '{name}', one per channel, got {}",
+            channels.labels.len(),
+            values.len()
+        );
+        channels.fields.push((name, values));
+        Ok(())
+    }
+
+    /// Turns channel values into fields suffixed with the channel label, so they are processed like
+    /// any other field. Returns the channels without their values, to group the fields again with
+    /// [`PublishData::group_channels`].
+    pub fn flatten_channels(&mut self) -> Option<Channels> {
+        let mut channels = self.channels.take()?;
+        for (name, values) in &mut channels.fields {
+            for (label, value) in channels.labels.iter().zip(values.drain(..)) {
+                if let Some(value) = value {
+                    self.fields
+                        .push(Field::Field(format!("{name}{label}"), value));
+                }
+            }
+        }
+        Some(channels)
+    }
+
+    /// Moves the suffixed fields of `channels` (as left by [`PublishData::flatten_channels`]) back
+    /// into channel values.
+    pub fn group_channels(&mut self, mut channels: Channels) {
+        for (name, values) in &mut channels.fields {
+            *values = channels
+                .labels
+                .iter()
+                .map(|label| {
+                    let suffixed = format!("{name}{label}");
+                    let index = self
+                        .fields
+                        .iter()
+                        .position(|f| matches!(f, Field::Field(n, _) if *n == suffixed))?;
+                    Some(self.fields.remove(index).value().clone())
+                })
+                .collect();
+        }
+        channels
+            .fields
+            .retain(|(_, values)| values.iter().any(Option::is_some));
+        self.channels = Some(channels);
+    }
+
+    /// Splits channel values into separate points, carrying the same tags as the reading plus
+    /// the channel tag. The reading itself is only kept if it has fields of its own.
+    pub fn into_points(mut self) -> Vec<PublishData> {
+        let Some(channels) = self.channels.take() else {
+            return vec![self];
+        };
+        let tags: Vec<_> = self
+            .fields
+            .iter()
+            .filter(|f| matches!(f, Field::Tag(..)))
+            .cloned()
+            .collect();
+        let mut points: Vec<_> = channels
+            .labels
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                let mut point = PublishData {
+                    fields: tags.clone(),
+                    measurement: self.measurement.clone(),
+                    timestamp: self.timestamp,
+                    quality: self.quality.clone(),
+                    ..Default::default()
+                };
+                point.set_tag(&channels.tag, label.clone());
+                for (name, values) in &channels.fields {
+                    if let Some(Some(value)) = values.get(i) {
+                        point.field(name, value.clone());
+                    }
+                }
+                point
+            })
+            .collect();
+        if self.has_fields() {
+            points.insert(0, self);
+        }
+        points
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn three_phase() -> PublishData {
+        let mut data = PublishData::default();
+        data.tag("deviceName", "meter".to_string());
+        data.field("totalPower", 690.0);
+        data.channels("phase", ["L1", "L2", "L3"]);
+        data.channel_field("voltage", [230.0, 231.0, 229.0])
+            .unwrap();
+        data.channel_field("power", [100.0, 290.0, 300.0]).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_channel_field() {
+        let mut data = PublishData::default();
+        assert!(data.channel_field("voltage", [230.0]).is_err());
+        data.channels("phase", ["L1", "L2", "L3"]);
+        assert!(data.channel_field("voltage", [230.0, 231.0]).is_err());
+    }
+
+    #[test]
+    fn test_flatten_channels() {
+        let mut data = three_phase();
+        data.flatten_channels();
+        assert_eq!(data.number("voltageL2"), Some(231.0));
+        assert_eq!(data.number("powerL3"), Some(300.0));
+        assert_eq!(data.into_points().len(), 1);
+    }
+
+    #[test]
+    fn test_group_channels() {
+        let mut data = three_phase();
+        let channels = data.flatten_channels().unwrap();
+        // As if the filter dropped one of them
+        data.fields.retain(|f| f.name() != "voltageL2");
+        data.group_channels(channels);
+        assert_eq!(data.number("powerL1"), None);
+        let points = data.into_points();
+        assert_eq!(points.len(), 4);
+        assert_eq!(points[1].number("voltage"), Some(230.0));
+        assert_eq!(points[2].number("voltage"), None);
+        assert_eq!(points[2].number("power"), Some(290.0));
+    }
+
+    #[test]
+    fn test_into_points() {
+        let points = three_phase().into_points();
+        assert_eq!(points.len(), 4);
+        assert_eq!(points[0].number("totalPower"), Some(690.0));
+        assert_eq!(points[2]["phase"], Value::String("L2".to_string()));
+        assert_eq!(points[2]["deviceName"], Value::String("meter".to_string()));
+        assert_eq!(points[2].number("voltage"), Some(231.0));
+        assert_eq!(points[2].number("totalPower"), None);
+    }
+}
@@ -0,0 +1,100 @@
+//! Fault injection for testing: with a given probability, polls and publishes fail with timeouts,
+//! malformed responses or server errors instead of reaching the device or target. This exercises
+//! the backoff, alerting and error reporting without breaking real devices.
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    Timeout,
+    /// A response that can't be parsed, counted as a parse failure
+    Malformed,
+    /// HTTP 503
+    ServerError,
+}
+
+impl Fault {
+    pub fn error(self) -> anyhow::Error {
+        match self {
+            Fault::Timeout => ureq::Error::from(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Injected fault: timed out",
+            ))
+            .into(),
+            Fault::Malformed => anyhow::anyhow!("Injected fault: malformed response"),
+            Fault::ServerError => {
+                match ureq::Response::new(503, "Service Unavailable", "Injected fault") {
+                    Ok(response) => ureq::Error::Status(503, response).into(),
+                    Err(err) => err.into(),
+                }
+            }
+        }
+    }
+}
+
+/// Injects faults with probability `rate`, from a pseudo random sequence starting at `seed`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chaos {
+    pub rate: f64,
+    state: u64,
+}
+
+impl Chaos {
+    pub fn new(rate: f64, seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+                ^ u64::from(std::process::id())
+        });
+        Chaos {
+            rate,
+            // xorshift gets stuck at 0
+            state: seed.max(1),
+        }
+    }
+
+    /// Uniformly distributed in `[0, 1)`.
+    fn next(&mut self) -> f64 {
+        // xorshift64*
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// The fault to inject into the next call, if any.
+    pub fn fault(&mut self) -> Option<Fault> {
+        if self.next() >= self.rate {
+            return None;
+        }
+        Some(match (self.next() * 3.0) as u8 {
+            0 => Fault::Timeout,
+            1 => Fault::Malformed,
+            _ => Fault::ServerError,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fault() {
+        let faults = |rate| {
+            let mut chaos = Chaos::new(rate, Some(42));
+            (0..1000).filter_map(|_| chaos.fault()).collect::<Vec<_>>()
+        };
+        assert!(faults(0.0).is_empty());
+        assert_eq!(faults(1.0).len(), 1000);
+        let some = faults(0.2);
+        assert!((150..250).contains(&some.len()), "{}", some.len());
+        for fault in [Fault::Timeout, Fault::Malformed, Fault::ServerError] {
+            assert!(some.contains(&fault));
+        }
+        assert_eq!(some, faults(0.2));
+        assert!(Fault::Timeout.error().is::<ureq::Error>());
+        assert!(!Fault::Malformed.error().is::<ureq::Error>());
+    }
+}
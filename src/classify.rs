@@ -0,0 +1,45 @@
+//! Reclassification of tags as fields and vice versa, applied when publishing.
+use crate::{Field, PublishData};
+use std::collections::BTreeMap;
+
+#[derive(
+    serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone, Copy,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Class {
+    /// Indexed, e.g. a string status to group by
+    Tag,
+    /// Un-indexed, e.g. a serial number, to reduce cardinality
+    Field,
+}
+
+/// Publishes the tags and fields named in `classes` as the given class.
+pub fn apply(data: &mut PublishData, classes: &BTreeMap<String, Class>) {
+    data.fields = std::mem::take(&mut data.fields)
+        .into_iter()
+        .map(|f| match (classes.get(f.name()), f) {
+            (Some(Class::Tag), Field::Field(name, value)) => Field::Tag(name, value),
+            (Some(Class::Field), Field::Tag(name, value)) => Field::Field(name, value),
+            (_, f) => f,
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    #[test]
+    fn test_classify() {
+        let classes = serde_json::from_str(r#"{"device": "field", "status": "tag"}"#).unwrap();
+        let mut data = PublishData::default();
+        data.tag("device", "1234".to_string());
+        data.field("status", "ok".to_string());
+        data.field("currentPower", 200.0);
+        apply(&mut data, &classes);
+        assert!(matches!(&data.fields()[0], Field::Field(name, _) if name == "device"));
+        assert!(matches!(&data.fields()[1], Field::Tag(name, _) if name == "status"));
+        assert_eq!(data["currentPower"], Value::F64(200.0));
+    }
+}
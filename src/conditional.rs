@@ -0,0 +1,112 @@
+//! Conditional requests (`If-None-Match` and `If-Modified-Since`), for sources on slow or metered
+//! links: when the server answers an unchanged response with `304 Not Modified`, nothing is
+//! transferred or parsed again and the previous reading is repeated.
+
+/// Validators of the last response, and what was parsed from it.
+#[derive(Default)]
+pub struct Conditional<T> {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    previous: Option<T>,
+    not_modified: bool,
+}
+
+impl<T> PartialEq for Conditional<T> {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl<T> std::fmt::Debug for Conditional<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Conditional")
+    }
+}
+
+impl<T: Clone> Conditional<T> {
+    /// Sends `request` with the validators of the last response, and parses the body with
+    /// `parse` unless it is unchanged. Failing to parse forgets the response, so the next
+    /// request fetches it again.
+    pub fn call(
+        &mut self,
+        mut request: ureq::Request,
+        parse: impl FnOnce(&str) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        if self.previous.is_some() {
+            if let Some(etag) = &self.etag {
+                request = request.set("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &self.last_modified {
+                request = request.set("If-Modified-Since", last_modified);
+            }
+        }
+        let response = request.call()?;
+        self.not_modified = response.status() == 304;
+        if self.not_modified {
+            if let Some(previous) = &self.previous {
+                return Ok(previous.clone());
+            }
+        }
+        self.etag = response.header("ETag").map(str::to_string);
+        self.last_modified = response.header("Last-Modified").map(str::to_string);
+        self.previous = None;
+        let parsed = parse(&response.into_string()?)?;
+        self.previous = Some(parsed.clone());
+        Ok(parsed)
+    }
+
+    /// Whether the last response was unchanged, and the previous reading repeated.
+    pub fn not_modified(&self) -> bool {
+        self.not_modified
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_conditional() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/status", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let stream = stream.unwrap();
+                let mut conditional = false;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    conditional |= line.eq_ignore_ascii_case("if-none-match: \"v1\"");
+                }
+                let response = match (i, conditional) {
+                    (0, _) => "200 OK\r\nETag: \"v1\"\r\nContent-Length: 2\r\n\r\n42",
+                    (1, true) => "304 Not Modified\r\nContent-Length: 0\r\n\r\n",
+                    _ => "200 OK\r\nContent-Length: 2\r\n\r\n43",
+                };
+                (&stream)
+                    .write_all(format!("HTTP/1.1 {response}").as_bytes())
+                    .unwrap();
+            }
+        });
+        let agent = ureq::AgentBuilder::new().max_idle_connections(0).build();
+        let mut cache = Conditional::default();
+        let mut parses = 0;
+        let mut poll = |cache: &mut Conditional<i64>| {
+            cache.call(agent.get(&url), |body| {
+                parses += 1;
+                Ok(body.parse()?)
+            })
+        };
+        assert_eq!(poll(&mut cache).unwrap(), 42);
+        assert!(!cache.not_modified());
+        assert_eq!(poll(&mut cache).unwrap(), 42);
+        assert!(cache.not_modified());
+        assert_eq!(poll(&mut cache).unwrap(), 43);
+        assert!(!cache.not_modified());
+        assert_eq!(parses, 2);
+    }
+}
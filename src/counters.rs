@@ -0,0 +1,435 @@
+//! Processing of readings that depends on previous polls, like rates of energy counters.
+use crate::quality::Quality;
+use crate::PublishData;
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, Utc};
+use chrono_tz::Tz;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+/// Detects resets of counters that should only ever increase, e.g. after firmware updates.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CounterReset {
+    /// Fields holding monotonic counters
+    pub fields: Vec<String>,
+    /// Keep the counters monotonic by adding the value before each reset
+    #[serde(default)]
+    pub correct: bool,
+    /// Decreases up to this amount are considered jitter and not a reset
+    #[serde(default)]
+    pub tolerance: f64,
+}
+
+/// Last raw value and accumulated correction, per counter field.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+pub struct CounterResetState {
+    counters: BTreeMap<String, (f64, f64)>,
+}
+
+impl CounterReset {
+    /// Adds a `counterReset` field to readings where a counter decreased, and corrects the
+    /// counters if configured.
+    pub fn apply(&self, data: &mut PublishData, state: &mut CounterResetState) {
+        let mut reset = false;
+        for name in &self.fields {
+            let Some(value) = data.field_mut(name) else {
+                continue;
+            };
+            let Some(raw) = value.as_f64() else {
+                continue;
+            };
+            let (last, offset) = state.counters.entry(name.clone()).or_insert((raw, 0.0));
+            if raw < *last - self.tolerance {
+                reset = true;
+                *offset += *last;
+            }
+            *last = raw;
+            if self.correct {
+                *value = (raw + *offset).into();
+            }
+        }
+        if reset {
+            data.field("counterReset", true);
+        }
+    }
+}
+
+/// Sums up the increments of counters, each weighted with the rate in effect when it was read. A
+/// decreasing counter (like a daily one at midnight) starts over.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+pub struct Weighted {
+    /// Last counter value and weighted sum, per counter field
+    counters: BTreeMap<String, (f64, f64)>,
+}
+
+impl Weighted {
+    /// Adds the increment of counter `name` since the last call, returns the weighted sum.
+    pub fn add(&mut self, name: &str, value: f64, rate: f64) -> f64 {
+        let (last, sum) = self
+            .counters
+            .entry(name.to_string())
+            .or_insert((value, value * rate));
+        if value < *last {
+            *sum = value * rate;
+        } else {
+            *sum += (value - *last) * rate;
+        }
+        *last = value;
+        *sum
+    }
+}
+
+/// Derives a rate (e.g. power) from the change of a counter (e.g. energy) between two polls.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Rate {
+    /// Field holding the counter
+    pub counter: String,
+    /// Name of the computed field
+    #[serde(default = "Rate::default_field")]
+    pub field: String,
+    /// Factor applied to the change per second, the default converts kWh to W
+    #[serde(default = "Rate::default_scale")]
+    pub scale: f64,
+    /// No rate is computed over a longer gap between two samples
+    #[serde(default, with = "crate::duration::option")]
+    #[schemars(with = "Option<crate::duration::Schema>")]
+    pub max_interval: Option<Duration>,
+}
+
+/// Counter value and time of the last poll, per counter field.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+pub struct RateState {
+    samples: BTreeMap<String, (f64, SystemTime)>,
+}
+
+impl Rate {
+    fn default_field() -> String {
+        "computedPower".to_string()
+    }
+
+    fn default_scale() -> f64 {
+        3_600_000.0
+    }
+
+    /// Adds the rate field, unless this is the first sample, the counter was reset or the
+    /// samples are too far apart.
+    pub fn apply(&self, data: &mut PublishData, state: &mut RateState, now: SystemTime) {
+        let Some(counter) = data.number(&self.counter) else {
+            return;
+        };
+        if let Some((previous, at)) = state.samples.insert(self.counter.clone(), (counter, now)) {
+            let Ok(elapsed) = now.duration_since(at) else {
+                return;
+            };
+            if elapsed.is_zero()
+                || counter < previous
+                || self.max_interval.is_some_and(|max| elapsed > max)
+            {
+                return;
+            }
+            data.field(
+                &self.field,
+                (counter - previous) / elapsed.as_secs_f64() * self.scale,
+            );
+            data.flag(Quality::Estimated);
+        }
+    }
+}
+
+/// Integrates a power reading into energy counters, for devices that only report power.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Integration {
+    /// Field holding the power
+    pub power: String,
+    /// Name of the field with the energy of the current day
+    #[serde(default = "Integration::default_today_field")]
+    pub today_field: String,
+    /// Name of the field with the total energy
+    #[serde(default = "Integration::default_total_field")]
+    pub total_field: String,
+    /// Factor converting power times seconds into energy, the default converts W into kWh
+    #[serde(default = "Integration::default_scale")]
+    pub scale: f64,
+    /// Longer gaps between two samples are not integrated
+    #[serde(default, with = "crate::duration::option")]
+    #[schemars(with = "Option<crate::duration::Schema>")]
+    pub max_interval: Option<Duration>,
+    /// Time zone the day starts in, defaults to the local time zone
+    #[serde(default)]
+    pub timezone: Option<Timezone>,
+}
+
+/// Integrated energy and the last power sample.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct IntegrationState {
+    last: Option<(f64, SystemTime)>,
+    day: Option<NaiveDate>,
+    today: f64,
+    total: f64,
+    /// Whether an interval was integrated, the counters aren't published before
+    integrated: bool,
+}
+
+impl Integration {
+    fn default_today_field() -> String {
+        "energyToday".to_string()
+    }
+
+    fn default_total_field() -> String {
+        "energyTotal".to_string()
+    }
+
+    fn default_scale() -> f64 {
+        1.0 / 3_600_000.0
+    }
+
+    /// Adds the energy since the last sample (using the trapezoidal rule) and publishes the
+    /// counters, once they have been integrated over an interval.
+    pub fn apply(&self, data: &mut PublishData, state: &mut IntegrationState, now: SystemTime) {
+        let Some(power) = data.number(&self.power) else {
+            return;
+        };
+        let day = Timezone::date(self.timezone, now);
+        if state.day != Some(day) {
+            state.day = Some(day);
+            state.today = 0.0;
+        }
+        if let Some((last_power, at)) = state.last {
+            if let Ok(elapsed) = now.duration_since(at) {
+                if self.max_interval.is_none_or(|max| elapsed <= max) {
+                    let energy = (last_power + power) / 2.0 * elapsed.as_secs_f64() * self.scale;
+                    state.today += energy;
+                    state.total += energy;
+                    state.integrated = true;
+                }
+            }
+        }
+        state.last = Some((power, now));
+        if !state.integrated {
+            return;
+        }
+        data.field(&self.today_field, state.today);
+        data.field(&self.total_field, state.total);
+        data.flag(Quality::Estimated);
+    }
+}
+
+/// Detects the daily reset of a "today" counter and optionally recomputes it from a total counter,
+/// for devices resetting at a different time than midnight in the configured time zone.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DailyYield {
+    /// Field holding the energy of the current day
+    #[serde(default = "DailyYield::default_today")]
+    pub today: String,
+    /// Field holding the total energy
+    #[serde(default = "DailyYield::default_total")]
+    pub total: String,
+    /// Time zone the day starts in, defaults to the local time zone
+    #[serde(default)]
+    pub timezone: Option<Timezone>,
+    /// Replace `today` with the change of `total` since the start of the day
+    #[serde(default)]
+    pub recompute: bool,
+}
+
+/// A time zone name from the tz database, like `Europe/Berlin`.
+#[derive(serde::Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(try_from = "String")]
+pub struct Timezone(pub Tz);
+
+crate::string_schema!(
+    Timezone,
+    "A time zone of the tz database, like `Europe/Berlin`"
+);
+
+impl TryFrom<String> for Timezone {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse()
+            .map(Timezone)
+            .map_err(|_| format!("Unknown time zone '{name}'"))
+    }
+}
+
+impl serde::Serialize for Timezone {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.name())
+    }
+}
+
+impl Timezone {
+    /// Calendar date of `time` in this time zone, or the local one if `None`.
+    pub fn date(timezone: Option<Timezone>, time: SystemTime) -> NaiveDate {
+        match timezone {
+            Some(Timezone(tz)) => DateTime::<Utc>::from(time).with_timezone(&tz).date_naive(),
+            None => DateTime::<Local>::from(time).date_naive(),
+        }
+    }
+
+    /// Time of day of `time` in this time zone, or the local one if `None`.
+    pub fn time(timezone: Option<Timezone>, time: SystemTime) -> NaiveTime {
+        match timezone {
+            Some(Timezone(tz)) => DateTime::<Utc>::from(time).with_timezone(&tz).time(),
+            None => DateTime::<Local>::from(time).time(),
+        }
+    }
+}
+
+/// Total at the start of the day (if known) and the last value of the "today" counter.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+pub struct DailyYieldState {
+    day: Option<NaiveDate>,
+    day_start_total: Option<f64>,
+    last_today: Option<f64>,
+}
+
+impl DailyYield {
+    fn default_today() -> String {
+        "yieldToday".to_string()
+    }
+
+    fn default_total() -> String {
+        "totalYield".to_string()
+    }
+
+    /// Marks readings where the device reset its "today" counter with a `<today>Rollover` field,
+    /// and replaces the "today" counter if configured.
+    pub fn apply(&self, data: &mut PublishData, state: &mut DailyYieldState, now: SystemTime) {
+        if let Some(today) = data.number(&self.today) {
+            if state.last_today.is_some_and(|last| today < last) {
+                data.field(format!("{}Rollover", self.today), true);
+            }
+            state.last_today = Some(today);
+        }
+        if let Some(total) = data.number(&self.total) {
+            let day = Timezone::date(self.timezone, now);
+            if state.day != Some(day) {
+                // The first reading may be from any time of the day, so it only gives the start
+                // if the device reports what it produced today
+                state.day_start_total = match (state.day, data.number(&self.today)) {
+                    (Some(_), _) => Some(total),
+                    (None, Some(today)) => Some(total - today),
+                    (None, None) => None,
+                };
+                state.day = Some(day);
+            }
+            if let (true, Some(day_start_total)) = (self.recompute, state.day_start_total) {
+                let recomputed = (total - day_start_total).max(0.0);
+                match data.field_mut(&self.today) {
+                    Some(value) => *value = recomputed.into(),
+                    None => data.field(&self.today, recomputed),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate() {
+        let rate: Rate =
+            serde_json::from_str(r#"{"counter": "totalYield", "maxInterval": "10m"}"#).unwrap();
+        let mut state = RateState::default();
+        let start = SystemTime::now();
+        let poll = |total: f64, after: u64, state: &mut RateState| {
+            let mut data = PublishData::default();
+            data.field("totalYield", total);
+            rate.apply(&mut data, state, start + Duration::from_secs(after));
+            data.number("computedPower")
+        };
+        assert_eq!(poll(100.0, 0, &mut state), None);
+        // 0.01 kWh in 1 minute is 600 W
+        assert_eq!(poll(100.01, 60, &mut state).map(f64::round), Some(600.0));
+        // Irregular interval: 0.01 kWh in 2 minutes
+        assert_eq!(poll(100.02, 180, &mut state).map(f64::round), Some(300.0));
+        // Counter reset
+        assert_eq!(poll(0.0, 240, &mut state), None);
+        // Gap too long
+        assert_eq!(poll(1.0, 1240, &mut state), None);
+        assert_eq!(poll(1.01, 1300, &mut state).map(f64::round), Some(600.0));
+    }
+
+    #[test]
+    fn test_integration() {
+        let integration: Integration =
+            serde_json::from_str(r#"{"power": "currentPower", "maxInterval": "5m"}"#).unwrap();
+        let mut state = IntegrationState::default();
+        let start = SystemTime::now();
+        let poll = |power: f64, after: u64, state: &mut IntegrationState| {
+            let mut data = PublishData::default();
+            data.field("currentPower", power);
+            integration.apply(&mut data, state, start + Duration::from_secs(after));
+            data.number("energyTotal")
+                .map(|total| (total * 1000.0).round() / 1000.0)
+        };
+        assert_eq!(poll(1000.0, 0, &mut state), None);
+        // One hour at 1kW
+        assert_eq!(poll(1000.0, 3600, &mut state), None);
+        let mut state = IntegrationState::default();
+        assert_eq!(poll(1000.0, 0, &mut state), None);
+        // 3 minutes ramping from 1kW to 3kW: 2kW * 0.05h
+        assert_eq!(poll(3000.0, 180, &mut state), Some(0.1));
+        assert_eq!(poll(3000.0, 360, &mut state), Some(0.25));
+        // Gaps are skipped, the counters are still known
+        assert_eq!(poll(3000.0, 3600, &mut state), Some(0.25));
+    }
+
+    #[test]
+    fn test_daily_yield() {
+        let daily: DailyYield =
+            serde_json::from_str(r#"{"timezone": "Europe/Berlin", "recompute": true}"#).unwrap();
+        let mut state = DailyYieldState::default();
+        // 2023-06-01 21:00 UTC is 23:00 in Berlin
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_685_653_200);
+        let poll = |today: f64, total: f64, after: u64, state: &mut DailyYieldState| {
+            let mut data = PublishData::default();
+            data.field("yieldToday", today);
+            data.field("totalYield", total);
+            daily.apply(&mut data, state, start + Duration::from_secs(after));
+            (data.number("yieldToday").unwrap(), data.fields().len() == 3)
+        };
+        assert_eq!(poll(5.0, 100.0, 0, &mut state), (5.0, false));
+        assert_eq!(poll(5.5, 100.5, 1800, &mut state), (5.5, false));
+        // Midnight in Berlin, the device hasn't reset yet
+        assert_eq!(poll(6.0, 101.0, 3600, &mut state), (0.0, false));
+        assert_eq!(poll(6.5, 101.5, 5400, &mut state), (0.5, false));
+        // The device resets an hour later
+        assert_eq!(poll(0.0, 102.0, 7200, &mut state), (1.0, true));
+
+        // Without the device's own counter, the start of the day is only known the next day
+        let mut state = DailyYieldState::default();
+        let mut data = PublishData::default();
+        data.field("totalYield", 100.0);
+        daily.apply(&mut data, &mut state, start);
+        assert_eq!(data.number("yieldToday"), None);
+        let mut data = PublishData::default();
+        data.field("totalYield", 101.0);
+        daily.apply(&mut data, &mut state, start + Duration::from_secs(3600));
+        assert_eq!(data.number("yieldToday"), Some(0.0));
+    }
+
+    #[test]
+    fn test_counter_reset() {
+        let reset: CounterReset =
+            serde_json::from_str(r#"{"fields": ["totalYield"], "correct": true}"#).unwrap();
+        let mut state = CounterResetState::default();
+        let poll = |total: f64, state: &mut CounterResetState| {
+            let mut data = PublishData::default();
+            data.field("totalYield", total);
+            reset.apply(&mut data, state);
+            (data.number("totalYield").unwrap(), data.fields().len() == 2)
+        };
+        assert_eq!(poll(1000.0, &mut state), (1000.0, false));
+        assert_eq!(poll(1010.0, &mut state), (1010.0, false));
+        assert_eq!(poll(0.5, &mut state), (1010.5, true));
+        assert_eq!(poll(2.0, &mut state), (1012.0, false));
+    }
+}
@@ -0,0 +1,250 @@
+//! CSV target, appending the readings to local files as a plain archive, independent of any
+//! database. The columns are given by the header of each file, written with its first row and
+//! extended (rewriting the file) when a reading has new fields.
+use crate::{template, PublishData, Target, Value};
+use anyhow::Context;
+use std::borrow::Cow;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendCsv {
+    /// File to append to, may be templated from the tags and fields like
+    /// `/var/lib/solar/{deviceName}.csv`
+    pub path: String,
+    /// Starts a new file every day, with the date before the extension (`solar-2024-06-01.csv`)
+    #[serde(default)]
+    pub rotate: Rotate,
+}
+
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotate {
+    #[default]
+    Never,
+    /// By the local date
+    Daily,
+}
+
+impl Target for BackendCsv {
+    fn id(&self) -> Cow<'_, str> {
+        (&self.path).into()
+    }
+
+    fn publish(&self, data: &PublishData) -> anyhow::Result<()> {
+        let time = data.timestamp().unwrap_or_else(SystemTime::now);
+        let path = self.file(data, time)?;
+        let header =
+            read_header(&path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+        let row: Vec<(&str, String)> = data
+            .fields()
+            .iter()
+            .map(|field| (field.name(), text(field.value())))
+            .collect();
+        let mut out = String::new();
+        let columns = match header {
+            Some(mut columns) => {
+                let added: Vec<_> = row
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .filter(|name| !columns.iter().any(|column| column == name))
+                    .map(str::to_string)
+                    .collect();
+                if !added.is_empty() {
+                    add_columns(&path, &added).with_context(|| {
+                        format!("Failed to add columns to '{}'", path.display())
+                    })?;
+                    columns.extend(added);
+                }
+                columns
+            }
+            None => {
+                let columns: Vec<_> = std::iter::once("time")
+                    .chain(row.iter().map(|(name, _)| *name))
+                    .map(str::to_string)
+                    .collect();
+                out += &line(columns.iter().map(String::as_str));
+                columns
+            }
+        };
+        let time = text(&Value::Timestamp(time));
+        out += &line(columns.iter().map(|column| {
+            match column.as_str() {
+                "time" => time.as_str(),
+                column => row
+                    .iter()
+                    .find(|(name, _)| *name == column)
+                    .map_or("", |(_, value)| value.as_str()),
+            }
+        }));
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(out.as_bytes()))
+            .with_context(|| format!("Failed to write '{}'", path.display()))
+    }
+}
+
+impl BackendCsv {
+    /// The file of a reading taken at `time`.
+    fn file(&self, data: &PublishData, time: SystemTime) -> anyhow::Result<PathBuf> {
+        let path = PathBuf::from(template::render(&self.path, data)?);
+        if self.rotate == Rotate::Never {
+            return Ok(path);
+        }
+        let date = chrono::DateTime::<chrono::Local>::from(time).format("%Y-%m-%d");
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(extension) => format!("{stem}-{date}.{}", extension.to_string_lossy()),
+            None => format!("{stem}-{date}"),
+        };
+        Ok(path.with_file_name(name))
+    }
+}
+
+/// The columns of an existing file.
+fn read_header(path: &Path) -> std::io::Result<Option<Vec<String>>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut header = String::new();
+    BufReader::new(file).read_line(&mut header)?;
+    if header.trim().is_empty() {
+        return Ok(None);
+    }
+    // Names are quoted like values, but don't contain line breaks
+    let mut columns = vec![];
+    let mut column = String::new();
+    let mut quoted = false;
+    let mut chars = header.trim_end_matches(['\r', '\n']).chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                column.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => columns.push(std::mem::take(&mut column)),
+            c => column.push(c),
+        }
+    }
+    columns.push(column);
+    Ok(Some(columns))
+}
+
+/// Adds empty `columns` to the header and the rows of an existing file.
+fn add_columns(path: &Path, columns: &[String]) -> std::io::Result<()> {
+    let content = std::fs::read_to_string(path)?;
+    let (header, rows) = content.split_once('\n').unwrap_or((&content, ""));
+    let header = header.trim_end_matches('\r');
+    let padding = ",".repeat(columns.len());
+    let mut out = format!("{header},{}", line(columns.iter().map(String::as_str)));
+    // Values may contain line breaks, but only quoted
+    let mut quoted = false;
+    for c in rows.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '\n' if !quoted => {
+                let crlf = out.ends_with('\r');
+                if crlf {
+                    out.pop();
+                }
+                out += &padding;
+                if crlf {
+                    out.push('\r');
+                }
+            }
+            _ => {}
+        }
+        out.push(c);
+    }
+    // Written to a temporary file first, so a crash can't lose the rows
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, out)?;
+    std::fs::rename(tmp, path)
+}
+
+/// A row of values, quoted if needed.
+fn line<'a>(values: impl Iterator<Item = &'a str>) -> String {
+    let values: Vec<_> = values
+        .map(|value| {
+            if value.contains([',', '"', '\n', '\r']) {
+                Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+            } else {
+                Cow::Borrowed(value)
+            }
+        })
+        .collect();
+    values.join(",") + "\n"
+}
+
+/// A value as plain text, strings without quotes.
+fn text(value: &Value) -> String {
+    match value.to_json() {
+        serde_json::Value::String(s) => s,
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_csv() {
+        let directory = std::env::temp_dir().join(format!("csv-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let csv: BackendCsv = serde_json::from_value(serde_json::json!({
+            "path": directory.join("{deviceName}.csv"),
+        }))
+        .unwrap();
+        let mut data = PublishData::default();
+        data.tag("deviceName", "inverter".to_string());
+        data.field("currentPower", 344.5);
+        data.field("status", "say \"hi\", then \"bye\"".to_string());
+        data.set_timestamp(UNIX_EPOCH);
+        csv.publish(&data).unwrap();
+        // Columns missing from the header are added
+        let mut data = PublishData::default();
+        data.tag("deviceName", "inverter".to_string());
+        data.field("online", true);
+        data.field("currentPower", 12_i64);
+        data.set_timestamp(UNIX_EPOCH + Duration::from_secs(30));
+        csv.publish(&data).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(directory.join("inverter.csv")).unwrap(),
+            "time,deviceName,currentPower,status,online\n\
+             1970-01-01T00:00:00+00:00,inverter,344.5,\"say \"\"hi\"\", then \"\"bye\"\"\",\n\
+             1970-01-01T00:00:30+00:00,inverter,12,,true\n"
+        );
+
+        let csv = BackendCsv {
+            rotate: Rotate::Daily,
+            ..csv
+        };
+        let time = chrono::DateTime::parse_from_rfc3339("2024-06-01T12:00:00+00:00").unwrap();
+        let file = csv.file(&data, time.into()).unwrap();
+        let date = time.with_timezone(&chrono::Local).format("%Y-%m-%d");
+        assert_eq!(file, directory.join(format!("inverter-{date}.csv")));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+//! Web page showing the latest readings of every device, reloading itself every few seconds.
+use crate::live::{Device, Live};
+use std::fmt::Write;
+use std::time::SystemTime;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:1em;background:#f4f4f4}\
+    .device{background:#fff;border-radius:6px;padding:.5em 1em;margin-bottom:1em}\
+    .error{color:#b00}.ok{color:#080}td{padding:0 .5em}td.value{text-align:right}\
+    polyline{fill:none;stroke:#e80;stroke-width:1.5}";
+
+/// Values shown in the sparklines.
+const SPARKLINE_VALUES: usize = 120;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Inline SVG line of `values`, scaled to their range.
+fn sparkline(values: &[f64]) -> String {
+    const WIDTH: f64 = 120.0;
+    const HEIGHT: f64 = 20.0;
+    if values.len() < 2 {
+        return String::new();
+    }
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let range = if max > min { max - min } else { 1.0 };
+    let step = WIDTH / (values.len() - 1) as f64;
+    let points: Vec<_> = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let y = HEIGHT - 1.0 - (value - min) / range * (HEIGHT - 2.0);
+            format!("{:.1},{y:.1}", i as f64 * step)
+        })
+        .collect();
+    format!(
+        "<svg width=\"{WIDTH}\" height=\"{HEIGHT}\"><polyline points=\"{}\"/></svg>",
+        points.join(" ")
+    )
+}
+
+fn ago(time: Option<SystemTime>, now: SystemTime) -> String {
+    match time {
+        Some(time) => {
+            let seconds = now.duration_since(time).unwrap_or_default().as_secs();
+            match seconds {
+                0..60 => format!("{seconds}s ago"),
+                60..3600 => format!("{}m ago", seconds / 60),
+                _ => format!("{}h ago", seconds / 3600),
+            }
+        }
+        None => "never".to_string(),
+    }
+}
+
+fn device(html: &mut String, id: &str, device: &Device, now: SystemTime) -> std::fmt::Result {
+    write!(html, "<div class=\"device\"><h2>{}</h2>", escape(id))?;
+    match &device.error {
+        Some(error) => write!(
+            html,
+            "<p class=\"error\">Failed {}: {}. Last reading {}.</p>",
+            ago(device.polled, now),
+            escape(error),
+            ago(device.updated, now)
+        )?,
+        None => write!(
+            html,
+            "<p class=\"ok\">Polled {}</p>",
+            ago(device.polled, now)
+        )?,
+    }
+    html.push_str("<table>");
+    for (name, value) in &device.values {
+        let value = match value {
+            serde_json::Value::String(s) => escape(s),
+            serde_json::Value::Number(n) => match n.as_f64() {
+                Some(f) if n.is_f64() => format!("{f:.2}"),
+                _ => n.to_string(),
+            },
+            value => value.to_string(),
+        };
+        let history = device.last_values(name, SPARKLINE_VALUES);
+        write!(
+            html,
+            "<tr><td>{}</td><td class=\"value\">{value}</td><td>{}</td></tr>",
+            escape(name),
+            sparkline(&history)
+        )?;
+    }
+    html.push_str("</table></div>");
+    Ok(())
+}
+
+/// The page, reloading every `refresh` seconds.
+pub fn html(live: &Live, refresh: u64, now: SystemTime) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+        <meta http-equiv=\"refresh\" content=\"{refresh}\">\
+        <meta name=\"viewport\" content=\"width=device-width\">\
+        <title>{name}</title><style>{STYLE}</style></head><body><h1>{name}</h1>",
+        name = env!("CARGO_PKG_NAME")
+    );
+    if live.devices.is_empty() {
+        html.push_str("<p>No readings yet</p>");
+    }
+    for (id, state) in &live.devices {
+        device(&mut html, id, state, now).expect("writing to a string");
+    }
+    html.push_str("</body></html>");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_html() {
+        let now = SystemTime::now();
+        let mut live = Live::default();
+        live.devices.insert(
+            "<plug>".to_string(),
+            Device {
+                values: [("currentPower".to_string(), 344.5.into())].into(),
+                error: Some("Timed out".to_string()),
+                polled: Some(now - Duration::from_secs(5)),
+                updated: Some(now - Duration::from_secs(120)),
+                ..Default::default()
+            },
+        );
+        let html = html(&live, 30, now);
+        assert!(html.contains("<h2>&lt;plug&gt;</h2>"));
+        assert!(html.contains("Failed 5s ago: Timed out. Last reading 2m ago."));
+        assert!(html.contains("<td class=\"value\">344.50</td>"));
+        assert_eq!(
+            sparkline(&[0.0, 10.0, 5.0]),
+            "<svg width=\"120\" height=\"20\"><polyline points=\"0.0,19.0 60.0,1.0 120.0,10.0\"/></svg>"
+        );
+    }
+}
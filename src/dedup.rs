@@ -0,0 +1,128 @@
+//! Skips publishing values that did not change since they were last written.
+use crate::{Field, PublishData, Value};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Dedup {
+    /// Only skip whole readings, if none of their fields changed
+    #[serde(default)]
+    pub whole_point: bool,
+    /// Unchanged values are written again after this time, so gaps don't look like outages
+    #[serde(with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub max_age: Duration,
+}
+
+/// Last written value of each field.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+pub struct DedupState {
+    written: BTreeMap<String, (Value, SystemTime)>,
+    /// Values of the last reading, written once it was published
+    #[serde(skip)]
+    pending: Vec<(String, Value, SystemTime)>,
+}
+
+impl DedupState {
+    /// Remembers the values of the last reading as written, after it was published.
+    pub fn published(&mut self) {
+        for (name, value, at) in self.pending.drain(..) {
+            self.written.insert(name, (value, at));
+        }
+    }
+}
+
+impl Dedup {
+    /// Removes fields which are unchanged. If all fields are removed, the reading is not published.
+    /// The remaining values only count as written after [`DedupState::published`].
+    pub fn apply(&self, data: &mut PublishData, state: &mut DedupState, now: SystemTime) {
+        let is_fresh = |name: &str, value: &Value| match state.written.get(name) {
+            Some((written, at)) => {
+                written == value && now.duration_since(*at).unwrap_or_default() < self.max_age
+            }
+            None => false,
+        };
+        if self.whole_point {
+            if data.fields.iter().all(|f| match f {
+                Field::Tag(..) => true,
+                Field::Field(name, value) => is_fresh(name, value),
+            }) {
+                data.fields.retain(|f| matches!(f, Field::Tag(..)));
+            }
+        } else {
+            data.fields.retain(|f| match f {
+                Field::Tag(..) => true,
+                Field::Field(name, value) => !is_fresh(name, value),
+            });
+        }
+        state.pending = data
+            .fields
+            .iter()
+            .filter_map(|f| match f {
+                Field::Field(name, value) => Some((name.clone(), value.clone(), now)),
+                Field::Tag(..) => None,
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup() {
+        let dedup = Dedup {
+            whole_point: false,
+            max_age: Duration::from_secs(600),
+        };
+        let mut state = DedupState::default();
+        let start = SystemTime::now();
+        let reading = |power: f64| {
+            let mut data = PublishData::default();
+            data.tag("deviceName", "plug".to_string());
+            data.field("currentPower", power);
+            data.field("totalYield", 1000.0);
+            data
+        };
+
+        let mut data = reading(300.0);
+        dedup.apply(&mut data, &mut state, start);
+        assert_eq!(data.fields().len(), 3);
+        state.published();
+
+        let mut data = reading(310.0);
+        dedup.apply(&mut data, &mut state, start + Duration::from_secs(60));
+        let names: Vec<_> = data.fields().iter().map(Field::name).collect();
+        assert_eq!(names, ["deviceName", "currentPower"]);
+        state.published();
+
+        let mut data = reading(310.0);
+        dedup.apply(&mut data, &mut state, start + Duration::from_secs(600));
+        let names: Vec<_> = data.fields().iter().map(Field::name).collect();
+        assert_eq!(names, ["deviceName", "totalYield"]);
+        state.published();
+
+        // Not published, so written again
+        let mut data = reading(330.0);
+        dedup.apply(&mut data, &mut state, start + Duration::from_secs(610));
+        let mut data = reading(330.0);
+        dedup.apply(&mut data, &mut state, start + Duration::from_secs(620));
+        let names: Vec<_> = data.fields().iter().map(Field::name).collect();
+        assert_eq!(names, ["deviceName", "currentPower"]);
+        state.published();
+
+        let dedup = Dedup {
+            whole_point: true,
+            ..dedup
+        };
+        let mut data = reading(320.0);
+        dedup.apply(&mut data, &mut state, start + Duration::from_secs(660));
+        assert_eq!(data.fields().len(), 3);
+        state.published();
+        let mut data = reading(320.0);
+        dedup.apply(&mut data, &mut state, start + Duration::from_secs(720));
+        assert!(!data.has_fields());
+    }
+}
@@ -0,0 +1,249 @@
+//! Discovery of supported devices on the local network, by probing hosts answering mDNS or SSDP
+//! queries (over IPv4 and IPv6) and all hosts of the local IPv4 /24 subnet over HTTP.
+use crate::http;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Kinds of devices recognized by their HTTP responses.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Kind {
+    /// Deye SUNxxx micro inverter, requiring a login for its status page
+    Inverter,
+    Tasmota,
+    Shelly,
+    OpenDtu,
+    Fronius,
+}
+
+/// Paths requested to recognize the devices, in order.
+const PROBES: [&str; 5] = [
+    "/",
+    "/shelly",
+    "/api/system/status",
+    "/solar_api/GetAPIVersion.cgi",
+    "/status.html",
+];
+
+#[derive(Debug, PartialEq)]
+pub struct Found {
+    pub ip: IpAddr,
+    pub kind: Kind,
+}
+
+impl Found {
+    /// Source config for the device, or `None` if it is not supported as a source yet.
+    pub fn snippet(&self) -> Option<serde_json::Value> {
+        let name = format!("{:?} {}", self.kind, self.ip).to_lowercase();
+        let host = self.ip.to_string();
+        match self.kind {
+            Kind::Inverter => Some(serde_json::json!({
+                "type": "Inverter",
+                "statusPageUrl": format!("http://{}/status.html", http::url_host(&host)),
+                "user": "admin",
+                "password": "admin",
+                "device_name": name,
+            })),
+            Kind::Tasmota => Some(serde_json::json!({
+                "type": "Tasmota",
+                "host": host,
+                "device_name": name,
+            })),
+            Kind::Shelly | Kind::OpenDtu | Kind::Fronius => None,
+        }
+    }
+}
+
+/// Recognizes a device by the response to one of the [`PROBES`].
+fn fingerprint(path: &str, status: u16, body: &str) -> Option<Kind> {
+    let json = || serde_json::from_str::<serde_json::Value>(body).ok();
+    match (path, status) {
+        ("/", 200) if body.contains("Tasmota") => Some(Kind::Tasmota),
+        ("/shelly", 200) => json()
+            .filter(|json| {
+                json.get("mac").is_some()
+                    && (json.get("type").is_some() || json.get("gen").is_some())
+            })
+            .map(|_| Kind::Shelly),
+        ("/api/system/status", 200) => json()
+            .filter(|json| json.get("git_hash").is_some())
+            .map(|_| Kind::OpenDtu),
+        ("/solar_api/GetAPIVersion.cgi", 200) => json()
+            .filter(|json| json.get("APIVersion").is_some())
+            .map(|_| Kind::Fronius),
+        ("/status.html", 401) => Some(Kind::Inverter),
+        _ => None,
+    }
+}
+
+fn probe(ip: IpAddr, timeout: Duration) -> Option<Kind> {
+    TcpStream::connect_timeout(&SocketAddr::from((ip, 80)), timeout).ok()?;
+    let agent = ureq::AgentBuilder::new()
+        .timeout(timeout * 4)
+        .redirects(0)
+        .build();
+    let host = ip.to_string();
+    let host = http::url_host(&host);
+    PROBES.iter().find_map(|path| {
+        let (status, body) = match agent.get(&format!("http://{host}{path}")).call() {
+            Ok(response) => (
+                response.status(),
+                response.into_string().unwrap_or_default(),
+            ),
+            Err(ureq::Error::Status(status, response)) => {
+                (status, response.into_string().unwrap_or_default())
+            }
+            Err(_) => return None,
+        };
+        fingerprint(path, status, &body)
+    })
+}
+
+/// Local IPv4 address used for the default route.
+fn local_ip() -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    // Connecting a UDP socket doesn't send anything
+    socket.connect("192.0.2.1:80").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        std::net::IpAddr::V4(ip) => Some(ip),
+        std::net::IpAddr::V6(_) => None,
+    }
+}
+
+/// Hosts answering to a multicast query within `timeout`. IPv6 link-local addresses are skipped,
+/// they can't be used without the interface in URLs.
+fn multicast_responders(query: &[u8], group: SocketAddr, timeout: Duration) -> Vec<IpAddr> {
+    let any = match group {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let Ok(socket) = UdpSocket::bind(any) else {
+        return vec![];
+    };
+    if socket.send_to(query, group).is_err() {
+        return vec![];
+    }
+    let mut responders = vec![];
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0; 2048];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        if left.is_zero() || socket.set_read_timeout(Some(left)).is_err() {
+            break;
+        }
+        match socket.recv_from(&mut buffer) {
+            Ok((_, SocketAddr::V6(from))) if from.ip().is_unicast_link_local() => (),
+            Ok((_, from)) => responders.push(from.ip()),
+            Err(_) => break,
+        }
+    }
+    responders
+}
+
+/// mDNS query for HTTP services (`_http._tcp.local` PTR), asking for unicast responses.
+fn mdns_query() -> Vec<u8> {
+    let mut query = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in ["_http", "_tcp", "local"] {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // End of name, type PTR, class IN with the unicast-response bit
+    query.extend_from_slice(&[0, 0, 12, 0x80, 1]);
+    query
+}
+
+fn ssdp_query(group: SocketAddr) -> String {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {group}\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\n\
+        ST: ssdp:all\r\n\r\n"
+    )
+}
+
+/// Probes the hosts answering mDNS and SSDP queries, and all hosts of the /24 subnet of `local`
+/// (the address of the default route by default).
+pub fn discover(local: Option<Ipv4Addr>, timeout: Duration) -> Vec<Found> {
+    let mut hosts = BTreeSet::new();
+    let mdns: [IpAddr; 2] = [
+        Ipv4Addr::new(224, 0, 0, 251).into(),
+        Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb).into(),
+    ];
+    let ssdp: [IpAddr; 2] = [
+        Ipv4Addr::new(239, 255, 255, 250).into(),
+        Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc).into(),
+    ];
+    for group in mdns {
+        let group = SocketAddr::new(group, 5353);
+        hosts.extend(multicast_responders(&mdns_query(), group, timeout));
+    }
+    for group in ssdp {
+        let group = SocketAddr::new(group, 1900);
+        hosts.extend(multicast_responders(
+            ssdp_query(group).as_bytes(),
+            group,
+            timeout,
+        ));
+    }
+    if let Some(local) = local.or_else(local_ip) {
+        let [a, b, c, _] = local.octets();
+        hosts.extend((1..255).map(|d| IpAddr::from(Ipv4Addr::new(a, b, c, d))));
+    }
+    let hosts: Vec<_> = hosts.into_iter().collect();
+    let mut found: Vec<_> = std::thread::scope(|scope| {
+        let workers: Vec<_> = hosts
+            .chunks(8)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .filter_map(|ip| probe(*ip, timeout).map(|kind| Found { ip: *ip, kind }))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_default())
+            .collect()
+    });
+    found.sort_by_key(|found| found.ip);
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(
+            fingerprint("/", 200, "<title>Tasmota</title>"),
+            Some(Kind::Tasmota)
+        );
+        assert_eq!(
+            fingerprint(
+                "/shelly",
+                200,
+                r#"{"type":"SHPLG-S","mac":"AABBCC","fw":"1.0"}"#
+            ),
+            Some(Kind::Shelly)
+        );
+        assert_eq!(
+            fingerprint("/solar_api/GetAPIVersion.cgi", 200, r#"{"APIVersion":1}"#),
+            Some(Kind::Fronius)
+        );
+        assert_eq!(fingerprint("/status.html", 401, ""), Some(Kind::Inverter));
+        assert_eq!(fingerprint("/", 200, "<title>Router</title>"), None);
+        let found = Found {
+            ip: Ipv4Addr::new(192, 168, 1, 23).into(),
+            kind: Kind::Tasmota,
+        };
+        assert_eq!(found.snippet().unwrap()["host"], "192.168.1.23");
+        let found = Found {
+            ip: "2001:db8::23".parse().unwrap(),
+            kind: Kind::Inverter,
+        };
+        assert_eq!(
+            found.snippet().unwrap()["statusPageUrl"],
+            "http://[2001:db8::23]/status.html"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Serde support for durations given in seconds (`90`) or with a unit (`"30s"`, `"5m"`, `"1h"`, `"1d"`),
+//! use with `#[serde(with = "crate::duration")]`.
+use anyhow::Context;
+use serde::{Deserialize, Deserializer, Serializer};
+use std::time::Duration;
+
+pub fn parse(s: &str) -> anyhow::Result<Duration> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("Invalid duration '{s}'"))?;
+    let factor = match unit.trim() {
+        "" | "s" => 1.0,
+        "ms" => 0.001,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        "d" => 86400.0,
+        unit => anyhow::bail!("Unknown unit '{unit}' in duration '{s}'"),
+    };
+    Ok(Duration::from_secs_f64(number * factor))
+}
+
+/// Formats a duration with the largest unit giving a whole number, the inverse of [`parse`].
+pub fn format(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if millis == 0 || !millis.is_multiple_of(1000) {
+        return format!("{millis}ms");
+    }
+    let seconds = millis / 1000;
+    match [(86400, "d"), (3600, "h"), (60, "m")]
+        .into_iter()
+        .find(|(factor, _)| seconds.is_multiple_of(*factor))
+    {
+        Some((factor, unit)) => format!("{}{unit}", seconds / factor),
+        None => format!("{seconds}s"),
+    }
+}
+
+pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(*duration))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Seconds(f64),
+        Text(String),
+    }
+    match Raw::deserialize(deserializer)? {
+        Raw::Seconds(seconds) if seconds >= 0.0 => Ok(Duration::from_secs_f64(seconds)),
+        Raw::Seconds(seconds) => Err(serde::de::Error::custom(format!(
+            "Negative duration: {seconds}"
+        ))),
+        Raw::Text(text) => parse(&text).map_err(serde::de::Error::custom),
+    }
+}
+
+/// JSON schema of durations: `#[schemars(with = "crate::duration::Schema")]`
+pub struct Schema;
+
+impl schemars::JsonSchema for Schema {
+    fn schema_name() -> String {
+        "Duration".to_string()
+    }
+
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        serde_json::from_value(serde_json::json!({
+            "description": "Seconds, or a number with a unit (ms, s, m, h, d) like \"5m\"",
+            "anyOf": [
+                {"type": "number", "minimum": 0},
+                {"type": "string", "pattern": "^\\s*[0-9.]+\\s*(ms|s|m|min|h|d)?\\s*$"}
+            ]
+        }))
+        .expect("valid schema")
+    }
+}
+
+/// For optional durations: `#[serde(default, with = "crate::duration::option")]`
+pub mod option {
+    use serde::{Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => super::serialize(duration, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        super::deserialize(deserializer).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse("1.5m").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse("250ms").unwrap(), Duration::from_millis(250));
+        assert!(parse("5 fortnights").is_err());
+        for duration in ["250ms", "90s", "5m", "2h", "1d"] {
+            assert_eq!(format(parse(duration).unwrap()), duration);
+        }
+    }
+}
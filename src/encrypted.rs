@@ -0,0 +1,70 @@
+//! Secrets encrypted with age, either as values of the config (ASCII armored) or as whole config
+//! files encrypted with sops. They are decrypted with the `age` and `sops` tools, using the age
+//! identity (key file) given by `SG_AGE_IDENTITY`.
+use crate::process::run;
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const AGE_ARMOR: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+
+fn identity() -> Option<PathBuf> {
+    std::env::var_os("SG_AGE_IDENTITY").map(PathBuf::from)
+}
+
+/// Whether the string is an ASCII armored age ciphertext.
+pub fn is_age(s: &str) -> bool {
+    s.trim_start().starts_with(AGE_ARMOR)
+}
+
+pub fn decrypt_age(armored: &str) -> anyhow::Result<String> {
+    let identity =
+        identity().context("Found an age encrypted value, but no identity, set SG_AGE_IDENTITY")?;
+    run(
+        Command::new("age").arg("--decrypt").arg("-i").arg(identity),
+        Some(armored.trim()),
+    )
+    .context("Failed to decrypt an age encrypted value")
+}
+
+/// Whether the parsed config file is encrypted with sops, which adds its metadata as `sops`.
+pub fn is_sops(value: &serde_json::Value) -> bool {
+    value
+        .get("sops")
+        .is_some_and(|sops| sops.get("mac").is_some())
+}
+
+/// Decrypts a sops encrypted file to JSON.
+pub fn decrypt_sops(path: &Path) -> anyhow::Result<String> {
+    let mut command = Command::new("sops");
+    if let Some(identity) = identity() {
+        command.env("SOPS_AGE_KEY_FILE", identity);
+    }
+    run(
+        command
+            .args(["--decrypt", "--output-type", "json"])
+            .arg(path),
+        None,
+    )
+    .with_context(|| format!("Failed to decrypt {} with sops", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert!(is_age(
+            "-----BEGIN AGE ENCRYPTED FILE-----\nYWdl...\n-----END AGE ENCRYPTED FILE-----\n"
+        ));
+        assert!(!is_age("plain"));
+        let sops: serde_json::Value = serde_json::from_str(
+            r#"{"token": "ENC[AES256_GCM,data:abc,iv:def,tag:ghi,type:str]",
+                "sops": {"mac": "ENC[...]", "age": [{"recipient": "age1..."}]}}"#,
+        )
+        .unwrap();
+        assert!(is_sops(&sops));
+        assert!(!is_sops(&serde_json::json!({"sops": "not metadata"})));
+    }
+}
@@ -0,0 +1,283 @@
+//! Configuration from structured environment variables, for container deployments: one variable
+//! per setting like `SG_SOURCE_0_TYPE=Tasmota` and `SG_TARGET_0_URL=http://influx:8086`.
+use crate::Config;
+use std::collections::BTreeMap;
+
+/// Settings which keep their snake case name.
+const SNAKE_CASE: [&str; 2] = ["device_name", "device_location"];
+
+/// `STATUS_PAGE_URL` to `statusPageUrl`.
+fn key(name: &str) -> String {
+    let lower = name.to_ascii_lowercase();
+    if SNAKE_CASE.contains(&lower.as_str()) {
+        return lower;
+    }
+    let mut key = String::new();
+    for (i, word) in lower.split('_').enumerate() {
+        let mut chars = word.chars();
+        match chars.next() {
+            Some(first) if i > 0 => {
+                key.push(first.to_ascii_uppercase());
+                key.extend(chars);
+            }
+            _ => key.push_str(word),
+        }
+    }
+    key
+}
+
+/// `statusPageUrl` to `STATUS_PAGE_URL`.
+fn variable_suffix(key: &str) -> String {
+    let mut name = String::new();
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    name
+}
+
+/// Settings are strings, unless they look like JSON objects or arrays (e.g. for `tags`), numbers
+/// or booleans. Numbers and booleans given for string settings are turned back by
+/// [`restore_string`].
+fn value(variable: &str, value: &str) -> anyhow::Result<serde_json::Value> {
+    if value.starts_with(['{', '[']) {
+        serde_json::from_str(value)
+            .map_err(|err| anyhow::anyhow!("{variable}: Invalid JSON: {err}"))
+    } else {
+        match serde_json::from_str(value) {
+            Ok(value @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_))) => Ok(value),
+            _ => Ok(value.into()),
+        }
+    }
+}
+
+/// The text of the variables, by `sources` or `targets`, index and key.
+type Raw = BTreeMap<(&'static str, usize, String), String>;
+
+/// Collects the settings of `SG_<kind>_<index>_<NAME>`, by index.
+fn entries(
+    vars: &[(String, String)],
+    kind: &str,
+    raw: &mut Raw,
+) -> anyhow::Result<BTreeMap<usize, serde_json::Map<String, serde_json::Value>>> {
+    let list = match kind {
+        "TARGET" => "targets",
+        _ => "sources",
+    };
+    let prefix = format!("SG_{kind}_");
+    let mut entries: BTreeMap<usize, serde_json::Map<_, _>> = BTreeMap::new();
+    for (variable, content) in vars {
+        let Some(rest) = variable.strip_prefix(&prefix) else {
+            continue;
+        };
+        let Some((index, name)) = rest.split_once('_') else {
+            continue;
+        };
+        let Ok(index) = index.parse() else {
+            continue;
+        };
+        let mut key = key(name);
+        // `URL` is the address of any kind of device or target
+        if key == "url" {
+            key = match kind {
+                "TARGET" => "influxUrl",
+                _ => "address",
+            }
+            .to_string();
+        }
+        raw.insert((list, index, key.clone()), content.clone());
+        entries
+            .entry(index)
+            .or_default()
+            .insert(key, value(variable, content)?);
+    }
+    for (index, entry) in &mut entries {
+        if let Some(address) = entry.remove("address") {
+            let key = match entry.get("type").and_then(|t| t.as_str()) {
+                Some("Tasmota") => "host",
+                _ => "statusPageUrl",
+            };
+            entry.insert(key.to_string(), address);
+            if let Some(content) = raw.remove(&(list, *index, "address".to_string())) {
+                raw.insert((list, *index, key.to_string()), content);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Turns the number or boolean given for a string setting (according to `problem`) back into the
+/// text of its variable. Returns `false` if `problem` is about something else.
+fn restore_string(
+    value: &mut serde_json::Value,
+    problem: &str,
+    raw: &Raw,
+    indices: &[(&str, Vec<usize>)],
+) -> bool {
+    lazy_static::lazy_static! {
+        static ref EXPECTED_STRING: regex::Regex = regex::Regex::new(
+            r"^(sources|targets)\[(\d+)\](?:\.(\w+))?: invalid type: (?:integer|floating point|boolean) `([^`]*)`, expected a string"
+        )
+        .unwrap();
+    }
+    let Some(captures) = EXPECTED_STRING.captures(problem) else {
+        return false;
+    };
+    let (list, indices) = match &captures[1] {
+        "sources" => ("sources", indices[0].1.as_slice()),
+        _ => ("targets", indices[1].1.as_slice()),
+    };
+    let position: usize = captures[2].parse().unwrap_or_default();
+    let index = indices.get(position).copied().unwrap_or(position);
+    let Some(serde_json::Value::Object(entry)) = value[list].get_mut(position) else {
+        return false;
+    };
+    // The settings of the device type are flattened into the source, so the path may end there
+    let key = match captures.get(3) {
+        Some(key) => key.as_str().to_string(),
+        None => {
+            let given = |value: &serde_json::Value| match value {
+                serde_json::Value::String(_) => false,
+                value => value.to_string().as_str() == &captures[4],
+            };
+            match entry.iter().find(|(_, value)| given(value)) {
+                Some((key, _)) => key.clone(),
+                None => return false,
+            }
+        }
+    };
+    match (entry.get_mut(&key), raw.get(&(list, index, key))) {
+        (Some(value), Some(content)) if !value.is_string() => {
+            *value = content.clone().into();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Names the variable of a problem reported at `sources[1].statusPageUrl`, or for a missing
+/// setting of `sources[1]` like "missing field `statusPageUrl`".
+fn locate(problem: &str, indices: &[(&str, Vec<usize>)]) -> String {
+    lazy_static::lazy_static! {
+        static ref LOCATION: regex::Regex =
+            regex::Regex::new(r"^(sources|targets)\[(\d+)\](?:\.(\w+))?: (.*)$").unwrap();
+        static ref MISSING: regex::Regex = regex::Regex::new(r"missing field `(\w+)`").unwrap();
+    }
+    let Some(captures) = LOCATION.captures(problem) else {
+        return problem.to_string();
+    };
+    let (kind, list) = match &captures[1] {
+        "sources" => ("SOURCE", indices[0].1.as_slice()),
+        _ => ("TARGET", indices[1].1.as_slice()),
+    };
+    let position: usize = captures[2].parse().unwrap_or_default();
+    let index = list.get(position).copied().unwrap_or(position);
+    let message = &captures[4];
+    match captures.get(3) {
+        Some(key) => format!(
+            "SG_{kind}_{index}_{}: {message}",
+            variable_suffix(key.as_str())
+        ),
+        None => match MISSING.captures(message) {
+            Some(missing) => format!(
+                "SG_{kind}_{index}_{}: Not set",
+                variable_suffix(&missing[1])
+            ),
+            // The device type is the tag of the flattened enum
+            None if message.starts_with("unknown variant") => {
+                format!("SG_{kind}_{index}_TYPE: {message}")
+            }
+            None => format!("SG_{kind}_{index}_*: {message}"),
+        },
+    }
+}
+
+/// The config given by the environment variables, or `None` if there are none.
+pub fn load(vars: impl IntoIterator<Item = (String, String)>) -> anyhow::Result<Option<Config>> {
+    let vars: Vec<_> = vars.into_iter().collect();
+    let mut raw = Raw::new();
+    let sources = entries(&vars, "SOURCE", &mut raw)?;
+    let targets = entries(&vars, "TARGET", &mut raw)?;
+    if sources.is_empty() && targets.is_empty() {
+        return Ok(None);
+    }
+    let indices = [
+        ("sources", sources.keys().copied().collect()),
+        ("targets", targets.keys().copied().collect()),
+    ];
+    let mut value = serde_json::json!({
+        "sources": sources.into_values().collect::<Vec<_>>(),
+        "targets": targets.into_values().collect::<Vec<_>>(),
+    });
+    loop {
+        let err = match crate::config::from_value(value.clone()) {
+            Ok(config) => return Ok(Some(config)),
+            Err(err) => format!("{err:#}"),
+        };
+        if !restore_string(&mut value, &err, &raw, &indices) {
+            anyhow::bail!("{}", locate(&err, &indices));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let vars = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let config = load(vars(&[
+            ("SG_SOURCE_0_TYPE", "Tasmota"),
+            ("SG_SOURCE_0_URL", "192.168.1.23"),
+            ("SG_SOURCE_0_DEVICE_NAME", "heat pump"),
+            ("SG_SOURCE_0_TAGS", r#"{"room": "cellar"}"#),
+            ("SG_TARGET_0_URL", "http://influx:8086"),
+            ("SG_TARGET_0_ORG", "org"),
+            ("SG_TARGET_0_BUCKET", "solar"),
+            ("SG_TARGET_0_TOKEN", "token"),
+            ("SG_TARGET_0_MEASUREMENT", "power"),
+            ("PATH", "/bin"),
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.sources[0].tags["room"], "cellar");
+        assert_eq!(
+            config.targets[0].backend.influxdb().unwrap().influx_url,
+            "http://influx:8086"
+        );
+        let err = load(vars(&[
+            ("SG_SOURCE_3_TYPE", "Inverter"),
+            ("SG_SOURCE_3_USER", "admin"),
+            ("SG_SOURCE_3_PASSWORD", "admin"),
+            ("SG_SOURCE_3_DEVICE_NAME", "roof"),
+        ]))
+        .unwrap_err();
+        assert_eq!(err.to_string(), "SG_SOURCE_3_STATUS_PAGE_URL: Not set");
+        let config = load(vars(&[
+            ("SG_SOURCE_0_TYPE", "Tasmota"),
+            ("SG_SOURCE_0_URL", "192.168.1.23"),
+            ("SG_SOURCE_0_DEVICE_NAME", "1.50"),
+            ("SG_TARGET_0_URL", "http://influx:8086"),
+            ("SG_TARGET_0_API_VERSION", "1"),
+            ("SG_TARGET_0_DATABASE", "solar"),
+            ("SG_TARGET_0_BATCH", "true"),
+            ("SG_TARGET_0_MEASUREMENT", "power"),
+            ("SG_TARGET_0_PASSWORD", "1234"),
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(crate::Source::id(&config.sources[0]), "1.50");
+        let influxdb = config.targets[0].backend.influxdb().unwrap();
+        assert_eq!(influxdb.api_version, 1);
+        assert_eq!(influxdb.password.as_deref(), Some("1234"));
+        assert!(influxdb.batch);
+        assert!(load(vars(&[("PATH", "/bin")])).unwrap().is_none());
+    }
+}
@@ -0,0 +1,319 @@
+//! A small arithmetic expression language for computed fields, e.g. `production - export`.
+//!
+//! Supports numbers, field names, `+ - * /`, parentheses and the functions `min`, `max` and `abs`.
+//! Comparisons (`< <= > >= == !=`) evaluate to `1` if true and `0` otherwise.
+//! Field names containing other characters than letters, digits, `_` and `.` can be quoted with
+//! backticks: `` `the thing`.currentPower ``.
+use anyhow::{bail, ensure, Context};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(f64),
+    Field(String),
+    Neg(Box<Node>),
+    Binary(Box<Node>, char, Box<Node>),
+    Compare(Box<Node>, &'static str, Box<Node>),
+    Call(String, Vec<Node>),
+}
+
+/// A parsed expression, deserialized from its textual form.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+crate::string_schema!(
+    Expr,
+    "An expression over fields, like `currentPower * 2 - max(a, b)`"
+);
+
+impl Expr {
+    /// Evaluates the expression, resolving field names with `lookup`.
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<f64>) -> anyhow::Result<f64> {
+        Self::eval_node(&self.root, lookup)
+    }
+
+    fn eval_node(node: &Node, lookup: &dyn Fn(&str) -> Option<f64>) -> anyhow::Result<f64> {
+        Ok(match node {
+            Node::Number(n) => *n,
+            Node::Field(name) => lookup(name).with_context(|| format!("Unknown field '{name}'"))?,
+            Node::Neg(n) => -Self::eval_node(n, lookup)?,
+            Node::Binary(l, op, r) => {
+                let (l, r) = (Self::eval_node(l, lookup)?, Self::eval_node(r, lookup)?);
+                match op {
+                    '+' => l + r,
+                    '-' => l - r,
+                    '*' => l * r,
+                    '/' => l / r,
+                    _ => unreachable!(),
+                }
+            }
+            Node::Compare(l, op, r) => {
+                let (l, r) = (Self::eval_node(l, lookup)?, Self::eval_node(r, lookup)?);
+                let result = match *op {
+                    "<" => l < r,
+                    "<=" => l <= r,
+                    ">" => l > r,
+                    ">=" => l >= r,
+                    "==" => l == r,
+                    "!=" => l != r,
+                    _ => unreachable!(),
+                };
+                if result {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Node::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| Self::eval_node(a, lookup))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                match function.as_str() {
+                    "min" => args.into_iter().fold(f64::INFINITY, f64::min),
+                    "max" => args.into_iter().fold(f64::NEG_INFINITY, f64::max),
+                    "abs" => args[0].abs(),
+                    _ => unreachable!(),
+                }
+            }
+        })
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl serde::Serialize for Expr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl FromStr for Expr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            chars: s.chars().collect(),
+            pos: 0,
+        };
+        let root = parser.comparison()?;
+        parser.skip_whitespace();
+        ensure!(
+            parser.pos == parser.chars.len(),
+            "Unexpected '{}' at position {} in '{s}'",
+            parser.chars[parser.pos],
+            parser.pos
+        );
+        Ok(Self {
+            source: s.to_string(),
+            root,
+        })
+    }
+}
+
+impl TryFrom<String> for Expr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, expected: char) -> anyhow::Result<()> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(c) => bail!(
+                "Expected '{expected}' but got '{c}' at position {}",
+                self.pos
+            ),
+            None => bail!("Expected '{expected}' but the expression ended"),
+        }
+    }
+
+    // comparison := expr (('<' | '<=' | '>' | '>=' | '==' | '!=') expr)?
+    fn comparison(&mut self) -> anyhow::Result<Node> {
+        let node = self.expr()?;
+        self.skip_whitespace();
+        let rest: String = self.chars[self.pos..].iter().take(2).collect();
+        let op = ["<=", ">=", "==", "!=", "<", ">"]
+            .into_iter()
+            .find(|op| rest.starts_with(op));
+        match op {
+            Some(op) => {
+                self.pos += op.len();
+                Ok(Node::Compare(Box::new(node), op, Box::new(self.expr()?)))
+            }
+            None => Ok(node),
+        }
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> anyhow::Result<Node> {
+        let mut node = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            node = Node::Binary(Box::new(node), op, Box::new(self.term()?));
+        }
+        Ok(node)
+    }
+
+    // term := factor (('*' | '/') factor)*
+    fn term(&mut self) -> anyhow::Result<Node> {
+        let mut node = self.factor()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.pos += 1;
+            node = Node::Binary(Box::new(node), op, Box::new(self.factor()?));
+        }
+        Ok(node)
+    }
+
+    // factor := '-' factor | number | name | name '(' args ')' | '(' expr ')'
+    fn factor(&mut self) -> anyhow::Result<Node> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(Node::Neg(Box::new(self.factor()?)))
+            }
+            Some('(') => {
+                self.pos += 1;
+                let node = self.comparison()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_alphabetic() || c == '_' || c == '`' => {
+                let name = self.name()?;
+                if self.peek() == Some('(') {
+                    self.pos += 1;
+                    self.call(name)
+                } else {
+                    Ok(Node::Field(name))
+                }
+            }
+            Some(c) => bail!("Unexpected '{c}' at position {}", self.pos),
+            None => bail!("Unexpected end of expression"),
+        }
+    }
+
+    fn number(&mut self) -> anyhow::Result<Node> {
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+        {
+            self.pos += 1;
+        }
+        let number: String = self.chars[start..self.pos].iter().collect();
+        Ok(Node::Number(number.parse().with_context(|| {
+            format!("Invalid number '{number}' at position {start}")
+        })?))
+    }
+
+    fn name(&mut self) -> anyhow::Result<String> {
+        let mut name = String::new();
+        loop {
+            match self.chars.get(self.pos) {
+                Some('`') => {
+                    let start = self.pos;
+                    self.pos += 1;
+                    while let Some(&c) = self.chars.get(self.pos) {
+                        self.pos += 1;
+                        if c == '`' {
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    ensure!(
+                        self.chars[self.pos - 1] == '`' && self.pos - start > 1,
+                        "Unterminated '`' at position {start}"
+                    );
+                }
+                Some(&c) if c.is_alphanumeric() || c == '_' || c == '.' => {
+                    name.push(c);
+                    self.pos += 1;
+                }
+                _ => return Ok(name),
+            }
+        }
+    }
+
+    fn call(&mut self, function: String) -> anyhow::Result<Node> {
+        let mut args = vec![];
+        if self.peek() != Some(')') {
+            loop {
+                args.push(self.comparison()?);
+                if self.peek() != Some(',') {
+                    break;
+                }
+                self.pos += 1;
+            }
+        }
+        self.expect(')')?;
+        match (function.as_str(), args.len()) {
+            ("min" | "max", 1..) | ("abs", 1) => Ok(Node::Call(function, args)),
+            ("min" | "max" | "abs", _) => {
+                bail!("Wrong number of arguments for '{function}'")
+            }
+            _ => bail!("Unknown function '{function}'"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval() {
+        let lookup = |name: &str| match name {
+            "production" => Some(500.0),
+            "export" => Some(120.0),
+            "the thing.currentPower" => Some(10.0),
+            _ => None,
+        };
+        let eval = |s: &str| s.parse::<Expr>().unwrap().eval(&lookup).unwrap();
+        assert_eq!(eval("production - export"), 380.0);
+        assert_eq!(eval("-(production - export) * 2 + 1"), -759.0);
+        assert_eq!(eval("max(production - export, 0) / 1000"), 0.38);
+        assert_eq!(eval("`the thing`.currentPower * 1.5"), 15.0);
+        assert_eq!(eval("production >= 500"), 1.0);
+        assert_eq!(eval("export != 120"), 0.0);
+        assert!("production -".parse::<Expr>().is_err());
+        assert!("foo(1)".parse::<Expr>().is_err());
+        assert!("unknown + 1"
+            .parse::<Expr>()
+            .unwrap()
+            .eval(&lookup)
+            .is_err());
+    }
+}
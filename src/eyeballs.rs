@@ -0,0 +1,202 @@
+//! Happy Eyeballs (RFC 8305) for hosts resolving to several addresses, e.g. dual-stack devices
+//! on networks with broken IPv6: connection attempts start staggered, alternating between IPv6
+//! and IPv4, and the first to succeed is used. The winner of each host is then connected to
+//! directly (falling back to the others) for a while, without racing again, as ureq makes a
+//! connection of its own and small devices handle few at a time.
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Delay before the next address is tried while an attempt is still pending.
+const STAGGER: Duration = Duration::from_millis(250);
+
+/// Longest connection attempt to each address.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the winner of a host is connected to without racing again.
+const WINNER_TTL: Duration = Duration::from_secs(10 * 60);
+
+lazy_static::lazy_static! {
+    // Connections aren't kept between polls, small devices handle few at a time
+    static ref AGENT: ureq::Agent = builder().max_idle_connections(0).build();
+    static ref TARGET_AGENT: ureq::Agent = builder().build();
+}
+
+/// Builder of agents connecting this way, keeping idle connections for reuse.
+pub fn builder() -> ureq::AgentBuilder {
+    ureq::AgentBuilder::new().resolver(HappyEyeballs::default())
+}
+
+/// Agent for the requests to devices.
+pub fn agent() -> &'static ureq::Agent {
+    &AGENT
+}
+
+/// Agent for the requests to targets, reusing connections (and their TLS sessions) between
+/// writes.
+pub fn target_agent() -> &'static ureq::Agent {
+    &TARGET_AGENT
+}
+
+/// Resolver returning the address which connected first at the front, for ureq to connect to.
+#[derive(Default)]
+pub struct HappyEyeballs {
+    /// The winner of each host, and when it won
+    winners: Mutex<HashMap<String, (SocketAddr, Instant)>>,
+}
+
+/// `addrs` alternating between IPv6 and IPv4 (starting with IPv6 as in the RFC), and the last
+/// winner first.
+fn order(addrs: Vec<SocketAddr>, winner: Option<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    if let Some(position) = winner.and_then(|w| ordered.iter().position(|a| *a == w)) {
+        let winner = ordered.remove(position);
+        ordered.insert(0, winner);
+    }
+    ordered
+}
+
+/// The first of `addrs` accepting a connection, starting an attempt every `stagger` (or as soon
+/// as the previous one failed).
+fn race(addrs: &[SocketAddr], stagger: Duration, timeout: Duration) -> Option<SocketAddr> {
+    let (sender, receiver) = mpsc::channel();
+    let mut pending = 0;
+    for addr in addrs.iter().copied() {
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            // Nobody listens any more if another attempt succeeded first
+            let _ = sender.send((addr, TcpStream::connect_timeout(&addr, timeout).is_ok()));
+        });
+        pending += 1;
+        let deadline = Instant::now() + stagger;
+        loop {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok((addr, true)) => return Some(addr),
+                Ok((_, false)) => {
+                    pending -= 1;
+                    if pending == 0 {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+    drop(sender);
+    while pending > 0 {
+        match receiver.recv() {
+            Ok((addr, true)) => return Some(addr),
+            Ok((_, false)) => pending -= 1,
+            Err(_) => break,
+        }
+    }
+    None
+}
+
+impl HappyEyeballs {
+    /// The addresses of `netloc` in the order to connect to.
+    fn connect_order(&self, netloc: &str, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let winner = self
+            .winners
+            .lock()
+            .expect("not poisoned")
+            .get(netloc)
+            .copied();
+        if let Some((winner, at)) = winner {
+            // ureq tries the others if the winner doesn't connect any more
+            if at.elapsed() < WINNER_TTL && addrs.contains(&winner) {
+                return order(addrs, Some(winner));
+            }
+        }
+        let addrs = order(addrs, winner.map(|(winner, _)| winner));
+        match race(&addrs, STAGGER, CONNECT_TIMEOUT) {
+            Some(winner) => {
+                let mut winners = self.winners.lock().expect("not poisoned");
+                winners.insert(netloc.to_string(), (winner, Instant::now()));
+                order(addrs, Some(winner))
+            }
+            // ureq reports the errors connecting to them
+            None => addrs,
+        }
+    }
+}
+
+impl ureq::Resolver for HappyEyeballs {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<_> = netloc.to_socket_addrs()?.collect();
+        if addrs.len() < 2 {
+            return Ok(addrs);
+        }
+        Ok(self.connect_order(netloc, addrs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_race() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            order(
+                vec![
+                    addr("10.0.0.1:80"),
+                    addr("10.0.0.2:80"),
+                    addr("[fd00::1]:80")
+                ],
+                Some(addr("10.0.0.2:80"))
+            ),
+            [
+                addr("10.0.0.2:80"),
+                addr("[fd00::1]:80"),
+                addr("10.0.0.1:80")
+            ]
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        // Closed right away, refusing connections
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // Documentation address, never answering (or unreachable)
+        let blackhole = addr("192.0.2.1:80");
+        let start = Instant::now();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            race(&[closed, blackhole, open], STAGGER, timeout),
+            Some(open)
+        );
+        assert!(start.elapsed() < timeout);
+        assert_eq!(race(&[closed], STAGGER, timeout), None);
+    }
+
+    #[test]
+    fn test_known_winner() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let open = listener.local_addr().unwrap();
+        let blackhole: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        let eyeballs = HappyEyeballs::default();
+        let ordered = eyeballs.connect_order("device:80", vec![blackhole, open]);
+        assert_eq!(ordered, [open, blackhole]);
+        assert!(listener.accept().is_ok());
+        // The winner is known, so ureq's connection is the only one
+        let ordered = eyeballs.connect_order("device:80", vec![blackhole, open]);
+        assert_eq!(ordered, [open, blackhole]);
+        assert!(listener.accept().is_err());
+    }
+}
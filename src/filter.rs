@@ -0,0 +1,144 @@
+//! Include/exclude rules deciding which fields and readings get published.
+use crate::expr::Expr;
+use crate::{Field, PublishData};
+use regex::Regex;
+use std::fmt;
+
+/// Field filter, applied per source or per target.
+#[derive(
+    serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Default, Clone,
+)]
+pub struct Filter {
+    /// If given, only fields matching any of these patterns are kept
+    #[serde(default)]
+    pub include: Vec<Pattern>,
+    /// Fields matching any of these patterns are removed
+    #[serde(default)]
+    pub exclude: Vec<Pattern>,
+    /// Readings for which this evaluates to `0` are dropped
+    #[serde(default, rename = "where")]
+    pub condition: Option<Expr>,
+}
+
+/// A glob (`*` and `?`) or, if enclosed in slashes, a regular expression matching field names.
+#[derive(serde::Deserialize, Clone)]
+#[serde(try_from = "String")]
+pub struct Pattern {
+    source: String,
+    regex: Regex,
+}
+
+crate::string_schema!(
+    Pattern,
+    "A glob like `yield*`, or a regular expression like `/^diag_\\d+$/`"
+);
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty() && self.condition.is_none()
+    }
+
+    /// Removes filtered fields. Returns `false` if the whole reading should be dropped, either
+    /// because of the condition or because no fields are left. A condition referring to fields
+    /// the reading doesn't have is not met.
+    pub fn apply(&self, data: &mut PublishData) -> bool {
+        if let Some(condition) = &self.condition {
+            match condition.eval(&|name| data.number(name)) {
+                Ok(value) if value != 0.0 => {}
+                Ok(_) => return false,
+                Err(err) => {
+                    tracing::debug!("Dropping reading, 'where' can't be evaluated: {err}");
+                    return false;
+                }
+            }
+        }
+        data.fields.retain(|f| match f {
+            Field::Tag(..) => true,
+            Field::Field(name, _) => {
+                (self.include.is_empty() || self.include.iter().any(|p| p.matches(name)))
+                    && !self.exclude.iter().any(|p| p.matches(name))
+            }
+        });
+        data.has_fields()
+    }
+}
+
+impl Pattern {
+    pub fn matches(&self, name: &str) -> bool {
+        self.regex.is_match(name)
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let regex = match source.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+            Some(regex) => Regex::new(regex)?,
+            None => {
+                let mut regex = "^".to_string();
+                for c in source.chars() {
+                    match c {
+                        '*' => regex.push_str(".*"),
+                        '?' => regex.push('.'),
+                        c => regex.push_str(&regex::escape(&c.to_string())),
+                    }
+                }
+                regex.push('$');
+                Regex::new(&regex)?
+            }
+        };
+        Ok(Self { source, regex })
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl serde::Serialize for Pattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter: Filter = serde_json::from_str(
+            r#"{"exclude": ["diag*", "/^debug_\\d+$/"], "where": "currentPower > 0"}"#,
+        )
+        .unwrap();
+        let mut data = PublishData::default();
+        data.tag("deviceName", "plug".to_string());
+        data.field("currentPower", 344.0);
+        data.field("diagRssi", -60.0);
+        data.field("debug_1", 1.0);
+        data.field("debug_x", 1.0);
+        assert!(filter.apply(&mut data));
+        let names: Vec<_> = data.fields().iter().map(Field::name).collect();
+        assert_eq!(names, ["deviceName", "currentPower", "debug_x"]);
+
+        let filter: Filter = serde_json::from_str(r#"{"include": ["current?ower"]}"#).unwrap();
+        assert!(filter.apply(&mut data));
+        assert_eq!(data.fields().len(), 2);
+
+        *data.field_mut("currentPower").unwrap() = 0.0.into();
+        let filter: Filter = serde_json::from_str(r#"{"where": "currentPower > 0"}"#).unwrap();
+        assert!(!filter.apply(&mut data));
+        // Missing fields don't meet the condition
+        let filter: Filter = serde_json::from_str(r#"{"where": "voltage > 200"}"#).unwrap();
+        assert!(!filter.apply(&mut data));
+    }
+}
@@ -0,0 +1,159 @@
+//! Endpoints of the Grafana JSON datasource (SimpleJSON) protocol over the in-memory history,
+//! below `/grafana`. Metrics are named `<device>.<field>`, like the fields of virtual devices.
+use crate::http::{Request, Response};
+use crate::live::Live;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::time::SystemTime;
+
+#[derive(serde::Deserialize)]
+struct Range {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+}
+
+#[derive(serde::Deserialize)]
+struct Target {
+    target: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Query {
+    range: Range,
+    targets: Vec<Target>,
+    max_data_points: Option<usize>,
+}
+
+fn timestamp_millis(time: SystemTime) -> i64 {
+    DateTime::<Utc>::from(time).timestamp_millis()
+}
+
+/// All `<device>.<field>` with values in the history.
+fn metrics(live: &Live) -> Vec<String> {
+    let mut metrics: Vec<_> = live
+        .devices
+        .iter()
+        .flat_map(|(id, device)| {
+            device
+                .history
+                .iter()
+                .flat_map(|sample| sample.values.keys())
+                .map(move |field| format!("{id}.{field}"))
+        })
+        .collect();
+    metrics.sort();
+    metrics.dedup();
+    metrics
+}
+
+fn query(live: &Live, query: Query) -> serde_json::Value {
+    let (from, to) = (query.range.from.into(), query.range.to.into());
+    query
+        .targets
+        .iter()
+        .map(|target| {
+            let mut datapoints: Vec<_> = target
+                .target
+                .rsplit_once('.')
+                .and_then(|(id, field)| Some((live.devices.get(id)?, field)))
+                .map(|(device, field)| {
+                    device
+                        .range(from, to)
+                        .filter_map(|sample| {
+                            Some(json!([
+                                sample.values.get(field)?,
+                                timestamp_millis(sample.time)
+                            ]))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            if let Some(max) = query.max_data_points.filter(|max| *max > 0) {
+                // Thin out evenly instead of truncating the range
+                let step = datapoints.len().div_ceil(max).max(1);
+                datapoints = datapoints.into_iter().step_by(step).collect();
+            }
+            json!({"target": target.target, "datapoints": datapoints})
+        })
+        .collect()
+}
+
+fn json(value: serde_json::Value) -> Response {
+    Response::new("application/json", value.to_string())
+}
+
+/// Answers requests below `/grafana`.
+pub fn respond(live: &Live, request: &Request) -> Response {
+    let body = || -> anyhow::Result<Query> {
+        serde_json::from_slice(&request.body).context("Invalid query")
+    };
+    match request.path.trim_start_matches("/grafana") {
+        // Grafana checks the connection with this
+        "" | "/" => Response::new("text/plain", "OK\n"),
+        "/search" => json(metrics(live).into()),
+        "/query" => match body() {
+            Ok(body) => json(query(live, body)),
+            Err(err) => Response::error(400, &format!("{err:#}")),
+        },
+        "/annotations" => json(json!([])),
+        _ => Response::not_found(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::live::{Device, Sample};
+    use std::time::Duration;
+
+    #[test]
+    fn test_query() {
+        let sample = |seconds, power| Sample {
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(seconds),
+            values: [("currentPower".to_string(), power)].into(),
+        };
+        let mut live = Live::default();
+        live.devices.insert(
+            "heat.pump".to_string(),
+            Device {
+                history: [sample(10, 1.0), sample(20, 2.0), sample(30, 3.0)].into(),
+                ..Default::default()
+            },
+        );
+        let request = |path: &str, body: serde_json::Value| {
+            let response = respond(
+                &live,
+                &Request {
+                    method: "POST".to_string(),
+                    path: path.to_string(),
+                    query: None,
+                    body: body.to_string().into_bytes(),
+                },
+            );
+            let body = String::from_utf8(response.body).unwrap();
+            (response.status, serde_json::from_str(&body).ok())
+        };
+        assert_eq!(
+            request("/grafana/search", json!({"target": ""})),
+            (200, Some(json!(["heat.pump.currentPower"])))
+        );
+        let query = json!({
+            "range": {"from": "1970-01-01T00:00:15Z", "to": "1970-01-01T00:01:00.000Z"},
+            "targets": [{"target": "heat.pump.currentPower", "refId": "A"}, {"target": "bogus"}],
+            "maxDataPoints": 500,
+        });
+        assert_eq!(
+            request("/grafana/query", query),
+            (
+                200,
+                Some(json!([
+                    {"target": "heat.pump.currentPower", "datapoints": [[2.0, 20000], [3.0, 30000]]},
+                    {"target": "bogus", "datapoints": []},
+                ]))
+            )
+        );
+        assert_eq!(request("/grafana/query", json!({})).0, 400);
+    }
+}
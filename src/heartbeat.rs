@@ -0,0 +1,47 @@
+//! Dead man's switch: a ping after every fully successful cycle polling a source, so a monitor like
+//! healthchecks.io alerts when the pings stop.
+use std::time::Duration;
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+pub struct Heartbeat {
+    /// URL requested with GET, e.g. `https://hc-ping.com/<uuid>`
+    pub url: String,
+    #[serde(default = "Heartbeat::default_timeout", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub timeout: Duration,
+}
+
+impl Heartbeat {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub fn ping(&self) -> anyhow::Result<()> {
+        ureq::get(&self.url).timeout(self.timeout).call()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{serve, Response};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_ping() {
+        let pings = Arc::new(AtomicUsize::new(0));
+        let counted = pings.clone();
+        let addr = serve("127.0.0.1:0".parse().unwrap(), move |request| {
+            assert_eq!(request.path, "/ping/abc");
+            counted.fetch_add(1, Ordering::SeqCst);
+            Response::new("text/plain", "OK")
+        })
+        .unwrap();
+        let heartbeat: Heartbeat =
+            serde_json::from_str(&format!(r#"{{"url": "http://{addr}/ping/abc"}}"#)).unwrap();
+        heartbeat.ping().unwrap();
+        assert_eq!(pings.load(Ordering::SeqCst), 1);
+    }
+}
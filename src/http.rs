@@ -0,0 +1,310 @@
+//! Minimal HTTP/1.1 server for the local endpoints (e.g. `/metrics`), one thread per connection.
+use std::borrow::Cow;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// `host` (a name or an address, optionally with a port) as written in URLs, with IPv6
+/// addresses in brackets.
+pub fn url_host(host: &str) -> Cow<'_, str> {
+    match host.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{host}]").into(),
+        Err(_) => host.into(),
+    }
+}
+
+/// Largest accepted request body.
+const MAX_BODY: usize = 1 << 20;
+
+/// Interval of the comments keeping idle event streams (and proxies in between) alive.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query
+    pub path: String,
+    pub query: Option<String>,
+    /// Body of `POST` requests
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+    /// Server-Sent Events written after the body until the sender is dropped or the client
+    /// disconnects
+    pub events: Option<Receiver<Vec<u8>>>,
+}
+
+impl Response {
+    pub fn new(content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status: 200,
+            content_type,
+            body: body.into(),
+            events: None,
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Response::error(404, "Not found")
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: format!("{message}\n").into_bytes(),
+            events: None,
+        }
+    }
+
+    /// A `text/event-stream` of the events sent to `broadcast`.
+    pub fn events(broadcast: &Broadcast) -> Self {
+        Response {
+            events: Some(broadcast.subscribe()),
+            ..Response::new("text/event-stream", "")
+        }
+    }
+}
+
+/// Sends Server-Sent Events to all [`Response::events`] streams.
+#[derive(Debug, Default)]
+pub struct Broadcast {
+    subscribers: Mutex<Vec<Sender<Vec<u8>>>>,
+}
+
+impl Broadcast {
+    fn subscribe(&self) -> Receiver<Vec<u8>> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().expect("not poisoned").push(sender);
+        receiver
+    }
+
+    /// Sends an event with `data` (without newlines, like JSON) to all streams.
+    pub fn send(&self, event: &str, data: &str) {
+        let message = format!("event: {event}\ndata: {data}\n\n").into_bytes();
+        // Streams of disconnected clients have dropped their receiver
+        self.subscribers
+            .lock()
+            .expect("not poisoned")
+            .retain(|subscriber| subscriber.send(message.clone()).is_ok());
+    }
+}
+
+/// Decodes `%XX` escapes, e.g. of device names in paths. Invalid escapes are kept as is.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "",
+    }
+}
+
+fn parse_request_line(line: &str) -> Option<Request> {
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?;
+    parts.next()?.strip_prefix("HTTP/")?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+    Some(Request {
+        method,
+        path: path.to_string(),
+        query,
+        body: vec![],
+    })
+}
+
+fn handle(
+    stream: TcpStream,
+    handler: &(dyn Fn(&Request) -> Response + Send + Sync),
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    // Only the length of the body is needed, but all headers have to be read before it
+    let mut header = String::new();
+    let mut length = 0;
+    while reader.read_line(&mut header)? > 2 {
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(usize::MAX);
+            }
+        }
+        header.clear();
+    }
+    let response = match parse_request_line(&line) {
+        Some(_) if length > MAX_BODY => Response::error(400, "Body too large"),
+        Some(mut request) if request.method == "POST" => {
+            request.body = vec![0; length];
+            reader.read_exact(&mut request.body)?;
+            handler(&request)
+        }
+        Some(request) if request.method == "GET" || request.method == "HEAD" => {
+            let mut response = handler(&request);
+            if request.method == "HEAD" {
+                response.body.clear();
+                response.events = None;
+            }
+            response
+        }
+        Some(_) => Response::error(405, "Method not allowed"),
+        None => Response::error(400, "Bad request"),
+    };
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+    )?;
+    let Some(events) = response.events else {
+        write!(
+            writer,
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            response.body.len()
+        )?;
+        writer.write_all(&response.body)?;
+        return writer.flush();
+    };
+    // Without a length, the stream ends when the connection is closed
+    write!(
+        writer,
+        "Cache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )?;
+    writer.write_all(&response.body)?;
+    writer.flush()?;
+    loop {
+        match events.recv_timeout(KEEPALIVE) {
+            Ok(event) => writer.write_all(&event)?,
+            Err(RecvTimeoutError::Timeout) => writer.write_all(b":\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        writer.flush()?;
+    }
+}
+
+/// Binds `addr` and answers requests with `handler` in the background. Returns the bound address,
+/// which has the actual port if `addr` asked for any (port 0).
+pub fn serve(
+    addr: SocketAddr,
+    handler: impl Fn(&Request) -> Response + Send + Sync + 'static,
+) -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)
+        .map_err(|err| anyhow::anyhow!("Failed to listen on {addr}: {err}"))?;
+    let local = listener.local_addr()?;
+    let handler: Arc<dyn Fn(&Request) -> Response + Send + Sync> = Arc::new(handler);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let handler = handler.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = handle(stream, handler.as_ref()) {
+                    tracing::debug!("Failed to answer HTTP request: {err}");
+                }
+            });
+        }
+    });
+    Ok(local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serve() {
+        assert_eq!(
+            parse_request_line("GET /metrics?name=x HTTP/1.1\r\n"),
+            Some(Request {
+                method: "GET".to_string(),
+                path: "/metrics".to_string(),
+                query: Some("name=x".to_string()),
+                body: vec![],
+            })
+        );
+        assert_eq!(percent_decode("heat%20pump%2x%C3%A4"), "heat pump%2xä");
+        assert_eq!(url_host("fd00::23"), "[fd00::23]");
+        assert_eq!(url_host("[fd00::23]:8080"), "[fd00::23]:8080");
+        assert_eq!(url_host("plug.local:8080"), "plug.local:8080");
+        let addr = serve("127.0.0.1:0".parse().unwrap(), |request| {
+            match request.path.as_str() {
+                "/hello" => Response::new("text/plain", "hi"),
+                "/echo" => Response::new("text/plain", request.body.clone()),
+                _ => Response::not_found(),
+            }
+        })
+        .unwrap();
+        let request = |request: &[u8]| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = request(b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhi"));
+        let response = request(b"POST /echo HTTP/1.1\r\nContent-Length: 4\r\n\r\nping");
+        assert!(response.ends_with("\r\n\r\nping"));
+
+        let broadcast = Arc::new(Broadcast::default());
+        let events = broadcast.clone();
+        let addr = serve("127.0.0.1:0".parse().unwrap(), move |_| {
+            Response::events(&events)
+        })
+        .unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        // The stream subscribes before the headers are written
+        broadcast.send("reading", r#"{"id":"plug"}"#);
+        let mut event = String::new();
+        for _ in 0..3 {
+            reader.read_line(&mut event).unwrap();
+        }
+        assert_eq!(event, "event: reading\ndata: {\"id\":\"plug\"}\n\n");
+    }
+}
@@ -0,0 +1,208 @@
+//! HTTP/2 for the writes to InfluxDB targets, available with the `http2` feature. The writes of
+//! all sources go through one client per certificate, which keeps a single connection to each
+//! server and multiplexes the concurrent writes over it, instead of a TLS handshake for each
+//! connection of HTTP/1.1.
+use crate::tls::{ClientTls, TlsFiles};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Longest connection attempt, as for HTTP/1.1.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    /// Clients by whether they speak HTTP/2 without negotiating it, and their certificate.
+    static ref CLIENTS: Mutex<Vec<(bool, Option<TlsFiles>, reqwest::blocking::Client)>> =
+        Mutex::new(vec![]);
+}
+
+/// An error status answered by the server.
+#[derive(Debug)]
+pub struct Status {
+    url: String,
+    pub status: u16,
+    body: String,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: status code {}", self.url, self.status)?;
+        if !self.body.is_empty() {
+            write!(f, ": {}", self.body.trim())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Status {}
+
+/// Posts `body` to `url`, returning the HTTP status, or a [`Status`] error for 4xx and 5xx ones.
+/// HTTPS servers are asked for HTTP/2 during the TLS handshake, falling back to HTTP/1.1 if they
+/// don't support it. Plain HTTP servers have to speak HTTP/2 without upgrade (h2c).
+pub fn post(
+    url: &url::Url,
+    tls: Option<&ClientTls>,
+    authorization: Option<&str>,
+    body: &str,
+) -> anyhow::Result<u16> {
+    let mut request = client(url.scheme() == "http", tls)?
+        .post(url.as_str())
+        .body(body.to_string());
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    let response = request.send()?;
+    let status = response.status().as_u16();
+    let body = response.text()?;
+    if status >= 400 {
+        return Err(Status {
+            url: url.to_string(),
+            status,
+            body,
+        }
+        .into());
+    }
+    Ok(status)
+}
+
+/// Whether a write failed because the server is unreachable or unavailable, `None` for errors of
+/// another client.
+pub fn unreachable(err: &anyhow::Error) -> Option<bool> {
+    if let Some(Status { status, .. }) = err.downcast_ref::<Status>() {
+        return Some(*status >= 500 || *status == 429);
+    }
+    let err = err.downcast_ref::<reqwest::Error>()?;
+    Some(err.is_connect() || err.is_timeout() || err.is_request())
+}
+
+/// The shared client, built on first use.
+fn client(
+    prior_knowledge: bool,
+    tls: Option<&ClientTls>,
+) -> anyhow::Result<reqwest::blocking::Client> {
+    let files = tls.map(ClientTls::files);
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some((.., client)) = clients
+        .iter()
+        .find(|(p, f, _)| *p == prior_knowledge && f.as_ref() == files)
+    {
+        return Ok(client.clone());
+    }
+    let mut builder = reqwest::blocking::Client::builder().connect_timeout(CONNECT_TIMEOUT);
+    if prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(files) = files {
+        builder = builder.identity(identity(files)?);
+        if let Some(ca_cert) = &files.ca_cert {
+            let pem = std::fs::read(ca_cert)?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+    }
+    let client = builder.build()?;
+    clients.push((prior_knowledge, files.cloned(), client.clone()));
+    Ok(client)
+}
+
+/// The client certificate and its key.
+fn identity(files: &TlsFiles) -> anyhow::Result<reqwest::Identity> {
+    use anyhow::Context;
+    let read = |path: &std::path::PathBuf| {
+        std::fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))
+    };
+    let mut pem = read(&files.client_cert)?;
+    pem.push(b'\n');
+    pem.extend(read(&files.client_key)?);
+    reqwest::Identity::from_pem(&pem)
+        .with_context(|| format!("Invalid key '{}'", files.client_key.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    const SETTINGS: u8 = 0x4;
+    const HEADERS: u8 = 0x1;
+    const DATA: u8 = 0x0;
+    const END_STREAM: u8 = 0x1;
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend([kind, flags]);
+        frame.extend(stream.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    #[test]
+    fn test_post() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url =
+            url::Url::parse(&format!("http://{}/write", listener.local_addr().unwrap())).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for (connection, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut preface = [0; 24];
+                stream.read_exact(&mut preface).unwrap();
+                assert_eq!(&preface, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+                stream.write_all(&frame(SETTINGS, 0, 0, &[])).unwrap();
+                let mut bodies = std::collections::HashMap::<u32, Vec<u8>>::new();
+                loop {
+                    let mut header = [0; 9];
+                    if stream.read_exact(&mut header).is_err() {
+                        break;
+                    }
+                    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+                    let (kind, flags) = (header[3], header[4]);
+                    let id = u32::from_be_bytes(header[5..].try_into().unwrap()) & 0x7fff_ffff;
+                    let mut payload = vec![0; len];
+                    stream.read_exact(&mut payload).unwrap();
+                    match kind {
+                        SETTINGS if flags == 0 => {
+                            stream.write_all(&frame(SETTINGS, 1, 0, &[])).unwrap();
+                        }
+                        DATA => {
+                            bodies.entry(id).or_default().extend(payload);
+                            if flags & END_STREAM != 0 {
+                                // `:status: 204` from the static table, ending the stream
+                                stream.write_all(&frame(HEADERS, 0x5, id, &[0x89])).unwrap();
+                                let body = String::from_utf8(bodies.remove(&id).unwrap());
+                                sender.send((connection, id, body.unwrap())).unwrap();
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        });
+        assert_eq!(post(&url, None, Some("Token t"), "m a=1i").unwrap(), 204);
+        assert_eq!(post(&url, None, None, "m a=2i").unwrap(), 204);
+        // Both on the first connection, as streams of their own
+        assert_eq!(receiver.recv().unwrap(), (0, 1, "m a=1i".to_string()));
+        assert_eq!(receiver.recv().unwrap(), (0, 3, "m a=2i".to_string()));
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let status = |status| {
+            anyhow::Error::from(Status {
+                url: "http://influx/api/v2/write".to_string(),
+                status,
+                body: "{\"message\":\"unavailable\"}\n".to_string(),
+            })
+        };
+        assert_eq!(unreachable(&status(503)), Some(true));
+        assert_eq!(unreachable(&status(400)), Some(false));
+        assert_eq!(
+            status(503).to_string(),
+            "http://influx/api/v2/write: status code 503: {\"message\":\"unavailable\"}"
+        );
+        assert_eq!(unreachable(&anyhow::anyhow!("Invalid line")), None);
+    }
+}
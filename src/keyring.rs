@@ -0,0 +1,128 @@
+//! Secrets in the OS keyring, referenced in the config as `keyring:<service>/<account>` (or
+//! `keyring:<account>` for the service `sun-status-grabber`).
+//!
+//! The keyring is accessed through the tools of the OS: `secret-tool` (Secret Service, e.g. GNOME
+//! Keyring or KWallet) on Linux, `security` (Keychain) on macOS and PowerShell's `PasswordVault`
+//! (Credential Manager) on Windows.
+use crate::process::run;
+use anyhow::{bail, Context};
+use std::process::Command;
+
+/// `(service, account)` of a reference without the `keyring:` prefix.
+pub fn entry(reference: &str) -> (&str, &str) {
+    reference
+        .rsplit_once('/')
+        .unwrap_or((env!("CARGO_PKG_NAME"), reference))
+}
+
+/// PowerShell string literal.
+#[cfg(windows)]
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Argument of a command in the interactive mode of `security`.
+#[cfg(target_os = "macos")]
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Reads the secret of a reference (without the `keyring:` prefix).
+pub fn get(reference: &str) -> anyhow::Result<String> {
+    let (service, account) = entry(reference);
+    #[cfg(target_os = "macos")]
+    let secret = run(
+        Command::new("security").args([
+            "find-generic-password",
+            "-s",
+            service,
+            "-a",
+            account,
+            "-w",
+        ]),
+        None,
+    );
+    #[cfg(windows)]
+    let secret = run(
+        Command::new("powershell").args([
+            "-NoProfile",
+            "-Command",
+            &format!(
+                "$c = (New-Object Windows.Security.Credentials.PasswordVault).Retrieve({}, {}); \
+                 $c.RetrievePassword(); $c.Password",
+                quote(service),
+                quote(account)
+            ),
+        ]),
+        None,
+    );
+    #[cfg(not(any(target_os = "macos", windows)))]
+    let secret = run(
+        Command::new("secret-tool").args(["lookup", "service", service, "account", account]),
+        None,
+    );
+    let secret =
+        secret.with_context(|| format!("Failed to read '{reference}' from the keyring"))?;
+    if secret.is_empty() {
+        bail!("No secret '{reference}' in the keyring");
+    }
+    Ok(secret)
+}
+
+/// Stores the secret of a reference (without the `keyring:` prefix), replacing an existing one.
+/// The secret is passed to the tools on stdin, never as an argument visible to other users.
+pub fn set(reference: &str, secret: &str) -> anyhow::Result<()> {
+    let (service, account) = entry(reference);
+    #[cfg(any(target_os = "macos", windows))]
+    if secret.contains(['\r', '\n']) {
+        bail!("Secrets stored in the keyring can't contain line breaks");
+    }
+    // Interactive mode reads the command from stdin
+    #[cfg(target_os = "macos")]
+    let result = run(
+        Command::new("security").arg("-i"),
+        Some(&format!(
+            "add-generic-password -U -s {} -a {} -w {}\n",
+            quote(service),
+            quote(account),
+            quote(secret)
+        )),
+    );
+    #[cfg(windows)]
+    let result = run(
+        Command::new("powershell").args(["-NoProfile", "-Command", "-"]),
+        Some(&format!(
+            "(New-Object Windows.Security.Credentials.PasswordVault).Add(\
+             (New-Object Windows.Security.Credentials.PasswordCredential({}, {}, {})))\n",
+            quote(service),
+            quote(account),
+            quote(secret)
+        )),
+    );
+    #[cfg(not(any(target_os = "macos", windows)))]
+    let result = run(
+        Command::new("secret-tool").args([
+            "store",
+            &format!("--label={service} {account}"),
+            "service",
+            service,
+            "account",
+            account,
+        ]),
+        Some(secret),
+    );
+    result.with_context(|| format!("Failed to store '{reference}' in the keyring"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        assert_eq!(entry("influx/token"), ("influx", "token"));
+        assert_eq!(entry("a/b/admin"), ("a/b", "admin"));
+        assert_eq!(entry("token"), ("sun-status-grabber", "token"));
+    }
+}
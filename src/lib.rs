@@ -0,0 +1,1347 @@
+//! Collects readings from solar inverters and smart plugs and publishes them to time series
+//! databases. The `sun-status-grabber` binary is a thin CLI around this crate.
+pub mod alerts;
+pub mod api;
+pub mod arp;
+pub mod backoff;
+pub mod bench;
+pub mod carbon;
+pub mod channels;
+pub mod chaos;
+pub mod classify;
+pub mod conditional;
+pub mod config;
+pub mod counters;
+pub mod csv;
+pub mod dashboard;
+pub mod dedup;
+pub mod discover;
+pub mod duration;
+pub mod encrypted;
+pub mod env;
+pub mod expr;
+pub mod eyeballs;
+pub mod filter;
+pub mod grafana;
+pub mod heartbeat;
+pub mod http;
+#[cfg(feature = "http2")]
+pub mod http2;
+pub mod influxdb;
+pub mod keyring;
+pub mod line_protocol;
+pub mod live;
+pub mod lock;
+pub mod measurements;
+pub mod missing;
+pub mod mock;
+pub mod modbus;
+pub mod mqtt;
+pub mod notify;
+pub mod number;
+pub mod oauth2;
+pub mod plugin;
+pub mod postgres;
+pub mod process;
+pub mod prometheus;
+pub mod quality;
+pub mod registry;
+pub mod retry;
+pub mod scheduler;
+pub mod script;
+pub mod shutdown;
+pub mod sites;
+pub mod size;
+pub mod smoothing;
+pub mod spool;
+pub mod sqlite;
+pub mod stats;
+pub mod sun600;
+pub mod tariff;
+pub mod tasmota;
+pub mod template;
+pub mod tls;
+pub mod transform;
+pub mod validation;
+pub mod vault;
+pub mod virtual_device;
+pub mod weather;
+pub mod window;
+
+use crate::alerts::{AlertRule, StaleAlert};
+use crate::backoff::Backoff;
+use crate::carbon::{Carbon, CarbonState};
+use crate::channels::{ChannelMode, Channels};
+use crate::classify::Class;
+use crate::counters::{
+    CounterReset, CounterResetState, DailyYield, DailyYieldState, Integration, IntegrationState,
+    Rate, RateState, Weighted,
+};
+pub use crate::csv::BackendCsv;
+use crate::dedup::{Dedup, DedupState};
+use crate::expr::Expr;
+use crate::filter::{Filter, Pattern};
+use crate::heartbeat::Heartbeat;
+pub use crate::influxdb::BackendInfluxDB;
+use crate::missing::{MissingFields, MissingFieldsState};
+use crate::modbus::ModbusServer;
+pub use crate::mqtt::BackendMqtt;
+use crate::notify::Notifier;
+use crate::plugin::Plugin;
+pub use crate::postgres::BackendPostgres;
+pub use crate::prometheus::BackendPrometheus;
+use crate::quality::Quality;
+use crate::registry::{RegisteredSource, RegisteredTarget};
+pub use crate::scheduler::Scheduler;
+use crate::script::Script;
+use crate::sites::Site;
+use crate::smoothing::Samples;
+pub use crate::sqlite::BackendSqlite;
+use crate::stats::SelfMetrics;
+use crate::sun600::Inverter;
+use crate::tariff::{Cost, Tariff};
+use crate::tasmota::Tasmota;
+use crate::transform::{Calibration, Codes};
+use crate::validation::{
+    NonFinite, NonFiniteState, Plausibility, PlausibilityState, Range, RangeState,
+};
+use crate::vault::Vault;
+use crate::virtual_device::VirtualDevice;
+use crate::weather::{Weather, WeatherState};
+use crate::window::{Window, WindowState};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// A device that can be polled for readings.
+pub trait Source: Send {
+    /// Name used to identify the device in logs and summaries.
+    fn id(&self) -> Cow<'_, str>;
+
+    /// Reads the device. A reading without fields is not published.
+    fn poll_data(&mut self) -> anyhow::Result<PublishData>;
+
+    /// Raw response of the device, for debugging.
+    fn fetch_raw(&self) -> anyhow::Result<String> {
+        anyhow::bail!("'{}' can't show its raw response", self.id())
+    }
+
+    /// Parses a response returned by [`Self::fetch_raw`], without the processing of the source.
+    fn parse_raw(&self, _raw: &str) -> anyhow::Result<PublishData> {
+        anyhow::bail!("'{}' can't parse raw responses", self.id())
+    }
+
+    /// Whether the last poll repeated the previous reading, as the device answered that its
+    /// response did not change.
+    fn not_modified(&self) -> bool {
+        false
+    }
+
+    /// The last reading before it was trimmed for publishing (filtered, windowed, deduplicated or
+    /// renamed), for the virtual devices. `None` if the published reading is complete.
+    fn unfiltered_data(&self) -> Option<PublishData> {
+        None
+    }
+
+    /// Fields of the last reading which were dropped or clamped as implausible.
+    fn implausible_fields(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Called once the last reading was published to all targets.
+    fn published(&mut self) {}
+
+    /// Interval to poll this source at instead of the one of the scheduler.
+    fn poll_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// State to remember between runs (e.g. with one-shot runs from a timer).
+    fn save_state(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Restores the state previously returned by `save_state`.
+    fn restore_state(&mut self, _state: serde_json::Value) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// A backend readings are published to.
+pub trait Target: Send {
+    /// Name used to identify the backend in logs and summaries.
+    fn id(&self) -> Cow<'_, str>;
+
+    fn publish(&self, data: &PublishData) -> anyhow::Result<()>;
+
+    /// Whether the readings of a cycle are collected, to publish them with [`Target::publish_batch`].
+    fn batch(&self) -> bool {
+        false
+    }
+
+    /// Publishes several readings at once, by default one after the other.
+    fn publish_batch(&self, data: &[PublishData]) -> anyhow::Result<()> {
+        for data in data {
+            self.publish(data)?;
+        }
+        Ok(())
+    }
+
+    /// Points discarded since the start without being published, e.g. pruned from a full spool.
+    fn dropped_points(&self) -> u64 {
+        0
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub sources: Vec<SourceConfig>,
+    #[serde(default)]
+    pub targets: Vec<TargetConfig>,
+    /// Energy prices, used by sources with `costs`
+    #[serde(default)]
+    pub tariff: Option<Tariff>,
+    /// Grid carbon intensity, used by sources with `co2`
+    #[serde(default)]
+    pub carbon: Option<Carbon>,
+    /// Location to publish the current weather of every cycle
+    #[serde(default)]
+    pub weather: Option<Weather>,
+    /// Devices computed from the readings of the sources
+    #[serde(default, rename = "virtualDevices")]
+    pub virtual_devices: Vec<VirtualDevice>,
+    /// Tags added to the readings of all sources, unless a source defines a tag of the same name
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Tags and fields published as the other class by all targets, unless a target classifies them itself
+    #[serde(default)]
+    pub classify: BTreeMap<String, Class>,
+    /// Keeps running, polling all sources every interval, unless given by `--interval`
+    #[serde(
+        default,
+        with = "crate::duration::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(with = "Option<crate::duration::Schema>")]
+    pub interval: Option<Duration>,
+    /// File to keep state of sources (e.g. `dedup`, `rates`, `integrate`) in between runs from a timer
+    #[serde(default, rename = "statePath")]
+    pub state_path: Option<PathBuf>,
+    /// Publishes counters about the grabber itself to all targets
+    #[serde(default, rename = "selfMetrics")]
+    pub self_metrics: Option<SelfMetrics>,
+    /// Pinged after every cycle in which all sources were polled and published
+    #[serde(default)]
+    pub heartbeat: Option<Heartbeat>,
+    /// Where alerts are sent to
+    #[serde(default)]
+    pub notifiers: Vec<Notifier>,
+    /// Rules over the readings, notifying when they start and stop firing
+    #[serde(default)]
+    pub alerts: Vec<AlertRule>,
+    /// Alerts once a source fails to be polled repeatedly, and once it recovers
+    #[serde(default, rename = "staleAlert")]
+    pub stale_alert: Option<StaleAlert>,
+    /// Polls failing sources less often, eventually quarantining them
+    #[serde(default)]
+    pub backoff: Option<Backoff>,
+    /// Vault (or OpenBao) server to resolve `vault:` references with
+    #[serde(default)]
+    pub vault: Option<Vault>,
+    /// Groups of sources, whose readings are tagged with `site` and may go to their own targets
+    #[serde(default)]
+    pub sites: Vec<Site>,
+    /// Serves the latest readings as Modbus registers, while running continuously
+    #[serde(default)]
+    pub modbus: Option<ModbusServer>,
+    /// Config and secret files read while loading, to reload on changes
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
+    /// When to reload, to read the secrets from Vault again before its token expires
+    #[serde(skip)]
+    pub refresh_at: Option<SystemTime>,
+}
+
+#[derive(serde::Serialize, schemars::JsonSchema, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum SourceDevice {
+    Inverter(Inverter),
+    Tasmota(Tasmota),
+    /// A type added with [`registry::register_source`]
+    #[serde(untagged)]
+    #[schemars(skip)]
+    Registered(RegisteredSource),
+}
+
+/// A configured source device, along with the settings common to all device types.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+pub struct SourceConfig {
+    #[serde(flatten)]
+    pub device: SourceDevice,
+    /// Additional tags added to every reading of this source
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// Takes several samples per poll, publishing their median or mean
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples: Option<Samples>,
+    /// What to do if some of the usual fields are missing from a reading
+    #[serde(default, rename = "missingFields")]
+    pub missing_fields: MissingFields,
+    /// What to do with NaN and infinite values: drop the field (default), the reading, or use the last value
+    #[serde(default, rename = "nonFinite")]
+    pub non_finite: NonFinite,
+    /// Whether per-phase/channel values are published as suffixed fields or separate points
+    #[serde(default)]
+    pub channels: ChannelMode,
+    /// Corrects readings of individual fields
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub calibration: BTreeMap<String, Calibration>,
+    /// Names of numeric status and alarm codes, published as tags
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub codes: BTreeMap<String, Codes>,
+    /// Valid ranges of fields, values outside are rejected (or clamped)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ranges: BTreeMap<String, Range>,
+    /// Checks dropping (or clamping) obviously bogus values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub plausibility: BTreeMap<String, Plausibility>,
+    /// Detection (and correction) of counter resets
+    #[serde(
+        default,
+        rename = "counterReset",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub counter_reset: Option<CounterReset>,
+    /// Rates computed from counters between polls, e.g. power from energy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rates: Vec<Rate>,
+    /// Energy counters integrated from a power reading
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrate: Option<Integration>,
+    /// Rollover detection and recomputation of the daily yield
+    #[serde(
+        default,
+        rename = "dailyYield",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub daily_yield: Option<DailyYield>,
+    /// Energy counters (in kWh) to publish the cost or revenue of
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub costs: BTreeMap<String, Cost>,
+    /// Energy prices of this source, defaults to the global tariff
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tariff: Option<Tariff>,
+    /// Energy counter (in kWh) of the production to publish the avoided CO2 emissions of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub co2: Option<String>,
+    /// Grid carbon intensity for this source, defaults to the global one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub carbon: Option<Carbon>,
+    /// Location to add the current weather of to each reading
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weather: Option<Weather>,
+    /// Computed fields, evaluated over the fields of each reading
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub derived: BTreeMap<String, Expr>,
+    /// Number of decimals to round fields to
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub precision: BTreeMap<String, u8>,
+    /// Rhai script transforming each reading, requires the `scripting` feature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<Script>,
+    /// WebAssembly plugin transforming each reading, requires the `wasm` feature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plugin: Option<Plugin>,
+    /// Fields (or whole readings) not to publish
+    #[serde(default, skip_serializing_if = "Filter::is_empty")]
+    pub filter: Filter,
+    /// Publishes the min/avg/max over a window instead of every reading
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<Window>,
+    /// Skips publishing unchanged values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup: Option<Dedup>,
+    /// Renames fields and tags, applied after all other processing
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rename: BTreeMap<String, String>,
+    /// Measurement (template) to publish the readings as, instead of the one of the targets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measurement: Option<String>,
+    /// Adds tags like `stale` or `clamped` to readings whose values were not measured as is
+    #[serde(default, rename = "qualityTags")]
+    pub quality_tags: bool,
+    /// Timestamps reported by the device deviating more from the local time are ignored
+    #[serde(
+        default = "SourceConfig::default_max_skew",
+        rename = "maxSkew",
+        with = "crate::duration"
+    )]
+    #[schemars(with = "crate::duration::Schema")]
+    pub max_skew: Duration,
+    /// Polls this source every interval instead of every `interval`, when running continuously
+    #[serde(
+        default,
+        rename = "pollInterval",
+        with = "crate::duration::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(with = "Option<crate::duration::Schema>")]
+    pub poll_interval: Option<Duration>,
+    #[serde(skip)]
+    pub state: SourceState,
+}
+
+/// Values of a source remembered between polls.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct SourceState {
+    pub non_finite: NonFiniteState,
+    pub missing_fields: MissingFieldsState,
+    pub ranges: RangeState,
+    pub plausibility: PlausibilityState,
+    pub counter_reset: CounterResetState,
+    pub dedup: DedupState,
+    pub rates: RateState,
+    pub integrate: IntegrationState,
+    pub daily_yield: DailyYieldState,
+    pub costs: Weighted,
+    pub carbon: CarbonState,
+    pub weather: WeatherState,
+    pub window: WindowState,
+    /// Last reading before filtering, windowing, deduplication and renaming
+    #[serde(skip)]
+    pub unfiltered: Option<PublishData>,
+    /// Fields dropped or clamped by the plausibility checks of the last poll
+    #[serde(skip)]
+    pub implausible: Vec<String>,
+}
+
+/// The backend of a target, InfluxDB unless the config gives another `type`.
+#[derive(serde::Serialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(tag = "type")]
+pub enum Backend {
+    #[serde(rename = "MQTT")]
+    Mqtt(Box<BackendMqtt>),
+    Prometheus(Box<BackendPrometheus>),
+    #[serde(rename = "CSV")]
+    Csv(Box<BackendCsv>),
+    #[serde(rename = "SQLite")]
+    Sqlite(Box<BackendSqlite>),
+    #[serde(rename = "PostgreSQL")]
+    Postgres(Box<BackendPostgres>),
+    #[serde(untagged)]
+    InfluxDB(Box<BackendInfluxDB>),
+    /// A type added with [`registry::register_target`]
+    #[serde(untagged)]
+    #[schemars(skip)]
+    Registered(RegisteredTarget),
+}
+
+/// A configured target, along with the settings common to all backends.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+pub struct TargetConfig {
+    #[serde(flatten)]
+    pub backend: Backend,
+    /// Name identifying the target in logs and summaries, the URL by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Fields (or whole readings) not to publish to this target
+    #[serde(default, skip_serializing_if = "Filter::is_empty")]
+    pub filter: Filter,
+    /// Tags to publish as fields, or fields to publish as tags
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub classify: BTreeMap<String, Class>,
+    /// Measurements (templates) to write the fields matching the patterns into
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub measurements: BTreeMap<String, Vec<Pattern>>,
+    /// Only publishes the readings of this site (by their `site` tag)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site: Option<String>,
+    /// Sites with their own targets, whose readings are not published to this one
+    #[serde(skip)]
+    pub excluded_sites: Vec<String>,
+}
+
+impl TargetConfig {
+    /// Whether the reading is published to this target, according to its site.
+    pub fn accepts(&self, data: &PublishData) -> bool {
+        let site = match data.tag_value("site") {
+            Some(Value::String(site)) => Some(site.as_str()),
+            _ => None,
+        };
+        match &self.site {
+            Some(only) => site == Some(only.as_str()),
+            None => site.is_none_or(|site| !self.excluded_sites.iter().any(|s| s == site)),
+        }
+    }
+
+    /// The points of an accepted reading, filtered, classified and split into measurements.
+    fn points(&self, data: &PublishData) -> Vec<PublishData> {
+        let mut data = data.clone();
+        if !self.filter.apply(&mut data) {
+            return vec![];
+        }
+        classify::apply(&mut data, &self.classify);
+        measurements::split(data, &self.measurements)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub enum Field {
+    // Indexed
+    Tag(String, Value),
+    // Un-indexed
+    Field(String, Value),
+}
+
+impl Field {
+    pub fn name(&self) -> &str {
+        match self {
+            Field::Tag(name, _) | Field::Field(name, _) => name,
+        }
+    }
+
+    pub fn value(&self) -> &Value {
+        match self {
+            Field::Tag(_, value) | Field::Field(_, value) => value,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
+pub enum Value {
+    String(String),
+    F64(f64),
+    I64(i64),
+    Bool(bool),
+    Timestamp(SystemTime),
+}
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct PublishData {
+    fields: Vec<Field>,
+    channels: Option<Channels>,
+    /// Measurement (template) overriding the one of the targets
+    measurement: Option<String>,
+    /// Time of the measurement reported by the device, the time of publishing otherwise
+    timestamp: Option<SystemTime>,
+    /// Fields the source usually reports, but couldn't this time
+    missing: Vec<String>,
+    /// How values were modified by the processing
+    quality: BTreeSet<Quality>,
+}
+
+impl Value {
+    /// Numeric value, if this is a number.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::F64(f) => Some(*f),
+            Value::I64(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+
+    /// Plain JSON value, timestamps as RFC 3339.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::String(s) => s.as_str().into(),
+            Value::F64(f) => (*f).into(),
+            Value::I64(i) => (*i).into(),
+            Value::Bool(b) => (*b).into(),
+            Value::Timestamp(t) => chrono::DateTime::<chrono::Utc>::from(*t)
+                .to_rfc3339()
+                .into(),
+        }
+    }
+}
+
+impl PublishData {
+    pub fn tag(&mut self, name: impl Into<String>, value: impl Into<Value>) {
+        self.fields.push(Field::Tag(name.into(), value.into()));
+    }
+
+    pub fn field(&mut self, name: impl Into<String>, value: impl Into<Value>) {
+        self.fields.push(Field::Field(name.into(), value.into()));
+    }
+
+    /// Sets a tag, replacing the value of an existing tag with the same name.
+    pub fn set_tag(&mut self, name: impl Into<String>, value: impl Into<Value>) {
+        let name = name.into();
+        let value = value.into();
+        match self
+            .fields
+            .iter_mut()
+            .find(|f| matches!(f, Field::Tag(n, _) if *n == name))
+        {
+            Some(Field::Tag(_, existing)) => *existing = value,
+            _ => self.fields.push(Field::Tag(name, value)),
+        }
+    }
+
+    /// Renames all fields, channel fields and tags called `from`.
+    pub fn rename(&mut self, from: &str, to: &str) {
+        for f in &mut self.fields {
+            match f {
+                Field::Tag(name, _) | Field::Field(name, _) if name == from => {
+                    *name = to.to_string()
+                }
+                _ => (),
+            }
+        }
+        let channel_fields = self.channels.iter_mut().flat_map(|c| &mut c.fields);
+        for (name, _) in channel_fields.filter(|(name, _)| name == from) {
+            *name = to.to_string();
+        }
+    }
+
+    /// Value of the tag called `name`.
+    pub fn tag_value(&self, name: &str) -> Option<&Value> {
+        self.fields.iter().find_map(|f| match f {
+            Field::Tag(n, value) if n == name => Some(value),
+            _ => None,
+        })
+    }
+
+    /// Value of the (un-indexed) field called `name`.
+    pub fn field_value(&self, name: &str) -> Option<&Value> {
+        self.fields.iter().find_map(|f| match f {
+            Field::Field(n, value) if n == name => Some(value),
+            _ => None,
+        })
+    }
+
+    /// Value of the (un-indexed) field called `name`.
+    pub fn field_mut(&mut self, name: &str) -> Option<&mut Value> {
+        self.fields.iter_mut().find_map(|f| match f {
+            Field::Field(n, value) if n == name => Some(value),
+            _ => None,
+        })
+    }
+
+    /// Numeric value of the (un-indexed) field called `name`.
+    pub fn number(&self, name: &str) -> Option<f64> {
+        self.fields.iter().find_map(|f| match f {
+            Field::Field(n, value) if n == name => value.as_f64(),
+            _ => None,
+        })
+    }
+
+    /// Whether there are any (un-indexed) fields, readings without are not published.
+    pub fn has_fields(&self) -> bool {
+        self.fields.iter().any(|f| matches!(f, Field::Field(..)))
+            || self.channels.as_ref().is_some_and(|c| !c.fields.is_empty())
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    pub fn measurement(&self) -> Option<&str> {
+        self.measurement.as_deref()
+    }
+
+    pub fn set_measurement(&mut self, measurement: impl Into<String>) {
+        self.measurement = Some(measurement.into());
+    }
+
+    /// Marks a field the source usually reports as missing from this reading.
+    pub fn missing(&mut self, name: impl Into<String>) {
+        self.missing.push(name.into());
+    }
+
+    pub fn missing_fields(&self) -> &[String] {
+        &self.missing
+    }
+
+    /// Marks the reading as (partially) not measured as is.
+    pub fn flag(&mut self, quality: Quality) {
+        self.quality.insert(quality);
+    }
+
+    pub fn quality(&self) -> &BTreeSet<Quality> {
+        &self.quality
+    }
+
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        self.timestamp
+    }
+
+    /// Sets the time of the measurement, for sources reporting their own timestamps.
+    pub fn set_timestamp(&mut self, timestamp: SystemTime) {
+        self.timestamp = Some(timestamp);
+    }
+}
+
+impl std::ops::Index<&str> for PublishData {
+    type Output = Value;
+
+    fn index(&self, index: &str) -> &Self::Output {
+        self.fields
+            .iter()
+            .filter_map(|f| match f {
+                Field::Tag(name, value) | Field::Field(name, value) if name == index => Some(value),
+                _ => None,
+            })
+            .next()
+            .unwrap()
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::F64(f)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::I64(i)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<SystemTime> for Value {
+    fn from(t: SystemTime) -> Self {
+        Value::Timestamp(t)
+    }
+}
+
+impl SourceDevice {
+    pub fn source(&self) -> &dyn Source {
+        match self {
+            SourceDevice::Inverter(d) => d,
+            SourceDevice::Tasmota(d) => d,
+            SourceDevice::Registered(d) => &*d.source,
+        }
+    }
+
+    pub fn source_mut(&mut self) -> &mut dyn Source {
+        match self {
+            SourceDevice::Inverter(d) => d,
+            SourceDevice::Tasmota(d) => d,
+            SourceDevice::Registered(d) => &mut *d.source,
+        }
+    }
+}
+
+impl Source for SourceDevice {
+    fn id(&self) -> Cow<'_, str> {
+        self.source().id()
+    }
+
+    fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+        self.source_mut().poll_data()
+    }
+
+    fn fetch_raw(&self) -> anyhow::Result<String> {
+        self.source().fetch_raw()
+    }
+
+    fn parse_raw(&self, raw: &str) -> anyhow::Result<PublishData> {
+        self.source().parse_raw(raw)
+    }
+
+    fn not_modified(&self) -> bool {
+        self.source().not_modified()
+    }
+
+    fn unfiltered_data(&self) -> Option<PublishData> {
+        self.source().unfiltered_data()
+    }
+
+    fn implausible_fields(&self) -> Vec<String> {
+        self.source().implausible_fields()
+    }
+
+    fn published(&mut self) {
+        self.source_mut().published()
+    }
+
+    fn save_state(&self) -> Option<serde_json::Value> {
+        self.source().save_state()
+    }
+
+    fn restore_state(&mut self, state: serde_json::Value) -> anyhow::Result<()> {
+        self.source_mut().restore_state(state)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for SourceDevice {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        #[derive(serde::Deserialize)]
+        #[serde(tag = "type")]
+        enum Builtin {
+            Inverter(Inverter),
+            Tasmota(Tasmota),
+        }
+        let config = serde_json::Value::deserialize(deserializer)?;
+        match config.get("type").and_then(|t| t.as_str()) {
+            None | Some("Inverter" | "Tasmota") => match Builtin::deserialize(config) {
+                Ok(Builtin::Inverter(d)) => Ok(SourceDevice::Inverter(d)),
+                Ok(Builtin::Tasmota(d)) => Ok(SourceDevice::Tasmota(d)),
+                Err(err) => Err(D::Error::custom(err)),
+            },
+            Some("Wasm") => {
+                let source =
+                    plugin::WasmSource::deserialize(config.clone()).map_err(D::Error::custom)?;
+                Ok(SourceDevice::Registered(RegisteredSource::new(
+                    config,
+                    Box::new(source),
+                )))
+            }
+            Some(_) => RegisteredSource::deserialize(config)
+                .map(SourceDevice::Registered)
+                .map_err(D::Error::custom),
+        }
+    }
+}
+
+impl Backend {
+    pub fn influxdb(&self) -> Option<&BackendInfluxDB> {
+        match self {
+            Backend::InfluxDB(backend) => Some(backend),
+            Backend::Mqtt(_)
+            | Backend::Prometheus(_)
+            | Backend::Csv(_)
+            | Backend::Sqlite(_)
+            | Backend::Postgres(_)
+            | Backend::Registered(_) => None,
+        }
+    }
+
+    pub fn influxdb_mut(&mut self) -> Option<&mut BackendInfluxDB> {
+        match self {
+            Backend::InfluxDB(backend) => Some(backend),
+            Backend::Mqtt(_)
+            | Backend::Prometheus(_)
+            | Backend::Csv(_)
+            | Backend::Sqlite(_)
+            | Backend::Postgres(_)
+            | Backend::Registered(_) => None,
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Backend {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let mut config = serde_json::Value::deserialize(deserializer)?;
+        match config.get("type").and_then(|t| t.as_str()) {
+            None => {}
+            Some("InfluxDB") => {
+                config.as_object_mut().map(|map| map.remove("type"));
+            }
+            Some("MQTT") => {
+                config.as_object_mut().map(|map| map.remove("type"));
+                let backend = BackendMqtt::deserialize(config).map_err(D::Error::custom)?;
+                // MQTT 3.1.1 only allows a password along with a user name
+                if backend.password.is_some() && backend.user.is_none() {
+                    return Err(D::Error::missing_field("user"));
+                }
+                return Ok(Backend::Mqtt(Box::new(backend)));
+            }
+            Some("Prometheus") => {
+                config.as_object_mut().map(|map| map.remove("type"));
+                let backend = BackendPrometheus::deserialize(config).map_err(D::Error::custom)?;
+                return Ok(Backend::Prometheus(Box::new(backend)));
+            }
+            Some("CSV") => {
+                config.as_object_mut().map(|map| map.remove("type"));
+                let backend = BackendCsv::deserialize(config).map_err(D::Error::custom)?;
+                return Ok(Backend::Csv(Box::new(backend)));
+            }
+            Some("SQLite") => {
+                config.as_object_mut().map(|map| map.remove("type"));
+                let backend = BackendSqlite::deserialize(config).map_err(D::Error::custom)?;
+                return Ok(Backend::Sqlite(Box::new(backend)));
+            }
+            Some("PostgreSQL") => {
+                config.as_object_mut().map(|map| map.remove("type"));
+                let backend = BackendPostgres::deserialize(config).map_err(D::Error::custom)?;
+                return Ok(Backend::Postgres(Box::new(backend)));
+            }
+            Some(_) => {
+                return RegisteredTarget::deserialize(config)
+                    .map(Backend::Registered)
+                    .map_err(D::Error::custom)
+            }
+        }
+        let backend = BackendInfluxDB::deserialize(config).map_err(D::Error::custom)?;
+        if backend.api_version != 1 {
+            if backend.token.is_empty() && backend.oauth2.is_none() {
+                return Err(D::Error::missing_field("token"));
+            }
+            if backend.org.is_empty() {
+                return Err(D::Error::missing_field("org"));
+            }
+        }
+        #[cfg(not(feature = "http2"))]
+        if backend.http2 {
+            return Err(D::Error::custom(
+                "HTTP/2 requires building with the 'http2' feature",
+            ));
+        }
+        Ok(Backend::InfluxDB(Box::new(backend)))
+    }
+}
+
+impl Target for Backend {
+    fn id(&self) -> Cow<'_, str> {
+        match self {
+            Backend::InfluxDB(backend) => backend.id(),
+            Backend::Mqtt(backend) => backend.id(),
+            Backend::Prometheus(backend) => backend.id(),
+            Backend::Csv(backend) => backend.id(),
+            Backend::Sqlite(backend) => backend.id(),
+            Backend::Postgres(backend) => backend.id(),
+            Backend::Registered(backend) => backend.target.id(),
+        }
+    }
+
+    fn publish(&self, data: &PublishData) -> anyhow::Result<()> {
+        match self {
+            Backend::InfluxDB(backend) => backend.publish(data),
+            Backend::Mqtt(backend) => backend.publish(data),
+            Backend::Prometheus(backend) => backend.publish(data),
+            Backend::Csv(backend) => backend.publish(data),
+            Backend::Sqlite(backend) => backend.publish(data),
+            Backend::Postgres(backend) => backend.publish(data),
+            Backend::Registered(backend) => backend.target.publish(data),
+        }
+    }
+
+    fn batch(&self) -> bool {
+        match self {
+            Backend::InfluxDB(backend) => backend.batch(),
+            Backend::Mqtt(backend) => backend.batch(),
+            Backend::Prometheus(backend) => backend.batch(),
+            Backend::Csv(backend) => backend.batch(),
+            Backend::Sqlite(backend) => backend.batch(),
+            Backend::Postgres(backend) => backend.batch(),
+            Backend::Registered(backend) => backend.target.batch(),
+        }
+    }
+
+    fn publish_batch(&self, data: &[PublishData]) -> anyhow::Result<()> {
+        match self {
+            Backend::InfluxDB(backend) => backend.publish_batch(data),
+            Backend::Mqtt(backend) => backend.publish_batch(data),
+            Backend::Prometheus(backend) => backend.publish_batch(data),
+            Backend::Csv(backend) => backend.publish_batch(data),
+            Backend::Sqlite(backend) => backend.publish_batch(data),
+            Backend::Postgres(backend) => backend.publish_batch(data),
+            Backend::Registered(backend) => backend.target.publish_batch(data),
+        }
+    }
+
+    fn dropped_points(&self) -> u64 {
+        match self {
+            Backend::InfluxDB(backend) => backend.dropped_points(),
+            Backend::Mqtt(backend) => backend.dropped_points(),
+            Backend::Prometheus(backend) => backend.dropped_points(),
+            Backend::Csv(backend) => backend.dropped_points(),
+            Backend::Sqlite(backend) => backend.dropped_points(),
+            Backend::Postgres(backend) => backend.dropped_points(),
+            Backend::Registered(backend) => backend.target.dropped_points(),
+        }
+    }
+}
+
+impl SourceConfig {
+    fn default_max_skew() -> Duration {
+        Duration::from_secs(300)
+    }
+}
+
+impl From<SourceDevice> for SourceConfig {
+    fn from(device: SourceDevice) -> Self {
+        Self {
+            device,
+            tags: Default::default(),
+            samples: None,
+            missing_fields: Default::default(),
+            non_finite: Default::default(),
+            channels: Default::default(),
+            calibration: Default::default(),
+            codes: Default::default(),
+            ranges: Default::default(),
+            plausibility: Default::default(),
+            counter_reset: None,
+            rates: Default::default(),
+            integrate: None,
+            daily_yield: None,
+            costs: Default::default(),
+            tariff: None,
+            co2: None,
+            carbon: None,
+            weather: None,
+            derived: Default::default(),
+            precision: Default::default(),
+            script: None,
+            plugin: None,
+            filter: Default::default(),
+            window: None,
+            dedup: None,
+            rename: Default::default(),
+            measurement: None,
+            quality_tags: false,
+            max_skew: SourceConfig::default_max_skew(),
+            poll_interval: None,
+            state: Default::default(),
+        }
+    }
+}
+
+impl From<BackendInfluxDB> for TargetConfig {
+    fn from(backend: BackendInfluxDB) -> Self {
+        Backend::InfluxDB(Box::new(backend)).into()
+    }
+}
+
+impl From<BackendMqtt> for TargetConfig {
+    fn from(backend: BackendMqtt) -> Self {
+        Backend::Mqtt(Box::new(backend)).into()
+    }
+}
+
+impl From<BackendPrometheus> for TargetConfig {
+    fn from(backend: BackendPrometheus) -> Self {
+        Backend::Prometheus(Box::new(backend)).into()
+    }
+}
+
+impl From<BackendCsv> for TargetConfig {
+    fn from(backend: BackendCsv) -> Self {
+        Backend::Csv(Box::new(backend)).into()
+    }
+}
+
+impl From<BackendSqlite> for TargetConfig {
+    fn from(backend: BackendSqlite) -> Self {
+        Backend::Sqlite(Box::new(backend)).into()
+    }
+}
+
+impl From<BackendPostgres> for TargetConfig {
+    fn from(backend: BackendPostgres) -> Self {
+        Backend::Postgres(Box::new(backend)).into()
+    }
+}
+
+impl From<Backend> for TargetConfig {
+    fn from(backend: Backend) -> Self {
+        Self {
+            backend,
+            name: None,
+            filter: Default::default(),
+            classify: Default::default(),
+            measurements: Default::default(),
+            site: None,
+            excluded_sites: vec![],
+        }
+    }
+}
+
+impl Source for SourceConfig {
+    fn id(&self) -> Cow<'_, str> {
+        self.device.id()
+    }
+
+    fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+        self.state.unfiltered = None;
+        self.state.implausible.clear();
+        // Channel values go through all stages as suffixed fields, like `voltageL1`
+        let mut channels = None;
+        let mut poll = || {
+            let mut data = self.device.poll_data()?;
+            channels = data.flatten_channels();
+            Ok(data)
+        };
+        let mut data = match &self.samples {
+            Some(samples) => samples.poll(poll)?,
+            None => poll()?,
+        };
+        if !self
+            .missing_fields
+            .apply(&mut data, &mut self.state.missing_fields)?
+        {
+            return Ok(PublishData::default());
+        }
+        let id = self.device.id().into_owned();
+        if !validation::sanitize(&mut data, self.non_finite, &mut self.state.non_finite, &id) {
+            return Ok(PublishData::default());
+        }
+        transform::calibrate(&mut data, &self.calibration);
+        transform::map_codes(&mut data, &self.codes);
+        let now = SystemTime::now();
+        if let Some(timestamp) = data.timestamp {
+            let skew = match timestamp.duration_since(now) {
+                Ok(ahead) => ahead,
+                Err(behind) => behind.duration(),
+            };
+            if skew > self.max_skew {
+                tracing::warn!("Ignoring timestamp of '{id}', which is off by {skew:?}");
+                data.timestamp = None;
+            }
+        }
+        let now = data.timestamp.unwrap_or(now);
+        validation::check_ranges(&mut data, &self.ranges, &mut self.state.ranges, &id);
+        self.state.implausible = validation::check_plausibility(
+            &mut data,
+            &self.plausibility,
+            &mut self.state.plausibility,
+            now,
+        );
+        if let Some(counter_reset) = &self.counter_reset {
+            counter_reset.apply(&mut data, &mut self.state.counter_reset);
+        }
+        for rate in &self.rates {
+            rate.apply(&mut data, &mut self.state.rates, now);
+        }
+        if let Some(integration) = &self.integrate {
+            integration.apply(&mut data, &mut self.state.integrate, now);
+        }
+        if let Some(daily_yield) = &self.daily_yield {
+            daily_yield.apply(&mut data, &mut self.state.daily_yield, now);
+        }
+        if let Some(tariff) = &self.tariff {
+            tariff.apply(&mut data, &self.costs, &mut self.state.costs, now);
+        }
+        if let (Some(field), Some(carbon)) = (&self.co2, &self.carbon) {
+            if let Err(err) = carbon.apply(&mut data, field, &mut self.state.carbon, now) {
+                tracing::warn!("Failed to compute avoided CO2 of '{id}': {err}");
+            }
+        }
+        if let Some(weather) = &self.weather {
+            if let Err(err) = weather.apply(&mut data, &mut self.state.weather, now) {
+                tracing::warn!("Failed to add weather to '{id}': {err:#}");
+            }
+        }
+        transform::derive(&mut data, &self.derived, &id);
+        transform::round(&mut data, &self.precision);
+        for (name, value) in &self.tags {
+            data.set_tag(name, value.clone());
+        }
+        if self.quality_tags {
+            quality::tag(&mut data);
+        }
+        if let Some(script) = &self.script {
+            data = match script.apply(data)? {
+                Some(data) => data,
+                None => return Ok(PublishData::default()),
+            };
+        }
+        if let Some(plugin) = &self.plugin {
+            data = match plugin.apply(data)? {
+                Some(data) => data,
+                None => return Ok(PublishData::default()),
+            };
+        }
+        self.state.unfiltered = Some(data.clone());
+        if !self.filter.apply(&mut data) {
+            return Ok(PublishData::default());
+        }
+        if let Some(window) = &self.window {
+            window.apply(&mut data, &mut self.state.window, now);
+        }
+        if let Some(dedup) = &self.dedup {
+            dedup.apply(&mut data, &mut self.state.dedup, now);
+        }
+        if let (ChannelMode::Points, Some(channels)) = (self.channels, channels) {
+            data.group_channels(channels);
+        }
+        for (from, to) in &self.rename {
+            data.rename(from, to);
+        }
+        if let Some(measurement) = &self.measurement {
+            data.set_measurement(measurement);
+        }
+        Ok(data)
+    }
+
+    fn not_modified(&self) -> bool {
+        self.device.not_modified()
+    }
+
+    fn unfiltered_data(&self) -> Option<PublishData> {
+        self.state.unfiltered.clone()
+    }
+
+    fn implausible_fields(&self) -> Vec<String> {
+        self.state.implausible.clone()
+    }
+
+    fn published(&mut self) {
+        self.device.published();
+        self.state.dedup.published();
+    }
+
+    fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval
+    }
+
+    fn save_state(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(&self.state).ok()
+    }
+
+    fn restore_state(&mut self, state: serde_json::Value) -> anyhow::Result<()> {
+        self.state = serde_json::from_value(state)?;
+        Ok(())
+    }
+}
+
+impl Target for TargetConfig {
+    fn id(&self) -> Cow<'_, str> {
+        match &self.name {
+            Some(name) => name.into(),
+            None => self.backend.id(),
+        }
+    }
+
+    fn publish(&self, data: &PublishData) -> anyhow::Result<()> {
+        if !self.accepts(data) {
+            return Ok(());
+        }
+        if self.filter.is_empty() && self.classify.is_empty() && self.measurements.is_empty() {
+            return self.backend.publish(data);
+        }
+        for point in self.points(data) {
+            self.backend.publish(&point)?;
+        }
+        Ok(())
+    }
+
+    fn batch(&self) -> bool {
+        self.backend.batch()
+    }
+
+    fn publish_batch(&self, data: &[PublishData]) -> anyhow::Result<()> {
+        let mut points = vec![];
+        for data in data.iter().filter(|data| self.accepts(data)) {
+            points.extend(self.points(data));
+        }
+        if points.is_empty() {
+            return Ok(());
+        }
+        self.backend.publish_batch(&points)
+    }
+
+    fn dropped_points(&self) -> u64 {
+        self.backend.dropped_points()
+    }
+}
+
+/// Implements `JsonSchema` for types deserialized from a string.
+#[macro_export]
+macro_rules! string_schema {
+    ($t: ty, $description: literal) => {
+        impl schemars::JsonSchema for $t {
+            fn schema_name() -> String {
+                stringify!($t).to_string()
+            }
+
+            fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+                schemars::schema::SchemaObject {
+                    instance_type: Some(schemars::schema::InstanceType::String.into()),
+                    metadata: Some(Box::new(schemars::schema::Metadata {
+                        description: Some($description.to_string()),
+                        ..Default::default()
+                    })),
+                    ..Default::default()
+                }
+                .into()
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_tags() {
+        let source: SourceConfig = serde_json::from_str(
+            r#"{"type":"Tasmota","host":"127.0.0.1","device_name":"plug","tags":{"site":"garage","owner":"me"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            source.device,
+            SourceDevice::Tasmota(Tasmota::new("127.0.0.1", "plug"))
+        );
+        assert_eq!(source.tags["site"], "garage");
+
+        let mut data = PublishData::default();
+        data.tag("site", "roof".to_string());
+        data.set_tag("site", "garage".to_string());
+        data.set_tag("owner", "me".to_string());
+        assert_eq!(data.fields().len(), 2);
+        assert_eq!(data["site"], Value::String("garage".to_string()));
+    }
+
+    #[derive(serde::Deserialize)]
+    struct ThreePhase {
+        name: String,
+    }
+
+    impl Source for ThreePhase {
+        fn id(&self) -> Cow<'_, str> {
+            (&self.name).into()
+        }
+
+        fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+            let mut data = PublishData::default();
+            data.channels("phase", ["L1", "L2", "L3"]);
+            data.channel_field("voltage", [230.0, 231.0, f64::NAN])?;
+            data.channel_field("power", [100.0, 290.0, 300.0])?;
+            Ok(data)
+        }
+    }
+
+    #[test]
+    fn test_channels_processed() {
+        registry::register_source::<ThreePhase>("ThreePhase");
+        let mut source: SourceConfig = serde_json::from_value(serde_json::json!({
+            "type": "ThreePhase",
+            "name": "meter",
+            "channels": "points",
+            "calibration": {"voltageL2": {"offset": -1}},
+            "filter": {"exclude": ["powerL1"]},
+            "rename": {"voltage": "u"},
+        }))
+        .unwrap();
+        let points = source.poll_data().unwrap().into_points();
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[0]["phase"], Value::String("L1".to_string()));
+        assert_eq!(points[0].number("u"), Some(230.0));
+        assert_eq!(points[0].number("power"), None);
+        assert_eq!(points[1].number("u"), Some(230.0));
+        // The NaN was dropped by the default policy
+        assert_eq!(points[2].number("u"), None);
+        assert_eq!(points[2].number("power"), Some(300.0));
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut data = PublishData::default();
+        data.tag("deviceName", "plug".to_string());
+        data.field("currentPower", 344.0);
+        data.rename("currentPower", "power_w");
+        assert_eq!(data["power_w"], Value::F64(344.0));
+        assert_eq!(data["deviceName"], Value::String("plug".to_string()));
+    }
+}
@@ -0,0 +1,254 @@
+//! Builder of InfluxDB line protocol points, see
+//! <https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/>.
+//!
+//! Tags are written sorted by key (as recommended for write performance), fields in the order
+//! they were added. Values line protocol can't represent (NaN, infinity, newlines in keys, empty
+//! keys, or keys with a backslash before a separator) and duplicate keys are errors instead of
+//! corrupting the whole batch.
+use anyhow::bail;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue<'a> {
+    Float(f64),
+    Integer(i64),
+    /// Written as an integer, for the nanoseconds of timestamps
+    Wide(i128),
+    Bool(bool),
+    String(&'a str),
+}
+
+#[derive(Debug, Default)]
+pub struct Point {
+    measurement: String,
+    tags: BTreeMap<String, String>,
+    fields: Vec<(String, String)>,
+    timestamp: Option<i128>,
+}
+
+/// Backslash escapes the `special` characters.
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes a measurement, tag key or value or field key. Backslashes are taken literally there,
+/// except before the `special` characters (or at the end, before the separator), so those can't
+/// be written.
+fn identifier(kind: &str, s: &str, special: &[char]) -> anyhow::Result<String> {
+    if s.is_empty() {
+        bail!("Empty {kind}");
+    }
+    if s.contains(['\n', '\r']) {
+        bail!("Line break in {kind} '{}'", s.escape_debug());
+    }
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek().is_none_or(|next| special.contains(next)) {
+            bail!("Backslash in {kind} '{s}' can't be written in line protocol");
+        }
+    }
+    Ok(escape(s, special))
+}
+
+impl Point {
+    pub fn new(measurement: &str) -> anyhow::Result<Self> {
+        Ok(Point {
+            measurement: identifier("measurement", measurement, &[',', ' '])?,
+            ..Default::default()
+        })
+    }
+
+    /// Adds a tag, tags with empty values are left out (line protocol has no empty tags).
+    pub fn tag(&mut self, key: &str, value: &str) -> anyhow::Result<&mut Self> {
+        if value.is_empty() {
+            return Ok(self);
+        }
+        let escaped = identifier("tag key", key, &[',', '=', ' '])?;
+        if self.tags.contains_key(&escaped) {
+            bail!("Duplicate tag '{key}'");
+        }
+        let value = identifier("tag value", value, &[',', '=', ' '])?;
+        self.tags.insert(escaped, value);
+        Ok(self)
+    }
+
+    pub fn field(&mut self, key: &str, value: FieldValue) -> anyhow::Result<&mut Self> {
+        let escaped = identifier("field key", key, &[',', '=', ' '])?;
+        if self.fields.iter().any(|(k, _)| *k == escaped) {
+            bail!("Duplicate field '{key}'");
+        }
+        let value = match value {
+            FieldValue::Float(f) if !f.is_finite() => bail!("Field '{key}' is {f}"),
+            // Debug formatting keeps integral values floats (`1.0`) and uses exponents for
+            // huge and tiny values, both are valid floats in line protocol
+            FieldValue::Float(f) => format!("{f:?}"),
+            FieldValue::Integer(i) => format!("{i}i"),
+            FieldValue::Wide(i) => format!("{i}i"),
+            FieldValue::Bool(b) => b.to_string(),
+            FieldValue::String(s) => format!("\"{}\"", escape(s, &['"', '\\'])),
+        };
+        self.fields.push((escaped, value));
+        Ok(self)
+    }
+
+    /// Nanoseconds since the epoch, the time of writing (by the server) if not set.
+    pub fn timestamp(&mut self, nanos: i128) -> &mut Self {
+        self.timestamp = Some(nanos);
+        self
+    }
+
+    /// The line, without a trailing line break.
+    pub fn build(&self) -> anyhow::Result<String> {
+        if self.fields.is_empty() {
+            bail!("No fields in '{}'", self.measurement);
+        }
+        let mut line = self.measurement.clone();
+        for (key, value) in &self.tags {
+            write!(line, ",{key}={value}")?;
+        }
+        for (i, (key, value)) in self.fields.iter().enumerate() {
+            let separator = if i == 0 { ' ' } else { ',' };
+            write!(line, "{separator}{key}={value}")?;
+        }
+        if let Some(timestamp) = self.timestamp {
+            write!(line, " {timestamp}")?;
+        }
+        Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The unescaped text before the first unescaped `separator`, and the rest after it. As in
+    /// InfluxDB, a backslash and the character after it are skipped over, and only the `escaped`
+    /// characters are unescaped.
+    fn split_once<'a>(s: &'a str, separator: char, escaped: &[char]) -> (String, &'a str) {
+        let mut unescaped = String::new();
+        let mut chars = s.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, next)) if escaped.contains(&next) => unescaped.push(next),
+                    next => unescaped.extend(Some(c).into_iter().chain(next.map(|(_, c)| c))),
+                },
+                c if c == separator => return (unescaped, &s[i + 1..]),
+                c => unescaped.push(c),
+            }
+        }
+        (unescaped, "")
+    }
+
+    #[test]
+    fn test_point() {
+        let mut point = Point::new("power generation").unwrap();
+        point
+            .tag("zone", "a,b")
+            .unwrap()
+            .tag("deviceName", "the thing")
+            .unwrap()
+            .tag("empty", "")
+            .unwrap()
+            .field("currentPower", FieldValue::Float(344.0))
+            .unwrap()
+            .field("count", FieldValue::Integer(-3))
+            .unwrap()
+            .field("status", FieldValue::String(r#"say "hi" \o/"#))
+            .unwrap()
+            .tag("path", r"C:\pv")
+            .unwrap()
+            .timestamp(3);
+        assert_eq!(
+            point.build().unwrap(),
+            r#"power\ generation,deviceName=the\ thing,path=C:\pv,zone=a\,b currentPower=344.0,count=-3i,status="say \"hi\" \\o/" 3"#
+        );
+        assert!(point.tag("dir", r"C:\").is_err());
+        assert!(point.tag(r"a\=b", "c").is_err());
+        assert!(point.field("count", FieldValue::Bool(true)).is_err());
+        assert!(point.tag("zone", "c").is_err());
+        assert!(point.field("nan", FieldValue::Float(f64::NAN)).is_err());
+        assert!(point.field("a\nb", FieldValue::Bool(true)).is_err());
+        assert!(Point::new("").is_err());
+        assert!(Point::new("empty").unwrap().build().is_err());
+
+        // Examples of the line protocol reference
+        let mut point = Point::new("my Measurement").unwrap();
+        point
+            .tag("tag Key1", "tag Value1")
+            .unwrap()
+            .tag("tag,Key2", "tag=Value2")
+            .unwrap()
+            .field("field=Key", FieldValue::String(r#"string "within" string"#))
+            .unwrap()
+            .field("field2", FieldValue::String(r"Hello\ world"))
+            .unwrap();
+        assert_eq!(
+            point.build().unwrap(),
+            r#"my\ Measurement,tag\ Key1=tag\ Value1,tag\,Key2=tag\=Value2 field\=Key="string \"within\" string",field2="Hello\\ world""#
+        );
+
+        // Random keys and values of special characters come out of a parser unchanged
+        let alphabet: Vec<char> = "ab ,=\"\\é".chars().collect();
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = |len: usize| -> String {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    alphabet[(state % alphabet.len() as u64) as usize]
+                })
+                .collect()
+        };
+        for _ in 0..500 {
+            let (measurement, tag_key, tag_value, field_key, value) =
+                (random(4), random(3), random(3), random(3), random(6));
+            let writable = |s: &str, special: &[char]| {
+                let mut chars = s.chars().peekable();
+                !std::iter::from_fn(|| Some((chars.next()?, chars.peek().copied())))
+                    .any(|(c, next)| c == '\\' && next.is_none_or(|n| special.contains(&n)))
+            };
+            let point = Point::new(&measurement).and_then(|mut point| {
+                point.tag(&tag_key, &tag_value)?;
+                point.field(&field_key, FieldValue::String(&value))?;
+                point.build()
+            });
+            let (measurement_chars, tag_chars) = (&[',', ' '], &[',', '=', ' ']);
+            let expected = writable(&measurement, measurement_chars)
+                && [&tag_key, &tag_value, &field_key]
+                    .iter()
+                    .all(|s| writable(s, tag_chars));
+            assert_eq!(point.is_ok(), expected, "{point:?}");
+            let Ok(line) = point else {
+                continue;
+            };
+            let (parsed_measurement, rest) = split_once(&line, ',', measurement_chars);
+            let (parsed_tag_key, rest) = split_once(rest, '=', tag_chars);
+            let (parsed_tag_value, rest) = split_once(rest, ' ', tag_chars);
+            let (parsed_field_key, rest) = split_once(rest, '=', tag_chars);
+            let quoted = rest.strip_prefix('"').unwrap().strip_suffix('"').unwrap();
+            let (parsed_value, rest) = split_once(quoted, '"', &['"', '\\']);
+            assert_eq!(
+                [
+                    parsed_measurement,
+                    parsed_tag_key,
+                    parsed_tag_value,
+                    parsed_field_key,
+                    parsed_value
+                ],
+                [measurement, tag_key, tag_value, field_key, value],
+                "{line}"
+            );
+            assert_eq!(rest, "", "{line}");
+        }
+    }
+}
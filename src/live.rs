@@ -0,0 +1,147 @@
+//! The latest reading and a rolling history of every device, for the local web endpoints. The
+//! history is only kept in memory, so short-term graphs work without (or while failing to publish
+//! to) a time series database.
+use crate::scheduler::CycleSummary;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// The numeric fields of a reading.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub time: SystemTime,
+    pub values: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Device {
+    /// Tags of the last successful reading
+    pub tags: BTreeMap<String, serde_json::Value>,
+    /// Fields of the last successful reading
+    pub values: BTreeMap<String, serde_json::Value>,
+    /// Error of the last poll, `None` if it succeeded
+    pub error: Option<String>,
+    pub polled: Option<SystemTime>,
+    /// Time of the last successful poll
+    pub updated: Option<SystemTime>,
+    /// Successful readings of the retention period, oldest first
+    pub history: VecDeque<Sample>,
+}
+
+impl Device {
+    /// The samples of the history between `from` and `to`.
+    pub fn range(&self, from: SystemTime, to: SystemTime) -> impl Iterator<Item = &Sample> {
+        self.history
+            .iter()
+            .filter(move |sample| sample.time >= from && sample.time <= to)
+    }
+
+    /// The last `count` values of a field, oldest first.
+    pub fn last_values(&self, field: &str, count: usize) -> Vec<f64> {
+        let mut values: Vec<_> = self
+            .history
+            .iter()
+            .rev()
+            .filter_map(|sample| sample.values.get(field).copied())
+            .take(count)
+            .collect();
+        values.reverse();
+        values
+    }
+}
+
+/// Devices by id, updated after every cycle.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Live {
+    pub devices: BTreeMap<String, Device>,
+    /// How long readings are kept in the history
+    pub retention: Duration,
+}
+
+impl Live {
+    pub fn new(retention: Duration) -> Self {
+        Live {
+            retention,
+            ..Default::default()
+        }
+    }
+
+    pub fn update(&mut self, summary: &CycleSummary, now: SystemTime) {
+        for source in &summary.sources {
+            let device = self.devices.entry(source.id.clone()).or_default();
+            device.polled = Some(now);
+            device.error = source.error.clone();
+            if !source.success {
+                // Keep showing the last values, marked as outdated by the error
+                continue;
+            }
+            device.updated = Some(now);
+            device.tags = source.tags.clone();
+            device.values = source.values.clone();
+            device.history.push_back(Sample {
+                time: now,
+                values: source
+                    .values
+                    .iter()
+                    .filter_map(|(name, value)| Some((name.clone(), value.as_f64()?)))
+                    .collect(),
+            });
+        }
+        let Some(start) = now.checked_sub(self.retention) else {
+            return;
+        };
+        for device in self.devices.values_mut() {
+            while device
+                .history
+                .front()
+                .is_some_and(|sample| sample.time < start)
+            {
+                device.history.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::SourceSummary;
+
+    #[test]
+    fn test_update() {
+        let summary = |power: Option<f64>| CycleSummary {
+            sources: vec![SourceSummary {
+                id: "plug".to_string(),
+                success: power.is_some(),
+                error: power.is_none().then(|| "Timed out".to_string()),
+                values: power
+                    .map(|power| [("currentPower".to_string(), power.into())].into())
+                    .unwrap_or_default(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut live = Live::new(Duration::from_secs(25));
+        for (i, power) in [Some(1.0), Some(2.0), None, Some(3.0)]
+            .into_iter()
+            .enumerate()
+        {
+            live.update(&summary(power), start + Duration::from_secs(10 * i as u64));
+        }
+        let device = &live.devices["plug"];
+        assert_eq!(device.last_values("currentPower", 5), [2.0, 3.0]);
+        assert_eq!(device.last_values("currentPower", 1), [3.0]);
+        let range: Vec<_> = device
+            .range(start, start + Duration::from_secs(15))
+            .map(|sample| sample.values["currentPower"])
+            .collect();
+        assert_eq!(range, [2.0]);
+        assert_eq!(device.values["currentPower"], 3.0);
+        assert_eq!(device.error, None);
+
+        live.update(&summary(None), start + Duration::from_secs(40));
+        let device = &live.devices["plug"];
+        assert_eq!(device.error.as_deref(), Some("Timed out"));
+        assert_eq!(device.values["currentPower"], 3.0);
+    }
+}
@@ -0,0 +1,106 @@
+//! Guard against running more than one instance at a time, e.g. overlapping cron invocations.
+//!
+//! The lock is either a file locked with `flock` (or `LockFileEx` on Windows) or, for names
+//! starting with `@`, a Linux abstract unix socket. Both are released by the OS when the process
+//! exits, so a crashed instance never leaves a stale lock behind.
+use anyhow::{bail, Context};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+/// Held until dropped.
+#[derive(Debug)]
+pub enum Lock {
+    File(File),
+    #[cfg(target_os = "linux")]
+    Socket(std::os::unix::net::UnixListener),
+}
+
+impl Lock {
+    /// Takes the lock `name`, a file path or `@<name>` for an abstract socket. Fails if another
+    /// process holds it.
+    pub fn acquire(name: &str) -> anyhow::Result<Lock> {
+        match name.strip_prefix('@') {
+            Some(name) => Self::socket(name),
+            None => Self::file(Path::new(name)),
+        }
+    }
+
+    fn file(path: &Path) -> anyhow::Result<Lock> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open lock file '{}'", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid).ok();
+                match pid.trim() {
+                    "" => bail!("Another instance holds the lock '{}'", path.display()),
+                    pid => bail!(
+                        "Another instance (pid {pid}) holds the lock '{}'",
+                        path.display()
+                    ),
+                }
+            }
+            Err(TryLockError::Error(err)) => {
+                return Err(err).with_context(|| format!("Failed to lock '{}'", path.display()))
+            }
+        }
+        // Only informational, the lock itself is what counts
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Lock::File(file))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn socket(name: &str) -> anyhow::Result<Lock> {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixListener};
+        let addr = SocketAddr::from_abstract_name(name)?;
+        match UnixListener::bind_addr(&addr) {
+            Ok(listener) => Ok(Lock::Socket(listener)),
+            Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => {
+                bail!("Another instance holds the lock '@{name}'")
+            }
+            Err(err) => Err(err).with_context(|| format!("Failed to bind '@{name}'")),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn socket(name: &str) -> anyhow::Result<Lock> {
+        bail!("Can't lock '@{name}', abstract sockets are only available on Linux")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let path = std::env::temp_dir().join(format!("sg-test-lock-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let lock = Lock::acquire(path).unwrap();
+        let err = Lock::acquire(path).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("pid {}", std::process::id())),
+            "{err}"
+        );
+        drop(lock);
+        Lock::acquire(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        #[cfg(target_os = "linux")]
+        {
+            let name = format!("@sg-test-lock-{}", std::process::id());
+            let _lock = Lock::acquire(&name).unwrap();
+            assert!(Lock::acquire(&name).is_err());
+        }
+    }
+}
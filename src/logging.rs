@@ -0,0 +1,182 @@
+//! Log output of the binary, filtered by `RUST_LOG` (`info` by default).
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
+
+/// Values of `--log-format`: human readable lines or one JSON object per line on stderr, or
+/// messages sent to the local syslog daemon or the systemd journal.
+pub const FORMATS: [&str; 4] = ["text", "json", "syslog", "journald"];
+
+fn filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// Installs the global subscriber.
+pub fn init(format: &str) {
+    let builder = tracing_subscriber::fmt().with_env_filter(filter());
+    #[cfg(unix)]
+    if let Some(kind) = match format {
+        "syslog" => Some(local::Kind::Syslog),
+        "journald" => Some(local::Kind::Journald),
+        _ => None,
+    } {
+        match local::Socket::connect(kind) {
+            Ok(socket) => {
+                builder
+                    .with_writer(socket)
+                    .with_ansi(false)
+                    .with_level(false)
+                    .without_time()
+                    .init();
+                return;
+            }
+            Err(err) => eprintln!("Logging to stderr, failed to connect to {kind:?}: {err}"),
+        }
+    }
+    let builder = builder.with_writer(std::io::stderr);
+    match format {
+        "json" => builder.json().init(),
+        _ => builder.init(),
+    }
+}
+
+/// Syslog severity of the level.
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// Legacy syslog message (RFC 3164 without timestamp and host, which the daemon adds) of the
+/// daemon facility.
+fn syslog_datagram(level: &Level, message: &str) -> Vec<u8> {
+    format!(
+        "<{}>{}[{}]: {message}",
+        3 * 8 + severity(level),
+        env!("CARGO_BIN_NAME"),
+        std::process::id()
+    )
+    .into_bytes()
+}
+
+/// Message of the journal's native protocol. The message may contain newlines, so it uses the
+/// binary form of a field: the name, a newline, the length as 64-bit little endian and the value.
+fn journald_datagram(level: &Level, message: &str) -> Vec<u8> {
+    let mut datagram = format!(
+        "PRIORITY={}\nSYSLOG_IDENTIFIER={}\nMESSAGE\n",
+        severity(level),
+        env!("CARGO_BIN_NAME")
+    )
+    .into_bytes();
+    datagram.extend_from_slice(&(message.len() as u64).to_le_bytes());
+    datagram.extend_from_slice(message.as_bytes());
+    datagram.push(b'\n');
+    datagram
+}
+
+#[cfg(unix)]
+mod local {
+    use std::io::Write;
+    use std::os::unix::net::UnixDatagram;
+    use tracing::{Level, Metadata};
+    use tracing_subscriber::fmt::MakeWriter;
+
+    #[derive(Debug, Clone, Copy)]
+    pub enum Kind {
+        Syslog,
+        Journald,
+    }
+
+    pub struct Socket {
+        kind: Kind,
+        socket: UnixDatagram,
+    }
+
+    impl Socket {
+        pub fn connect(kind: Kind) -> std::io::Result<Socket> {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(match kind {
+                Kind::Syslog => "/dev/log",
+                Kind::Journald => "/run/systemd/journal/socket",
+            })?;
+            Ok(Socket { kind, socket })
+        }
+    }
+
+    /// Buffers one formatted event, which is sent as a single datagram when dropped.
+    pub struct Message<'a> {
+        socket: &'a Socket,
+        level: Level,
+        buffer: Vec<u8>,
+    }
+
+    impl Write for Message<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for Message<'_> {
+        fn drop(&mut self) {
+            let message = String::from_utf8_lossy(&self.buffer);
+            let message = message.trim_end();
+            if message.is_empty() {
+                return;
+            }
+            let datagram = match self.socket.kind {
+                Kind::Syslog => super::syslog_datagram(&self.level, message),
+                Kind::Journald => super::journald_datagram(&self.level, message),
+            };
+            // There is nowhere left to report a failure to log
+            let _ = self.socket.socket.send(&datagram);
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Socket {
+        type Writer = Message<'a>;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            Message {
+                socket: self,
+                level: Level::INFO,
+                buffer: vec![],
+            }
+        }
+
+        fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+            Message {
+                socket: self,
+                level: *meta.level(),
+                buffer: vec![],
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datagrams() {
+        let syslog = String::from_utf8(syslog_datagram(&Level::WARN, "poll: down")).unwrap();
+        assert!(syslog.starts_with(concat!("<28>", env!("CARGO_BIN_NAME"), "[")));
+        assert!(syslog.ends_with("]: poll: down"));
+        let journald = journald_datagram(&Level::ERROR, "a\nb");
+        let header = concat!(
+            "PRIORITY=3\nSYSLOG_IDENTIFIER=",
+            env!("CARGO_BIN_NAME"),
+            "\nMESSAGE\n"
+        );
+        assert!(journald.starts_with(header.as_bytes()));
+        assert_eq!(journald[header.len()..header.len() + 8], 3u64.to_le_bytes());
+        assert_eq!(journald[header.len() + 8..], *b"a\nb\n");
+    }
+}
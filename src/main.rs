@@ -1,244 +1,689 @@
-mod sun600;
-mod tasmota;
+mod logging;
+mod wizard;
 
-use crate::sun600::Inverter;
-use crate::tasmota::Tasmota;
 use anyhow::{bail, Context};
-use clap::{Arg, Command};
-use std::borrow::Cow;
-use std::fs::File;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use sun_status_grabber::chaos::Chaos;
+use sun_status_grabber::config::{self, Format};
+use sun_status_grabber::http::{self, Broadcast, Response};
+use sun_status_grabber::live::Live;
+use sun_status_grabber::lock::Lock;
+use sun_status_grabber::stats::Stats;
+use sun_status_grabber::{
+    api, bench, dashboard, discover, duration, env, grafana, keyring, mock, shutdown, Config,
+    Field, PublishData, Scheduler, Source, Target, Value,
+};
 
-#[derive(serde::Deserialize, Debug, PartialEq)]
-pub struct Config {
-    pub sources: Vec<SourceDevice>,
-    pub targets: Vec<BackendInfluxDB>,
-}
-
-#[derive(serde::Deserialize, Debug, PartialEq)]
-pub struct BackendInfluxDB {
-    #[serde(rename = "influxUrl")]
-    pub influx_url: String,
-    pub bucket: String,
-    pub org: String,
-    pub token: String,
-    pub measurement: String,
-}
-
-#[derive(serde::Deserialize, Debug, PartialEq)]
-#[serde(tag = "type")]
-pub enum SourceDevice {
-    Inverter(Inverter),
-    Tasmota(Tasmota),
+fn cli() -> Command {
+    Command::new("Solar Info Grabber")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Pulls readings of solar inverters and smart plugs into InfluxDB")
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .env("SG_CONFIG")
+                .help("Config file or directory")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("sources")
+                .long("sources")
+                .env("SG_SOURCES")
+                .help("Sources as JSON, instead of a config file"),
+        )
+        .arg(
+            Arg::new("targets")
+                .env("SG_INFLUXDBS")
+                .help("Targets as JSON, instead of a config file"),
+        )
+        .arg(
+            Arg::new("tags")
+                .long("tags")
+                .env("SG_TAGS")
+                .help("Tags added to all readings, as JSON"),
+        )
+        .arg(
+            Arg::new("state-path")
+                .long("state-path")
+                .env("SG_STATE_PATH")
+                .help("File to keep state in between runs")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("summary-json")
+                .long("summary-json")
+                .env("SG_SUMMARY_JSON")
+                .help("Prints a summary of the run as JSON, with the values and status of each source and target")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("interval")
+                .long("interval")
+                .env("SG_INTERVAL")
+                .help("Keeps running, polling all sources every interval (e.g. 30s), instead of once; overrides 'interval' of the config")
+                .value_parser(duration::parse),
+        )
+        .arg(
+            Arg::new("lock")
+                .long("lock")
+                .env("SG_LOCK")
+                .help("Lock file (or @name for an abstract socket on Linux) held while running, so only one instance runs at a time"),
+        )
+        .arg(
+            Arg::new("chaos")
+                .long("chaos")
+                .env("SG_CHAOS")
+                .help("For testing: probability (0 to 1) of injecting a timeout, malformed response or server error into each poll and publish")
+                .value_parser(|rate: &str| match rate.parse::<f64>() {
+                    Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+                    _ => Err(format!("Expected a probability between 0 and 1, got '{rate}'")),
+                }),
+        )
+        .arg(
+            Arg::new("chaos-seed")
+                .long("chaos-seed")
+                .env("SG_CHAOS_SEED")
+                .help("Seed of the injected faults, for reproducible runs")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("metrics-listen")
+                .long("metrics-listen")
+                .env("SG_METRICS_LISTEN")
+                .help("Address to serve the grabber's own metrics on, at /metrics (e.g. 127.0.0.1:9100 or [::1]:9100)")
+                .value_parser(clap::value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("dashboard-listen")
+                .long("dashboard-listen")
+                .env("SG_DASHBOARD_LISTEN")
+                .help("Address to serve a web page and a JSON API with the latest readings on (e.g. 0.0.0.0:8080 or [::]:8080)")
+                .value_parser(clap::value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("history")
+                .long("history")
+                .env("SG_HISTORY")
+                .help("How long readings are kept in memory for the dashboard and the API")
+                .default_value("24h")
+                .value_parser(duration::parse),
+        )
+        .arg(
+            Arg::new("age-identity")
+                .long("age-identity")
+                .env("SG_AGE_IDENTITY")
+                .help("age key file to decrypt encrypted values and sops files of the config with")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .env("SG_LOG_FORMAT")
+                .help("Format of the log output on stderr, filtered by RUST_LOG")
+                .default_value("text")
+                .value_parser(logging::FORMATS),
+        )
+        .subcommand(Command::new("schema").about("Prints the JSON schema of the config file"))
+        .subcommand(
+            Command::new("completions")
+                .about("Prints the shell completions")
+                .arg(
+                    Arg::new("shell")
+                        .required(true)
+                        .value_parser(clap::value_parser!(clap_complete::Shell)),
+                ),
+        )
+        .subcommand(Command::new("manpage").about("Prints the man page"))
+        .subcommand(
+            Command::new("init")
+                .about("Asks for the devices and InfluxDB, and writes a config file")
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .help("Config file to write")
+                        .default_value(concat!("/etc/", env!("CARGO_BIN_NAME"), ".conf"))
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Overwrites an existing config file")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("migrate-config")
+                .about("Prints the config (from SG_SOURCES etc. or a file) as TOML or YAML")
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .help("Format to write")
+                        .default_value("toml")
+                        .value_parser(["toml", "yaml", "json"]),
+                ),
+        )
+        .subcommand(
+            Command::new("print-config")
+                .about("Prints the effective config, with defaults and global settings applied and secrets redacted")
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .help("Format to write")
+                        .default_value("toml")
+                        .value_parser(["toml", "yaml", "json"]),
+                ),
+        )
+        .subcommand(
+            Command::new("keyring-set")
+                .about("Stores a secret read from stdin in the OS keyring, for `keyring:` references")
+                .arg(
+                    Arg::new("reference")
+                        .help("<service>/<account>, or <account> of the service sun-status-grabber")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("test-source")
+                .about("Polls a single source and prints its readings, without publishing them")
+                .arg(
+                    Arg::new("name")
+                        .required(true)
+                        .help("Device name of the source"),
+                )
+                .arg(
+                    Arg::new("raw")
+                        .long("raw")
+                        .help("Prints the raw response of the device too")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("test-target")
+                .about("Publishes a test point to a single target")
+                .arg(
+                    Arg::new("name")
+                        .required(true)
+                        .help("Name (or URL) of the target"),
+                ),
+        )
+        .subcommand(
+            Command::new("discover")
+                .about("Looks for supported devices on the local network")
+                .arg(
+                    Arg::new("subnet")
+                        .long("subnet")
+                        .help("Any address of the /24 subnet to scan, the local one by default")
+                        .value_parser(clap::value_parser!(Ipv4Addr)),
+                ),
+        )
+        .subcommand(
+            Command::new("mock-server")
+                .about("Serves canned responses of an inverter, a Tasmota plug and a Shelly plug, for trying without the devices")
+                .arg(
+                    Arg::new("listen")
+                        .long("listen")
+                        .help("Address to listen on")
+                        .default_value("127.0.0.1")
+                        .value_parser(clap::value_parser!(IpAddr)),
+                )
+                .arg(
+                    Arg::new("port")
+                        .long("port")
+                        .help("Port of the inverter, the plugs get the following ones (0 for any free ports)")
+                        .default_value("8081")
+                        .value_parser(clap::value_parser!(u16)),
+                )
+                .arg(
+                    Arg::new("fixtures")
+                        .long("fixtures")
+                        .help("Directory with responses replacing the built-in ones: sun600.html, tasmota.html, shelly.json, shelly-status.json")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Polls all sources repeatedly and prints the latency percentiles of fetching, parsing and publishing")
+                .arg(
+                    Arg::new("count")
+                        .long("count")
+                        .help("Polls per source")
+                        .default_value("20")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("no-publish")
+                        .long("no-publish")
+                        .help("Doesn't publish the readings, so only the sources are measured")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("validate")
+                .about("Checks the config, exiting with an error if there are problems")
+                .arg(
+                    Arg::new("resolve")
+                        .long("resolve")
+                        .help("Checks that host names can be resolved")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
 }
 
-#[derive(Debug)]
-pub enum Field {
-    // Indexed
-    Tag(String, Value),
-    // Un-indexed
-    Field(String, Value),
-}
+fn load_config(matches: &ArgMatches) -> anyhow::Result<Config> {
+    let sources = matches.get_one::<String>("sources");
+    let targets = matches.get_one::<String>("targets");
 
-#[derive(Debug, PartialEq)]
-pub enum Value {
-    String(String),
-    F64(f64),
-}
+    let config = matches.get_one::<PathBuf>("config");
 
-#[derive(Default)]
-pub struct PublishData {
-    fields: Vec<Field>,
+    let mut result = match (config, sources, targets) {
+        (Some(config), _, _) => Config::load(config)?,
+        (None, Some(sources), Some(targets)) => Config {
+            sources: serde_json::from_str(sources)
+                .with_context(|| "Expected JSON for 'sources'")?,
+            targets: serde_json::from_str(targets)
+                .with_context(|| "Expected JSON for 'targets'")
+                .unwrap_or(vec![]),
+            tags: match matches.get_one::<String>("tags") {
+                Some(tags) => {
+                    serde_json::from_str(tags).with_context(|| "Expected JSON for 'tags'")?
+                }
+                None => Default::default(),
+            },
+            ..Default::default()
+        },
+        (None, Some(_), None) | (None, None, Some(_)) => {
+            bail!("Supply all arguments or none")
+        }
+        (None, None, None) => match env::load(std::env::vars())? {
+            Some(config) => config,
+            None => Config::load_first(
+                &[
+                    format!("/etc/{}.conf", env!("CARGO_BIN_NAME")),
+                    format!("/etc/{}.toml", env!("CARGO_BIN_NAME")),
+                    format!("/etc/{}.yaml", env!("CARGO_BIN_NAME")),
+                ],
+                format!("/etc/{}.d", env!("CARGO_BIN_NAME")),
+            )?,
+        },
+    };
+    if let Some(state_path) = matches.get_one::<PathBuf>("state-path") {
+        result.state_path = Some(state_path.clone());
+    }
+    Ok(result)
 }
 
-impl PublishData {
-    pub fn tag(&mut self, name: impl Into<String>, value: impl Into<Value>) {
-        self.fields.push(Field::Tag(name.into(), value.into()));
+fn main() -> anyhow::Result<ExitCode> {
+    let matches = cli().get_matches();
+    logging::init(matches.get_one::<String>("log-format").expect("defaulted"));
+    if let Some(("discover", args)) = matches.subcommand() {
+        let subnet = args.get_one::<Ipv4Addr>("subnet").copied();
+        let found = discover::discover(subnet, Duration::from_millis(500));
+        let mut sources = vec![];
+        for found in &found {
+            match found.snippet() {
+                Some(snippet) => sources.push(snippet),
+                None => eprintln!("Found {:?} at {}, not supported yet", found.kind, found.ip),
+            }
+        }
+        println!("{}", serde_json::to_string_pretty(&sources)?);
+        return Ok(ExitCode::SUCCESS);
     }
-
-    pub fn field(&mut self, name: impl Into<String>, value: impl Into<Value>) {
-        self.fields.push(Field::Field(name.into(), value.into()));
+    if let Some(("mock-server", args)) = matches.subcommand() {
+        let ip = *args.get_one::<IpAddr>("listen").expect("defaulted");
+        let port = *args.get_one::<u16>("port").expect("defaulted");
+        let servers = mock::serve(ip, port, args.get_one::<PathBuf>("fixtures").cloned())?;
+        let mut sources = vec![];
+        for (device, addr) in servers {
+            eprintln!("Serving a mock {device:?} on http://{addr}/");
+            sources.extend(device.snippet(addr));
+        }
+        eprintln!("Poll them with these sources:");
+        println!("{}", serde_json::to_string_pretty(&sources)?);
+        loop {
+            std::thread::park();
+        }
     }
-}
-
-impl std::ops::Index<&str> for PublishData {
-    type Output = Value;
-
-    fn index(&self, index: &str) -> &Self::Output {
-        self.fields
-            .iter()
-            .filter_map(|f| match f {
-                Field::Tag(name, value) | Field::Field(name, value) if name == index => Some(value),
-                _ => None,
-            })
-            .next()
-            .unwrap()
+    if let Some(("completions", args)) = matches.subcommand() {
+        let shell = *args
+            .get_one::<clap_complete::Shell>("shell")
+            .expect("required");
+        let mut out = std::io::stdout();
+        clap_complete::generate(shell, &mut cli(), env!("CARGO_BIN_NAME"), &mut out);
+        return Ok(ExitCode::SUCCESS);
     }
-}
-
-impl From<String> for Value {
-    fn from(s: String) -> Self {
-        Value::String(s)
+    if let Some(("manpage", _)) = matches.subcommand() {
+        clap_mangen::Man::new(cli().name(env!("CARGO_BIN_NAME"))).render(&mut std::io::stdout())?;
+        return Ok(ExitCode::SUCCESS);
     }
-}
-
-impl From<f64> for Value {
-    fn from(f: f64) -> Self {
-        Value::F64(f)
+    if let Some(("init", args)) = matches.subcommand() {
+        let output = args.get_one::<PathBuf>("output").expect("defaulted");
+        wizard::init(output, args.get_flag("force"))?;
+        return Ok(ExitCode::SUCCESS);
     }
-}
-
-impl SourceDevice {
-    fn poll_data(&mut self) -> anyhow::Result<PublishData> {
-        match self {
-            SourceDevice::Inverter(d) => d.poll_data(),
-            SourceDevice::Tasmota(d) => d.poll_data(),
+    if let Some(("keyring-set", args)) = matches.subcommand() {
+        let reference = args.get_one::<String>("reference").expect("required");
+        let mut secret = String::new();
+        std::io::stdin().read_line(&mut secret)?;
+        keyring::set(reference, secret.trim_end_matches(['\r', '\n']))?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(("schema", _)) = matches.subcommand() {
+        let schema = schemars::schema_for!(Config);
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(identity) = matches.get_one::<PathBuf>("age-identity") {
+        // Read by the config loading, before any other thread is started
+        std::env::set_var("SG_AGE_IDENTITY", identity);
+    }
+    if let Some(("migrate-config", args)) = matches.subcommand() {
+        print!(
+            "{}",
+            config::write(&raw_config(&matches)?, format_arg(args))?
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut config = load_config(&matches)?;
+    config.expand_sites();
+    if let Some(("print-config", args)) = matches.subcommand() {
+        config.inherit_globals();
+        let mut value = serde_json::to_value(&config)?;
+        config::redact(&mut value);
+        print!("{}", config::write(&value, format_arg(args))?);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(("validate", validate)) = matches.subcommand() {
+        let problems = config.validate(validate.get_flag("resolve"));
+        for problem in &problems {
+            eprintln!("{problem}");
+        }
+        if !problems.is_empty() {
+            bail!("Found {} problems in the config", problems.len());
         }
+        println!("Config is valid");
+        return Ok(ExitCode::SUCCESS);
     }
-
-    fn id(&self) -> Cow<str> {
-        match self {
-            SourceDevice::Inverter(d) => d.id(),
-            SourceDevice::Tasmota(d) => d.id(),
+    if let Some(("bench", args)) = matches.subcommand() {
+        config.inherit_globals();
+        let count = *args.get_one::<usize>("count").expect("defaulted");
+        let stages = bench::run(&config, count, !args.get_flag("no-publish"));
+        print!("{}", bench::table(&stages));
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(("test-source", args)) = matches.subcommand() {
+        let name = args.get_one::<String>("name").expect("required");
+        config.inherit_globals();
+        let source = config
+            .sources
+            .iter_mut()
+            .find(|source| source.id() == *name)
+            .with_context(|| format!("No source '{name}'"))?;
+        if args.get_flag("raw") {
+            println!("{}\n", source.device.fetch_raw()?);
+        }
+        for point in source.poll_data()?.into_points() {
+            print_reading(&point);
         }
+        return Ok(ExitCode::SUCCESS);
     }
-}
-
-impl Config {
-    pub fn load() -> anyhow::Result<Self> {
-        let matches = Command::new("Solar Info Grabber")
-            .arg(Arg::new("sources").long("sources").env("SG_SOURCES"))
-            .arg(Arg::new("targets").env("SG_INFLUXDBS"))
-            .get_matches();
-        let sources = matches.get_one::<String>("sources");
-        let targets = matches.get_one::<String>("targets");
-
-        let result = match (sources, targets) {
-            (Some(sources), Some(targets)) => Self {
-                sources: serde_json::from_str(sources)
-                    .with_context(|| "Expected JSON for 'sources'")?,
-                targets: serde_json::from_str(targets)
-                    .with_context(|| "Expected JSON for 'targets'")
-                    .unwrap_or(vec![]),
-            },
-            (Some(_), None) | (None, Some(_)) => {
-                bail!("Supply all arguments or none")
-            }
-            _ => {
-                let path = format!("/etc/{}.conf", env!("CARGO_BIN_NAME"));
-                serde_json::from_reader(
-                    File::open(&path)
-                        .with_context(|| format!("Failed to load config file: {}", path))?,
-                )?
+    if let Some(("test-target", args)) = matches.subcommand() {
+        let name = args.get_one::<String>("name").expect("required");
+        let target = config
+            .targets
+            .iter()
+            .find(|target| {
+                target.id() == *name
+                    || target
+                        .backend
+                        .influxdb()
+                        .is_some_and(|backend| backend.influx_url == *name)
+            })
+            .with_context(|| format!("No target '{name}'"))?;
+        let mut data = PublishData::default();
+        data.tag("deviceName", env!("CARGO_BIN_NAME").to_string());
+        data.field("testPoint", true);
+        let start = Instant::now();
+        let status = match target.backend.influxdb() {
+            Some(backend) => format!("HTTP {}", backend.write(&data)?),
+            None => {
+                target.backend.publish(&data)?;
+                "OK".to_string()
             }
         };
-        if result.sources.is_empty() {
-            bail!("No sources given");
+        println!(
+            "Published a test point to '{}': {status} after {} ms",
+            target.id(),
+            start.elapsed().as_millis()
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+    if config.sources.is_empty() {
+        bail!("No sources given");
+    }
+    if config.targets.is_empty() {
+        bail!("No publishers given, try 'targets' (SG_INFLUXDBS)");
+    }
+    let _lock = matches
+        .get_one::<String>("lock")
+        .map(|name| Lock::acquire(name))
+        .transpose()?;
+    let state_path = config.state_path.clone();
+    let mut watched = Watched::new(&config);
+    let modbus = config.modbus.clone();
+    let interval_arg = matches.get_one::<Duration>("interval").copied();
+    let mut interval = interval_arg.or(config.interval);
+    let mut scheduler = Scheduler::from(config);
+    if let Some(path) = state_path {
+        scheduler.load_state(path)?;
+    }
+    if let Some(rate) = matches.get_one::<f64>("chaos") {
+        tracing::warn!(
+            "Injecting faults into {:.0}% of the polls and publishes",
+            rate * 100.0
+        );
+        let seed = matches.get_one::<u64>("chaos-seed").copied();
+        scheduler.set_chaos(Chaos::new(*rate, seed));
+    }
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    if let Some(addr) = matches.get_one::<SocketAddr>("metrics-listen") {
+        let stats = stats.clone();
+        let addr = http::serve(*addr, move |request| match request.path.as_str() {
+            "/metrics" => Response::new(
+                "text/plain; version=0.0.4",
+                stats.lock().expect("not poisoned").prometheus(),
+            ),
+            _ => Response::not_found(),
+        })?;
+        tracing::info!("Serving metrics on http://{addr}/metrics");
+    }
+    let history = *matches.get_one::<Duration>("history").expect("default");
+    let live = Arc::new(Mutex::new(Live::new(history)));
+    let broadcast = Arc::new(Broadcast::default());
+    if let Some(addr) = matches.get_one::<SocketAddr>("dashboard-listen") {
+        let live = live.clone();
+        let broadcast = broadcast.clone();
+        let refresh = interval.map_or(30, |interval| interval.as_secs().max(5));
+        let addr = http::serve(*addr, move |request| {
+            let live = live.lock().expect("not poisoned");
+            match request.path.as_str() {
+                "/" => Response::new(
+                    "text/html; charset=utf-8",
+                    dashboard::html(&live, refresh, SystemTime::now()),
+                ),
+                "/api/stream" => Response::events(&broadcast),
+                path if path.starts_with("/grafana") => grafana::respond(&live, request),
+                path if path.starts_with("/api/") => api::respond(&live, request),
+                _ => Response::not_found(),
+            }
+        })?;
+        tracing::info!("Serving the dashboard on http://{addr}/ and the API at /api/devices");
+    }
+    if let Some(modbus) = modbus {
+        let addr = modbus.serve(live.clone())?;
+        tracing::info!("Serving Modbus TCP on {addr}");
+    }
+    if interval.is_some() {
+        shutdown::install()?;
+    }
+    loop {
+        let start = Instant::now();
+        if watched.changed() {
+            match load_config(&matches) {
+                Ok(config) => {
+                    tracing::info!("Reloading the changed config");
+                    watched = Watched::new(&config);
+                    interval = interval_arg.or(config.interval).or(interval);
+                    scheduler.reload(config);
+                }
+                Err(err) => tracing::error!("Keeping the config, failed to reload it: {err:#}"),
+            }
+        }
+        if let Some(interval) = interval {
+            scheduler.set_interval(interval);
         }
-        if result.targets.is_empty() {
-            bail!("No publishers given, try 'targets' (SG_INFLUXDBS)");
+        let summary = scheduler.run_cycle();
+        *stats.lock().expect("not poisoned") = scheduler.stats().clone();
+        let now = SystemTime::now();
+        live.lock().expect("not poisoned").update(&summary, now);
+        for event in api::events(&summary, now) {
+            broadcast.send("reading", &event);
+        }
+        if matches.get_flag("summary-json") {
+            println!("{}", serde_json::to_string(&summary)?);
+        }
+        let Some(interval) = interval else {
+            return Ok(ExitCode::from(summary.exit_code()));
+        };
+        let next = scheduler.next_due().unwrap_or(start + interval);
+        if shutdown::requested() || shutdown::sleep(next.saturating_duration_since(Instant::now()))
+        {
+            tracing::info!("Shutting down");
+            return Ok(ExitCode::SUCCESS);
         }
-        Ok(result)
     }
 }
 
-impl BackendInfluxDB {
-    pub fn publish(&self, data: &PublishData) -> anyhow::Result<()> {
-        // // influxdb2 crate forces the whole tokio ecosystem, so we'll do it manually
-        let mut write_url = url::Url::parse(&self.influx_url)?;
-        write_url.set_path("api/v2/write");
-        let mut line = escape!(&self.measurement; ',' ' ');
-        for f in &data.fields {
-            if let Field::Tag(name, value) = f {
-                line.push(',');
-                line.push_str(&escape!(name; ',' '=' ' '));
-                line.push('=');
-                line.push_str(&match value {
-                    Value::String(s) => escape!(s; ',' '=' ' '),
-                    Value::F64(f) => f.to_string(),
-                });
-            }
-        }
-        line.push(' ');
-        let mut first = true;
-        for f in &data.fields {
-            if let Field::Field(name, value) = f {
-                if first {
-                    first = false;
-                } else {
-                    line.push(',');
+fn format_arg(args: &ArgMatches) -> Format {
+    match args.get_one::<String>("format").map(String::as_str) {
+        Some("yaml") => Format::Yaml,
+        Some("json") => Format::Json,
+        _ => Format::Toml,
+    }
+}
+
+/// The settings given by the arguments (or their environment variables) or the config file,
+/// without interpolation and secrets resolved.
+fn raw_config(matches: &ArgMatches) -> anyhow::Result<serde_json::Value> {
+    let json = |name: &str| -> anyhow::Result<Option<serde_json::Value>> {
+        matches
+            .get_one::<String>(name)
+            .map(|value| serde_json::from_str(value))
+            .transpose()
+            .with_context(|| format!("Expected JSON for '{name}'"))
+    };
+    if matches.get_one::<PathBuf>("config").is_none() {
+        if let Some(sources) = json("sources")? {
+            let mut config = serde_json::json!({"sources": sources});
+            for name in ["targets", "tags"] {
+                if let Some(value) = json(name)? {
+                    config[name] = value;
                 }
-                line.push_str(&escape!(name; ',' '=' ' '));
-                line.push('=');
-                line.push_str(&match value {
-                    Value::String(s) => escape!(s; '"' '\\'),
-                    Value::F64(f) => f.to_string(),
-                });
             }
+            if let Some(state_path) = matches.get_one::<PathBuf>("state-path") {
+                config["statePath"] = state_path.to_string_lossy().into();
+            }
+            // Unlike config files, these are not interpolated
+            escape_dollars(&mut config);
+            return Ok(config);
         }
-        ureq::post(write_url.as_str())
-            .query_pairs([("bucket", self.bucket.as_str()), ("org", self.org.as_str())])
-            .set("Authorization", &format!("Token {}", self.token))
-            .send_string(&line)?;
-        Ok(())
     }
+    let path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => path.clone(),
+        None => ["conf", "toml", "yaml"]
+            .iter()
+            .map(|ext| PathBuf::from(format!("/etc/{}.{ext}", env!("CARGO_BIN_NAME"))))
+            .find(|path| path.exists())
+            .context("No config given, try 'sources' (SG_SOURCES) or 'config' (SG_CONFIG)")?,
+    };
+    if path.is_dir() {
+        bail!(
+            "'{}' is a directory, migrate its files one by one",
+            path.display()
+        );
+    }
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to load config file: {}", path.display()))?;
+    Format::of(&path).parse_value(&content)
 }
 
-#[macro_export]
-macro_rules! escape {
-    ($i: expr ; $($l: literal)+) => {{
-        let x = $i;
-        let mut result = String::with_capacity(x.len());
-        for c in x.chars() {
-            match c {
-                $(
-                    $l => {
-                        result.push('\\');
-                    }
-                )*
-                _ => ()
-            }
-            result.push(c);
-        }
-        result
-    }};
+fn escape_dollars(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(s) => *s = s.replace('$', "$$"),
+        serde_json::Value::Array(values) => values.iter_mut().for_each(escape_dollars),
+        serde_json::Value::Object(values) => values.values_mut().for_each(escape_dollars),
+        _ => (),
+    }
 }
 
-pub fn escape_tag_value(value: &str) -> String {
-    value
-        .replace('\\', "\\\\")
-        .replace(' ', "\\ ")
-        .replace('=', "\\=")
-        .replace(',', "\\,")
+/// Modification times of the config and secret files, to reload them once they change (e.g.
+/// rotated secrets mounted in Kubernetes), and when the secrets from Vault have to be read again.
+struct Watched(Vec<(PathBuf, Option<SystemTime>)>, Option<SystemTime>);
+
+impl Watched {
+    fn new(config: &Config) -> Self {
+        Watched(
+            config
+                .files
+                .iter()
+                .map(|file| (file.clone(), Self::modified(file)))
+                .collect(),
+            config.refresh_at,
+        )
+    }
+
+    fn modified(file: &Path) -> Option<SystemTime> {
+        std::fs::metadata(file).and_then(|m| m.modified()).ok()
+    }
+
+    fn changed(&self) -> bool {
+        self.0
+            .iter()
+            .any(|(file, modified)| Self::modified(file) != *modified)
+            || self.1.is_some_and(|at| SystemTime::now() >= at)
+    }
 }
 
-fn main() -> anyhow::Result<()> {
-    let mut config = Config::load()?;
-    for src in &mut config.sources {
-        match src.poll_data() {
-            Ok(data) => {
-                for dst in &config.targets {
-                    if let Err(err) = dst.publish(&data) {
-                        eprintln!("Failed to publish data to '{}': {err}", dst.influx_url);
-                    }
-                }
-            }
-            Err(err) => {
-                eprintln!("Failed to receive data from '{}': {err}", src.id());
-            }
-        }
-        // match src.request_status() {
-        //     Ok(status_data) => {
-        //     }
-        //     Err(err) => {
-        //     }
-        // }
-    }
-    Ok(())
+fn print_reading(data: &PublishData) {
+    if let Some(measurement) = data.measurement() {
+        println!("measurement {measurement}");
+    }
+    for f in data.fields() {
+        let (kind, name, value) = match f {
+            Field::Tag(name, value) => ("tag", name, value),
+            Field::Field(name, value) => ("field", name, value),
+        };
+        let value = match value {
+            Value::String(s) => format!("{s:?}"),
+            Value::F64(f) => f.to_string(),
+            Value::I64(i) => i.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Timestamp(t) => format!("{t:?}"),
+        };
+        println!("{kind:<5} {name} = {value}");
+    }
+    println!();
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use sun_status_grabber::sun600::Inverter;
+    use sun_status_grabber::{BackendInfluxDB, SourceDevice};
 
     #[test]
     fn test_env_config() {
@@ -257,7 +702,7 @@ mod tests {
                     ),
                 ),
             ],
-            || Config::load().unwrap(),
+            || load_config(&cli().get_matches()).unwrap(),
         );
         assert_eq!(
             result,
@@ -267,15 +712,29 @@ mod tests {
                     user: "user".to_string(),
                     password: "password".to_string(),
                     device_name: "the thing".to_string(),
-                    device_location: Some("backyard".to_string())
-                })],
+                    device_location: Some("backyard".to_string()),
+                    locale: Default::default(),
+                    conditional: Default::default(),
+                })
+                .into()],
                 targets: vec![BackendInfluxDB {
                     influx_url: "http://influx".to_string(),
+                    api_version: 2,
                     bucket: "bucket".to_string(),
                     org: "org".to_string(),
                     token: "token".to_string(),
-                    measurement: "measurement".to_string()
-                }]
+                    user: None,
+                    password: None,
+                    measurement: "measurement".to_string(),
+                    tls: None,
+                    oauth2: None,
+                    spool: None,
+                    retry: None,
+                    batch: false,
+                    http2: false,
+                }
+                .into()],
+                ..Default::default()
             }
         );
     }
@@ -0,0 +1,60 @@
+//! Routing of fields into different measurements.
+use crate::filter::Pattern;
+use crate::{Field, PublishData};
+use std::collections::BTreeMap;
+
+/// Splits the reading into one per measurement, holding the fields matching its patterns along
+/// with all tags. Fields matching several measurements are written to each, fields matching
+/// none stay in the original reading.
+pub fn split(data: PublishData, measurements: &BTreeMap<String, Vec<Pattern>>) -> Vec<PublishData> {
+    let matches = |name: &str, patterns: &[Pattern]| patterns.iter().any(|p| p.matches(name));
+    let mut points: Vec<_> = measurements
+        .iter()
+        .map(|(measurement, patterns)| {
+            let mut point = data.clone();
+            point.fields.retain(|f| match f {
+                Field::Tag(..) => true,
+                Field::Field(name, _) => matches(name, patterns),
+            });
+            point.set_measurement(measurement);
+            point
+        })
+        .collect();
+    let mut rest = data;
+    rest.fields.retain(|f| match f {
+        Field::Tag(..) => true,
+        Field::Field(name, _) => !measurements
+            .values()
+            .any(|patterns| matches(name, patterns)),
+    });
+    points.insert(0, rest);
+    points.retain(PublishData::has_fields);
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let measurements = serde_json::from_str(
+            r#"{"solar_power": ["currentPower"], "solar_energy": ["yield*", "totalYield"]}"#,
+        )
+        .unwrap();
+        let mut data = PublishData::default();
+        data.tag("deviceName", "roof".to_string());
+        data.field("currentPower", 300.0);
+        data.field("yieldToday", 1.5);
+        data.field("totalYield", 120.0);
+        data.field("rssi", -60_i64);
+        let points = split(data, &measurements);
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[0].measurement(), None);
+        assert_eq!(points[0].number("rssi"), Some(-60.0));
+        assert_eq!(points[1].measurement(), Some("solar_energy"));
+        assert_eq!(points[1].fields().len(), 3);
+        assert_eq!(points[2].measurement(), Some("solar_power"));
+        assert_eq!(points[2].number("currentPower"), Some(300.0));
+    }
+}
@@ -0,0 +1,96 @@
+//! What to do with readings lacking some of the fields a source usually reports.
+use crate::quality::Quality;
+use crate::{Field, PublishData, Value};
+use anyhow::bail;
+use std::collections::BTreeMap;
+
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    Debug,
+    PartialEq,
+    Default,
+    Clone,
+    Copy,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum MissingFields {
+    /// Fail the reading
+    #[default]
+    Error,
+    /// Skip the reading, without reporting an error
+    Drop,
+    /// Publish the fields which are there
+    Partial,
+    /// Publish the last known values of the missing fields
+    Fill,
+}
+
+/// Last known value of each field.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+pub struct MissingFieldsState {
+    last: BTreeMap<String, Value>,
+}
+
+impl MissingFields {
+    /// Applies the policy to the fields the source reported as missing. Returns `false` if the
+    /// reading should be dropped.
+    pub fn apply(
+        &self,
+        data: &mut PublishData,
+        state: &mut MissingFieldsState,
+    ) -> anyhow::Result<bool> {
+        if *self == MissingFields::Fill {
+            for f in &data.fields {
+                if let Field::Field(name, value) = f {
+                    state.last.insert(name.clone(), value.clone());
+                }
+            }
+        }
+        let missing = std::mem::take(&mut data.missing);
+        if missing.is_empty() {
+            return Ok(true);
+        }
+        match self {
+            MissingFields::Error => bail!("Could not parse {}", missing.join(", ")),
+            MissingFields::Drop => return Ok(false),
+            MissingFields::Partial => (),
+            MissingFields::Fill => {
+                for name in missing {
+                    if let Some(value) = state.last.get(&name) {
+                        data.field(name, value.clone());
+                        data.flag(Quality::Stale);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill() {
+        let mut state = MissingFieldsState::default();
+        let mut data = PublishData::default();
+        data.field("currentPower", 300.0);
+        data.field("totalYield", 12.5);
+        assert!(MissingFields::Fill.apply(&mut data, &mut state).unwrap());
+
+        let mut data = PublishData::default();
+        data.field("currentPower", 250.0);
+        data.missing("totalYield");
+        assert!(MissingFields::Fill.apply(&mut data, &mut state).unwrap());
+        assert_eq!(data.number("currentPower"), Some(250.0));
+        assert_eq!(data.number("totalYield"), Some(12.5));
+
+        data.missing("yieldToday");
+        assert!(!MissingFields::Drop.apply(&mut data, &mut state).unwrap());
+        data.missing("yieldToday");
+        assert!(MissingFields::Error.apply(&mut data, &mut state).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Local servers answering like the supported devices, for end-to-end tests and for trying the
+//! grabber without the hardware. The responses are built in, or read from fixture files on every
+//! request so they can be changed while running.
+use crate::http::{self, Request, Response};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+const SUN600: &str = r#"<html><head><title>Status</title></head><body><script>
+var cover_mid = "2304011234";
+var webdata_now_p = "344";
+var webdata_today_e = "1.25";
+var webdata_total_e = "1010.2";
+var webdata_alarm = "";
+var webdata_utime = "0";
+</script></body></html>
+"#;
+
+const TASMOTA_STATUS: &str = "{t}</table><hr/>{t}{s}Voltage{m}</td><td style='text-align:left'>234</td>\
+    <td>&nbsp;</td><td> V{e}{s}Active Power{m}</td><td style='text-align:left'>344</td><td>&nbsp;</td>\
+    <td> W{e}{s}Energy Today{m}</td><td style='text-align:left'>0.289</td><td>&nbsp;</td><td> kWh{e}\
+    {s}Energy Total{m}</td><td style='text-align:left'>0.291</td><td>&nbsp;</td><td> kWh{e}</table>";
+
+const TASMOTA_JSON: &str = r#"{"StatusSNS":{"Time":"2023-04-01T12:00:00","ENERGY":{"Total":0.291,"Yesterday":0.002,"Today":0.289,"Power":344,"Voltage":234}}}"#;
+
+const TASMOTA_INDEX: &str =
+    "<!DOCTYPE html><html><head><title>Tasmota</title></head><body></body></html>";
+
+const SHELLY: &str =
+    r#"{"type":"SHPLG-S","mac":"AABBCCDDEEFF","auth":false,"fw":"20230913-112003/v1.14.0"}"#;
+
+const SHELLY_STATUS: &str =
+    r#"{"relays":[{"ison":true}],"meters":[{"power":344.5,"is_valid":true,"total":60612}]}"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Device {
+    Sun600,
+    Tasmota,
+    Shelly,
+}
+
+impl Device {
+    pub const ALL: [Device; 3] = [Device::Sun600, Device::Tasmota, Device::Shelly];
+
+    /// Responses: the path (with the query), the fixture file, the built-in response and its
+    /// content type.
+    fn responses(self) -> &'static [(&'static str, &'static str, &'static str, &'static str)] {
+        match self {
+            Device::Sun600 => &[("/status.html", "sun600.html", SUN600, "text/html")],
+            Device::Tasmota => &[
+                (
+                    "/cm?cmnd=Status%208",
+                    "tasmota.json",
+                    TASMOTA_JSON,
+                    "application/json",
+                ),
+                ("/?m=1", "tasmota.html", TASMOTA_STATUS, "text/html"),
+                ("/", "tasmota-index.html", TASMOTA_INDEX, "text/html"),
+            ],
+            Device::Shelly => &[
+                ("/shelly", "shelly.json", SHELLY, "application/json"),
+                (
+                    "/status",
+                    "shelly-status.json",
+                    SHELLY_STATUS,
+                    "application/json",
+                ),
+            ],
+        }
+    }
+
+    /// Source config polling the mock at `addr`, if the device is supported as a source.
+    pub fn snippet(self, addr: SocketAddr) -> Option<serde_json::Value> {
+        match self {
+            Device::Sun600 => Some(serde_json::json!({
+                "type": "Inverter",
+                "statusPageUrl": format!("http://{addr}/status.html"),
+                "user": "admin",
+                "password": "admin",
+                "device_name": "mock inverter",
+            })),
+            Device::Tasmota => Some(serde_json::json!({
+                "type": "Tasmota",
+                "host": addr.to_string(),
+                "device_name": "mock plug",
+            })),
+            Device::Shelly => None,
+        }
+    }
+}
+
+fn respond(device: Device, fixtures: Option<&PathBuf>, request: &Request) -> Response {
+    let target = match &request.query {
+        Some(query) => format!("{}?{query}", request.path),
+        None => request.path.clone(),
+    };
+    let Some((_, file, builtin, content_type)) =
+        device.responses().iter().find(|(path, ..)| *path == target)
+    else {
+        return Response::not_found();
+    };
+    match fixtures.map(|dir| dir.join(file)) {
+        Some(path) if path.exists() => match std::fs::read(&path) {
+            Ok(body) => Response::new(content_type, body),
+            Err(err) => {
+                Response::error(500, &format!("Failed to read '{}': {err}", path.display()))
+            }
+        },
+        _ => Response::new(content_type, *builtin),
+    }
+}
+
+/// Serves every device on its own port, counting up from `port` (any free ports for 0). Files in
+/// `fixtures` named like `sun600.html` or `shelly.json` replace the built-in responses.
+pub fn serve(
+    ip: IpAddr,
+    port: u16,
+    fixtures: Option<PathBuf>,
+) -> anyhow::Result<Vec<(Device, SocketAddr)>> {
+    Device::ALL
+        .iter()
+        .enumerate()
+        .map(|(i, device)| {
+            let port = if port == 0 { 0 } else { port + i as u16 };
+            let fixtures = fixtures.clone();
+            let device = *device;
+            let addr = http::serve(SocketAddr::new(ip, port), move |request| {
+                respond(device, fixtures.as_ref(), request)
+            })?;
+            Ok((device, addr))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Source, SourceConfig};
+
+    #[test]
+    fn test_serve() {
+        let dir = std::env::temp_dir().join(format!("sg-test-mock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("sun600.html"), SUN600.replace("344", "512")).unwrap();
+        let mut servers = serve([127, 0, 0, 1].into(), 0, Some(dir.clone())).unwrap();
+        // Unless the test runs without IPv6
+        servers.extend(
+            serve(std::net::Ipv6Addr::LOCALHOST.into(), 0, Some(dir.clone()))
+                .ok()
+                .into_iter()
+                .flatten(),
+        );
+        for (device, addr) in servers {
+            let Some(snippet) = device.snippet(addr) else {
+                continue;
+            };
+            let mut source: SourceConfig = serde_json::from_value(snippet).unwrap();
+            let data = source.poll_data().unwrap();
+            let power = match device {
+                Device::Sun600 => 512.0,
+                _ => 344.0,
+            };
+            assert_eq!(data.number("currentPower"), Some(power), "{device:?}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
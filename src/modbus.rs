@@ -0,0 +1,252 @@
+//! Read-only Modbus TCP server of the latest readings, for wallboxes and energy managers which
+//! only speak Modbus. Fields are mapped to registers by the config, and served as holding and
+//! input registers alike (function codes 3 and 4), in big-endian word order.
+use crate::live::Live;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+/// Most registers read by one request.
+const MAX_COUNT: u16 = 125;
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+pub struct ModbusServer {
+    /// Address to listen on, like `0.0.0.0:502`
+    pub listen: SocketAddr,
+    pub registers: Vec<Register>,
+}
+
+/// A field of a device, at `address` and the registers after it for the 32-bit types.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Register {
+    /// Zero-based address of the (first) register
+    pub address: u16,
+    pub device: String,
+    pub field: String,
+    #[serde(default, rename = "type")]
+    pub kind: RegisterType,
+    /// Factor applied before rounding to the integer types, e.g. `10` for tenths
+    #[serde(default = "Register::default_scale")]
+    pub scale: f64,
+}
+
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum RegisterType {
+    #[default]
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl Register {
+    fn default_scale() -> f64 {
+        1.0
+    }
+
+    /// Number of registers taken.
+    pub fn width(&self) -> u16 {
+        match self.kind {
+            RegisterType::U16 | RegisterType::I16 => 1,
+            RegisterType::U32 | RegisterType::I32 | RegisterType::F32 => 2,
+        }
+    }
+
+    /// The words of `value`, saturated to the range of the type. Values which are unavailable
+    /// (e.g. the device failed to be polled) are the "not implemented" values of SunSpec.
+    fn words(&self, value: Option<f64>) -> Vec<u16> {
+        let value = value.map(|v| v * self.scale).filter(|v| v.is_finite());
+        let bits: u32 = match (self.kind, value) {
+            (RegisterType::U16, Some(v)) => v.round() as u16 as u32,
+            (RegisterType::I16, Some(v)) => v.round() as i16 as u16 as u32,
+            (RegisterType::U32, Some(v)) => v.round() as u32,
+            (RegisterType::I32, Some(v)) => v.round() as i32 as u32,
+            (RegisterType::F32, Some(v)) => (v as f32).to_bits(),
+            (RegisterType::U16, None) => 0xffff,
+            (RegisterType::I16, None) => 0x8000,
+            (RegisterType::U32, None) => 0xffff_ffff,
+            (RegisterType::I32, None) => 0x8000_0000,
+            (RegisterType::F32, None) => f32::NAN.to_bits(),
+        };
+        match self.width() {
+            1 => vec![bits as u16],
+            _ => vec![(bits >> 16) as u16, bits as u16],
+        }
+    }
+}
+
+impl ModbusServer {
+    /// The registers by address, with the current values.
+    fn values(&self, live: &Live) -> BTreeMap<u16, u16> {
+        let mut values = BTreeMap::new();
+        for register in &self.registers {
+            let value = live
+                .devices
+                .get(&register.device)
+                .filter(|device| device.error.is_none())
+                .and_then(|device| device.values.get(&register.field))
+                .and_then(|value| match value {
+                    serde_json::Value::Bool(b) => Some(f64::from(u8::from(*b))),
+                    value => value.as_f64(),
+                });
+            for (address, word) in (register.address as u32..).zip(register.words(value)) {
+                if let Ok(address) = u16::try_from(address) {
+                    values.entry(address).or_insert(word);
+                }
+            }
+        }
+        values
+    }
+
+    /// The response to the request `pdu` (function code and data).
+    fn respond(&self, live: &Live, pdu: &[u8]) -> Vec<u8> {
+        let function = pdu.first().copied().unwrap_or_default();
+        let exception = |code: u8| vec![function | 0x80, code];
+        if function != READ_HOLDING_REGISTERS && function != READ_INPUT_REGISTERS {
+            return exception(ILLEGAL_FUNCTION);
+        }
+        let [_, start_high, start_low, count_high, count_low] = *pdu else {
+            return exception(ILLEGAL_DATA_VALUE);
+        };
+        let start = u16::from_be_bytes([start_high, start_low]);
+        let count = u16::from_be_bytes([count_high, count_low]);
+        if !(1..=MAX_COUNT).contains(&count) {
+            return exception(ILLEGAL_DATA_VALUE);
+        }
+        let values = self.values(live);
+        let mut response = vec![function, (count * 2) as u8];
+        for address in (start as u32)..(start as u32 + count as u32) {
+            match u16::try_from(address).ok().and_then(|a| values.get(&a)) {
+                Some(word) => response.extend_from_slice(&word.to_be_bytes()),
+                None => return exception(ILLEGAL_DATA_ADDRESS),
+            }
+        }
+        response
+    }
+
+    fn handle(&self, mut stream: TcpStream, live: &Mutex<Live>) -> io::Result<()> {
+        loop {
+            // Transaction and protocol id, length and unit id
+            let mut header = [0; 7];
+            match stream.read_exact(&mut header) {
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                result => result?,
+            }
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if !(2..=254).contains(&length) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid length {length}"),
+                ));
+            }
+            let mut pdu = vec![0; length - 1];
+            stream.read_exact(&mut pdu)?;
+            let response = self.respond(&live.lock().expect("not poisoned"), &pdu);
+            let mut frame = header[..4].to_vec();
+            frame.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+            frame.push(header[6]);
+            frame.extend_from_slice(&response);
+            stream.write_all(&frame)?;
+        }
+    }
+
+    /// Binds `listen` and answers requests from `live` in the background, returning the bound
+    /// address.
+    pub fn serve(self, live: Arc<Mutex<Live>>) -> anyhow::Result<SocketAddr> {
+        let listener = TcpListener::bind(self.listen)
+            .map_err(|err| anyhow::anyhow!("Failed to listen on {}: {err}", self.listen))?;
+        let local = listener.local_addr()?;
+        let server = Arc::new(self);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let (server, live) = (server.clone(), live.clone());
+                std::thread::spawn(move || {
+                    if let Err(err) = server.handle(stream, &live) {
+                        tracing::debug!("Failed to answer Modbus request: {err}");
+                    }
+                });
+            }
+        });
+        Ok(local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modbus() {
+        let server: ModbusServer = serde_json::from_value(serde_json::json!({
+            "listen": "127.0.0.1:0",
+            "registers": [
+                {"address": 0, "device": "inverter", "field": "currentPower"},
+                {"address": 1, "device": "meter", "field": "gridPower", "type": "i32", "scale": 10},
+                {"address": 3, "device": "inverter", "field": "yieldToday", "type": "f32"},
+                {"address": 5, "device": "plug", "field": "power", "type": "i16"},
+            ],
+        }))
+        .unwrap();
+        let mut live = Live::default();
+        let inverter = live.devices.entry("inverter".to_string()).or_default();
+        inverter.values = [
+            ("currentPower".to_string(), 998.4.into()),
+            ("yieldToday".to_string(), 1.5.into()),
+        ]
+        .into();
+        let meter = live.devices.entry("meter".to_string()).or_default();
+        meter.values = [("gridPower".to_string(), (-12.34).into())].into();
+        let live = Arc::new(Mutex::new(live));
+        let addr = server.serve(live).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut request = |pdu: &[u8]| {
+            let mut frame = vec![0x12, 0x34, 0, 0, 0, pdu.len() as u8 + 1, 1];
+            frame.extend_from_slice(pdu);
+            stream.write_all(&frame).unwrap();
+            let mut header = [0; 7];
+            stream.read_exact(&mut header).unwrap();
+            assert_eq!(header[..4], [0x12, 0x34, 0, 0]);
+            let mut response = vec![0; header[5] as usize - 1];
+            stream.read_exact(&mut response).unwrap();
+            response
+        };
+        assert_eq!(
+            request(&[3, 0, 0, 0, 6]),
+            [
+                3, 12, // Byte count
+                0x03, 0xe6, // 998
+                0xff, 0xff, 0xff, 0x85, // -123
+                0x3f, 0xc0, 0x00, 0x00, // 1.5
+                0x80, 0x00, // Not available
+            ]
+        );
+        assert_eq!(request(&[4, 0, 1, 0, 2]), [4, 4, 0xff, 0xff, 0xff, 0x85]);
+        assert_eq!(request(&[3, 0, 5, 0, 2]), [0x83, ILLEGAL_DATA_ADDRESS]);
+        assert_eq!(request(&[3, 0, 0, 0, 0]), [0x83, ILLEGAL_DATA_VALUE]);
+        assert_eq!(request(&[6, 0, 0, 0, 1]), [0x86, ILLEGAL_FUNCTION]);
+    }
+}
@@ -0,0 +1,381 @@
+//! MQTT target, publishing the readings to a broker for Home Assistant, Node-RED and the like,
+//! without InfluxDB. Speaks just enough MQTT 3.1.1 to publish, connecting for every write.
+use crate::{template, Field, PublishData, Target, Value};
+use anyhow::{bail, Context};
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_PORT: u16 = 1883;
+const TIMEOUT: Duration = Duration::from_secs(10);
+const KEEP_ALIVE: u16 = 60;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PUBREC: u8 = 0x50;
+const PUBREL: u8 = 0x62;
+const PUBCOMP: u8 = 0x70;
+const DISCONNECT: u8 = 0xe0;
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendMqtt {
+    /// Broker as `host` or `host:port`, port 1883 by default
+    pub broker: String,
+    /// Topic, may be templated from the tags and fields like `solar/{deviceName}`
+    pub topic: String,
+    /// Publishes every field to `<topic>/<field>` as plain value, instead of the reading as JSON
+    #[serde(default)]
+    pub per_field: bool,
+    /// Quality of service: 0 (at most once), 1 (at least once) or 2 (exactly once)
+    #[serde(default)]
+    pub qos: u8,
+    /// Whether the broker keeps the last message of each topic for new subscribers
+    #[serde(default)]
+    pub retain: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Password of the `user`, which MQTT 3.1.1 doesn't allow without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default = "BackendMqtt::default_client_id")]
+    pub client_id: String,
+}
+
+impl Target for BackendMqtt {
+    fn id(&self) -> Cow<'_, str> {
+        format!("mqtt://{}", self.broker).into()
+    }
+
+    fn publish(&self, data: &PublishData) -> anyhow::Result<()> {
+        self.publish_batch(std::slice::from_ref(data))
+    }
+
+    fn publish_batch(&self, data: &[PublishData]) -> anyhow::Result<()> {
+        if self.qos > 2 {
+            bail!("Invalid QoS {}, expected 0, 1 or 2", self.qos);
+        }
+        let mut messages = vec![];
+        for data in data {
+            messages.extend(self.messages(data)?);
+        }
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let mut stream = self.connect()?;
+        for (i, (topic, payload)) in messages.iter().enumerate() {
+            let packet_id = (i % usize::from(u16::MAX)) as u16 + 1;
+            self.send(&mut stream, packet_id, topic, payload)
+                .with_context(|| format!("Failed to publish to '{topic}'"))?;
+        }
+        write_packet(&mut stream, DISCONNECT, &[])?;
+        Ok(())
+    }
+}
+
+impl BackendMqtt {
+    fn default_client_id() -> String {
+        env!("CARGO_PKG_NAME").to_string()
+    }
+
+    /// The topics and payloads of a reading.
+    fn messages(&self, data: &PublishData) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let topic = template::render(&self.topic, data)?;
+        if self.per_field {
+            let fields = data.fields().iter().filter_map(|field| match field {
+                Field::Field(name, value) => Some((format!("{topic}/{name}"), plain(value))),
+                Field::Tag(..) => None,
+            });
+            return Ok(fields.collect());
+        }
+        let mut object = serde_json::Map::new();
+        for field in data.fields() {
+            object.insert(field.name().to_string(), field.value().to_json());
+        }
+        let time = data.timestamp().unwrap_or_else(SystemTime::now);
+        object.insert("time".to_string(), Value::Timestamp(time).to_json());
+        Ok(vec![(topic, serde_json::to_vec(&object)?)])
+    }
+
+    fn addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        let addrs = match self.broker.to_socket_addrs() {
+            Ok(addrs) => addrs,
+            // Without a port
+            Err(_) => (self.broker.as_str(), DEFAULT_PORT)
+                .to_socket_addrs()
+                .with_context(|| format!("Failed to resolve broker '{}'", self.broker))?,
+        };
+        Ok(addrs.collect())
+    }
+
+    fn connect(&self) -> anyhow::Result<TcpStream> {
+        let mut last_err = None;
+        let mut stream = None;
+        for addr in self.addrs()? {
+            match TcpStream::connect_timeout(&addr, TIMEOUT) {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        let Some(mut stream) = stream else {
+            return Err(match last_err {
+                Some(err) => anyhow::Error::from(err),
+                None => anyhow::anyhow!("No address"),
+            })
+            .with_context(|| format!("Failed to connect to '{}'", self.broker));
+        };
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        // Clean session
+        let mut flags = 0x02;
+        let mut payload = string(&self.client_id)?;
+        if let Some(user) = &self.user {
+            flags |= 0x80;
+            payload.extend(string(user)?);
+        }
+        if let Some(password) = &self.password {
+            flags |= 0x40;
+            payload.extend(string(password)?);
+        }
+        let mut body = string("MQTT")?;
+        body.extend([4, flags]);
+        body.extend(KEEP_ALIVE.to_be_bytes());
+        body.extend(payload);
+        write_packet(&mut stream, CONNECT, &body)?;
+        let (kind, body) = read_packet(&mut stream)?;
+        if kind != CONNACK || body.len() != 2 {
+            bail!("Expected CONNACK from '{}', got {kind:#04x}", self.broker);
+        }
+        let reason = match body[1] {
+            0 => return Ok(stream),
+            1 => "unacceptable protocol version".to_string(),
+            2 => "client id rejected".to_string(),
+            3 => "server unavailable".to_string(),
+            4 => "bad user name or password".to_string(),
+            5 => "not authorized".to_string(),
+            code => format!("return code {code}"),
+        };
+        bail!("Broker '{}' refused the connection: {reason}", self.broker)
+    }
+
+    /// Publishes a message, waiting for the acknowledgements of its QoS.
+    fn send(
+        &self,
+        stream: &mut TcpStream,
+        packet_id: u16,
+        topic: &str,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut body = string(topic)?;
+        if self.qos > 0 {
+            body.extend(packet_id.to_be_bytes());
+        }
+        body.extend(payload);
+        write_packet(
+            stream,
+            PUBLISH | self.qos << 1 | u8::from(self.retain),
+            &body,
+        )?;
+        match self.qos {
+            0 => {}
+            1 => expect(stream, PUBACK, packet_id)?,
+            _ => {
+                expect(stream, PUBREC, packet_id)?;
+                write_packet(stream, PUBREL, &packet_id.to_be_bytes())?;
+                expect(stream, PUBCOMP, packet_id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A value as plain text, strings without quotes.
+fn plain(value: &Value) -> Vec<u8> {
+    match value.to_json() {
+        serde_json::Value::String(s) => s.into_bytes(),
+        value => value.to_string().into_bytes(),
+    }
+}
+
+/// A length-prefixed UTF-8 string.
+fn string(s: &str) -> anyhow::Result<Vec<u8>> {
+    let Ok(len) = u16::try_from(s.len()) else {
+        bail!(
+            "String of {} bytes is too long, at most 65535 are allowed",
+            s.len()
+        );
+    };
+    let mut bytes = len.to_be_bytes().to_vec();
+    bytes.extend(s.as_bytes());
+    Ok(bytes)
+}
+
+fn write_packet(stream: &mut impl Write, kind: u8, body: &[u8]) -> anyhow::Result<()> {
+    if body.len() > 268_435_455 {
+        bail!("Message of {} bytes is too large", body.len());
+    }
+    let mut packet = vec![kind];
+    // Remaining length, 7 bits per byte
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend(body);
+    stream.write_all(&packet)?;
+    Ok(())
+}
+
+fn read_packet(stream: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0];
+    stream.read_exact(&mut byte)?;
+    let kind = byte[0];
+    let mut len = 0;
+    for shift in [0, 7, 14, 21] {
+        stream.read_exact(&mut byte)?;
+        len |= usize::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; len];
+            stream.read_exact(&mut body)?;
+            return Ok((kind, body));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Invalid remaining length",
+    ))
+}
+
+/// Reads the acknowledgement `kind` of the packet `packet_id`.
+fn expect(stream: &mut impl Read, kind: u8, packet_id: u16) -> anyhow::Result<()> {
+    let (got, body) = read_packet(stream)?;
+    if got & 0xf0 != kind & 0xf0 || body != packet_id.to_be_bytes() {
+        bail!("Expected acknowledgement {kind:#04x} of packet {packet_id}, got {got:#04x}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_mqtt() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let (kind, connect) = read_packet(&mut stream).unwrap();
+                assert_eq!(kind, CONNECT);
+                // Protocol "MQTT" level 4, with user, password and clean session
+                assert_eq!(connect[..8], [0, 4, b'M', b'Q', b'T', b'T', 4, 0xc2]);
+                stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+                loop {
+                    let (kind, body) = read_packet(&mut stream).unwrap();
+                    if kind == DISCONNECT {
+                        break;
+                    }
+                    let len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+                    let topic = String::from_utf8(body[2..2 + len].to_vec()).unwrap();
+                    let packet_id = [body[2 + len], body[3 + len]];
+                    let payload = String::from_utf8(body[4 + len..].to_vec()).unwrap();
+                    stream.write_all(&[PUBACK, 2]).unwrap();
+                    stream.write_all(&packet_id).unwrap();
+                    sender.send((kind, topic, payload)).unwrap();
+                }
+            }
+        });
+        let mut mqtt: BackendMqtt = serde_json::from_value(serde_json::json!({
+            "broker": broker,
+            "topic": "solar/{deviceName}",
+            "qos": 1,
+            "retain": true,
+            "user": "grabber",
+            "password": "secret",
+        }))
+        .unwrap();
+        let mut data = PublishData::default();
+        data.tag("deviceName", "inverter".to_string());
+        data.field("currentPower", 344.5);
+        data.field("status", "ok".to_string());
+        data.set_timestamp(std::time::UNIX_EPOCH);
+        mqtt.publish(&data).unwrap();
+        let (kind, topic, payload) = receiver.recv().unwrap();
+        // QoS 1, retained
+        assert_eq!(kind, 0x33);
+        assert_eq!(topic, "solar/inverter");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&payload).unwrap(),
+            serde_json::json!({
+                "deviceName": "inverter",
+                "currentPower": 344.5,
+                "status": "ok",
+                "time": "1970-01-01T00:00:00+00:00",
+            })
+        );
+
+        let target = crate::TargetConfig::from(mqtt.clone());
+        let config = serde_json::to_value(&target).unwrap();
+        assert_eq!(config["type"], "MQTT");
+        assert_eq!(
+            serde_json::from_value::<crate::TargetConfig>(config).unwrap(),
+            target
+        );
+
+        mqtt.per_field = true;
+        mqtt.publish(&data).unwrap();
+        let messages: Vec<_> = receiver
+            .iter()
+            .take(2)
+            .map(|(_, topic, payload)| (topic, payload))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (
+                    "solar/inverter/currentPower".to_string(),
+                    "344.5".to_string()
+                ),
+                ("solar/inverter/status".to_string(), "ok".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_password_without_user() {
+        let config = serde_json::json!({
+            "type": "MQTT",
+            "broker": "localhost",
+            "topic": "solar",
+            "password": "secret",
+        });
+        let err = serde_json::from_value::<crate::Backend>(config).unwrap_err();
+        assert_eq!(err.to_string(), "missing field `user`");
+    }
+
+    #[test]
+    fn test_string() {
+        assert_eq!(string("MQTT").unwrap(), [0, 4, b'M', b'Q', b'T', b'T']);
+        assert_eq!(string(&"a".repeat(65535)).unwrap().len(), 65537);
+        assert_eq!(
+            string(&"a".repeat(65536)).unwrap_err().to_string(),
+            "String of 65536 bytes is too long, at most 65535 are allowed"
+        );
+    }
+}
@@ -0,0 +1,52 @@
+//! Notification targets for alerts, e.g. ntfy, Gotify or any other webhook.
+use std::collections::BTreeMap;
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+pub struct Notifier {
+    /// Referenced by the `notify` of alert rules
+    pub name: String,
+    /// URL the notifications are POSTed to
+    pub url: String,
+    #[serde(default)]
+    pub format: NotifyFormat,
+    /// Additional request headers, e.g. `Authorization`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    Default,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum NotifyFormat {
+    /// The message as plain text, with the title in a `Title` header (ntfy)
+    #[default]
+    Text,
+    /// `{"title": ..., "message": ...}` (Gotify)
+    Json,
+}
+
+impl Notifier {
+    pub fn send(&self, title: &str, message: &str) -> anyhow::Result<()> {
+        let mut request = ureq::post(&self.url);
+        for (name, value) in &self.headers {
+            request = request.set(name, value);
+        }
+        match self.format {
+            NotifyFormat::Text => request.set("Title", title).send_string(message)?,
+            NotifyFormat::Json => request
+                .set("Content-Type", "application/json")
+                .send_string(
+                    &serde_json::json!({"title": title, "message": message}).to_string(),
+                )?,
+        };
+        Ok(())
+    }
+}
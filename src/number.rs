@@ -0,0 +1,141 @@
+//! Tolerant parsing of numbers scraped from status pages, which some firmware localizes.
+use anyhow::{bail, Context};
+
+/// How numbers on the status page are formatted.
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    Default,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Locale {
+    /// Guesses from the separators: the last of `.` and `,` is the decimal separator, unless it is
+    /// repeated. Fails on a single separator followed by three digits, like `1,234`
+    #[default]
+    Auto,
+    /// `1,234.5`
+    DecimalPoint,
+    /// `1.234,5`
+    DecimalComma,
+}
+
+/// Parses a number, ignoring thousands separators and surrounding whitespace.
+pub fn parse(s: &str, locale: Locale) -> anyhow::Result<f64> {
+    let trimmed = s.trim();
+    let decimal = match locale {
+        Locale::DecimalPoint => Some('.'),
+        Locale::DecimalComma => Some(','),
+        Locale::Auto => match (trimmed.rfind('.'), trimmed.rfind(',')) {
+            (Some(point), Some(comma)) => Some(if comma > point { ',' } else { '.' }),
+            (Some(last), None) | (None, Some(last)) => {
+                let separator = trimmed[last..].chars().next().unwrap_or('.');
+                let (int, frac) = trimmed.split_at(last);
+                let frac = &frac[1..];
+                if int.contains(separator) {
+                    // Only thousands separators, like `1,234,567`
+                    None
+                } else if frac.len() == 3
+                    && frac.chars().all(|c| c.is_ascii_digit())
+                    && !int
+                        .trim_start_matches('-')
+                        .trim_start_matches('0')
+                        .is_empty()
+                {
+                    bail!(
+                        "Ambiguous number '{s}', set the 'locale' to 'decimalPoint' or 'decimalComma'"
+                    );
+                } else {
+                    Some(separator)
+                }
+            }
+            (None, None) => None,
+        },
+    };
+    let normalized: String = trimmed
+        .chars()
+        .filter_map(|c| match c {
+            c if Some(c) == decimal => Some('.'),
+            '.' | ',' | '\'' | ' ' | '\u{a0}' | '\u{202f}' => None,
+            c => Some(c),
+        })
+        .collect();
+    normalized
+        .parse()
+        .with_context(|| format!("Invalid number '{s}'"))
+}
+
+/// Canonical units of the values read from devices.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Unit {
+    /// W
+    Power,
+    /// kWh
+    Energy,
+}
+
+/// Recognized suffixes, along with their factor to the canonical unit.
+const UNITS: [(&str, Unit, f64); 6] = [
+    ("MWh", Unit::Energy, 1000.0),
+    ("kWh", Unit::Energy, 1.0),
+    ("Wh", Unit::Energy, 0.001),
+    ("MW", Unit::Power, 1_000_000.0),
+    ("kW", Unit::Power, 1000.0),
+    ("W", Unit::Power, 1.0),
+];
+
+/// Parses a number, which may be followed by a unit like `kW` or `Wh`, converting it to the
+/// canonical `unit`.
+pub fn parse_quantity(s: &str, locale: Locale, unit: Unit) -> anyhow::Result<f64> {
+    let trimmed = s.trim();
+    for (suffix, suffix_unit, factor) in UNITS {
+        if let Some(number) = trimmed.strip_suffix(suffix) {
+            if suffix_unit != unit {
+                bail!("Unexpected unit '{suffix}' in '{s}', expected {unit:?}");
+            }
+            return Ok(parse(number, locale)? * factor);
+        }
+    }
+    parse(trimmed, locale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(" 344 ", Locale::Auto).unwrap(), 344.0);
+        assert_eq!(parse("0.289", Locale::Auto).unwrap(), 0.289);
+        assert_eq!(parse("0,289", Locale::Auto).unwrap(), 0.289);
+        assert_eq!(parse("12,5", Locale::Auto).unwrap(), 12.5);
+        assert_eq!(parse("1,234,567", Locale::Auto).unwrap(), 1234567.0);
+        // Thousands or decimal separator, depending on the locale
+        assert!(parse("1,234", Locale::Auto).is_err());
+        assert!(parse("1.234", Locale::Auto).is_err());
+        assert_eq!(parse("1,234", Locale::DecimalPoint).unwrap(), 1234.0);
+        assert_eq!(parse("1.010,2", Locale::Auto).unwrap(), 1010.2);
+        assert_eq!(parse("1,010.2", Locale::Auto).unwrap(), 1010.2);
+        assert_eq!(parse("1.010", Locale::DecimalComma).unwrap(), 1010.0);
+        assert_eq!(parse("1 010,5", Locale::DecimalComma).unwrap(), 1010.5);
+        assert!(parse("n/a", Locale::Auto).is_err());
+    }
+
+    #[test]
+    fn test_parse_quantity() {
+        let parse = |s| parse_quantity(s, Locale::Auto, Unit::Energy);
+        assert_eq!(parse("0.289").unwrap(), 0.289);
+        assert_eq!(parse("0.289 kWh").unwrap(), 0.289);
+        assert_eq!(parse("289Wh").unwrap(), 0.289);
+        assert_eq!(parse("1,2 MWh").unwrap(), 1200.0);
+        assert!(parse("344 W").is_err());
+        assert_eq!(
+            parse_quantity("0.5 kW", Locale::Auto, Unit::Power).unwrap(),
+            500.0
+        );
+    }
+}
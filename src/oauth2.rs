@@ -0,0 +1,148 @@
+//! OAuth2 client credentials grant (RFC 6749 section 4.4), for targets behind proxies
+//! authenticating with bearer tokens. Tokens are fetched on first use, and again shortly before
+//! they expire or after the target rejected them.
+use crate::eyeballs;
+use anyhow::Context;
+use base64::{engine::general_purpose, Engine as _};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Tokens are refreshed this long before they expire, for slow requests and clock skew.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OAuth2 {
+    /// Token endpoint, like `https://auth.example.com/realms/home/protocol/openid-connect/token`
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Space separated scopes to request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip)]
+    token: TokenCache,
+}
+
+/// Shared by the clones of a target, e.g. for several sites.
+#[derive(Clone, Default)]
+struct TokenCache(Arc<Mutex<Option<Token>>>);
+
+impl PartialEq for TokenCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl std::fmt::Debug for TokenCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TokenCache")
+    }
+}
+
+struct Token {
+    access_token: String,
+    /// Tokens without `expires_in` are used until rejected
+    expires: Option<Instant>,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl OAuth2 {
+    /// The current access token, fetching a new one if there is none or it expires soon.
+    pub fn token(&self) -> anyhow::Result<String> {
+        let mut cached = self.token.0.lock().expect("not poisoned");
+        let valid = |token: &&Token| {
+            token
+                .expires
+                .is_none_or(|expires| Instant::now() + EXPIRY_MARGIN < expires)
+        };
+        if let Some(token) = cached.as_ref().filter(valid) {
+            return Ok(token.access_token.clone());
+        }
+        let token = self.fetch()?;
+        let access_token = token.access_token.clone();
+        *cached = Some(token);
+        Ok(access_token)
+    }
+
+    /// Forgets the token, after the target rejected it.
+    pub fn invalidate(&self) {
+        *self.token.0.lock().expect("not poisoned") = None;
+    }
+
+    fn fetch(&self) -> anyhow::Result<Token> {
+        // The credentials are form encoded before going into the Basic authorization
+        let encode =
+            |s: &str| url::form_urlencoded::byte_serialize(s.as_bytes()).collect::<String>();
+        let credentials = format!(
+            "{}:{}",
+            encode(&self.client_id),
+            encode(&self.client_secret)
+        );
+        let mut form = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+        let response = eyeballs::agent()
+            .post(&self.token_url)
+            .set(
+                "Authorization",
+                &format!("Basic {}", general_purpose::STANDARD.encode(credentials)),
+            )
+            .send_form(&form)
+            .with_context(|| format!("Failed to get a token from '{}'", self.token_url))?;
+        let response: TokenResponse = serde_json::from_str(&response.into_string()?)
+            .with_context(|| format!("Invalid token response of '{}'", self.token_url))?;
+        Ok(Token {
+            access_token: response.access_token,
+            expires: response
+                .expires_in
+                .map(|seconds| Instant::now() + Duration::from_secs(seconds)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{serve, Response};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_token() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let addr = serve("127.0.0.1:0".parse().unwrap(), move |request| {
+            let body = String::from_utf8_lossy(&request.body);
+            if request.method != "POST" || !body.contains("grant_type=client_credentials") {
+                return Response::error(400, "Bad request");
+            }
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            // The first token expires within the margin
+            let expires_in = if n == 0 { 10 } else { 3600 };
+            Response::new(
+                "application/json",
+                format!(r#"{{"access_token":"token-{n}","token_type":"Bearer","expires_in":{expires_in}}}"#),
+            )
+        })
+        .unwrap();
+        let oauth2: OAuth2 = serde_json::from_value(serde_json::json!({
+            "tokenUrl": format!("http://{addr}/token"),
+            "clientId": "grabber",
+            "clientSecret": "s3cret&",
+            "scope": "write",
+        }))
+        .unwrap();
+        assert_eq!(oauth2.token().unwrap(), "token-0");
+        assert_eq!(oauth2.token().unwrap(), "token-1");
+        assert_eq!(oauth2.clone().token().unwrap(), "token-1");
+        oauth2.invalidate();
+        assert_eq!(oauth2.token().unwrap(), "token-2");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,450 @@
+//! Custom sources and transforms as WebAssembly plugins, available with the `wasm` feature. A
+//! plugin is a `.wasm` file, written in any language compiling to WebAssembly, run in an
+//! interpreter without access to the system.
+//!
+//! Readings are passed as JSON objects `{"fields": {...}, "tags": {...}}` through the memory of
+//! the plugin, timestamps as RFC 3339 strings. Plugins export:
+//! - `memory`
+//! - `alloc(len: i32) -> i32`, reserving `len` bytes for the grabber to write to
+//! - `init(ptr: i32, len: i32)` (optional), called once with the `settings` of a source as JSON,
+//!   `null` for transforms
+//! - `poll() -> i64` (sources), returning a reading as `ptr << 32 | len`
+//! - `transform(ptr: i32, len: i32) -> i64` (transforms), returning the transformed reading like
+//!   `poll`, or 0 to drop it
+//!
+//! A returned `{"error": "..."}` fails the poll. Plugins may import `env.log(level: i32, ptr: i32,
+//! len: i32)` to log a message at level 1 (error) to 4 (debug).
+use crate::{Field, PublishData, Source, Value};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(serde::Deserialize)]
+#[serde(try_from = "PathBuf")]
+pub struct Plugin {
+    path: PathBuf,
+    #[cfg(feature = "wasm")]
+    instance: std::sync::Mutex<wasm::Instance>,
+}
+
+crate::string_schema!(Plugin, "Path of a WebAssembly plugin");
+
+impl TryFrom<PathBuf> for Plugin {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        Plugin::load(path, &serde_json::Value::Null)
+    }
+}
+
+impl Plugin {
+    /// Loads the plugin, passing the `settings` to its `init`.
+    #[cfg(feature = "wasm")]
+    pub fn load(path: PathBuf, settings: &serde_json::Value) -> anyhow::Result<Self> {
+        let instance = wasm::Instance::load(&path, settings)
+            .map_err(|err| anyhow::anyhow!("Failed to load '{}': {err:#}", path.display()))?;
+        Ok(Plugin {
+            path,
+            instance: instance.into(),
+        })
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    pub fn load(path: PathBuf, _settings: &serde_json::Value) -> anyhow::Result<Self> {
+        anyhow::bail!(
+            "Can't load '{}', plugins require building with the 'wasm' feature",
+            path.display()
+        )
+    }
+
+    /// Calls `poll` of the plugin, returning its reading.
+    pub fn poll(&self) -> anyhow::Result<PublishData> {
+        let mut data = PublishData::default();
+        if let Some(reading) = self.call("poll", None)? {
+            self.read(&mut data, &reading, &BTreeSet::new())?;
+        }
+        Ok(data)
+    }
+
+    /// Runs `transform` of the plugin on a reading, returns `None` if the plugin dropped it. Like
+    /// with scripts, only the fields and tags are replaced.
+    pub fn apply(&self, mut data: PublishData) -> anyhow::Result<Option<PublishData>> {
+        let mut fields = serde_json::Map::new();
+        let mut tags = serde_json::Map::new();
+        let mut timestamps = BTreeSet::new();
+        for f in data.fields() {
+            if let Value::Timestamp(_) = f.value() {
+                timestamps.insert(f.name().to_string());
+            }
+            match f {
+                Field::Tag(name, value) => tags.insert(name.clone(), value.to_json()),
+                Field::Field(name, value) => fields.insert(name.clone(), value.to_json()),
+            };
+        }
+        let reading = serde_json::json!({"fields": fields, "tags": tags}).to_string();
+        let Some(reading) = self.call("transform", Some(reading.as_bytes()))? else {
+            return Ok(None);
+        };
+        data.fields.clear();
+        self.read(&mut data, &reading, &timestamps)?;
+        Ok(Some(data))
+    }
+
+    /// Adds the fields and tags of a reading returned by the plugin, turning the strings named in
+    /// `timestamps` back into timestamps.
+    fn read(
+        &self,
+        data: &mut PublishData,
+        reading: &[u8],
+        timestamps: &BTreeSet<String>,
+    ) -> anyhow::Result<()> {
+        #[derive(serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Reading {
+            #[serde(default)]
+            fields: serde_json::Map<String, serde_json::Value>,
+            #[serde(default)]
+            tags: serde_json::Map<String, serde_json::Value>,
+            error: Option<String>,
+        }
+
+        let path = self.path.display();
+        let reading: Reading = serde_json::from_slice(reading)
+            .map_err(|err| anyhow::anyhow!("Invalid reading of '{path}': {err}"))?;
+        if let Some(error) = reading.error {
+            anyhow::bail!("Plugin '{path}' failed: {error}");
+        }
+        let value = |name: &str, value: serde_json::Value| {
+            use serde_json::Value as Json;
+            Ok(match value {
+                Json::String(s) if timestamps.contains(name) => {
+                    match chrono::DateTime::parse_from_rfc3339(&s) {
+                        Ok(time) => Value::Timestamp(time.into()),
+                        Err(_) => Value::String(s),
+                    }
+                }
+                Json::String(s) => Value::String(s),
+                Json::Bool(b) => Value::Bool(b),
+                Json::Number(n) => match n.as_i64() {
+                    Some(i) => Value::I64(i),
+                    None => Value::F64(n.as_f64().unwrap_or(f64::NAN)),
+                },
+                other => anyhow::bail!("Unsupported value {other} of '{name}' from '{path}'"),
+            })
+        };
+        for (name, json) in reading.tags {
+            let value = value(&name, json)?;
+            data.tag(name, value);
+        }
+        for (name, json) in reading.fields {
+            let value = value(&name, json)?;
+            data.field(name, value);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "wasm")]
+    fn call(&self, function: &str, input: Option<&[u8]>) -> anyhow::Result<Option<Vec<u8>>> {
+        self.instance
+            .lock()
+            .expect("not poisoned")
+            .call(function, input)
+            .map_err(|err| anyhow::anyhow!("Plugin '{}' failed: {err:#}", self.path.display()))
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    fn call(&self, _function: &str, _input: Option<&[u8]>) -> anyhow::Result<Option<Vec<u8>>> {
+        unreachable!("plugins can't be loaded without the 'wasm' feature")
+    }
+}
+
+impl PartialEq for Plugin {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl serde::Serialize for Plugin {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.path.serialize(serializer)
+    }
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Plugin").field("path", &self.path).finish()
+    }
+}
+
+/// Settings of a [`WasmSource`].
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WasmSettings {
+    device_name: String,
+    module: PathBuf,
+    #[serde(default)]
+    settings: serde_json::Value,
+}
+
+/// A source implemented by a plugin, configured as `{"type": "Wasm", "deviceName": ...,
+/// "module": "meter.wasm", "settings": {...}}`.
+#[derive(serde::Deserialize)]
+#[serde(try_from = "WasmSettings")]
+pub struct WasmSource {
+    device_name: String,
+    plugin: Plugin,
+}
+
+impl TryFrom<WasmSettings> for WasmSource {
+    type Error = anyhow::Error;
+
+    fn try_from(settings: WasmSettings) -> Result<Self, Self::Error> {
+        Ok(WasmSource {
+            plugin: Plugin::load(settings.module, &settings.settings)?,
+            device_name: settings.device_name,
+        })
+    }
+}
+
+impl Source for WasmSource {
+    fn id(&self) -> Cow<'_, str> {
+        (&self.device_name).into()
+    }
+
+    fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+        let mut data = self.plugin.poll()?;
+        data.tag("deviceName", self.device_name.clone());
+        Ok(data)
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod wasm {
+    use anyhow::Context;
+    use std::path::Path;
+    use wasmi::{Caller, Engine, Extern, Linker, Memory, Module, Store};
+
+    /// Instructions a plugin may run per call, so a plugin stuck in a loop fails instead of
+    /// stalling its source.
+    const FUEL: u64 = 100_000_000;
+
+    pub struct Instance {
+        store: Store<String>,
+        instance: wasmi::Instance,
+        memory: Memory,
+    }
+
+    impl Instance {
+        pub fn load(path: &Path, settings: &serde_json::Value) -> anyhow::Result<Self> {
+            let wasm = std::fs::read(path)?;
+            let mut config = wasmi::Config::default();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config);
+            let module = Module::new(&engine, &wasm[..])?;
+            let mut store = Store::new(&engine, path.display().to_string());
+            let mut linker = Linker::new(&engine);
+            linker.func_wrap(
+                "env",
+                "log",
+                |caller: Caller<'_, String>, level: i32, ptr: i32, len: i32| {
+                    let Some(memory) = caller.get_export("memory").and_then(Extern::into_memory)
+                    else {
+                        return;
+                    };
+                    let message = memory
+                        .data(&caller)
+                        .get(ptr as u32 as usize..)
+                        .and_then(|data| data.get(..len as u32 as usize))
+                        .map(String::from_utf8_lossy)
+                        .unwrap_or_default();
+                    let plugin = caller.data();
+                    match level {
+                        1 => tracing::error!("{plugin}: {message}"),
+                        2 => tracing::warn!("{plugin}: {message}"),
+                        3 => tracing::info!("{plugin}: {message}"),
+                        _ => tracing::debug!("{plugin}: {message}"),
+                    }
+                },
+            )?;
+            store.set_fuel(FUEL)?;
+            let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+            let memory = instance
+                .get_memory(&store, "memory")
+                .context("No exported 'memory'")?;
+            let mut instance = Instance {
+                store,
+                instance,
+                memory,
+            };
+            if let Ok(init) = instance
+                .instance
+                .get_typed_func::<(i32, i32), ()>(&instance.store, "init")
+            {
+                let (ptr, len) = instance.write(settings.to_string().as_bytes())?;
+                init.call(&mut instance.store, (ptr, len))?;
+            }
+            Ok(instance)
+        }
+
+        /// Writes `input` to memory reserved with `alloc`, returning its pointer and length.
+        fn write(&mut self, input: &[u8]) -> anyhow::Result<(i32, i32)> {
+            let len = i32::try_from(input.len())?;
+            let ptr = self
+                .instance
+                .get_typed_func::<i32, i32>(&self.store, "alloc")
+                .context("No exported 'alloc'")?
+                .call(&mut self.store, len)?;
+            self.memory
+                .write(&mut self.store, ptr as u32 as usize, input)?;
+            Ok((ptr, len))
+        }
+
+        /// Calls `function` with `input` written to the memory of the plugin, returning what the
+        /// function returned, `None` for 0.
+        pub fn call(
+            &mut self,
+            function: &str,
+            input: Option<&[u8]>,
+        ) -> anyhow::Result<Option<Vec<u8>>> {
+            self.store.set_fuel(FUEL)?;
+            let packed = match input {
+                None => self
+                    .instance
+                    .get_typed_func::<(), i64>(&self.store, function)
+                    .with_context(|| format!("No exported '{function}'"))?
+                    .call(&mut self.store, ())?,
+                Some(input) => {
+                    let (ptr, len) = self.write(input)?;
+                    self.instance
+                        .get_typed_func::<(i32, i32), i64>(&self.store, function)
+                        .with_context(|| format!("No exported '{function}'"))?
+                        .call(&mut self.store, (ptr, len))?
+                }
+            };
+            if packed == 0 {
+                return Ok(None);
+            }
+            let (ptr, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+            let mut output = vec![0; len];
+            self.memory.read(&self.store, ptr, &mut output)?;
+            Ok(Some(output))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "wasm"))]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Writes the module in WebAssembly text format to a temporary `.wasm` file.
+    fn module(name: &str, wat: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.wasm", std::process::id()));
+        std::fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+        path
+    }
+
+    /// Returns its settings from `poll`.
+    const ECHO: &str = r#"(module
+        (import "env" "log" (func $log (param i32 i32 i32)))
+        (memory (export "memory") 1)
+        (global $next (mut i32) (i32.const 1024))
+        (global $settings (mut i64) (i64.const 0))
+        (data (i32.const 0) "initialized")
+        (func (export "alloc") (param $len i32) (result i32)
+            (global.get $next)
+            (global.set $next (i32.add (global.get $next) (local.get $len))))
+        (func (export "init") (param $ptr i32) (param $len i32)
+            (call $log (i32.const 3) (i32.const 0) (i32.const 11))
+            (global.set $settings (i64.or
+                (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                (i64.extend_i32_u (local.get $len)))))
+        (func (export "poll") (result i64) (global.get $settings)))"#;
+
+    #[test]
+    fn test_source() {
+        let path = module("echo", ECHO);
+        let config = serde_json::json!({
+            "type": "Wasm",
+            "deviceName": "meter",
+            "module": path,
+            "settings": {"fields": {"currentPower": 344.5, "count": 3}, "tags": {"phase": "L1"}},
+        });
+        let mut source: crate::SourceConfig = serde_json::from_value(config.clone()).unwrap();
+        assert_eq!(crate::Source::id(&source), "meter");
+        let data = crate::Source::poll_data(&mut source).unwrap();
+        assert_eq!(data.number("currentPower"), Some(344.5));
+        assert_eq!(data["count"], Value::I64(3));
+        assert_eq!(data.tag_value("phase"), Some(&Value::String("L1".into())));
+        assert_eq!(
+            data.tag_value("deviceName"),
+            Some(&Value::String("meter".into()))
+        );
+        assert_eq!(serde_json::to_value(&source.device).unwrap(), config);
+
+        let mut failing: crate::SourceConfig = serde_json::from_value(serde_json::json!({
+            "type": "Wasm",
+            "deviceName": "meter",
+            "module": path,
+            "settings": {"error": "No meter"},
+        }))
+        .unwrap();
+        let err = crate::Source::poll_data(&mut failing).unwrap_err();
+        assert!(err.to_string().ends_with("failed: No meter"), "{err}");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_transform() {
+        let reading = r#"{"fields":{"power":1.5,"lastUpdate":"1970-01-01T00:00:02+00:00"},"tags":{"site":"garage"}}"#;
+        let wat = format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{}")
+                (func (export "alloc") (param $len i32) (result i32) (i32.const 1024))
+                (func (export "transform") (param $ptr i32) (param $len i32) (result i64)
+                    ;; Drops readings without fields
+                    (if (result i64) (i32.lt_u (local.get $len) (i32.const 30))
+                        (then (i64.const 0))
+                        (else (i64.const {})))))"#,
+            reading.replace('"', "\\\""),
+            reading.len()
+        );
+        let path = module("transform", &wat);
+        let plugin = Plugin::try_from(path.clone()).unwrap();
+        let mut data = PublishData::default();
+        data.field("currentPower", 1500.0);
+        data.field("lastUpdate", UNIX_EPOCH + Duration::from_secs(1));
+        data.set_measurement("power");
+        data.set_timestamp(UNIX_EPOCH);
+        let data = plugin.apply(data).unwrap().unwrap();
+        assert_eq!(data.number("power"), Some(1.5));
+        assert_eq!(data.number("currentPower"), None);
+        assert_eq!(
+            data["lastUpdate"],
+            Value::Timestamp(UNIX_EPOCH + Duration::from_secs(2))
+        );
+        assert_eq!(
+            data.tag_value("site"),
+            Some(&Value::String("garage".into()))
+        );
+        assert_eq!(data.measurement(), Some("power"));
+        assert_eq!(data.timestamp(), Some(UNIX_EPOCH));
+        assert_eq!(plugin.apply(PublishData::default()).unwrap(), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_endless_loop() {
+        let path = module(
+            "loop",
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "poll") (result i64) (loop (br 0)) (i64.const 0)))"#,
+        );
+        let plugin = Plugin::try_from(path.clone()).unwrap();
+        assert!(plugin.poll().is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+//! PostgreSQL target, inserting the readings into a table (optionally a TimescaleDB hypertable) for
+//! those already running Postgres. Tags and fields are stored as JSONB, so new ones don't change
+//! the schema. Uses the `psql` tool.
+use crate::process::run;
+use crate::{template, Field, PublishData, Target};
+use anyhow::Context;
+use std::borrow::Cow;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendPostgres {
+    /// Connection string, like `postgresql://grabber@db/solar` or `host=db dbname=solar`
+    pub dsn: String,
+    /// Password, if not given in `dsn`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Table, optionally with the schema like `energy.readings`
+    #[serde(default = "BackendPostgres::default_table")]
+    pub table: String,
+    /// Measurement of readings without one, may be templated from the tags and fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measurement: Option<String>,
+    /// Makes the table a TimescaleDB hypertable when creating it
+    #[serde(default)]
+    pub timescale: bool,
+    #[serde(skip)]
+    created: Created,
+}
+
+/// Whether the table was created (if missing) since the start.
+#[derive(Clone, Default)]
+struct Created(Arc<AtomicBool>);
+
+impl PartialEq for Created {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl std::fmt::Debug for Created {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.load(Ordering::Relaxed))
+    }
+}
+
+impl Target for BackendPostgres {
+    fn id(&self) -> Cow<'_, str> {
+        format!("{}/{}", self.connection().0, self.table).into()
+    }
+
+    fn publish(&self, data: &PublishData) -> anyhow::Result<()> {
+        self.publish_batch(std::slice::from_ref(data))
+    }
+
+    fn publish_batch(&self, data: &[PublishData]) -> anyhow::Result<()> {
+        let create = !self.created.0.load(Ordering::Relaxed);
+        let script = self.script(data, create)?;
+        let (dsn, password) = self.connection();
+        let mut command = Command::new("psql");
+        command
+            .args(["--no-psqlrc", "--quiet", "--single-transaction"])
+            .args(["--set", "ON_ERROR_STOP=1", "--dbname"])
+            .arg(&dsn);
+        // Not on the command line, where other users could see it
+        if let Some(password) = password {
+            command.env("PGPASSWORD", password);
+        }
+        run(&mut command, Some(&script))
+            .with_context(|| format!("Failed to insert into '{}'", self.id()))?;
+        self.created.0.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl BackendPostgres {
+    fn default_table() -> String {
+        "readings".to_string()
+    }
+
+    /// The connection string without the password, and the password.
+    fn connection(&self) -> (String, Option<String>) {
+        let mut password = self.password.clone();
+        let mut dsn = self.dsn.clone();
+        if let Ok(mut url) = url::Url::parse(&self.dsn) {
+            if let Some(in_url) = url.password() {
+                password = Some(crate::http::percent_decode(in_url));
+                if url.set_password(None).is_ok() {
+                    dsn = url.to_string();
+                }
+            }
+        }
+        (dsn, password)
+    }
+
+    /// The statements inserting the readings, after creating the table if `create`.
+    fn script(&self, data: &[PublishData], create: bool) -> anyhow::Result<String> {
+        let table = table_name(&self.table);
+        // Backslashes in the strings are taken literally only with this
+        let mut script = "SET standard_conforming_strings = on;\n".to_string();
+        if create {
+            script += &format!(
+                "CREATE TABLE IF NOT EXISTS {table} (\
+                 time TIMESTAMPTZ NOT NULL, measurement TEXT, tags JSONB, fields JSONB);\n"
+            );
+            if self.timescale {
+                // Resolved as regclass, which takes the quoted name like the statements
+                script += &format!(
+                    "SELECT create_hypertable({}, 'time', if_not_exists => TRUE);\n",
+                    string(&table)
+                );
+            }
+        }
+        for data in data {
+            let measurement = match (data.measurement(), &self.measurement) {
+                (Some(measurement), _) => string(measurement),
+                (None, Some(measurement)) => string(&template::render(measurement, data)?),
+                (None, None) => "NULL".to_string(),
+            };
+            let time = chrono::DateTime::<chrono::Utc>::from(
+                data.timestamp().unwrap_or_else(SystemTime::now),
+            );
+            let (mut tags, mut fields) = (serde_json::Map::new(), serde_json::Map::new());
+            for field in data.fields() {
+                match field {
+                    Field::Tag(name, value) => tags.insert(name.clone(), value.to_json()),
+                    Field::Field(name, value) => fields.insert(name.clone(), value.to_json()),
+                };
+            }
+            script += &format!(
+                "INSERT INTO {table} (time, measurement, tags, fields) VALUES ({}, {measurement}, {}::jsonb, {}::jsonb);\n",
+                string(&time.to_rfc3339_opts(chrono::SecondsFormat::Micros, true)),
+                string(&serde_json::Value::Object(tags).to_string()),
+                string(&serde_json::Value::Object(fields).to_string()),
+            );
+        }
+        Ok(script)
+    }
+}
+
+/// A quoted table name, with the schema if given like `energy.readings`.
+fn table_name(name: &str) -> String {
+    let parts: Vec<_> = name
+        .split('.')
+        .map(|part| format!("\"{}\"", part.replace('"', "\"\"")))
+        .collect();
+    parts.join(".")
+}
+
+/// A quoted SQL string.
+fn string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_script() {
+        let postgres: BackendPostgres = serde_json::from_value(serde_json::json!({
+            "dsn": "postgresql://grabber:s%40cret@db:5432/solar",
+            "table": "energy.Readings",
+            "measurement": "power",
+            "timescale": true,
+        }))
+        .unwrap();
+        assert_eq!(
+            postgres.connection(),
+            (
+                "postgresql://grabber@db:5432/solar".to_string(),
+                Some("s@cret".to_string())
+            )
+        );
+        assert_eq!(
+            postgres.id(),
+            "postgresql://grabber@db:5432/solar/energy.Readings"
+        );
+        let mut data = PublishData::default();
+        data.tag("deviceName", "o'clock\\".to_string());
+        data.field("currentPower", 344.5);
+        data.set_timestamp(UNIX_EPOCH + Duration::from_micros(1_500_000));
+        assert_eq!(
+            postgres.script(&[data], true).unwrap(),
+            "SET standard_conforming_strings = on;\n\
+             CREATE TABLE IF NOT EXISTS \"energy\".\"Readings\" \
+             (time TIMESTAMPTZ NOT NULL, measurement TEXT, tags JSONB, fields JSONB);\n\
+             SELECT create_hypertable('\"energy\".\"Readings\"', 'time', if_not_exists => TRUE);\n\
+             INSERT INTO \"energy\".\"Readings\" (time, measurement, tags, fields) VALUES \
+             ('1970-01-01T00:00:01.500000Z', 'power', '{\"deviceName\":\"o''clock\\\\\"}'::jsonb, \
+             '{\"currentPower\":344.5}'::jsonb);\n"
+        );
+    }
+}
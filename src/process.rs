@@ -0,0 +1,32 @@
+//! Running external tools, like `secret-tool` for the keyring, `age` for secrets or `psql` for targets.
+use anyhow::{bail, Context};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Runs a tool, returning its output without the trailing newline.
+pub(crate) fn run(command: &mut Command, input: Option<&str>) -> anyhow::Result<String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run '{program}'"))?;
+    if let Some(input) = input {
+        child
+            .stdin
+            .take()
+            .expect("piped")
+            .write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "'{program}' failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?
+        .trim_end_matches(['\r', '\n'])
+        .to_string())
+}
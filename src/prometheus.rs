@@ -0,0 +1,248 @@
+//! Prometheus target, serving the latest readings at `/metrics` to be scraped, instead of pushing
+//! them. Numeric fields are gauges named after the field, labeled with the tags of the reading.
+//! Series without readings for a while are dropped, so changed tags or dead devices don't linger.
+use crate::http::{self, Response};
+use crate::stats::label;
+use crate::{Field, PublishData, Target, Value};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// The bound address and the metrics served on it.
+type Exporter = (SocketAddr, Arc<Mutex<Metrics>>);
+
+/// The exporters by the address to listen on, kept over reloads of the config.
+static EXPORTERS: Mutex<BTreeMap<SocketAddr, Exporter>> = Mutex::new(BTreeMap::new());
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendPrometheus {
+    /// Address to serve `/metrics` on, like `0.0.0.0:9101`
+    pub listen: SocketAddr,
+    /// Prefix of the metric names
+    #[serde(default = "BackendPrometheus::default_prefix")]
+    pub prefix: String,
+    /// Series without a reading for this long are no longer served
+    #[serde(
+        default = "BackendPrometheus::default_expire_after",
+        with = "crate::duration"
+    )]
+    #[schemars(with = "crate::duration::Schema")]
+    pub expire_after: Duration,
+}
+
+/// The latest values of the metrics and when they were updated, by the labels of the readings.
+#[derive(Default)]
+struct Metrics {
+    series: BTreeMap<String, (Instant, BTreeMap<String, f64>)>,
+    expire_after: Duration,
+}
+
+impl Target for BackendPrometheus {
+    fn id(&self) -> Cow<'_, str> {
+        format!("http://{}/metrics", self.listen).into()
+    }
+
+    fn publish(&self, data: &PublishData) -> anyhow::Result<()> {
+        let (_, metrics) = self.exporter()?;
+        let mut metrics = metrics.lock().expect("not poisoned");
+        metrics.expire_after = self.expire_after;
+        metrics.record(&self.prefix, data, Instant::now());
+        Ok(())
+    }
+}
+
+impl BackendPrometheus {
+    fn default_prefix() -> String {
+        "solar".to_string()
+    }
+
+    fn default_expire_after() -> Duration {
+        Duration::from_secs(600)
+    }
+
+    /// The bound address and metrics served on `listen`, serving them on the first call.
+    fn exporter(&self) -> anyhow::Result<Exporter> {
+        let mut exporters = EXPORTERS.lock().expect("not poisoned");
+        if let Some(exporter) = exporters.get(&self.listen) {
+            return Ok(exporter.clone());
+        }
+        let metrics = Arc::<Mutex<Metrics>>::default();
+        let served = metrics.clone();
+        let addr = http::serve(self.listen, move |request| match request.path.as_str() {
+            "/metrics" => Response::new(
+                "text/plain; version=0.0.4",
+                served.lock().expect("not poisoned").render(Instant::now()),
+            ),
+            _ => Response::not_found(),
+        })?;
+        tracing::info!("Serving readings on http://{addr}/metrics");
+        exporters.insert(self.listen, (addr, metrics.clone()));
+        Ok((addr, metrics))
+    }
+}
+
+impl Metrics {
+    /// Updates the metrics of the fields of `data`, keeping the ones it leaves out (e.g. by
+    /// `dedup`).
+    fn record(&mut self, prefix: &str, data: &PublishData, now: Instant) {
+        let mut labels = BTreeMap::new();
+        let mut values = BTreeMap::new();
+        for field in data.fields() {
+            match field {
+                Field::Tag(name, value) => {
+                    let value = match value {
+                        Value::String(s) => s.clone(),
+                        value => value.to_json().to_string(),
+                    };
+                    labels.insert(metric_name(name, false), label(&value));
+                }
+                Field::Field(name, value) => {
+                    let value = match value {
+                        Value::F64(f) => *f,
+                        Value::I64(i) => *i as f64,
+                        Value::Bool(b) => f64::from(u8::from(*b)),
+                        Value::Timestamp(t) => match t.duration_since(UNIX_EPOCH) {
+                            Ok(since) => since.as_secs_f64(),
+                            Err(before) => -before.duration().as_secs_f64(),
+                        },
+                        Value::String(_) => continue,
+                    };
+                    let name = metric_name(name, true);
+                    values.insert(format!("{prefix}_{name}"), value);
+                }
+            }
+        }
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(name, value)| format!("{name}=\"{value}\""))
+            .collect();
+        let (updated, series) = self
+            .series
+            .entry(labels.join(","))
+            .or_insert_with(|| (now, BTreeMap::new()));
+        *updated = now;
+        series.extend(values);
+    }
+
+    /// The gauges in the Prometheus text exposition format, without the expired series.
+    fn render(&mut self, now: Instant) -> String {
+        let expire_after = self.expire_after;
+        self.series
+            .retain(|_, (updated, _)| now.saturating_duration_since(*updated) <= expire_after);
+        let mut series: BTreeMap<&str, Vec<(&str, f64)>> = BTreeMap::new();
+        for (labels, (_, values)) in &self.series {
+            for (name, value) in values {
+                series.entry(name).or_default().push((labels, *value));
+            }
+        }
+        let mut out = String::new();
+        for (name, values) in series {
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            for (labels, value) in values {
+                let value = match value {
+                    f64::INFINITY => "+Inf".to_string(),
+                    f64::NEG_INFINITY => "-Inf".to_string(),
+                    value => value.to_string(),
+                };
+                let _ = writeln!(out, "{name}{{{labels}}} {value}");
+            }
+        }
+        out
+    }
+}
+
+/// A valid metric or label name, with camel case names like `currentPower` in snake case
+/// (`current_power`) for metrics.
+fn metric_name(name: &str, snake_case: bool) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut previous = None;
+    for c in name.chars() {
+        let lower_before =
+            previous.is_some_and(|p: char| p.is_ascii_lowercase() || p.is_ascii_digit());
+        if snake_case && c.is_ascii_uppercase() && lower_before {
+            result.push('_');
+        }
+        match c {
+            'A'..='Z' if snake_case => result.push(c.to_ascii_lowercase()),
+            'a'..='z' | 'A'..='Z' | '_' => result.push(c),
+            '0'..='9' if !result.is_empty() => result.push(c),
+            _ => result.push('_'),
+        }
+        previous = Some(c);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus() {
+        let target: BackendPrometheus = serde_json::from_value(serde_json::json!({
+            "listen": "127.0.0.1:0",
+        }))
+        .unwrap();
+        let mut data = PublishData::default();
+        data.tag("deviceName", "heat \"pump\"".to_string());
+        data.tag("deviceLocation", "cellar".to_string());
+        data.field("currentPower", 344.5);
+        data.field("voltageL1", 230_i64);
+        data.field("online", true);
+        data.field("status", "ok".to_string());
+        target.publish(&data).unwrap();
+        // Fields left out keep their last value
+        let mut data = PublishData::default();
+        data.tag("deviceName", "heat \"pump\"".to_string());
+        data.tag("deviceLocation", "cellar".to_string());
+        data.field("currentPower", f64::INFINITY);
+        target.publish(&data).unwrap();
+
+        let (addr, _) = target.exporter().unwrap();
+        let metrics = ureq::get(&format!("http://{addr}/metrics"))
+            .call()
+            .unwrap()
+            .into_string()
+            .unwrap();
+        let labels = r#"deviceLocation="cellar",deviceName="heat \"pump\"""#;
+        assert_eq!(
+            metrics,
+            format!(
+                "# TYPE solar_current_power gauge\n\
+                 solar_current_power{{{labels}}} +Inf\n\
+                 # TYPE solar_online gauge\n\
+                 solar_online{{{labels}}} 1\n\
+                 # TYPE solar_voltage_l1 gauge\n\
+                 solar_voltage_l1{{{labels}}} 230\n"
+            )
+        );
+        assert_eq!(metric_name("1st power.total", true), "_st_power_total");
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut metrics = Metrics {
+            expire_after: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let reading = |status: &str| {
+            let mut data = PublishData::default();
+            data.tag("status", status.to_string());
+            data.field("currentPower", 344.5);
+            data
+        };
+        metrics.record("solar", &reading("starting"), start);
+        metrics.record("solar", &reading("ok"), start + Duration::from_secs(300));
+        assert_eq!(
+            metrics.render(start + Duration::from_secs(601)),
+            "# TYPE solar_current_power gauge\n\
+             solar_current_power{status=\"ok\"} 344.5\n"
+        );
+        assert_eq!(metrics.render(start + Duration::from_secs(901)), "");
+    }
+}
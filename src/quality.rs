@@ -0,0 +1,51 @@
+//! Flags marking readings whose values were not measured as is.
+use crate::PublishData;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Quality {
+    /// Filled in from an earlier reading
+    Stale,
+    /// Computed instead of measured, e.g. integrated energy
+    Estimated,
+    /// Corrected by a calibration
+    Calibrated,
+    /// Limited to a valid range
+    Clamped,
+}
+
+impl Quality {
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Quality::Stale => "stale",
+            Quality::Estimated => "estimated",
+            Quality::Calibrated => "calibrated",
+            Quality::Clamped => "clamped",
+        }
+    }
+}
+
+/// Adds a `true` tag for each quality flag of the reading.
+pub fn tag(data: &mut PublishData) {
+    for quality in data.quality.clone() {
+        data.set_tag(quality.tag(), true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    #[test]
+    fn test_tag() {
+        let mut data = PublishData::default();
+        data.field("currentPower", 300.0);
+        data.flag(Quality::Clamped);
+        data.flag(Quality::Stale);
+        data.flag(Quality::Clamped);
+        tag(&mut data);
+        assert_eq!(data["clamped"], Value::Bool(true));
+        assert_eq!(data["stale"], Value::Bool(true));
+        assert_eq!(data.fields().len(), 3);
+    }
+}
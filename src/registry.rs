@@ -0,0 +1,222 @@
+//! Source and target types added at runtime, by downstream crates or optional modules, without
+//! extending the built-in ones. A registered type is configured like the built-in ones, by its
+//! `type` in the config; the other settings are deserialized into the registered type.
+use crate::{Source, Target};
+use serde::de::{DeserializeOwned, Error};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Functions reading the settings of each type.
+type Registry<T> = Mutex<BTreeMap<String, fn(serde_json::Value) -> anyhow::Result<T>>>;
+
+static SOURCES: Registry<Box<dyn Source>> = Mutex::new(BTreeMap::new());
+static TARGETS: Registry<Arc<dyn Target + Sync>> = Mutex::new(BTreeMap::new());
+
+fn source<T: Source + DeserializeOwned + 'static>(
+    config: serde_json::Value,
+) -> anyhow::Result<Box<dyn Source>> {
+    Ok(Box::new(serde_json::from_value::<T>(config)?))
+}
+
+fn target<T: Target + Sync + DeserializeOwned + 'static>(
+    config: serde_json::Value,
+) -> anyhow::Result<Arc<dyn Target + Sync>> {
+    Ok(Arc::new(serde_json::from_value::<T>(config)?))
+}
+
+/// Makes sources of `type_name` read into `T`. Registering a name again replaces the type, the
+/// built-in types `Inverter`, `Tasmota` and `Wasm` can't be replaced.
+pub fn register_source<T: Source + DeserializeOwned + 'static>(type_name: &str) {
+    SOURCES
+        .lock()
+        .expect("not poisoned")
+        .insert(type_name.to_string(), source::<T>);
+}
+
+/// Makes targets of `type_name` read into `T`. Targets without `type` (or `"InfluxDB"`) are
+/// always InfluxDB targets, and the other built-in types (`MQTT`, `Prometheus`, `CSV`,
+/// `SQLite` and `PostgreSQL`) can't be replaced.
+pub fn register_target<T: Target + Sync + DeserializeOwned + 'static>(type_name: &str) {
+    TARGETS
+        .lock()
+        .expect("not poisoned")
+        .insert(type_name.to_string(), target::<T>);
+}
+
+/// The `type` of `config`, and the settings without it.
+fn split_type(mut config: serde_json::Value) -> (Option<String>, serde_json::Value) {
+    let type_name = config
+        .as_object_mut()
+        .and_then(|map| map.remove("type"))
+        .and_then(|t| t.as_str().map(str::to_string));
+    (type_name, config)
+}
+
+/// Creates the registered type named by `config`, `builtin` are the types known anyway.
+fn create<T, D: Error>(
+    registry: &Registry<T>,
+    kind: &str,
+    builtin: &[&str],
+    config: &serde_json::Value,
+) -> Result<T, D> {
+    let (type_name, settings) = split_type(config.clone());
+    let type_name = type_name.ok_or_else(|| D::missing_field("type"))?;
+    let registry = registry.lock().expect("not poisoned");
+    let Some(factory) = registry.get(&type_name) else {
+        let known: Vec<_> = builtin
+            .iter()
+            .copied()
+            .chain(registry.keys().map(String::as_str))
+            .collect();
+        return Err(D::custom(format!(
+            "Unknown {kind} type '{type_name}', expected one of {}",
+            known.join(", ")
+        )));
+    };
+    factory(settings).map_err(|err| D::custom(format!("{err:#}")))
+}
+
+/// A source of a registered type, along with its config to show it again.
+pub struct RegisteredSource {
+    config: serde_json::Value,
+    pub source: Box<dyn Source>,
+}
+
+impl RegisteredSource {
+    /// A source of a built-in type kept like registered ones, such as plugins.
+    pub(crate) fn new(config: serde_json::Value, source: Box<dyn Source>) -> Self {
+        RegisteredSource { config, source }
+    }
+}
+
+/// A target of a registered type, along with its config to show it again.
+#[derive(Clone)]
+pub struct RegisteredTarget {
+    config: serde_json::Value,
+    pub target: Arc<dyn Target + Sync>,
+}
+
+impl<'de> serde::Deserialize<'de> for RegisteredSource {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let config = serde_json::Value::deserialize(deserializer)?;
+        let source = create(
+            &SOURCES,
+            "source",
+            &["Inverter", "Tasmota", "Wasm"],
+            &config,
+        )?;
+        Ok(RegisteredSource { config, source })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for RegisteredTarget {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let config = serde_json::Value::deserialize(deserializer)?;
+        let target = create(
+            &TARGETS,
+            "target",
+            &[
+                "InfluxDB",
+                "MQTT",
+                "Prometheus",
+                "CSV",
+                "SQLite",
+                "PostgreSQL",
+            ],
+            &config,
+        )?;
+        Ok(RegisteredTarget { config, target })
+    }
+}
+
+macro_rules! config_impls {
+    ($t:ty) => {
+        impl serde::Serialize for $t {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                self.config.serialize(serializer)
+            }
+        }
+
+        impl std::fmt::Debug for $t {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}", self.config)
+            }
+        }
+
+        impl PartialEq for $t {
+            fn eq(&self, other: &Self) -> bool {
+                self.config == other.config
+            }
+        }
+    };
+}
+
+config_impls!(RegisteredSource);
+config_impls!(RegisteredTarget);
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, PublishData, Source, Target};
+    use std::borrow::Cow;
+
+    #[derive(serde::Deserialize)]
+    struct Counter {
+        name: String,
+        start: i64,
+    }
+
+    impl Source for Counter {
+        fn id(&self) -> Cow<'_, str> {
+            (&self.name).into()
+        }
+
+        fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+            self.start += 1;
+            let mut data = PublishData::default();
+            data.field("count", self.start);
+            Ok(data)
+        }
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Discard {}
+
+    impl Target for Discard {
+        fn id(&self) -> Cow<'_, str> {
+            "discard".into()
+        }
+
+        fn publish(&self, _data: &PublishData) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        super::register_source::<Counter>("Counter");
+        super::register_target::<Discard>("Discard");
+        let json = serde_json::json!({
+            "sources": [{"type": "Counter", "name": "counter", "start": 1, "tags": {"a": "b"}}],
+            "targets": [{"type": "Discard"}, {"influxUrl": "http://influx", "bucket": "b",
+                "org": "o", "measurement": "m", "token": "t"}],
+        });
+        let mut config: Config = serde_json::from_value(json).unwrap();
+        let source = &mut config.sources[0];
+        assert_eq!(source.id(), "counter");
+        assert_eq!(source.poll_data().unwrap().number("count"), Some(2.0));
+        assert_eq!(
+            serde_json::to_value(&source.device).unwrap(),
+            serde_json::json!({"type": "Counter", "name": "counter", "start": 1})
+        );
+        assert_eq!(config.targets[0].id(), "discard");
+        assert!(config.targets[1].backend.influxdb().is_some());
+        let unknown = serde_json::json!({"sources": [{"type": "Bogus"}]});
+        let err = serde_json::from_value::<Config>(unknown).unwrap_err();
+        assert!(
+            err.to_string().starts_with(
+                "Unknown source type 'Bogus', expected one of Inverter, Tasmota, Wasm, "
+            ),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Retrying failed writes with exponential backoff, so a brief hiccup of a target doesn't lose a
+//! reading. The delays are jittered, so several collectors don't retry in lockstep.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Retry {
+    /// Attempts in total, including the first one
+    #[serde(default = "Retry::default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled with every further one
+    #[serde(default = "Retry::default_initial", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub initial: Duration,
+    /// Longest delay in between attempts
+    #[serde(default = "Retry::default_max_delay", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub max_delay: Duration,
+}
+
+impl Retry {
+    fn default_max_attempts() -> u32 {
+        3
+    }
+
+    fn default_initial() -> Duration {
+        Duration::from_millis(500)
+    }
+
+    fn default_max_delay() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    /// Delay before the attempt after `failures` failed ones, given `random` in `[0, 1)`: between
+    /// half and all of the exponential delay.
+    pub fn delay(&self, failures: u32, random: f64) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        let delay = self
+            .initial
+            .saturating_mul(1 << doublings)
+            .min(self.max_delay);
+        delay.mul_f64(0.5 + random / 2.0)
+    }
+
+    /// Calls `attempt` until it succeeds, fails with an error which isn't `retryable`, or fails
+    /// `max_attempts` times. Stops early on a shutdown.
+    pub fn run<T>(
+        &self,
+        mut attempt: impl FnMut() -> anyhow::Result<T>,
+        retryable: impl Fn(&anyhow::Error) -> bool,
+    ) -> anyhow::Result<T> {
+        let mut failures = 0;
+        loop {
+            let err = match attempt() {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            failures += 1;
+            if failures >= self.max_attempts || !retryable(&err) {
+                return Err(if failures > 1 {
+                    err.context(format!("Failed after {failures} attempts"))
+                } else {
+                    err
+                });
+            }
+            let delay = self.delay(failures, random());
+            tracing::debug!("Retrying in {delay:?}: {err}");
+            if crate::shutdown::sleep(delay) {
+                return Err(err.context("Shutting down"));
+            }
+        }
+    }
+}
+
+/// Uniformly distributed in `[0, 1)`, from the random keys std seeds its hash maps with.
+fn random() -> f64 {
+    let hash = RandomState::new().build_hasher().finish();
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let retry: Retry = serde_json::from_value(serde_json::json!({
+            "initial": "10ms",
+            "maxDelay": "25ms",
+        }))
+        .unwrap();
+        assert_eq!(retry.max_attempts, 3);
+        let millis = |failures, random| retry.delay(failures, random).as_secs_f64() * 1000.0;
+        assert!((millis(1, 0.0) - 5.0).abs() < 0.01);
+        assert!((millis(2, 0.99) - 19.9).abs() < 0.01);
+        assert!((millis(3, 0.0) - 12.5).abs() < 0.01);
+        assert!((millis(40, 0.5) - 18.75).abs() < 0.01);
+        assert!((0.0..1.0).contains(&random()));
+
+        let mut attempts = 0;
+        let result = retry.run(
+            || {
+                attempts += 1;
+                match attempts {
+                    1 | 2 => anyhow::bail!("Unavailable"),
+                    _ => Ok(attempts),
+                }
+            },
+            |_| true,
+        );
+        assert_eq!(result.unwrap(), 3);
+
+        let mut attempts = 0;
+        let err = retry
+            .run(
+                || -> anyhow::Result<()> {
+                    attempts += 1;
+                    anyhow::bail!("Unavailable")
+                },
+                |_| true,
+            )
+            .unwrap_err();
+        assert_eq!(attempts, 3);
+        assert_eq!(err.to_string(), "Failed after 3 attempts");
+
+        // Rejected, failing again
+        let mut attempts = 0;
+        let err = retry
+            .run(
+                || -> anyhow::Result<()> {
+                    attempts += 1;
+                    anyhow::bail!("Bad request")
+                },
+                |_| false,
+            )
+            .unwrap_err();
+        assert_eq!(attempts, 1);
+        assert_eq!(err.to_string(), "Bad request");
+    }
+}
@@ -0,0 +1,948 @@
+use crate::alerts::Alerts;
+use crate::backoff::{Backoff, BackoffState};
+use crate::chaos::Chaos;
+use crate::heartbeat::Heartbeat;
+use crate::stats::{SelfMetrics, Stats};
+use crate::virtual_device::VirtualDevice;
+use crate::weather::{Weather, WeatherState};
+use crate::{Config, PublishData, Source, Target};
+use anyhow::Context;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Polls all sources and publishes their readings to all targets.
+#[derive(Default)]
+pub struct Scheduler {
+    sources: Vec<Box<dyn Source>>,
+    targets: Vec<Box<dyn Target>>,
+    virtual_devices: Vec<VirtualDevice>,
+    weather: Option<Weather>,
+    weather_state: WeatherState,
+    state_path: Option<PathBuf>,
+    self_metrics: Option<SelfMetrics>,
+    heartbeat: Option<Heartbeat>,
+    alerts: Alerts,
+    backoff: Option<Backoff>,
+    backoff_state: BTreeMap<String, BackoffState>,
+    chaos: Option<Chaos>,
+    stats: Stats,
+    /// Interval of the sources without their own, while running continuously
+    interval: Option<Duration>,
+    /// When each source is to be polled next, while running continuously
+    due: BTreeMap<String, Instant>,
+    /// Last successful reading of each source before it was trimmed for publishing, for the
+    /// virtual devices
+    latest: BTreeMap<String, PublishData>,
+}
+
+/// Keys of the alert and backoff state in the state file, next to the source ids.
+const ALERTS_STATE: &str = "$alerts";
+const BACKOFF_STATE: &str = "$backoff";
+
+/// Outcome of a single polling cycle, with one entry per source and target. Durations are in
+/// seconds.
+#[derive(serde::Serialize, Debug, Default)]
+pub struct CycleSummary {
+    pub sources: Vec<SourceSummary>,
+    pub targets: Vec<TargetSummary>,
+    pub duration: f64,
+}
+
+#[derive(serde::Serialize, Debug, Default)]
+pub struct SourceSummary {
+    pub id: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Tags of the reading, empty if polling failed
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, serde_json::Value>,
+    /// Fields of the reading, empty if polling failed
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub values: BTreeMap<String, serde_json::Value>,
+    pub duration: f64,
+}
+
+#[derive(serde::Serialize, Debug, Default)]
+pub struct TargetSummary {
+    pub id: String,
+    pub success: bool,
+    pub published: usize,
+    pub failed: usize,
+    /// Last error publishing to the target
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration: f64,
+}
+
+impl CycleSummary {
+    /// Exit status for one-shot runs:
+    /// * 0 - all sources polled and published
+    /// * 2 - some sources failed
+    /// * 3 - all sources failed
+    /// * 4 - publishing to at least one target failed
+    pub fn exit_code(&self) -> u8 {
+        let failed_sources = self.sources.iter().filter(|s| s.error.is_some()).count();
+        if failed_sources > 0 && failed_sources == self.sources.len() {
+            3
+        } else if self.targets.iter().any(|t| t.failed > 0) {
+            4
+        } else if failed_sources > 0 {
+            2
+        } else {
+            0
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn add_source(&mut self, source: impl Source + 'static) {
+        self.sources.push(Box::new(source));
+    }
+
+    pub fn add_target(&mut self, target: impl Target + 'static) {
+        self.targets.push(Box::new(target));
+    }
+
+    pub fn add_virtual_device(&mut self, device: VirtualDevice) {
+        self.virtual_devices.push(device);
+    }
+
+    /// Publishes the [`Stats`] to all targets after every cycle.
+    pub fn set_self_metrics(&mut self, self_metrics: SelfMetrics) {
+        self.self_metrics = Some(self_metrics);
+    }
+
+    /// Pings `heartbeat` after every fully successful cycle which polled at least one source.
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.heartbeat = Some(heartbeat);
+    }
+
+    /// Evaluates the alert rules against the readings of every cycle.
+    pub fn set_alerts(&mut self, alerts: Alerts) {
+        self.alerts = alerts;
+    }
+
+    /// Backs off from polling failing sources.
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = Some(backoff);
+    }
+
+    /// Injects faults into polls and publishes, for testing.
+    pub fn set_chaos(&mut self, chaos: Chaos) {
+        self.chaos = Some(chaos);
+    }
+
+    /// Polls the sources every `interval` (or their own `pollInterval`) instead of every cycle.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = Some(interval);
+    }
+
+    /// When the next source is due, to run the next cycle then.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.due.values().min().copied()
+    }
+
+    /// Counters of all cycles run so far.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Restores the state of all sources from `path` (if it exists), and saves it there after
+    /// every cycle.
+    pub fn load_state(&mut self, path: impl Into<PathBuf>) -> anyhow::Result<()> {
+        let path = path.into();
+        if path.exists() {
+            let mut state: BTreeMap<String, serde_json::Value> = serde_json::from_reader(
+                File::open(&path)
+                    .with_context(|| format!("Failed to open state file: {}", path.display()))?,
+            )
+            .with_context(|| format!("Invalid state file: {}", path.display()))?;
+            for src in &mut self.sources {
+                if let Some(src_state) = state.remove(src.id().as_ref()) {
+                    src.restore_state(src_state)
+                        .with_context(|| format!("Failed to restore state of '{}'", src.id()))?;
+                }
+            }
+            if let Some(alerts) = state.remove(ALERTS_STATE) {
+                self.alerts.state =
+                    serde_json::from_value(alerts).context("Failed to restore state of alerts")?;
+            }
+            if let Some(backoff) = state.remove(BACKOFF_STATE) {
+                self.backoff_state = serde_json::from_value(backoff)
+                    .context("Failed to restore state of the backoff")?;
+            }
+        }
+        self.state_path = Some(path);
+        Ok(())
+    }
+
+    /// Replaces the sources, targets and settings with those of `config`. Sources keep their state
+    /// if their id is unchanged, as do the alerts, the backoff and the stats.
+    pub fn reload(&mut self, config: Config) {
+        let mut reloaded = Scheduler::from(config);
+        for src in &mut reloaded.sources {
+            let Some(old) = self.sources.iter().find(|old| old.id() == src.id()) else {
+                continue;
+            };
+            if let Some(state) = old.save_state() {
+                if let Err(err) = src.restore_state(state) {
+                    tracing::warn!("Failed to keep the state of '{}': {err}", src.id());
+                }
+            }
+        }
+        reloaded.alerts.state = std::mem::take(&mut self.alerts.state);
+        reloaded.backoff_state = std::mem::take(&mut self.backoff_state);
+        reloaded.chaos = self.chaos.take();
+        reloaded.stats = std::mem::take(&mut self.stats);
+        reloaded.interval = self.interval;
+        reloaded.due = std::mem::take(&mut self.due);
+        reloaded.latest = std::mem::take(&mut self.latest);
+        reloaded.weather_state = std::mem::take(&mut self.weather_state);
+        reloaded.state_path = self.state_path.take();
+        *self = reloaded;
+    }
+
+    fn save_state(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.state_path else {
+            return Ok(());
+        };
+        let mut state: BTreeMap<_, _> = self
+            .sources
+            .iter()
+            .filter_map(|src| Some((src.id().into_owned(), src.save_state()?)))
+            .collect();
+        if !self.alerts.state.is_empty() {
+            state.insert(
+                ALERTS_STATE.to_string(),
+                serde_json::to_value(&self.alerts.state)?,
+            );
+        }
+        if !self.backoff_state.is_empty() {
+            state.insert(
+                BACKOFF_STATE.to_string(),
+                serde_json::to_value(&self.backoff_state)?,
+            );
+        }
+        // Write to a temporary file first, so a crash can't leave a truncated state behind
+        let tmp = path.with_extension("tmp");
+        serde_json::to_writer(
+            File::create(&tmp)
+                .with_context(|| format!("Failed to write state file: {}", tmp.display()))?,
+            &state,
+        )?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Polls every source once, computes the virtual devices and publishes each reading to every
+    /// target.
+    pub fn run_cycle(&mut self) -> CycleSummary {
+        let cycle_start = Instant::now();
+        let mut summary = CycleSummary {
+            targets: self
+                .targets
+                .iter()
+                .map(|dst| TargetSummary {
+                    id: dst.id().into_owned(),
+                    success: true,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut readings: Vec<(String, PublishData)> = vec![];
+        let mut batches = vec![vec![]; self.targets.len()];
+        let mut sources = vec![];
+        // Sources whose reading didn't make it to all targets
+        let mut failed = BTreeSet::new();
+        // Sources removed by reloading aren't due any more
+        let ids: BTreeSet<_> = self
+            .sources
+            .iter()
+            .map(|src| src.id().into_owned())
+            .collect();
+        self.due.retain(|id, _| ids.contains(id));
+        self.latest.retain(|id, _| ids.contains(id));
+        let (sender, receiver) = mpsc::channel();
+        std::thread::scope(|scope| {
+            for (index, src) in self.sources.iter_mut().enumerate() {
+                let id = src.id().into_owned();
+                if self.due.get(&id).is_some_and(|due| *due > cycle_start) {
+                    continue;
+                }
+                if let Some(interval) = src.poll_interval().or(self.interval) {
+                    self.due.insert(id.clone(), cycle_start + interval);
+                }
+                let _span = tracing::info_span!("poll", device = %id).entered();
+                let now = SystemTime::now();
+                if let Some(state) = self.backoff_state.get(&id).filter(|s| !s.is_due(now)) {
+                    tracing::debug!("Backing off after {} failures", state.failures);
+                    let error = format!("Skipped, backing off after {} failures", state.failures);
+                    sources.push((
+                        index,
+                        SourceSummary {
+                            id,
+                            error: Some(error),
+                            ..Default::default()
+                        },
+                    ));
+                    continue;
+                }
+                let fault = self.chaos.as_mut().and_then(Chaos::fault);
+                let sender = sender.clone();
+                // Polled concurrently, so a slow device doesn't hold up the others
+                scope.spawn(move || {
+                    let _span = tracing::info_span!("poll", device = %id).entered();
+                    let start = Instant::now();
+                    let result = match fault {
+                        Some(fault) => Err(fault.error()),
+                        None => src.poll_data(),
+                    };
+                    let not_modified = src.not_modified();
+                    let unfiltered = src.unfiltered_data();
+                    let implausible = src.implausible_fields();
+                    let _ = sender.send((
+                        index,
+                        id,
+                        start.elapsed(),
+                        result,
+                        not_modified,
+                        unfiltered,
+                        implausible,
+                    ));
+                });
+            }
+            drop(sender);
+            // Published as they arrive, while the slower sources are still being polled
+            for (index, id, duration, result, not_modified, unfiltered, implausible) in receiver {
+                let _span = tracing::info_span!("poll", device = %id).entered();
+                let now = SystemTime::now();
+                self.stats
+                    .polled(&id, duration, result.as_ref().map(|_| not_modified));
+                self.stats.implausible(&id, &implausible);
+                let (mut tags, mut values) = Default::default();
+                let error = match result {
+                    Ok(data) => {
+                        tracing::debug!("Received {} fields", data.fields().len());
+                        (tags, values) = split_values(&data);
+                        if !publish(
+                            &self.targets,
+                            &mut self.chaos,
+                            &mut self.stats,
+                            &mut summary.targets,
+                            &mut batches,
+                            &id,
+                            &data,
+                        ) {
+                            failed.insert(id.clone());
+                        }
+                        let complete = unfiltered.unwrap_or_else(|| data.clone());
+                        self.latest.insert(id.clone(), complete);
+                        readings.push((id.clone(), data));
+                        None
+                    }
+                    Err(err) if self.alerts.is_stale(&id) => {
+                        self.latest.remove(&id);
+                        // Already alerted, so don't repeat the error every cycle
+                        tracing::debug!("Failed to receive data from '{id}': {err}");
+                        Some(err.to_string())
+                    }
+                    Err(err) => {
+                        self.latest.remove(&id);
+                        tracing::error!("Failed to receive data from '{id}': {err}");
+                        Some(err.to_string())
+                    }
+                };
+                self.alerts.polled(&id, error.as_deref(), now);
+                if let Some(backoff) = &self.backoff {
+                    let state = self.backoff_state.entry(id.clone()).or_default();
+                    let was_quarantined = backoff.is_quarantined(state);
+                    backoff.polled(state, error.is_none(), now);
+                    if backoff.is_quarantined(state) && !was_quarantined {
+                        tracing::warn!(
+                            "Quarantining '{id}' after {} failures, probing it every {:?}",
+                            state.failures,
+                            backoff.probe_interval
+                        );
+                    } else if was_quarantined && error.is_none() {
+                        tracing::info!("'{id}' is back from quarantine");
+                    }
+                    if state.failures == 0 {
+                        self.backoff_state.remove(&id);
+                    }
+                }
+                sources.push((
+                    index,
+                    SourceSummary {
+                        id,
+                        success: error.is_none(),
+                        error,
+                        tags,
+                        values,
+                        duration: duration.as_secs_f64(),
+                    },
+                ));
+            }
+        });
+        // In the order of the config, not of arrival
+        sources.sort_by_key(|(index, _)| *index);
+        summary.sources = sources.into_iter().map(|(_, source)| source).collect();
+        // Cycles without any source due don't tell whether the sources are alive
+        let polled = summary.sources.iter().any(|s| s.success);
+        // Sources not due in this cycle contribute their last reading
+        let mut inputs: Vec<_> = self
+            .latest
+            .iter()
+            .map(|(id, data)| (id.clone(), data.clone()))
+            .collect();
+        if let Some(weather) = &self.weather {
+            let _span = tracing::info_span!("poll", device = %weather.device_name).entered();
+            let start = Instant::now();
+            match weather.point(&mut self.weather_state, SystemTime::now()) {
+                Ok(Some(data)) => {
+                    let (tags, values) = split_values(&data);
+                    publish(
+                        &self.targets,
+                        &mut self.chaos,
+                        &mut self.stats,
+                        &mut summary.targets,
+                        &mut batches,
+                        &weather.device_name,
+                        &data,
+                    );
+                    inputs.push((weather.device_name.clone(), data.clone()));
+                    readings.push((weather.device_name.clone(), data));
+                    summary.sources.push(SourceSummary {
+                        id: weather.device_name.clone(),
+                        success: true,
+                        tags,
+                        values,
+                        duration: start.elapsed().as_secs_f64(),
+                        ..Default::default()
+                    });
+                }
+                Ok(None) => {}
+                // Not a failure of the sources, so not in the summary
+                Err(err) => tracing::warn!("{err:#}"),
+            }
+        }
+        for device in &self.virtual_devices {
+            let _span = tracing::info_span!("compute", device = %device.device_name).entered();
+            let start = Instant::now();
+            let (mut tags, mut values) = Default::default();
+            let error = match device.compute(&inputs) {
+                Ok(data) => {
+                    (tags, values) = split_values(&data);
+                    publish(
+                        &self.targets,
+                        &mut self.chaos,
+                        &mut self.stats,
+                        &mut summary.targets,
+                        &mut batches,
+                        &device.device_name,
+                        &data,
+                    );
+                    // Virtual devices may also aggregate ones before them
+                    inputs.push((device.device_name.clone(), data.clone()));
+                    readings.push((device.device_name.clone(), data));
+                    None
+                }
+                Err(err) => {
+                    tracing::error!("Failed to compute '{}': {err}", device.device_name);
+                    Some(err.to_string())
+                }
+            };
+            summary.sources.push(SourceSummary {
+                id: device.device_name.clone(),
+                success: error.is_none(),
+                error,
+                tags,
+                values,
+                duration: start.elapsed().as_secs_f64(),
+            });
+        }
+        failed.extend(publish_batches(
+            &self.targets,
+            &mut self.chaos,
+            &mut self.stats,
+            &mut summary.targets,
+            batches,
+        ));
+        for src in &mut self.sources {
+            let id = src.id();
+            if readings.iter().any(|(polled, _)| *polled == id) && !failed.contains(id.as_ref()) {
+                src.published();
+            }
+        }
+        self.alerts.check(&readings, SystemTime::now());
+        for (dst, dst_summary) in self.targets.iter().zip(&summary.targets) {
+            self.stats.dropped(&dst_summary.id, dst.dropped_points());
+        }
+        if let Some(self_metrics) = &self.self_metrics {
+            let points = self.stats.points(&self_metrics.measurement);
+            for dst in &self.targets {
+                let results = if dst.batch() {
+                    vec![dst.publish_batch(&points)]
+                } else {
+                    points.iter().map(|data| dst.publish(data)).collect()
+                };
+                for err in results.into_iter().filter_map(Result::err) {
+                    tracing::error!("Failed to publish self-metrics to '{}': {err}", dst.id());
+                }
+            }
+        }
+        if let Err(err) = self.save_state() {
+            tracing::error!("Failed to save state: {err}");
+        }
+        if let Some(heartbeat) = &self.heartbeat {
+            if polled && summary.exit_code() == 0 {
+                if let Err(err) = heartbeat.ping() {
+                    tracing::warn!("Failed to ping heartbeat: {err}");
+                }
+            }
+        }
+        summary.duration = cycle_start.elapsed().as_secs_f64();
+        summary
+    }
+}
+
+type Values = BTreeMap<String, serde_json::Value>;
+
+/// Publishes the reading of source `id` to every target, or adds it to the batches of the targets
+/// batching writes. Returns whether all targets took it so far.
+fn publish(
+    targets: &[Box<dyn Target>],
+    chaos: &mut Option<Chaos>,
+    stats: &mut Stats,
+    summaries: &mut [TargetSummary],
+    batches: &mut [Vec<(String, PublishData)>],
+    id: &str,
+    data: &PublishData,
+) -> bool {
+    let mut success = true;
+    let points = data.clone().into_points();
+    for data in points.iter().filter(|data| data.has_fields()) {
+        for ((dst, dst_summary), batch) in targets.iter().zip(&mut *summaries).zip(&mut *batches) {
+            if dst.batch() {
+                // Written later, so the target must not take the time of writing
+                let mut data = data.clone();
+                if data.timestamp().is_none() {
+                    data.set_timestamp(SystemTime::now());
+                }
+                batch.push((id.to_string(), data));
+                continue;
+            }
+            let _span = tracing::info_span!("publish", target = %dst.id()).entered();
+            let start = Instant::now();
+            let result = match chaos.as_mut().and_then(Chaos::fault) {
+                Some(fault) => Err(fault.error()),
+                None => dst.publish(data),
+            };
+            success &= published(stats, dst.as_ref(), dst_summary, start, 1, result);
+        }
+    }
+    success
+}
+
+/// Publishes the batches of the cycle, one request per target. Returns the sources of the batches
+/// that failed.
+fn publish_batches(
+    targets: &[Box<dyn Target>],
+    chaos: &mut Option<Chaos>,
+    stats: &mut Stats,
+    summaries: &mut [TargetSummary],
+    batches: Vec<Vec<(String, PublishData)>>,
+) -> BTreeSet<String> {
+    let mut failed = BTreeSet::new();
+    for ((dst, dst_summary), batch) in targets.iter().zip(summaries).zip(batches) {
+        if batch.is_empty() {
+            continue;
+        }
+        let (ids, batch): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        let _span = tracing::info_span!("publish", target = %dst.id()).entered();
+        let start = Instant::now();
+        let result = match chaos.as_mut().and_then(Chaos::fault) {
+            Some(fault) => Err(fault.error()),
+            None => dst.publish_batch(&batch),
+        };
+        if !published(stats, dst.as_ref(), dst_summary, start, batch.len(), result) {
+            failed.extend(ids);
+        }
+    }
+    failed
+}
+
+/// Records the outcome of publishing `points` points, returns whether it succeeded.
+fn published(
+    stats: &mut Stats,
+    dst: &dyn Target,
+    dst_summary: &mut TargetSummary,
+    start: Instant,
+    points: usize,
+    result: anyhow::Result<()>,
+) -> bool {
+    dst_summary.duration += start.elapsed().as_secs_f64();
+    for _ in 0..points {
+        stats.published(&dst_summary.id, result.is_ok());
+    }
+    if let Err(err) = result {
+        tracing::error!("Failed to publish data to '{}': {err}", dst.id());
+        dst_summary.success = false;
+        dst_summary.failed += points;
+        dst_summary.error = Some(err.to_string());
+        false
+    } else {
+        dst_summary.published += points;
+        true
+    }
+}
+
+/// Tags and fields of a reading.
+fn split_values(data: &PublishData) -> (Values, Values) {
+    let (mut tags, mut fields) = (Values::new(), Values::new());
+    for field in data.fields() {
+        match field {
+            crate::Field::Tag(name, value) => tags.insert(name.clone(), value.to_json()),
+            crate::Field::Field(name, value) => fields.insert(name.clone(), value.to_json()),
+        };
+    }
+    (tags, fields)
+}
+
+impl From<Config> for Scheduler {
+    fn from(mut config: Config) -> Self {
+        config.inherit_globals();
+        let mut scheduler = Scheduler::default();
+        for source in config.sources {
+            scheduler.add_source(source);
+        }
+        for device in config.virtual_devices {
+            scheduler.add_virtual_device(device);
+        }
+        for target in config.targets {
+            scheduler.add_target(target);
+        }
+        scheduler.weather = config.weather;
+        scheduler.self_metrics = config.self_metrics;
+        scheduler.heartbeat = config.heartbeat;
+        scheduler.alerts = Alerts {
+            rules: config.alerts,
+            stale: config.stale_alert,
+            notifiers: config.notifiers,
+            ..Default::default()
+        };
+        scheduler.backoff = config.backoff;
+        scheduler
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        let source = |error: Option<&str>| SourceSummary {
+            id: "src".to_string(),
+            success: error.is_none(),
+            error: error.map(str::to_string),
+            ..Default::default()
+        };
+        let target = |failed| TargetSummary {
+            id: "http://influx".to_string(),
+            success: failed == 0,
+            published: 1,
+            failed,
+            ..Default::default()
+        };
+        let summary = |sources, targets| CycleSummary {
+            sources,
+            targets,
+            ..Default::default()
+        };
+        assert_eq!(summary(vec![source(None)], vec![target(0)]).exit_code(), 0);
+        assert_eq!(
+            summary(vec![source(None), source(Some("down"))], vec![target(0)]).exit_code(),
+            2
+        );
+        assert_eq!(
+            summary(vec![source(Some("down"))], vec![target(0)]).exit_code(),
+            3
+        );
+        assert_eq!(
+            summary(vec![source(None), source(Some("down"))], vec![target(1)]).exit_code(),
+            4
+        );
+    }
+
+    struct Meter;
+
+    impl Source for Meter {
+        fn id(&self) -> std::borrow::Cow<'_, str> {
+            "meter".into()
+        }
+
+        fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+            let mut data = PublishData::default();
+            data.tag("deviceName", "meter".to_string());
+            data.field("currentPower", 344.5);
+            Ok(data)
+        }
+    }
+
+    struct Slow(&'static str, u64);
+
+    impl Source for Slow {
+        fn id(&self) -> std::borrow::Cow<'_, str> {
+            self.0.into()
+        }
+
+        fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+            std::thread::sleep(std::time::Duration::from_millis(self.1));
+            let mut data = PublishData::default();
+            data.field("delay", self.1 as i64);
+            Ok(data)
+        }
+    }
+
+    /// Reports a constant power, to be configured with the processing of the sources.
+    #[derive(serde::Deserialize)]
+    struct Plug {
+        name: String,
+        power: f64,
+    }
+
+    impl Source for Plug {
+        fn id(&self) -> std::borrow::Cow<'_, str> {
+            (&self.name).into()
+        }
+
+        fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+            let mut data = PublishData::default();
+            data.tag("deviceName", self.name.clone());
+            data.field("currentPower", self.power);
+            Ok(data)
+        }
+    }
+
+    struct Fast;
+
+    impl Source for Fast {
+        fn id(&self) -> std::borrow::Cow<'_, str> {
+            "fast".into()
+        }
+
+        fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+            let mut data = PublishData::default();
+            data.field("count", 1_i64);
+            Ok(data)
+        }
+
+        fn poll_interval(&self) -> Option<Duration> {
+            Some(Duration::from_millis(50))
+        }
+    }
+
+    struct Down;
+
+    impl Target for Down {
+        fn id(&self) -> std::borrow::Cow<'_, str> {
+            "http://influx".into()
+        }
+
+        fn publish(&self, _: &PublishData) -> anyhow::Result<()> {
+            anyhow::bail!("Connection refused")
+        }
+    }
+
+    /// Records the sizes of the batches published.
+    struct Batching(std::sync::Arc<std::sync::Mutex<Vec<usize>>>);
+
+    impl Target for Batching {
+        fn id(&self) -> std::borrow::Cow<'_, str> {
+            "http://cloud".into()
+        }
+
+        fn publish(&self, _: &PublishData) -> anyhow::Result<()> {
+            anyhow::bail!("Not batched")
+        }
+
+        fn batch(&self) -> bool {
+            true
+        }
+
+        fn publish_batch(&self, data: &[PublishData]) -> anyhow::Result<()> {
+            assert!(data.iter().all(|data| data.timestamp().is_some()));
+            self.0.lock().unwrap().push(data.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut scheduler = Scheduler::default();
+        scheduler.add_source(Meter);
+        scheduler.add_target(Down);
+        let summary = serde_json::to_value(scheduler.run_cycle()).unwrap();
+        assert_eq!(summary["sources"][0]["success"], true);
+        assert_eq!(summary["sources"][0]["tags"]["deviceName"], "meter");
+        assert_eq!(
+            summary["sources"][0]["values"],
+            serde_json::json!({"currentPower": 344.5})
+        );
+        assert_eq!(summary["targets"][0]["success"], false);
+        assert_eq!(summary["targets"][0]["failed"], 1);
+        assert_eq!(summary["targets"][0]["error"], "Connection refused");
+        assert!(summary["duration"].is_f64());
+    }
+
+    #[test]
+    fn test_concurrent_polling() {
+        let mut scheduler = Scheduler::default();
+        scheduler.add_source(Slow("inverter", 300));
+        scheduler.add_source(Slow("plug", 300));
+        scheduler.add_source(Meter);
+        let start = Instant::now();
+        let summary = scheduler.run_cycle();
+        assert!(start.elapsed() < std::time::Duration::from_millis(500));
+        let ids: Vec<_> = summary.sources.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["inverter", "plug", "meter"]);
+        assert!(summary.sources.iter().all(|s| s.success));
+    }
+
+    #[test]
+    fn test_poll_intervals() {
+        let mut scheduler = Scheduler::default();
+        scheduler.set_interval(Duration::from_secs(3600));
+        scheduler.add_source(Meter);
+        scheduler.add_source(Fast);
+        let ids = |summary: CycleSummary| -> Vec<String> {
+            summary.sources.into_iter().map(|s| s.id).collect()
+        };
+        assert_eq!(ids(scheduler.run_cycle()), ["meter", "fast"]);
+        assert!(ids(scheduler.run_cycle()).is_empty());
+        let next = scheduler.next_due().unwrap();
+        assert!(next < Instant::now() + Duration::from_millis(100));
+        std::thread::sleep(next.saturating_duration_since(Instant::now()));
+        assert_eq!(ids(scheduler.run_cycle()), ["fast"]);
+    }
+
+    #[test]
+    fn test_heartbeat_only_after_polling() {
+        use crate::http::{serve, Response};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        let pings = Arc::new(AtomicUsize::new(0));
+        let counted = pings.clone();
+        let addr = serve("127.0.0.1:0".parse().unwrap(), move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Response::new("text/plain", "OK")
+        })
+        .unwrap();
+        let mut scheduler = Scheduler::default();
+        scheduler.set_interval(Duration::from_secs(3600));
+        scheduler.add_source(Meter);
+        scheduler.set_heartbeat(
+            serde_json::from_str(&format!(r#"{{"url": "http://{addr}/ping"}}"#)).unwrap(),
+        );
+        assert_eq!(scheduler.run_cycle().exit_code(), 0);
+        assert_eq!(pings.load(Ordering::SeqCst), 1);
+        // Nothing due, so nothing to vouch for
+        assert_eq!(scheduler.run_cycle().exit_code(), 0);
+        assert_eq!(pings.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_batch() {
+        let batches = std::sync::Arc::default();
+        let mut scheduler = Scheduler::default();
+        scheduler.add_source(Meter);
+        scheduler.add_source(Fast);
+        scheduler.add_target(Batching(std::sync::Arc::clone(&batches)));
+        let summary = scheduler.run_cycle();
+        assert_eq!(*batches.lock().unwrap(), [2]);
+        assert!(summary.targets[0].success);
+        assert_eq!(summary.targets[0].published, 2);
+    }
+
+    #[test]
+    fn test_virtual_device_of_deduplicated() {
+        crate::registry::register_source::<Plug>("Plug");
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "sources": [
+                {"type": "Plug", "name": "east", "power": 100.0, "dedup": {"maxAge": "1h"}},
+                {"type": "Plug", "name": "west", "power": 50.0, "dedup": {"maxAge": "1h"},
+                    "rename": {"currentPower": "power"}},
+            ],
+            "virtualDevices": [{"device_name": "total", "sources": ["east", "west"],
+                "aggregate": {"currentPower": "sum"}}],
+        }))
+        .unwrap();
+        let mut scheduler = Scheduler::from(config);
+        for _ in 0..2 {
+            let summary = scheduler.run_cycle();
+            assert_eq!(summary.sources[2].id, "total");
+            assert_eq!(
+                summary.sources[2].values["currentPower"],
+                serde_json::json!(150.0)
+            );
+        }
+        // The second readings were deduplicated
+        assert!(scheduler.run_cycle().sources[0].values.is_empty());
+    }
+
+    #[test]
+    fn test_dedup_after_failed_publish() {
+        crate::registry::register_source::<Plug>("Plug");
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "sources": [{"type": "Plug", "name": "east", "power": 100.0,
+                "dedup": {"maxAge": "1h"}}],
+        }))
+        .unwrap();
+        let mut scheduler = Scheduler::from(config);
+        scheduler.add_target(Down);
+        for _ in 0..2 {
+            let summary = scheduler.run_cycle();
+            assert_eq!(summary.targets[0].failed, 1);
+            assert!(summary.sources[0].values.contains_key("currentPower"));
+        }
+    }
+
+    #[test]
+    fn test_weather() {
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = requests.clone();
+        let addr = crate::http::serve("127.0.0.1:0".parse().unwrap(), move |_| {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            crate::http::Response::new(
+                "application/json",
+                r#"{"current": {"temperature_2m": 21.5, "shortwave_radiation": 612.0}}"#,
+            )
+        })
+        .unwrap();
+        crate::registry::register_source::<Plug>("Plug");
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "sources": [
+                {"type": "Plug", "name": "east", "power": 100.0},
+                {"type": "Plug", "name": "west", "power": 50.0},
+            ],
+            "weather": {"latitude": 52.5, "longitude": 13.4, "url": format!("http://{addr}/")},
+            "tags": {"site": "home"},
+        }))
+        .unwrap();
+        let mut scheduler = Scheduler::from(config);
+        for _ in 0..2 {
+            let summary = scheduler.run_cycle();
+            let weather = &summary.sources[2];
+            assert_eq!(weather.id, "weather");
+            assert_eq!(weather.tags["site"], "home");
+            assert_eq!(weather.values["irradiance"], serde_json::json!(612.0));
+            assert!(!summary.sources[0].values.contains_key("irradiance"));
+        }
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,190 @@
+//! Custom transforms of readings with [Rhai](https://rhai.rs) scripts, available with the
+//! `scripting` feature.
+//!
+//! Scripts see the fields and tags of a reading as the maps `fields` and `tags`, and may add,
+//! modify or remove entries. A script evaluating to `false` drops the whole reading:
+//! ```rhai
+//! fields.power_kw = fields.currentPower / 1000.0;
+//! fields.remove("totalYield");
+//! tags.site = "garage";
+//! fields.currentPower > 0
+//! ```
+use crate::PublishData;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(serde::Deserialize)]
+#[serde(try_from = "PathBuf")]
+pub struct Script {
+    path: PathBuf,
+    #[cfg(feature = "scripting")]
+    engine: rhai::Engine,
+    #[cfg(feature = "scripting")]
+    ast: rhai::AST,
+}
+
+crate::string_schema!(Script, "Path of a Rhai script");
+
+#[cfg(feature = "scripting")]
+impl TryFrom<PathBuf> for Script {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        let engine = rhai::Engine::new();
+        let ast = engine
+            .compile_file(path.clone())
+            .map_err(|err| anyhow::anyhow!("Failed to compile '{}': {err}", path.display()))?;
+        Ok(Self { path, engine, ast })
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+impl TryFrom<PathBuf> for Script {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        anyhow::bail!(
+            "Can't load '{}', scripts require building with the 'scripting' feature",
+            path.display()
+        )
+    }
+}
+
+impl Script {
+    /// Runs the script on a reading, returns `None` if the script dropped it. Only the fields and
+    /// tags are replaced, the timestamp, measurement, channels and quality flags are kept.
+    #[cfg(feature = "scripting")]
+    pub fn apply(&self, mut data: PublishData) -> anyhow::Result<Option<PublishData>> {
+        use crate::{Field, Value};
+        use rhai::{Dynamic, Map, Scope};
+        use std::collections::BTreeSet;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        fn to_dynamic(value: &Value) -> Dynamic {
+            match value {
+                Value::String(s) => s.clone().into(),
+                Value::F64(f) => (*f).into(),
+                Value::I64(i) => (*i).into(),
+                Value::Bool(b) => (*b).into(),
+                Value::Timestamp(t) => (crate::influxdb::timestamp_nanos(t) as i64).into(),
+            }
+        }
+        fn to_value(name: &str, value: Dynamic) -> anyhow::Result<Value> {
+            Ok(match value.type_name() {
+                "f64" => Value::F64(value.cast()),
+                "i64" => Value::I64(value.cast()),
+                "bool" => Value::Bool(value.cast()),
+                "string" => Value::String(value.cast::<rhai::ImmutableString>().to_string()),
+                other => anyhow::bail!("Unsupported type '{other}' of '{name}'"),
+            })
+        }
+
+        let mut fields = Map::new();
+        let mut tags = Map::new();
+        // Scripts see timestamps as nanoseconds since the epoch
+        let mut timestamps = BTreeSet::new();
+        for f in data.fields() {
+            if let Value::Timestamp(_) = f.value() {
+                timestamps.insert(f.name().to_string());
+            }
+            match f {
+                Field::Tag(name, value) => tags.insert(name.into(), to_dynamic(value)),
+                Field::Field(name, value) => fields.insert(name.into(), to_dynamic(value)),
+            };
+        }
+        let mut scope = Scope::new();
+        scope.push("fields", fields);
+        scope.push("tags", tags);
+        let result: Dynamic = self
+            .engine
+            .eval_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|err| anyhow::anyhow!("Script '{}' failed: {err}", self.path.display()))?;
+        if result.as_bool() == Ok(false) {
+            return Ok(None);
+        }
+        let restore = |name: &str, value: Dynamic| -> anyhow::Result<Value> {
+            Ok(match to_value(name, value)? {
+                Value::I64(nanos) if nanos >= 0 && timestamps.contains(name) => {
+                    Value::Timestamp(UNIX_EPOCH + Duration::from_nanos(nanos as u64))
+                }
+                value => value,
+            })
+        };
+        data.fields.clear();
+        for (name, value) in scope.get_value::<Map>("tags").unwrap_or_default() {
+            data.tag(name.as_str(), restore(&name, value)?);
+        }
+        for (name, value) in scope.get_value::<Map>("fields").unwrap_or_default() {
+            data.field(name.as_str(), restore(&name, value)?);
+        }
+        Ok(Some(data))
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    pub fn apply(&self, data: PublishData) -> anyhow::Result<Option<PublishData>> {
+        Ok(Some(data))
+    }
+}
+
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl serde::Serialize for Script {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.path.serialize(serializer)
+    }
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Script").field("path", &self.path).finish()
+    }
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    #[test]
+    fn test_script() {
+        let path = std::env::temp_dir().join("sun-status-grabber-test.rhai");
+        std::fs::write(
+            &path,
+            r#"
+            fields.power_kw = fields.currentPower / 1000.0;
+            fields.remove("totalYield");
+            tags.site = "garage";
+            fields.currentPower > 0.0
+            "#,
+        )
+        .unwrap();
+        let script = Script::try_from(path).unwrap();
+        let timestamp = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let mut data = PublishData::default();
+        data.tag("deviceName", "plug".to_string());
+        data.field("currentPower", 500.0);
+        data.field("totalYield", 1.0);
+        data.field("lastReset", Value::Timestamp(timestamp));
+        data.set_timestamp(timestamp);
+        data.set_measurement("garage");
+        data.flag(crate::quality::Quality::Calibrated);
+        let data = script.apply(data).unwrap().unwrap();
+        assert_eq!(data["power_kw"], Value::F64(0.5));
+        assert_eq!(data["site"], Value::String("garage".to_string()));
+        assert!(data.fields().iter().all(|f| f.name() != "totalYield"));
+        assert_eq!(data["lastReset"], Value::Timestamp(timestamp));
+        assert_eq!(data.timestamp(), Some(timestamp));
+        assert_eq!(data.measurement(), Some("garage"));
+        assert!(data
+            .quality()
+            .contains(&crate::quality::Quality::Calibrated));
+
+        let mut data = PublishData::default();
+        data.field("currentPower", 0.0);
+        assert!(script.apply(data).unwrap().is_none());
+    }
+}
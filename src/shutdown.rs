@@ -0,0 +1,101 @@
+//! Graceful shutdown on SIGTERM and SIGINT while running continuously: the current cycle is
+//! finished (and the state saved) before exiting. A second signal terminates right away.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often sleeping checks for a shutdown.
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[cfg(unix)]
+extern "C" fn request(_signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Handles SIGTERM and SIGINT by requesting a shutdown, once.
+#[cfg(unix)]
+pub fn install() -> anyhow::Result<()> {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        let result = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = request as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // The default handler terminates on the next signal
+            action.sa_flags = libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if result != 0 {
+            anyhow::bail!(
+                "Failed to handle signal {signal}: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Signals terminate right away on other platforms.
+#[cfg(not(unix))]
+pub fn install() -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Whether a signal asked to shut down.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Sleeps for `duration`, or until a shutdown is requested. Returns whether it was.
+pub fn sleep(duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while !requested() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return false;
+        }
+        std::thread::sleep(left.min(CHECK_INTERVAL));
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sleep() {
+        let start = Instant::now();
+        assert!(!sleep(Duration::from_millis(150)));
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    /// Raises SIGTERM in a child process running only this test, as the requested shutdown can't
+    /// be taken back.
+    #[cfg(unix)]
+    #[test]
+    fn test_signal() {
+        if std::env::var_os("SG_TEST_SIGNAL").is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "shutdown::tests::test_signal",
+                    "--test-threads=1",
+                ])
+                .env("SG_TEST_SIGNAL", "1")
+                .stdout(std::process::Stdio::null())
+                .status()
+                .unwrap();
+            assert!(status.success());
+            assert!(!requested());
+            return;
+        }
+        install().unwrap();
+        // SAFETY: handled by requesting a shutdown
+        unsafe { libc::raise(libc::SIGTERM) };
+        let start = Instant::now();
+        assert!(sleep(Duration::from_secs(60)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
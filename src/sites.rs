@@ -0,0 +1,119 @@
+//! Sites grouping sources, e.g. several rental units or customer installations handled by one
+//! grabber. Their readings are tagged with `site`, and may be published to their own targets.
+use crate::{Config, SourceConfig, Target, TargetConfig};
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+pub struct Site {
+    /// Value of the `site` tag
+    pub name: String,
+    #[serde(default)]
+    pub sources: Vec<SourceConfig>,
+    /// Targets only for the readings of this site, which are then not published to the global
+    /// targets
+    #[serde(default)]
+    pub targets: Vec<TargetConfig>,
+    /// Publishes the readings of this site to the global targets, but into this bucket
+    #[serde(default)]
+    pub bucket: Option<String>,
+    /// Publishes the readings of this site to the global targets, but into this organization
+    #[serde(default)]
+    pub org: Option<String>,
+    /// Token for `bucket` and `org`, if the global one has no access
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl Site {
+    /// Whether the readings of the site go to other targets than the global ones.
+    fn is_routed(&self) -> bool {
+        !self.targets.is_empty() || self.bucket.is_some() || self.org.is_some()
+    }
+}
+
+impl Config {
+    /// Moves the sources and targets of the sites into the global ones, routing the readings of
+    /// each site by their `site` tag.
+    pub fn expand_sites(&mut self) {
+        let sites = std::mem::take(&mut self.sites);
+        let globals = self.targets.iter().filter(|t| t.site.is_none()).count();
+        for mut site in sites {
+            for mut source in std::mem::take(&mut site.sources) {
+                source
+                    .tags
+                    .entry("site".to_string())
+                    .or_insert_with(|| site.name.clone());
+                self.sources.push(source);
+            }
+            if !site.is_routed() {
+                continue;
+            }
+            let mut targets = vec![];
+            if site.bucket.is_some() || site.org.is_some() {
+                for global in &self.targets[..globals] {
+                    let mut target = global.clone();
+                    target.name = Some(format!("{} ({})", global.id(), site.name));
+                    if let Some(backend) = target.backend.influxdb_mut() {
+                        if let Some(bucket) = &site.bucket {
+                            backend.bucket = bucket.clone();
+                        }
+                        if let Some(org) = &site.org {
+                            backend.org = org.clone();
+                        }
+                        if let Some(token) = &site.token {
+                            backend.token = token.clone();
+                        }
+                    }
+                    targets.push(target);
+                }
+            }
+            targets.extend(site.targets);
+            for target in &mut targets {
+                target.site = Some(site.name.clone());
+            }
+            for global in &mut self.targets[..globals] {
+                global.excluded_sites.push(site.name.clone());
+            }
+            self.targets.extend(targets);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Format;
+    use crate::{PublishData, Source, Target};
+
+    #[test]
+    fn test_expand_sites() {
+        let mut config = Format::Json
+            .parse(
+                r#"{
+                    "targets": [{"influxUrl": "http://influx", "org": "me", "bucket": "home",
+                                 "token": "t", "measurement": "power"}],
+                    "sites": [
+                        {"name": "home", "sources": [{"type": "Tasmota", "host": "plug", "device_name": "plug"}]},
+                        {"name": "unit-1", "bucket": "unit-1",
+                         "sources": [{"type": "Tasmota", "host": "plug-1", "device_name": "plug 1"}]}
+                    ]
+                }"#,
+            )
+            .unwrap();
+        config.expand_sites();
+        assert_eq!(config.sources.len(), 2);
+        assert_eq!(config.sources[1].tags["site"], "unit-1");
+        assert_eq!(config.targets.len(), 2);
+        assert_eq!(
+            config.targets[1].backend.influxdb().unwrap().bucket,
+            "unit-1"
+        );
+        assert_eq!(config.targets[1].id(), "http://influx (unit-1)");
+        let mut reading = PublishData::default();
+        reading.tag("site", "unit-1".to_string());
+        assert!(!config.targets[0].accepts(&reading));
+        assert!(config.targets[1].accepts(&reading));
+        reading.set_tag("site", "home".to_string());
+        assert!(config.targets[0].accepts(&reading));
+        assert!(!config.targets[1].accepts(&reading));
+        assert!(config.sources[0].id() == "plug");
+    }
+}
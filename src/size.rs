@@ -0,0 +1,102 @@
+//! Serde support for sizes given in bytes (`1048576`) or with a unit (`"500kB"`, `"100MB"`,
+//! `"1GiB"`), use with `#[serde(with = "crate::size")]`.
+use anyhow::Context;
+use serde::{Deserialize, Deserializer, Serializer};
+
+/// Units by factor, decimal ones (`MB`) and binary ones (`MiB`).
+const UNITS: [(&str, u64); 7] = [
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+    ("GB", 1_000_000_000),
+    ("MB", 1_000_000),
+    ("kB", 1000),
+    ("B", 1),
+];
+
+pub fn parse(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("Invalid size '{s}'"))?;
+    let factor = match unit.trim() {
+        "" => 1,
+        unit => match UNITS.iter().find(|(u, _)| u.eq_ignore_ascii_case(unit)) {
+            Some((_, factor)) => *factor,
+            None => anyhow::bail!("Unknown unit '{unit}' in size '{s}'"),
+        },
+    };
+    Ok((number * factor as f64) as u64)
+}
+
+/// Formats a size with the largest unit giving a whole number, the inverse of [`parse`].
+pub fn format(bytes: u64) -> String {
+    match UNITS
+        .iter()
+        .filter(|(_, factor)| bytes >= *factor)
+        .find(|(_, factor)| bytes.is_multiple_of(*factor))
+    {
+        Some((unit, factor)) => format!("{}{unit}", bytes / factor),
+        None => bytes.to_string(),
+    }
+}
+
+pub fn serialize<S: Serializer>(bytes: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(*bytes))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Bytes(u64),
+        Text(String),
+    }
+    match Raw::deserialize(deserializer)? {
+        Raw::Bytes(bytes) => Ok(bytes),
+        Raw::Text(text) => parse(&text).map_err(serde::de::Error::custom),
+    }
+}
+
+/// JSON schema of sizes: `#[schemars(with = "crate::size::Schema")]`
+pub struct Schema;
+
+impl schemars::JsonSchema for Schema {
+    fn schema_name() -> String {
+        "Size".to_string()
+    }
+
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        serde_json::from_value(serde_json::json!({
+            "description": "Bytes, or a number with a unit (B, kB, MB, GB, KiB, MiB, GiB) like \"100MB\"",
+            "anyOf": [
+                {"type": "integer", "minimum": 0},
+                {"type": "string", "pattern": "^\\s*[0-9.]+\\s*([kKmMgG][iI]?)?[bB]?\\s*$"}
+            ]
+        }))
+        .expect("valid schema")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("1024").unwrap(), 1024);
+        assert_eq!(parse("500kB").unwrap(), 500_000);
+        assert_eq!(parse("1.5 MB").unwrap(), 1_500_000);
+        assert_eq!(parse("2GiB").unwrap(), 2 << 30);
+        assert_eq!(parse("100mb").unwrap(), 100_000_000);
+        assert!(parse("5 floppies").is_err());
+        for size in ["100B", "500kB", "100MB", "1GB", "64KiB", "3GiB"] {
+            assert_eq!(format(parse(size).unwrap()), size);
+        }
+        assert_eq!(format(0), "0");
+    }
+}
@@ -0,0 +1,119 @@
+//! Smoothing of jittery readings by taking several quick samples per poll.
+use crate::{Field, PublishData, Value};
+use std::time::Duration;
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+pub struct Samples {
+    /// Number of samples per poll
+    pub count: usize,
+    /// Time between samples
+    #[serde(default = "Samples::default_interval", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub interval: Duration,
+    #[serde(default)]
+    pub method: Method,
+}
+
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    Default,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Method {
+    /// Robust against single bad reads
+    #[default]
+    Median,
+    Mean,
+}
+
+impl Samples {
+    fn default_interval() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    /// Takes the samples, and combines the floating point fields of all successful ones. All other
+    /// fields and tags are taken from the last sample. Fails only if all samples failed.
+    pub fn poll(
+        &self,
+        mut poll: impl FnMut() -> anyhow::Result<PublishData>,
+    ) -> anyhow::Result<PublishData> {
+        let mut samples = vec![];
+        let mut error = None;
+        for i in 0..self.count.max(1) {
+            if i > 0 {
+                std::thread::sleep(self.interval);
+            }
+            match poll() {
+                Ok(sample) => samples.push(sample),
+                Err(err) => error = Some(err),
+            }
+        }
+        match (samples.pop(), error) {
+            (Some(last), _) => Ok(self.combine(last, &samples)),
+            (None, Some(err)) => Err(err),
+            (None, None) => unreachable!("at least one sample is taken"),
+        }
+    }
+
+    fn combine(&self, mut last: PublishData, others: &[PublishData]) -> PublishData {
+        for f in &mut last.fields {
+            let Field::Field(name, Value::F64(value)) = f else {
+                continue;
+            };
+            let mut values: Vec<f64> = others
+                .iter()
+                .filter_map(|sample| match sample.field_value(name) {
+                    Some(Value::F64(value)) => Some(*value),
+                    _ => None,
+                })
+                .collect();
+            values.push(*value);
+            *value = match self.method {
+                Method::Mean => values.iter().sum::<f64>() / values.len() as f64,
+                Method::Median => {
+                    values.sort_by(f64::total_cmp);
+                    let mid = values.len() / 2;
+                    if values.len().is_multiple_of(2) {
+                        (values[mid - 1] + values[mid]) / 2.0
+                    } else {
+                        values[mid]
+                    }
+                }
+            };
+        }
+        last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median() {
+        let samples = Samples {
+            count: 4,
+            interval: Duration::ZERO,
+            method: Method::Median,
+        };
+        let mut powers = [300.0, 65535.0, 310.0, 0.0].into_iter();
+        let data = samples
+            .poll(|| {
+                let power = powers.next().unwrap();
+                if power == 0.0 {
+                    anyhow::bail!("Timeout");
+                }
+                let mut data = PublishData::default();
+                data.field("currentPower", power);
+                Ok(data)
+            })
+            .unwrap();
+        assert_eq!(data.number("currentPower"), Some(310.0));
+    }
+}
@@ -0,0 +1,206 @@
+//! Disk spool of the writes which failed while a target was unreachable, written once it is back.
+//! The spool is limited in size and age (oldest files are pruned first), so a long outage can't
+//! fill the disk of a small collector.
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Extension of the spooled files, each holding the lines of one failed write.
+const EXTENSION: &str = "lp";
+
+/// Distinguishes files spooled within the same nanosecond.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Spool {
+    /// Directory of the spooled files, created if missing
+    pub directory: PathBuf,
+    /// Largest total size of the spooled files
+    #[serde(default = "Spool::default_max_size", with = "crate::size")]
+    #[schemars(with = "crate::size::Schema")]
+    pub max_size: u64,
+    /// Age after which spooled writes are dropped
+    #[serde(default = "Spool::default_max_age", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub max_age: Duration,
+    #[serde(skip)]
+    dropped: Dropped,
+}
+
+/// Points pruned since the start, shared by the clones of a target.
+#[derive(Clone, Default)]
+struct Dropped(Arc<AtomicU64>);
+
+impl PartialEq for Dropped {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl std::fmt::Debug for Dropped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// A spooled file.
+struct Spooled {
+    path: PathBuf,
+    time: SystemTime,
+    size: u64,
+}
+
+impl Spool {
+    fn default_max_size() -> u64 {
+        100_000_000
+    }
+
+    fn default_max_age() -> Duration {
+        Duration::from_secs(7 * 86400)
+    }
+
+    /// Points dropped by pruning since the start.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.0.load(Ordering::Relaxed)
+    }
+
+    /// Spools the `lines` of a failed write, pruning the oldest files to stay within the limits.
+    pub fn push(&self, lines: &str) -> anyhow::Result<()> {
+        let now = SystemTime::now();
+        std::fs::create_dir_all(&self.directory)
+            .with_context(|| format!("Failed to create spool '{}'", self.directory.display()))?;
+        let size = lines.len() as u64;
+        if size > self.max_size {
+            self.drop_points(lines.lines().count());
+            anyhow::bail!("Write of {size} bytes exceeds the spool size");
+        }
+        self.prune(size, now)?;
+        let nanos = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let path = self
+            .directory
+            .join(format!("{nanos:020}-{sequence:06}.{EXTENSION}"));
+        std::fs::write(&path, lines)
+            .with_context(|| format!("Failed to spool to '{}'", path.display()))
+    }
+
+    /// Sends the spooled writes with `send`, oldest first, removing them once sent. Stops at the
+    /// first failure, keeping the rest for the next time.
+    pub fn drain(&self, mut send: impl FnMut(&str) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        if !self.directory.exists() {
+            return Ok(());
+        }
+        self.prune(0, SystemTime::now())?;
+        for spooled in self.files()? {
+            let lines = std::fs::read_to_string(&spooled.path)?;
+            send(&lines)?;
+            std::fs::remove_file(&spooled.path)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the files older than `max_age`, and the oldest ones until `incoming` more bytes
+    /// fit.
+    fn prune(&self, incoming: u64, now: SystemTime) -> anyhow::Result<()> {
+        let files = self.files()?;
+        let mut total: u64 = files.iter().map(|f| f.size).sum::<u64>() + incoming;
+        for spooled in files {
+            let expired = now
+                .duration_since(spooled.time)
+                .is_ok_and(|age| age > self.max_age);
+            if !expired && total <= self.max_size {
+                break;
+            }
+            let points = std::fs::read_to_string(&spooled.path)
+                .map(|lines| lines.lines().count())
+                .unwrap_or_default();
+            std::fs::remove_file(&spooled.path)?;
+            total -= spooled.size;
+            self.drop_points(points);
+            tracing::warn!(
+                "Dropped {points} spooled points of '{}'",
+                self.directory.display()
+            );
+        }
+        Ok(())
+    }
+
+    fn drop_points(&self, points: usize) {
+        self.dropped.0.fetch_add(points as u64, Ordering::Relaxed);
+    }
+
+    /// The spooled files, oldest first.
+    fn files(&self) -> anyhow::Result<Vec<Spooled>> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if let Some(time) = spool_time(&path) {
+                let size = std::fs::metadata(&path)?.len();
+                files.push(Spooled { path, time, size });
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(files)
+    }
+}
+
+/// The time a file was spooled, from its name.
+fn spool_time(path: &Path) -> Option<SystemTime> {
+    if path.extension()? != EXTENSION {
+        return None;
+    }
+    let (nanos, _) = path.file_stem()?.to_str()?.split_once('-')?;
+    Some(UNIX_EPOCH + Duration::from_nanos(nanos.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spool() {
+        let directory = std::env::temp_dir().join(format!("spool-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let spool: Spool = serde_json::from_value(serde_json::json!({
+            "directory": directory,
+            "maxSize": "40B",
+        }))
+        .unwrap();
+        assert_eq!(spool.max_age, Duration::from_secs(7 * 86400));
+        spool.push("m a=1i 1\nm a=2i 2\n").unwrap();
+        spool.push("m a=3i 3\n").unwrap();
+        // Doesn't fit with the first file
+        spool.push("m a=4i 4\nm a=5i 5\n").unwrap();
+        assert_eq!(spool.dropped(), 2);
+        assert!(spool.push(&"m a=6i 6\n".repeat(5)).is_err());
+        assert_eq!(spool.dropped(), 7);
+        // Expired spooled writes are dropped
+        std::fs::write(
+            directory.join("00000000000000000001-000000.lp"),
+            "m a=0i 0\n",
+        )
+        .unwrap();
+
+        let mut sent = vec![];
+        let mut fail = true;
+        let mut send = |lines: &str| {
+            if std::mem::take(&mut fail) {
+                anyhow::bail!("Unreachable");
+            }
+            sent.push(lines.to_string());
+            Ok(())
+        };
+        assert!(spool.drain(&mut send).is_err());
+        spool.drain(&mut send).unwrap();
+        assert_eq!(sent, ["m a=3i 3\n", "m a=4i 4\nm a=5i 5\n"]);
+        assert_eq!(spool.dropped(), 8);
+        assert!(spool.files().unwrap().is_empty());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+//! SQLite target, storing the readings in a local database for devices without a network, like a
+//! Raspberry Pi in the garden shed. The table is created, and its columns added for new tags and
+//! fields, as needed. Uses the `sqlite3` tool.
+use crate::process::run;
+use crate::{template, Field, PublishData, Target, Value};
+use anyhow::Context;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendSqlite {
+    /// Database file, created if missing
+    pub path: PathBuf,
+    #[serde(default = "BackendSqlite::default_table")]
+    pub table: String,
+    /// Measurement of readings without one, may be templated from the tags and fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measurement: Option<String>,
+    #[serde(skip)]
+    columns: Columns,
+}
+
+/// Columns of the table in lower case (as SQLite ignores the case of ASCII letters), once read
+/// from the database.
+#[derive(Clone, Default)]
+struct Columns(Arc<Mutex<Option<BTreeSet<String>>>>);
+
+impl PartialEq for Columns {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl std::fmt::Debug for Columns {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Columns")
+    }
+}
+
+impl Target for BackendSqlite {
+    fn id(&self) -> Cow<'_, str> {
+        self.path.to_string_lossy()
+    }
+
+    fn publish(&self, data: &PublishData) -> anyhow::Result<()> {
+        self.publish_batch(std::slice::from_ref(data))
+    }
+
+    /// Inserts the readings in one transaction.
+    fn publish_batch(&self, data: &[PublishData]) -> anyhow::Result<()> {
+        let mut columns = self.columns.0.lock().expect("not poisoned");
+        let known = match &mut *columns {
+            Some(known) => known,
+            None => columns.insert(self.read_columns()?),
+        };
+        let mut added = known.clone();
+        let script = self.script(data, &mut added)?;
+        if let Err(err) = self.sqlite(&script) {
+            // Read again, in case they were changed by someone else
+            *columns = None;
+            return Err(err);
+        }
+        *known = added;
+        Ok(())
+    }
+}
+
+impl BackendSqlite {
+    fn default_table() -> String {
+        "readings".to_string()
+    }
+
+    fn sqlite(&self, script: &str) -> anyhow::Result<String> {
+        // Stopping at the first error rolls back the transaction
+        run(
+            Command::new("sqlite3").arg("-bail").arg(&self.path),
+            Some(script),
+        )
+        .with_context(|| format!("Failed to write to '{}'", self.path.display()))
+    }
+
+    /// Creates the table if missing, returning its columns.
+    fn read_columns(&self) -> anyhow::Result<BTreeSet<String>> {
+        let table = identifier(&self.table);
+        let output = self.sqlite(&format!(
+            "CREATE TABLE IF NOT EXISTS {table} (measurement TEXT, time TEXT);\n\
+             SELECT name FROM pragma_table_info({});\n",
+            string(&self.table)
+        ))?;
+        Ok(output.lines().map(str::to_ascii_lowercase).collect())
+    }
+
+    /// The statements inserting the readings, adding the missing columns to `columns`.
+    fn script(
+        &self,
+        data: &[PublishData],
+        columns: &mut BTreeSet<String>,
+    ) -> anyhow::Result<String> {
+        let table = identifier(&self.table);
+        let mut script = "BEGIN;\n".to_string();
+        for data in data {
+            let measurement = match (data.measurement(), &self.measurement) {
+                (Some(measurement), _) => string(measurement),
+                (None, Some(measurement)) => string(&template::render(measurement, data)?),
+                (None, None) => "NULL".to_string(),
+            };
+            let time = chrono::DateTime::<chrono::Utc>::from(
+                data.timestamp().unwrap_or_else(SystemTime::now),
+            );
+            let mut names = vec!["measurement".to_string(), "time".to_string()];
+            let mut values = vec![
+                measurement,
+                string(&time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+            ];
+            for field in data.fields() {
+                let (name, value) = (field.name(), field.value());
+                // The first of names differing only in case is kept
+                if names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                    continue;
+                }
+                if columns.insert(name.to_ascii_lowercase()) {
+                    let affinity = match (field, value) {
+                        (Field::Tag(..), _) => "TEXT",
+                        (_, Value::F64(_)) => "REAL",
+                        (_, Value::I64(_) | Value::Bool(_)) => "INTEGER",
+                        (_, Value::String(_) | Value::Timestamp(_)) => "TEXT",
+                    };
+                    script += &format!(
+                        "ALTER TABLE {table} ADD COLUMN {} {affinity};\n",
+                        identifier(name)
+                    );
+                }
+                names.push(name.to_string());
+                values.push(literal(value));
+            }
+            let names: Vec<_> = names.iter().map(|name| identifier(name)).collect();
+            script += &format!(
+                "INSERT INTO {table} ({}) VALUES ({});\n",
+                names.join(", "),
+                values.join(", ")
+            );
+        }
+        script += "COMMIT;\n";
+        Ok(script)
+    }
+}
+
+/// A quoted SQL identifier.
+fn identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// A quoted SQL string.
+fn string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn literal(value: &Value) -> String {
+    match value {
+        Value::String(s) => string(s),
+        Value::F64(f) if f.is_finite() => format!("{f:?}"),
+        Value::F64(_) => "NULL".to_string(),
+        Value::I64(i) => i.to_string(),
+        Value::Bool(b) => u8::from(*b).to_string(),
+        Value::Timestamp(t) => string(
+            &chrono::DateTime::<chrono::Utc>::from(*t)
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_script() {
+        let sqlite: BackendSqlite = serde_json::from_value(serde_json::json!({
+            "path": "/var/lib/solar.db",
+            "measurement": "power",
+        }))
+        .unwrap();
+        let mut data = PublishData::default();
+        data.tag("deviceName", "o'clock".to_string());
+        data.field("currentPower", 344.5);
+        data.field("online", true);
+        data.field("DeviceName", "other".to_string());
+        data.set_timestamp(UNIX_EPOCH + Duration::from_millis(1500));
+        let mut columns: BTreeSet<_> = ["measurement", "time", "devicename"]
+            .map(str::to_string)
+            .into();
+        let mut other = data.clone();
+        other.set_measurement("weather");
+        assert_eq!(
+            sqlite.script(&[data, other], &mut columns).unwrap(),
+            "BEGIN;\n\
+             ALTER TABLE \"readings\" ADD COLUMN \"currentPower\" REAL;\n\
+             ALTER TABLE \"readings\" ADD COLUMN \"online\" INTEGER;\n\
+             INSERT INTO \"readings\" (\"measurement\", \"time\", \"deviceName\", \"currentPower\", \"online\") \
+             VALUES ('power', '1970-01-01T00:00:01.500Z', 'o''clock', 344.5, 1);\n\
+             INSERT INTO \"readings\" (\"measurement\", \"time\", \"deviceName\", \"currentPower\", \"online\") \
+             VALUES ('weather', '1970-01-01T00:00:01.500Z', 'o''clock', 344.5, 1);\n\
+             COMMIT;\n"
+        );
+        assert!(columns.contains("online"));
+    }
+}
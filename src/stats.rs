@@ -0,0 +1,304 @@
+//! Counters about the grabber itself, published as self-metrics.
+use crate::PublishData;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Publishes the [`Stats`] as an additional measurement to all targets after every cycle.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+pub struct SelfMetrics {
+    #[serde(default = "SelfMetrics::default_measurement")]
+    pub measurement: String,
+}
+
+impl SelfMetrics {
+    fn default_measurement() -> String {
+        env!("CARGO_PKG_NAME").replace('-', "_")
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SourceStats {
+    pub polls: u64,
+    /// Successful polls which repeated the previous reading, as the response did not change
+    pub not_modified: u64,
+    /// Failed polls, including the parse failures
+    pub errors: u64,
+    /// Failed polls for which the device responded, but the response could not be parsed
+    pub parse_failures: u64,
+    /// Failed polls since the last successful one
+    pub consecutive_errors: u64,
+    pub last_duration: Duration,
+    pub last_success: Option<SystemTime>,
+    /// Values dropped or clamped by the plausibility checks, by field
+    pub implausible: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TargetStats {
+    pub published: u64,
+    pub failed: u64,
+    /// Points discarded by the target, e.g. pruned from its spool
+    pub dropped: u64,
+}
+
+/// Counters since the start of the process, by source and target id.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub sources: BTreeMap<String, SourceStats>,
+    pub targets: BTreeMap<String, TargetStats>,
+}
+
+impl Stats {
+    /// Counts a poll, `result` tells whether the response was unchanged if it succeeded.
+    pub fn polled(&mut self, id: &str, duration: Duration, result: Result<bool, &anyhow::Error>) {
+        let stats = self.sources.entry(id.to_string()).or_default();
+        stats.polls += 1;
+        stats.last_duration = duration;
+        match result {
+            Ok(not_modified) => {
+                if not_modified {
+                    stats.not_modified += 1;
+                }
+                stats.consecutive_errors = 0;
+                stats.last_success = Some(SystemTime::now());
+            }
+            Err(err) => {
+                stats.errors += 1;
+                stats.consecutive_errors += 1;
+                // Anything but a failed request means the response was unexpected
+                if !err.chain().any(|err| err.is::<ureq::Error>()) {
+                    stats.parse_failures += 1;
+                }
+            }
+        }
+    }
+
+    pub fn implausible(&mut self, id: &str, fields: &[String]) {
+        let stats = self.sources.entry(id.to_string()).or_default();
+        for field in fields {
+            *stats.implausible.entry(field.clone()).or_default() += 1;
+        }
+    }
+
+    pub fn published(&mut self, id: &str, ok: bool) {
+        let stats = self.targets.entry(id.to_string()).or_default();
+        if ok {
+            stats.published += 1;
+        } else {
+            stats.failed += 1;
+        }
+    }
+
+    pub fn dropped(&mut self, id: &str, dropped: u64) {
+        self.targets.entry(id.to_string()).or_default().dropped = dropped;
+    }
+
+    /// One point per source (tagged with `deviceName`), field with implausible values (tagged with
+    /// `deviceName` and `field`) and target (tagged with `target`).
+    pub fn points(&self, measurement: &str) -> Vec<PublishData> {
+        let sources = self.sources.iter().map(|(id, stats)| {
+            let mut data = PublishData::default();
+            data.tag("deviceName", id.clone());
+            data.field("polls", stats.polls as i64);
+            data.field("notModified", stats.not_modified as i64);
+            data.field("pollErrors", stats.errors as i64);
+            data.field("parseFailures", stats.parse_failures as i64);
+            data.field("consecutiveErrors", stats.consecutive_errors as i64);
+            data.field("pollDuration", stats.last_duration.as_secs_f64());
+            data
+        });
+        let implausible = self.sources.iter().flat_map(|(id, stats)| {
+            stats.implausible.iter().map(|(field, count)| {
+                let mut data = PublishData::default();
+                data.tag("deviceName", id.clone());
+                data.tag("field", field.clone());
+                data.field("implausibleValues", *count as i64);
+                data
+            })
+        });
+        let targets = self.targets.iter().map(|(id, stats)| {
+            let mut data = PublishData::default();
+            data.tag("target", id.clone());
+            data.field("published", stats.published as i64);
+            data.field("publishFailures", stats.failed as i64);
+            data.field("droppedPoints", stats.dropped as i64);
+            data
+        });
+        sources
+            .chain(implausible)
+            .chain(targets)
+            .map(|mut data| {
+                data.set_measurement(measurement.to_string());
+                data
+            })
+            .collect()
+    }
+}
+
+/// Escapes a Prometheus label value.
+pub(crate) fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Stats {
+    /// The counters in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let prefix = env!("CARGO_PKG_NAME").replace('-', "_");
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, f64)>| {
+            let _ = writeln!(out, "# HELP {prefix}_{name} {help}");
+            let _ = writeln!(out, "# TYPE {prefix}_{name} {kind}");
+            for (labels, value) in values {
+                let _ = writeln!(out, "{prefix}_{name}{{{labels}}} {value}");
+            }
+        };
+        let sources = |value: fn(&SourceStats) -> Option<f64>| {
+            self.sources
+                .iter()
+                .filter_map(|(id, stats)| {
+                    Some((format!("device=\"{}\"", label(id)), value(stats)?))
+                })
+                .collect()
+        };
+        metric(
+            "polls_total",
+            "counter",
+            "Polls of the source",
+            sources(|s| Some(s.polls as f64)),
+        );
+        metric(
+            "not_modified_total",
+            "counter",
+            "Polls of the source with an unchanged response, repeating the previous reading",
+            sources(|s| Some(s.not_modified as f64)),
+        );
+        metric(
+            "poll_errors_total",
+            "counter",
+            "Failed polls of the source",
+            sources(|s| Some(s.errors as f64)),
+        );
+        metric(
+            "parse_failures_total",
+            "counter",
+            "Polls of the source with an unexpected response",
+            sources(|s| Some(s.parse_failures as f64)),
+        );
+        metric(
+            "consecutive_errors",
+            "gauge",
+            "Failed polls of the source since the last successful one",
+            sources(|s| Some(s.consecutive_errors as f64)),
+        );
+        metric(
+            "poll_duration_seconds",
+            "gauge",
+            "Duration of the last poll of the source",
+            sources(|s| Some(s.last_duration.as_secs_f64())),
+        );
+        metric(
+            "last_success_timestamp_seconds",
+            "gauge",
+            "Time of the last successful poll of the source",
+            sources(|s| {
+                Some(
+                    s.last_success?
+                        .duration_since(UNIX_EPOCH)
+                        .ok()?
+                        .as_secs_f64(),
+                )
+            }),
+        );
+        metric(
+            "implausible_values_total",
+            "counter",
+            "Values of the source dropped or clamped by the plausibility checks",
+            self.sources
+                .iter()
+                .flat_map(|(id, stats)| {
+                    stats.implausible.iter().map(|(field, count)| {
+                        (
+                            format!("device=\"{}\",field=\"{}\"", label(id), label(field)),
+                            *count as f64,
+                        )
+                    })
+                })
+                .collect(),
+        );
+        let targets = |value: fn(&TargetStats) -> u64| {
+            self.targets
+                .iter()
+                .map(|(id, stats)| (format!("target=\"{}\"", label(id)), value(stats) as f64))
+                .collect()
+        };
+        metric(
+            "published_total",
+            "counter",
+            "Points published to the target",
+            targets(|t| t.published),
+        );
+        metric(
+            "publish_failures_total",
+            "counter",
+            "Points which failed to publish to the target",
+            targets(|t| t.failed),
+        );
+        metric(
+            "dropped_points_total",
+            "counter",
+            "Points discarded by the target, e.g. pruned from its spool",
+            targets(|t| t.dropped),
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::default();
+        stats.polled("inverter", Duration::from_millis(250), Ok(false));
+        stats.polled("inverter", Duration::from_millis(250), Ok(true));
+        stats.polled(
+            "inverter",
+            Duration::from_millis(500),
+            Err(&anyhow::anyhow!("Invalid status page")),
+        );
+        stats.implausible("inverter", &["currentPower".to_string()]);
+        stats.implausible("inverter", &["currentPower".to_string()]);
+        stats.published("http://influx", false);
+        stats.dropped("http://influx", 4);
+        let source = &stats.sources["inverter"];
+        assert_eq!(
+            (source.polls, source.errors, source.parse_failures),
+            (3, 1, 1)
+        );
+        assert_eq!(source.consecutive_errors, 1);
+        assert_eq!(source.not_modified, 1);
+        let points = stats.points("grabber");
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[0].measurement(), Some("grabber"));
+        assert_eq!(points[0].number("pollDuration"), Some(0.5));
+        assert_eq!(
+            points[1].tag_value("field"),
+            Some(&crate::Value::String("currentPower".to_string()))
+        );
+        assert_eq!(points[1].number("implausibleValues"), Some(2.0));
+        assert_eq!(points[2].number("publishFailures"), Some(1.0));
+        assert_eq!(points[2].number("droppedPoints"), Some(4.0));
+        let prometheus = stats.prometheus();
+        assert!(prometheus.contains("\nsun_status_grabber_polls_total{device=\"inverter\"} 3\n"));
+        assert!(prometheus.contains(
+            "\nsun_status_grabber_implausible_values_total{device=\"inverter\",field=\"currentPower\"} 2\n"
+        ));
+        assert!(prometheus
+            .contains("\nsun_status_grabber_publish_failures_total{target=\"http://influx\"} 1\n"));
+    }
+}
@@ -1,8 +1,10 @@
-use crate::PublishData;
+use crate::conditional::Conditional;
+use crate::number::{self, Locale, Unit};
+use crate::{eyeballs, PublishData, Source};
 use anyhow::{bail, Context};
 use base64::{engine::general_purpose, Engine as _};
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 
 const P_DEVICE_SN: &str = r#"var cover_mid\s*=\s*"?([^;"]+)\s*"?;"#;
@@ -10,7 +12,7 @@ const P_CURRENT_POWER: &str = r#"var webdata_now_p\s*=\s*"?([^;"]+)\s*"?;"#;
 const P_YIELD_TODAY: &str = r#"var webdata_today_e\s*=\s*"?([^;"]+)\s*"?;"#;
 const P_TOTAL_YIELD: &str = r#"var webdata_total_e\s*=\s*"?([^;"]+)\s*"?;"#;
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, schemars::JsonSchema, PartialEq, Debug)]
 pub struct Inverter {
     #[serde(rename = "statusPageUrl")]
     pub status_page_url: String,
@@ -19,26 +21,55 @@ pub struct Inverter {
     pub device_name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub device_location: Option<String>,
+    /// Number format of the status page
+    #[serde(default)]
+    pub locale: Locale,
+    /// The last status page, for conditional requests
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub conditional: Box<Conditional<PublishData>>,
 }
 
-impl Inverter {
-    pub fn id(&self) -> Cow<str> {
+impl Source for Inverter {
+    fn id(&self) -> Cow<'_, str> {
         (&self.device_name).into()
     }
 
-    pub fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+    fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+        let mut conditional = std::mem::take(&mut self.conditional);
+        let result = conditional.call(self.request(), |html| self.parse_html(html));
+        self.conditional = conditional;
+        result
+    }
+
+    fn not_modified(&self) -> bool {
+        self.conditional.not_modified()
+    }
+
+    fn fetch_raw(&self) -> anyhow::Result<String> {
+        self.fetch()
+    }
+
+    fn parse_raw(&self, raw: &str) -> anyhow::Result<PublishData> {
+        self.parse_html(raw)
+    }
+}
+
+impl Inverter {
+    fn request(&self) -> ureq::Request {
         let token = format!("{}:{}", self.user, self.password);
-        let html = ureq::get(&self.status_page_url)
-            .set(
-                "Authorization",
-                &format!("Basic {}", general_purpose::STANDARD_NO_PAD.encode(token)),
-            )
-            .call()?
-            .into_string()?;
-        self.parse_html(&html)
+        eyeballs::agent().get(&self.status_page_url).set(
+            "Authorization",
+            &format!("Basic {}", general_purpose::STANDARD_NO_PAD.encode(token)),
+        )
+    }
+
+    /// Raw status page.
+    pub fn fetch(&self) -> anyhow::Result<String> {
+        Ok(self.request().call()?.into_string()?)
     }
 
-    fn parse_html(&self, html: &str) -> anyhow::Result<PublishData> {
+    pub(crate) fn parse_html(&self, html: &str) -> anyhow::Result<PublishData> {
         lazy_static::lazy_static! {
             static ref R_DEVICE_SN : Regex = Regex::new(P_DEVICE_SN).unwrap();
             static ref R_CURRENT_POWER : Regex = Regex::new(P_CURRENT_POWER).unwrap();
@@ -50,36 +81,35 @@ impl Inverter {
             .with_context(|| "Could not parse device sn")?[1]
             .trim()
             .to_string();
-        let current_power = R_CURRENT_POWER
-            .captures(html)
-            .with_context(|| "Could not parse current power")?[1]
-            .to_string()
-            .parse::<f64>()?;
-        let yield_today = R_YIELD_TODAY
-            .captures(html)
-            .with_context(|| "Could not parse yield today")?[1]
-            .to_string()
-            .parse::<f64>()?;
-        let total_yield = R_TOTAL_YIELD
-            .captures(html)
-            .with_context(|| "Could not parse total yield")?[1]
-            .to_string()
-            .parse::<f64>()?;
-        if current_power == 0.0 && yield_today == 0.0 && total_yield == 0.0 {
-            bail!(
-                "Filtering out device '{}' data (all values are zero).",
-                device_sn
-            )
-        }
         let mut publisher = PublishData::default();
         publisher.tag("deviceName", self.device_name.clone());
         if let Some(device_location) = &self.device_location {
             publisher.tag("deviceLocation", device_location.clone());
         }
-        publisher.tag("device", device_sn);
-        publisher.field("currentPower", current_power);
-        publisher.field("yieldToday", yield_today);
-        publisher.field("totalYield", total_yield);
+        publisher.tag("device", device_sn.clone());
+        for (name, regex, unit) in [
+            ("currentPower", &*R_CURRENT_POWER, Unit::Power),
+            ("yieldToday", &*R_YIELD_TODAY, Unit::Energy),
+            ("totalYield", &*R_TOTAL_YIELD, Unit::Energy),
+        ] {
+            match regex.captures(html) {
+                Some(captures) => publisher.field(
+                    name,
+                    number::parse_quantity(&captures[1], self.locale, unit)
+                        .with_context(|| format!("Could not parse {name}"))?,
+                ),
+                None => publisher.missing(name),
+            }
+        }
+        if ["currentPower", "yieldToday", "totalYield"]
+            .iter()
+            .all(|name| publisher.number(name) == Some(0.0))
+        {
+            bail!(
+                "Filtering out device '{}' data (all values are zero).",
+                device_sn
+            )
+        }
         Ok(publisher)
     }
 }
@@ -97,6 +127,8 @@ mod tests {
             device_name: "name".to_string(),
             password: "password".to_string(),
             user: "user".to_string(),
+            locale: Locale::Auto,
+            conditional: Default::default(),
         }
         .parse_html(
             r#"
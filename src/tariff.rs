@@ -0,0 +1,135 @@
+//! Energy prices, for publishing the cost (or feed-in revenue) of energy counters.
+use crate::counters::{Timezone, Weighted};
+use crate::PublishData;
+use chrono::NaiveTime;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Tariff {
+    /// Price per kWh drawn from the grid
+    #[serde(default)]
+    pub price: f64,
+    /// Revenue per kWh fed into the grid
+    #[serde(default)]
+    pub feed_in: f64,
+    /// Time-of-use periods overriding the prices above
+    #[serde(default)]
+    pub schedule: Vec<Period>,
+    /// Time zone of the schedule, defaults to the local time zone
+    #[serde(default)]
+    pub timezone: Option<Timezone>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Period {
+    /// Start time, like `"22:00"`
+    pub from: NaiveTime,
+    /// End time (exclusive), may be before `from` for periods spanning midnight
+    pub to: NaiveTime,
+    pub price: Option<f64>,
+    pub feed_in: Option<f64>,
+}
+
+/// Which price applies to an energy counter.
+#[derive(
+    serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone, Copy,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Cost {
+    /// Energy drawn from the grid, published as `<field>Cost`
+    Cost,
+    /// Energy fed into the grid, published as `<field>Revenue`
+    Revenue,
+}
+
+impl Tariff {
+    /// Price and feed-in rate in effect at `time`.
+    pub fn rates_at(&self, time: SystemTime) -> (f64, f64) {
+        let now = Timezone::time(self.timezone, time);
+        let period = self.schedule.iter().find(|p| {
+            if p.from <= p.to {
+                p.from <= now && now < p.to
+            } else {
+                now >= p.from || now < p.to
+            }
+        });
+        (
+            period.and_then(|p| p.price).unwrap_or(self.price),
+            period.and_then(|p| p.feed_in).unwrap_or(self.feed_in),
+        )
+    }
+
+    /// Adds the cost or revenue of each configured energy counter (in kWh). Increments of the
+    /// counters are priced at the rate in effect when they were read, so time-of-use prices apply
+    /// correctly. A decreasing counter (like a daily one at midnight) starts over.
+    pub fn apply(
+        &self,
+        data: &mut PublishData,
+        costs: &BTreeMap<String, Cost>,
+        state: &mut Weighted,
+        now: SystemTime,
+    ) {
+        let (price, feed_in) = self.rates_at(now);
+        for (name, cost) in costs {
+            let Some(energy) = data.number(name) else {
+                continue;
+            };
+            let (rate, suffix) = match cost {
+                Cost::Cost => (price, "Cost"),
+                Cost::Revenue => (feed_in, "Revenue"),
+            };
+            let amount = state.add(name, energy, rate);
+            data.field(format!("{name}{suffix}"), amount);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_time_of_use() {
+        let tariff: Tariff = serde_json::from_str(
+            r#"{
+                "price": 0.30,
+                "feedIn": 0.08,
+                "timezone": "UTC",
+                "schedule": [{"from": "22:00", "to": "06:00", "price": 0.20}]
+            }"#,
+        )
+        .unwrap();
+        let costs = BTreeMap::from([
+            ("imported".to_string(), Cost::Cost),
+            ("exported".to_string(), Cost::Revenue),
+        ]);
+        let mut state = Weighted::default();
+        // 2023-06-01 21:00 UTC
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_685_653_200);
+        let mut poll = |imported: f64, exported: f64, after: u64| {
+            let mut data = PublishData::default();
+            data.field("imported", imported);
+            data.field("exported", exported);
+            tariff.apply(
+                &mut data,
+                &costs,
+                &mut state,
+                start + Duration::from_secs(after),
+            );
+            let round = |v: Option<f64>| (v.unwrap() * 1000.0).round() / 1000.0;
+            (
+                round(data.number("importedCost")),
+                round(data.number("exportedRevenue")),
+            )
+        };
+        assert_eq!(poll(10.0, 1.0, 0), (3.0, 0.08));
+        // One hour later, night tariff
+        assert_eq!(poll(12.0, 1.0, 3600), (3.4, 0.08));
+        // Counters reset
+        assert_eq!(poll(1.0, 0.0, 7200), (0.2, 0.0));
+    }
+}
@@ -1,58 +1,351 @@
-use crate::PublishData;
+use crate::number::{self, Locale, Unit};
+use crate::{arp, eyeballs, http, PublishData, Source};
 use anyhow::Context;
 use regex::Regex;
 use std::borrow::Cow;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 
-#[derive(serde::Deserialize, PartialEq, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, PartialEq, Debug)]
 pub struct Tasmota {
-    ip: Ipv4Addr,
+    /// IP (v4 or v6) address or host name, host names are resolved again on every poll
+    #[serde(alias = "ip")]
+    host: String,
+    /// MAC address used to find the device again if it got a new DHCP lease
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
     pub device_name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub device_location: Option<String>,
+    /// Number format of the web UI
+    #[serde(default)]
+    pub locale: Locale,
+    #[serde(skip)]
+    rediscovered: Option<IpAddr>,
+    /// Set once the device turned out not to answer `Status 8` with JSON
+    #[serde(skip)]
+    html_only: bool,
 }
 
-impl Tasmota {
-    pub fn id(&self) -> Cow<str> {
+impl Source for Tasmota {
+    fn id(&self) -> Cow<'_, str> {
         (&self.device_name).into()
     }
 
-    pub fn poll_data(&mut self) -> anyhow::Result<PublishData> {
-        let html = ureq::get(&format!("http://{}/?m=1", &self.ip))
+    fn poll_data(&mut self) -> anyhow::Result<PublishData> {
+        let host = match self.rediscovered {
+            Some(ip) => ip.to_string(),
+            None => self.host.clone(),
+        };
+        let response = match Self::request(&host, &mut self.html_only) {
+            Ok(response) => response,
+            Err(err) => {
+                let Some(ip) = self.mac.as_deref().and_then(arp::lookup) else {
+                    return Err(err);
+                };
+                if ip.to_string() == host {
+                    return Err(err);
+                }
+                let response = Self::request(&ip.to_string(), &mut self.html_only)
+                    .with_context(|| format!("Device moved to '{ip}', but is not reachable"))?;
+                self.rediscovered = Some(ip);
+                response
+            }
+        };
+        self.parse(&response)
+    }
+
+    fn fetch_raw(&self) -> anyhow::Result<String> {
+        self.fetch()
+    }
+
+    fn parse_raw(&self, raw: &str) -> anyhow::Result<PublishData> {
+        self.parse(raw)
+    }
+}
+
+/// Fields with their unit, the key in the `ENERGY` JSON object and the (normalized, see
+/// [`normalize`]) labels of the web UI in the languages Tasmota is translated to.
+const FIELDS: [(&str, Unit, &str, &[&str]); 3] = [
+    (
+        "currentPower",
+        Unit::Power,
+        "Power",
+        &[
+            "activepower",
+            "power",
+            "wirkleistung",
+            "leistung",
+            "puissanceactive",
+            "potenzaattiva",
+            "potenciaactiva",
+            "potenciaativa",
+            "werkelijkvermogen",
+            "vermogen",
+            "mocczynna",
+        ],
+    ),
+    (
+        "yieldToday",
+        Unit::Energy,
+        "Today",
+        &[
+            "energytoday",
+            "energieheute",
+            "energieaujourdhui",
+            "energiaoggi",
+            "energiahoy",
+            "energiahoje",
+            "verbruikvandaag",
+            "energiadzisiaj",
+        ],
+    ),
+    (
+        "totalYield",
+        Unit::Energy,
+        "Total",
+        &[
+            "energytotal",
+            "energieinsgesamt",
+            "energiegesamt",
+            "energietotale",
+            "energiatotale",
+            "energiatotal",
+            "verbruiktotaal",
+            "energiaogolem",
+        ],
+    ),
+];
+
+/// Lower case letters and digits of `label`, without accents, so labels match across template
+/// changes and minor differences between translations.
+fn normalize(label: &str) -> String {
+    label
+        .chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ą' => 'a',
+            'ç' | 'ć' | 'č' => 'c',
+            'è' | 'é' | 'ê' | 'ë' | 'ę' => 'e',
+            'ì' | 'í' | 'î' | 'ï' => 'i',
+            'ł' => 'l',
+            'ñ' | 'ń' => 'n',
+            'ò' | 'ó' | 'ô' | 'õ' | 'ö' => 'o',
+            'ś' | 'š' => 's',
+            'ù' | 'ú' | 'û' | 'ü' => 'u',
+            'ź' | 'ż' | 'ž' => 'z',
+            c => c,
+        })
+        .filter(char::is_ascii_alphanumeric)
+        .collect()
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Text of the cells of every table row. The status page sends rows as `{s}label{m}value{e}`
+/// templates expanded by the browser, or as plain `<tr>` rows in older firmware.
+fn table_rows(html: &str) -> Vec<Vec<String>> {
+    let html = html
+        .replace("{s}", "<tr><th>")
+        .replace("{m}", "</th><td>")
+        .replace("{e}", "</td></tr>");
+    let mut rows = Vec::new();
+    let mut row: Vec<String> = Vec::new();
+    let mut cell: Option<String> = None;
+    let end_cell = |row: &mut Vec<String>, cell: &mut Option<String>| {
+        if let Some(text) = cell.take() {
+            let text = decode_entities(&text);
+            let text = text.trim();
+            if !text.is_empty() {
+                row.push(text.to_string());
+            }
+        }
+    };
+    let mut rest = html.as_str();
+    while !rest.is_empty() {
+        let (text, tag) = match rest.find('<') {
+            Some(start) => {
+                let end = rest[start..]
+                    .find('>')
+                    .map_or(rest.len(), |end| start + end + 1);
+                (&rest[..start], &rest[start..end])
+            }
+            None => (rest, ""),
+        };
+        if let Some(cell) = &mut cell {
+            cell.push_str(text);
+        }
+        rest = &rest[text.len() + tag.len()..];
+        let name: String = tag
+            .trim_start_matches('<')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == '/')
+            .collect::<String>()
+            .to_ascii_lowercase();
+        match name.as_str() {
+            "tr" | "/tr" | "table" | "/table" => {
+                end_cell(&mut row, &mut cell);
+                if !row.is_empty() {
+                    rows.push(std::mem::take(&mut row));
+                }
+            }
+            "td" | "th" => {
+                end_cell(&mut row, &mut cell);
+                cell = Some(String::new());
+            }
+            "/td" | "/th" => end_cell(&mut row, &mut cell),
+            _ => {}
+        }
+    }
+    end_cell(&mut row, &mut cell);
+    if !row.is_empty() {
+        rows.push(row);
+    }
+    rows
+}
+
+impl Tasmota {
+    pub fn new(host: impl Into<String>, device_name: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            mac: None,
+            device_name: device_name.into(),
+            device_location: None,
+            locale: Locale::Auto,
+            rediscovered: None,
+            html_only: false,
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Raw response, the JSON status if the firmware supports it or the web UI status.
+    pub fn fetch(&self) -> anyhow::Result<String> {
+        let mut html_only = self.html_only;
+        match self.rediscovered {
+            Some(ip) => Self::request(&ip.to_string(), &mut html_only),
+            None => Self::request(&self.host, &mut html_only),
+        }
+    }
+
+    /// The `Status 8` JSON, falling back to the web UI status (for good, by setting `html_only`)
+    /// if the device answers without energy readings, e.g. if the web UI is password protected.
+    fn request(host: &str, html_only: &mut bool) -> anyhow::Result<String> {
+        let host = http::url_host(host);
+        if !*html_only {
+            match eyeballs::agent()
+                .get(&format!("http://{host}/cm?cmnd=Status%208"))
+                .call()
+            {
+                Ok(response) => {
+                    let json = response.into_string()?;
+                    if json.contains("\"ENERGY\"") {
+                        return Ok(json);
+                    }
+                }
+                Err(ureq::Error::Status(..)) => {}
+                Err(err) => return Err(err.into()),
+            }
+            *html_only = true;
+        }
+        Ok(eyeballs::agent()
+            .get(&format!("http://{}/?m=1", host))
             .call()?
-            .into_string()?;
-        self.parse_html(&html)
+            .into_string()?)
     }
 
-    fn parse_html(&self, html: &str) -> anyhow::Result<PublishData> {
-        lazy_static::lazy_static! {
-            static ref R_CURRENT_POWER : Regex = Regex::new("Active Power[^>]*>[^>]*>([^<]*)").unwrap();
-            static ref R_YIELD_TODAY : Regex = Regex::new("Energy Today[^>]*>[^>]*>([^<]*)").unwrap();
-            static ref R_TOTAL_YIELD : Regex = Regex::new("Energy Total[^>]*>[^>]*>([^<]*)").unwrap();
+    /// Readings from a response of [`Tasmota::fetch`].
+    pub(crate) fn parse(&self, response: &str) -> anyhow::Result<PublishData> {
+        if response.trim_start().starts_with('{') {
+            self.parse_json(response)
+        } else {
+            self.parse_html(response)
         }
-        let current_power = R_CURRENT_POWER
-            .captures(html)
-            .with_context(|| "Could not parse current power")?[1]
-            .to_string()
-            .parse::<f64>()?;
-        let yield_today = R_YIELD_TODAY
-            .captures(html)
-            .with_context(|| "Could not parse yield today")?[1]
-            .to_string()
-            .parse::<f64>()?;
-        let total_yield = R_TOTAL_YIELD
-            .captures(html)
-            .with_context(|| "Could not parse total yield")?[1]
-            .to_string()
-            .parse::<f64>()?;
+    }
+
+    fn publisher(&self) -> PublishData {
         let mut publisher = PublishData::default();
         publisher.tag("deviceName", self.device_name.clone());
         if let Some(device_location) = &self.device_location {
             publisher.tag("deviceLocation", device_location.clone());
         }
-        publisher.field("currentPower", current_power);
-        publisher.field("yieldToday", yield_today);
-        publisher.field("totalYield", total_yield);
+        publisher
+    }
+
+    fn parse_json(&self, json: &str) -> anyhow::Result<PublishData> {
+        let status: serde_json::Value =
+            serde_json::from_str(json).context("Invalid status JSON")?;
+        let energy = &status["StatusSNS"]["ENERGY"];
+        let mut publisher = self.publisher();
+        for (name, _, key, _) in FIELDS {
+            // Devices with several channels report an array, one value per channel, published as
+            // channel values besides their sum
+            let value = match &energy[key] {
+                serde_json::Value::Array(values) => {
+                    let values: Option<Vec<f64>> = values.iter().map(|v| v.as_f64()).collect();
+                    if let Some(values) = &values {
+                        if !publisher.has_channels() {
+                            publisher
+                                .channels("channel", (1..=values.len()).map(|i| i.to_string()));
+                        }
+                        publisher.channel_field(name, values.iter().copied())?;
+                    }
+                    values.map(|values| values.iter().sum())
+                }
+                value => value.as_f64(),
+            };
+            match value {
+                Some(value) => publisher.field(name, value),
+                None => publisher.missing(name),
+            }
+        }
+        Ok(publisher)
+    }
+
+    /// Finds the rows by their label, and takes the first number behind it. The regular
+    /// expressions of earlier versions are only tried for labels not found in the table.
+    pub(crate) fn parse_html(&self, html: &str) -> anyhow::Result<PublishData> {
+        lazy_static::lazy_static! {
+            static ref R_CURRENT_POWER : Regex = Regex::new("Active Power[^>]*>[^>]*>([^<]*)").unwrap();
+            static ref R_YIELD_TODAY : Regex = Regex::new("Energy Today[^>]*>[^>]*>([^<]*)").unwrap();
+            static ref R_TOTAL_YIELD : Regex = Regex::new("Energy Total[^>]*>[^>]*>([^<]*)").unwrap();
+        }
+        let rows = table_rows(html);
+        let mut publisher = self.publisher();
+        for ((name, unit, _, labels), regex) in
+            FIELDS
+                .into_iter()
+                .zip([&*R_CURRENT_POWER, &*R_YIELD_TODAY, &*R_TOTAL_YIELD])
+        {
+            let cell = labels
+                .iter()
+                .find_map(|label| {
+                    let row = rows.iter().find(|row| normalize(&row[0]) == *label)?;
+                    // Units may be in a cell of their own, or follow the number
+                    row[1..].iter().find(|cell| {
+                        cell.starts_with(|c: char| c.is_ascii_digit() || c == '-')
+                            && number::parse_quantity(cell, self.locale, unit).is_ok()
+                    })
+                })
+                .map(String::as_str)
+                .or_else(|| Some(regex.captures(html)?.get(1)?.as_str()));
+            match cell {
+                Some(cell) => publisher.field(
+                    name,
+                    number::parse_quantity(cell, self.locale, unit)
+                        .with_context(|| format!("Could not parse {name}"))?,
+                ),
+                None => publisher.missing(name),
+            }
+        }
         Ok(publisher)
     }
 }
@@ -69,7 +362,11 @@ mod tests {
         let status_data = Tasmota {
             device_location: Some("location".to_string()),
             device_name: "name".to_string(),
-            ip: [127, 0, 0, 1].into(),
+            host: "127.0.0.1".to_string(),
+            mac: None,
+            locale: Locale::Auto,
+            rediscovered: None,
+            html_only: false,
         }
         .parse_html(data)
         .unwrap();
@@ -77,4 +374,37 @@ mod tests {
         assert_eq!(status_data["yieldToday"], Value::F64(0.289));
         assert_eq!(status_data["totalYield"], Value::F64(0.291));
     }
+
+    #[test]
+    fn test_localized() {
+        let tasmota = Tasmota {
+            locale: Locale::DecimalComma,
+            ..Tasmota::new("127.0.0.1", "name")
+        };
+        let german = "<table><tr><th>Spannung</th><td>234 V</td></tr>\
+            <tr><th>Wirkleistung</th><td style='text-align:left'>1.344</td><td>&nbsp;</td><td>W</td></tr>\
+            <tr><th>Energie heute</th><td>0,289 kWh</td></tr></table>";
+        let data = tasmota.parse(german).unwrap();
+        assert_eq!(data["currentPower"], Value::F64(1344.0));
+        assert_eq!(data["yieldToday"], Value::F64(0.289));
+        assert_eq!(data.number("totalYield"), None);
+        assert_eq!(
+            normalize("Énergie aujourd'hui"),
+            FIELDS[1].3[2],
+            "accents and punctuation are ignored"
+        );
+        let json = r#"{"StatusSNS":{"Time":"2023-04-01T12:00:00","ENERGY":{"Total":0.291,
+            "Yesterday":0.002,"Today":0.289,"Power":[300,44],"Voltage":234}}}"#;
+        let mut data = tasmota.parse(json).unwrap();
+        assert_eq!(data["currentPower"], Value::F64(344.0));
+        assert_eq!(data["yieldToday"], Value::F64(0.289));
+        assert_eq!(data["totalYield"], Value::F64(0.291));
+        data.flatten_channels();
+        assert_eq!(data["currentPower1"], Value::F64(300.0));
+        assert_eq!(data["currentPower2"], Value::F64(44.0));
+        assert_eq!(
+            data.tag_value("deviceName"),
+            Some(&Value::from("name".to_string()))
+        );
+    }
 }
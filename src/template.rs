@@ -0,0 +1,69 @@
+//! Names templated from the tags and fields of a reading, e.g. `solar_{deviceLocation}`.
+use crate::{PublishData, Value};
+use anyhow::{bail, Context};
+
+/// Replaces each `{name}` with the value of the tag or field `name`. Snake case names like
+/// `{device_location}` refer to the camel case tag (`deviceLocation`).
+pub fn render(template: &str, data: &PublishData) -> anyhow::Result<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("Unclosed placeholder in '{template}'"))?;
+        let name = &rest[start + 1..start + end];
+        let value = lookup(data, name)
+            .or_else(|| lookup(data, &camel_case(name)))
+            .with_context(|| format!("No tag or field '{name}' for '{template}'"))?;
+        match value {
+            Value::String(s) => result.push_str(s),
+            Value::F64(f) => result.push_str(&f.to_string()),
+            Value::I64(i) => result.push_str(&i.to_string()),
+            Value::Bool(b) => result.push_str(&b.to_string()),
+            Value::Timestamp(_) => bail!("Timestamp '{name}' can't be used in '{template}'"),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn lookup<'a>(data: &'a PublishData, name: &str) -> Option<&'a Value> {
+    data.fields
+        .iter()
+        .find(|f| f.name() == name)
+        .map(|f| f.value())
+}
+
+fn camel_case(name: &str) -> String {
+    let mut parts = name.split('_');
+    let mut result = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            result.extend(first.to_uppercase());
+            result.push_str(chars.as_str());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut data = PublishData::default();
+        data.tag("deviceLocation", "roof".to_string());
+        data.field("phases", 3_i64);
+        assert_eq!(render("solar", &data).unwrap(), "solar");
+        assert_eq!(
+            render("solar_{device_location}_{phases}", &data).unwrap(),
+            "solar_roof_3"
+        );
+        assert!(render("solar_{device_name}", &data).is_err());
+        assert!(render("solar_{deviceLocation", &data).is_err());
+    }
+}
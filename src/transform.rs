@@ -0,0 +1,182 @@
+//! Processing of readings applied per source, before they are published.
+use crate::expr::Expr;
+use crate::quality::Quality;
+use crate::{PublishData, Value};
+use std::collections::BTreeMap;
+
+/// Linear correction of a reading: `value * scale + offset`.
+#[derive(
+    serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone, Copy,
+)]
+pub struct Calibration {
+    #[serde(default = "Calibration::default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+}
+
+impl Calibration {
+    fn default_scale() -> f64 {
+        1.0
+    }
+
+    pub fn apply(&self, value: f64) -> f64 {
+        value * self.scale + self.offset
+    }
+
+    /// Calibrates an integer, `None` unless scale and offset are whole numbers and the result fits.
+    fn apply_integer(&self, value: i64) -> Option<i64> {
+        let whole = |f: f64| (f.fract() == 0.0 && f.abs() < i64::MAX as f64).then_some(f as i64);
+        value
+            .checked_mul(whole(self.scale)?)?
+            .checked_add(whole(self.offset)?)
+    }
+}
+
+/// Human-readable names of the numeric codes of a status or alarm field.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+pub struct Codes {
+    /// Tag to publish the name as, `<field>Text` by default
+    #[serde(default)]
+    pub tag: Option<String>,
+    pub values: BTreeMap<String, String>,
+    /// Name of codes not in `values`, the code itself by default
+    #[serde(default)]
+    pub unknown: Option<String>,
+}
+
+impl Codes {
+    fn name(&self, value: &Value) -> String {
+        let code = match value {
+            Value::F64(f) if f.fract() == 0.0 => format!("{f:.0}"),
+            Value::F64(f) => f.to_string(),
+            Value::I64(i) => i.to_string(),
+            Value::String(s) => s.clone(),
+            Value::Bool(b) => b.to_string(),
+            Value::Timestamp(_) => String::new(),
+        };
+        match self.values.get(&code) {
+            Some(name) => name.clone(),
+            None => self.unknown.clone().unwrap_or(code),
+        }
+    }
+}
+
+/// Adds the name of the code of each configured field as a tag. The raw code is kept as a field.
+pub fn map_codes(data: &mut PublishData, codes: &BTreeMap<String, Codes>) {
+    for (name, codes) in codes {
+        let Some(value) = data.field_value(name) else {
+            continue;
+        };
+        let text = codes.name(value);
+        let tag = codes.tag.clone().unwrap_or_else(|| format!("{name}Text"));
+        data.set_tag(tag, text);
+    }
+}
+
+/// Applies the calibration of each configured field. Non-numeric fields are left untouched,
+/// integers stay integers if scale and offset are whole numbers.
+pub fn calibrate(data: &mut PublishData, calibration: &BTreeMap<String, Calibration>) {
+    for (name, calibration) in calibration {
+        let Some(value) = data.field_mut(name) else {
+            continue;
+        };
+        let Some(f) = value.as_f64() else {
+            continue;
+        };
+        *value = match value {
+            Value::I64(i) => calibration
+                .apply_integer(*i)
+                .map_or_else(|| Value::F64(calibration.apply(f)), Value::I64),
+            _ => Value::F64(calibration.apply(f)),
+        };
+        data.flag(Quality::Calibrated);
+    }
+}
+
+/// Rounds the configured fields to the given number of decimals.
+pub fn round(data: &mut PublishData, precision: &BTreeMap<String, u8>) {
+    for (name, decimals) in precision {
+        if let Some(Value::F64(value)) = data.field_mut(name) {
+            let factor = 10f64.powi(*decimals as i32);
+            *value = (*value * factor).round() / factor;
+        }
+    }
+}
+
+/// Adds the computed fields. Expressions can only refer to fields read from the device, fields
+/// that can't be computed (e.g. because a referenced field is missing) are skipped.
+pub fn derive(data: &mut PublishData, derived: &BTreeMap<String, Expr>, source_id: &str) {
+    let computed: Vec<_> = derived
+        .iter()
+        .filter_map(
+            |(name, expr)| match expr.eval(&|field| data.number(field)) {
+                Ok(value) => Some((name.clone(), value)),
+                Err(err) => {
+                    tracing::warn!("Failed to compute '{name}' of '{source_id}': {err}");
+                    None
+                }
+            },
+        )
+        .collect();
+    for (name, value) in computed {
+        data.field(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibrate() {
+        let mut data = PublishData::default();
+        data.field("currentPower", 104.0);
+        data.field("totalYield", 10_i64);
+        data.field("runtime", 7_i64);
+        data.field("status", "ok".to_string());
+        let calibration: BTreeMap<String, Calibration> = serde_json::from_str(
+            r#"{"currentPower": {"scale": 0.5}, "totalYield": {"offset": -2}, "runtime": {"scale": 0.5},
+                "status": {"scale": 2}}"#,
+        )
+        .unwrap();
+        calibrate(&mut data, &calibration);
+        assert_eq!(data["currentPower"], Value::F64(52.0));
+        assert_eq!(data["totalYield"], Value::I64(8));
+        assert_eq!(data["runtime"], Value::F64(3.5));
+        assert_eq!(data["status"], Value::String("ok".to_string()));
+    }
+
+    #[test]
+    fn test_round() {
+        let mut data = PublishData::default();
+        data.field("yieldToday", 0.28900000000000003);
+        data.field("currentPower", 344.56);
+        let precision = [("yieldToday", 3), ("currentPower", 0)]
+            .into_iter()
+            .map(|(name, decimals)| (name.to_string(), decimals))
+            .collect();
+        round(&mut data, &precision);
+        assert_eq!(data["yieldToday"], Value::F64(0.289));
+        assert_eq!(data["currentPower"], Value::F64(345.0));
+    }
+
+    #[test]
+    fn test_map_codes() {
+        let mut data = PublishData::default();
+        data.field("alarm", 17.0);
+        data.field("status", 3_i64);
+        let codes: BTreeMap<String, Codes> = serde_json::from_str(
+            r#"{"alarm": {"tag": "alarmText", "values": {"17": "Grid overvoltage"}},
+                "status": {"values": {"1": "Running"}, "unknown": "Unknown"}}"#,
+        )
+        .unwrap();
+        map_codes(&mut data, &codes);
+        assert_eq!(
+            data["alarmText"],
+            Value::String("Grid overvoltage".to_string())
+        );
+        assert_eq!(data["alarm"], Value::F64(17.0));
+        assert_eq!(data["statusText"], Value::String("Unknown".to_string()));
+    }
+}
@@ -0,0 +1,292 @@
+//! Rejection of implausible readings, like the occasional 65535 W reported by a SUN600.
+use crate::quality::Quality;
+use crate::{Field, PublishData, Value};
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// What to do with a value failing a check.
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    Default,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Action {
+    /// Remove the field from the reading
+    #[default]
+    Drop,
+    /// Replace the value with the nearest plausible one
+    Clamp,
+}
+
+/// What to do with NaN and infinite values, which InfluxDB rejects.
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    Default,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum NonFinite {
+    /// Remove the field from the reading
+    #[default]
+    DropField,
+    /// Skip the whole reading
+    DropPoint,
+    /// Replace the value with the last finite one, or drop the field if there is none
+    Last,
+}
+
+/// Last finite value of each field, and the number of non-finite values.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+pub struct NonFiniteState {
+    last: BTreeMap<String, f64>,
+    occurrences: u64,
+}
+
+/// Handles non-finite values according to `policy`, with a warning counting the occurrences.
+/// Returns `false` if the reading should be dropped.
+pub fn sanitize(
+    data: &mut PublishData,
+    policy: NonFinite,
+    state: &mut NonFiniteState,
+    source_id: &str,
+) -> bool {
+    let mut keep = true;
+    let mut stale = false;
+    data.fields.retain_mut(|f| {
+        let Field::Field(name, Value::F64(value)) = f else {
+            return true;
+        };
+        if value.is_finite() {
+            state.last.insert(name.clone(), *value);
+            return true;
+        }
+        state.occurrences += 1;
+        tracing::warn!(
+            "'{source_id}' reported {name}={value} ({} non-finite values so far)",
+            state.occurrences
+        );
+        match (policy, state.last.get(name.as_str())) {
+            (NonFinite::DropPoint, _) => {
+                keep = false;
+                true
+            }
+            (NonFinite::Last, Some(last)) => {
+                *value = *last;
+                stale = true;
+                true
+            }
+            _ => false,
+        }
+    });
+    if stale {
+        data.flag(Quality::Stale);
+    }
+    keep
+}
+
+/// Range of valid values of a single field.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+pub struct Range {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    #[serde(default)]
+    pub action: Action,
+}
+
+/// Number of out of range values per field.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+pub struct RangeState {
+    violations: BTreeMap<String, u64>,
+}
+
+/// Rejects (or clamps) values outside of their configured range, with a warning counting the
+/// violations of each field.
+pub fn check_ranges(
+    data: &mut PublishData,
+    ranges: &BTreeMap<String, Range>,
+    state: &mut RangeState,
+    source_id: &str,
+) {
+    for (name, range) in ranges {
+        let Some(value) = data.number(name) else {
+            continue;
+        };
+        let valid = value
+            .max(range.min.unwrap_or(f64::NEG_INFINITY))
+            .min(range.max.unwrap_or(f64::INFINITY));
+        if valid == value {
+            continue;
+        }
+        let count = state.violations.entry(name.clone()).or_default();
+        *count += 1;
+        tracing::warn!(
+            "'{source_id}' reported {name}={value}, outside of its valid range ({count} times so far)"
+        );
+        match range.action {
+            Action::Drop => data
+                .fields
+                .retain(|f| !matches!(f, Field::Field(n, _) if n == name)),
+            Action::Clamp => {
+                if let Some(value) = data.field_mut(name) {
+                    *value = Value::F64(valid);
+                }
+                data.flag(Quality::Clamped);
+            }
+        }
+    }
+}
+
+/// Plausibility checks of a single field.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Plausibility {
+    /// Maximum absolute value
+    pub max_abs: Option<f64>,
+    /// Maximum change per second compared to the last plausible value
+    pub max_delta_per_second: Option<f64>,
+    #[serde(default)]
+    pub action: Action,
+}
+
+/// Last plausible value of each field.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+pub struct PlausibilityState {
+    last: BTreeMap<String, (f64, SystemTime)>,
+}
+
+/// Checks all configured fields. Returns the names of the fields which were dropped or clamped.
+pub fn check_plausibility(
+    data: &mut PublishData,
+    checks: &BTreeMap<String, Plausibility>,
+    state: &mut PlausibilityState,
+    now: SystemTime,
+) -> Vec<String> {
+    let mut implausible = vec![];
+    for (name, check) in checks {
+        let Some(value) = data.number(name) else {
+            continue;
+        };
+        let mut plausible = value;
+        if let Some(max_abs) = check.max_abs {
+            plausible = plausible.clamp(-max_abs, max_abs);
+        }
+        if let (Some(max_delta), Some((last, at))) =
+            (check.max_delta_per_second, state.last.get(name))
+        {
+            let max_delta = max_delta * now.duration_since(*at).unwrap_or_default().as_secs_f64();
+            plausible = plausible.clamp(last - max_delta, last + max_delta);
+        }
+        if plausible != value {
+            implausible.push(name.clone());
+            match check.action {
+                Action::Drop => {
+                    data.fields
+                        .retain(|f| !matches!(f, Field::Field(n, _) if n == name));
+                    continue;
+                }
+                Action::Clamp => {
+                    if let Some(value) = data.field_mut(name) {
+                        *value = Value::F64(plausible);
+                    }
+                    data.flag(Quality::Clamped);
+                }
+            }
+        }
+        state.last.insert(name.clone(), (plausible, now));
+    }
+    implausible
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_plausibility() {
+        let checks: BTreeMap<String, Plausibility> = serde_json::from_str(
+            r#"{
+                "currentPower": {"maxAbs": 1000, "maxDeltaPerSecond": 10},
+                "yieldToday": {"maxAbs": 10, "action": "clamp"}
+            }"#,
+        )
+        .unwrap();
+        let mut state = PlausibilityState::default();
+        let start = SystemTime::now();
+        let mut poll = |power: f64, today: f64, after: u64| {
+            let mut data = PublishData::default();
+            data.field("currentPower", power);
+            data.field("yieldToday", today);
+            let implausible = check_plausibility(
+                &mut data,
+                &checks,
+                &mut state,
+                start + Duration::from_secs(after),
+            );
+            (data, implausible)
+        };
+        let (data, implausible) = poll(65535.0, 1.0, 0);
+        assert_eq!(data.number("currentPower"), None);
+        assert_eq!(implausible, ["currentPower"]);
+        let (data, implausible) = poll(500.0, 1.0, 10);
+        assert_eq!(data.number("currentPower"), Some(500.0));
+        assert!(implausible.is_empty());
+        // Jumped by more than 10 W/s
+        let (data, implausible) = poll(800.0, 12.0, 20);
+        assert_eq!(data.number("currentPower"), None);
+        assert_eq!(data.number("yieldToday"), Some(10.0));
+        assert_eq!(data.number("implausibleFields"), None);
+        assert_eq!(implausible, ["currentPower", "yieldToday"]);
+        let (data, _) = poll(800.0, 1.0, 50);
+        assert_eq!(data.number("currentPower"), Some(800.0));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let mut state = NonFiniteState::default();
+        let mut data = PublishData::default();
+        data.field("currentPower", 300.0);
+        assert!(sanitize(&mut data, NonFinite::Last, &mut state, "test"));
+        let mut data = PublishData::default();
+        data.field("currentPower", f64::NAN);
+        data.field("yieldToday", f64::INFINITY);
+        assert!(sanitize(&mut data, NonFinite::Last, &mut state, "test"));
+        assert_eq!(data.number("currentPower"), Some(300.0));
+        assert_eq!(data.number("yieldToday"), None);
+        data.field("totalYield", f64::NAN);
+        assert!(!sanitize(
+            &mut data,
+            NonFinite::DropPoint,
+            &mut state,
+            "test"
+        ));
+        assert_eq!(state.occurrences, 3);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let ranges: BTreeMap<String, Range> = serde_json::from_str(
+            r#"{"currentPower": {"min": 0, "max": 800}, "yieldToday": {"min": 0, "action": "clamp"}}"#,
+        )
+        .unwrap();
+        let mut state = RangeState::default();
+        let mut data = PublishData::default();
+        data.field("currentPower", 1200.0);
+        data.field("yieldToday", -1.0);
+        check_ranges(&mut data, &ranges, &mut state, "inverter");
+        assert_eq!(data.number("currentPower"), None);
+        assert_eq!(data.number("yieldToday"), Some(0.0));
+        assert_eq!(state.violations["currentPower"], 1);
+    }
+}
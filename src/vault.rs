@@ -0,0 +1,247 @@
+//! Secrets in HashiCorp Vault or OpenBao (KV v2), referenced in the config as
+//! `vault:<mount>/<path>#<key>`, like `vault:secret/solar/influx#token`.
+//!
+//! The secrets are read with the config, which is loaded again every `refresh` and before the
+//! token expires: AppRole logs in again, a renewable token is renewed.
+use crate::eyeballs;
+use crate::tls::ClientTls;
+use anyhow::{bail, Context};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Vault {
+    /// Address like `https://vault:8200`, defaults to `VAULT_ADDR`
+    #[serde(default)]
+    pub address: Option<String>,
+    /// Token, defaults to `VAULT_TOKEN`
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Logs in with AppRole instead of using a token
+    #[serde(default)]
+    pub app_role: Option<AppRole>,
+    /// Namespace (Vault Enterprise, OpenBao), defaults to `VAULT_NAMESPACE`
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// How often the secrets are read again, to pick up rotated ones
+    #[serde(default = "Vault::default_refresh", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub refresh: Duration,
+    /// Client certificate and private CA, as for InfluxDB targets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<ClientTls>,
+}
+
+impl Vault {
+    fn default_refresh() -> Duration {
+        Duration::from_secs(3600)
+    }
+}
+
+impl Default for Vault {
+    fn default() -> Self {
+        Self {
+            address: None,
+            token: None,
+            app_role: None,
+            namespace: None,
+            refresh: Self::default_refresh(),
+            tls: None,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AppRole {
+    pub role_id: String,
+    pub secret_id: String,
+    /// Mount of the AppRole auth method
+    #[serde(default = "AppRole::default_mount")]
+    pub mount: String,
+}
+
+impl AppRole {
+    fn default_mount() -> String {
+        "approle".to_string()
+    }
+}
+
+/// A logged in client, caching the secrets read.
+pub struct Client {
+    address: String,
+    token: String,
+    namespace: Option<String>,
+    agent: ureq::Agent,
+    secrets: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
+    /// When the secrets have to be read again
+    refresh_at: SystemTime,
+}
+
+/// Seconds the token of an `auth` response is valid, `None` if it doesn't expire.
+fn lease(auth: &serde_json::Value) -> Option<Duration> {
+    Some(Duration::from_secs(auth["lease_duration"].as_u64()?)).filter(|d| !d.is_zero())
+}
+
+impl Client {
+    pub fn login(vault: &Vault) -> anyhow::Result<Client> {
+        let agent = match &vault.tls {
+            Some(tls) => tls.agent().clone(),
+            None => eyeballs::target_agent().clone(),
+        };
+        let address = vault
+            .address
+            .clone()
+            .or_else(|| std::env::var("VAULT_ADDR").ok())
+            .context("No Vault address given, set 'vault.address' or VAULT_ADDR")?;
+        let address = address.trim_end_matches('/').to_string();
+        let namespace = vault
+            .namespace
+            .clone()
+            .or_else(|| std::env::var("VAULT_NAMESPACE").ok());
+        let request = |method: &str, path: &str| {
+            let request = agent.request(method, &format!("{address}/v1/{path}"));
+            match &namespace {
+                Some(namespace) => request.set("X-Vault-Namespace", namespace),
+                None => request,
+            }
+        };
+        let (token, ttl) = match &vault.app_role {
+            Some(app_role) => {
+                let request = request("POST", &format!("auth/{}/login", app_role.mount));
+                let response: serde_json::Value = serde_json::from_str(
+                    &request
+                        .send_string(
+                            &serde_json::json!({
+                                "role_id": app_role.role_id,
+                                "secret_id": app_role.secret_id,
+                            })
+                            .to_string(),
+                        )
+                        .context("Failed to log in to Vault with AppRole")?
+                        .into_string()?,
+                )?;
+                let token = response["auth"]["client_token"]
+                    .as_str()
+                    .context("No token in the AppRole login response")?
+                    .to_string();
+                (token, lease(&response["auth"]))
+            }
+            None => {
+                let token = vault
+                    .token
+                    .clone()
+                    .or_else(|| std::env::var("VAULT_TOKEN").ok())
+                    .context(
+                        "No Vault token given, set 'vault.token', 'vault.appRole' or VAULT_TOKEN",
+                    )?;
+                // Extends a token which would expire, as far as its policy allows
+                let ttl = match request("POST", "auth/token/renew-self")
+                    .set("X-Vault-Token", &token)
+                    .send_string("{}")
+                {
+                    Ok(response) => {
+                        let response: serde_json::Value =
+                            serde_json::from_str(&response.into_string()?)?;
+                        lease(&response["auth"])
+                    }
+                    // Not renewable, like the root token
+                    Err(ureq::Error::Status(..)) => None,
+                    Err(err) => return Err(err).context("Failed to renew the Vault token"),
+                };
+                (token, ttl)
+            }
+        };
+        // Read again with two thirds of the lifetime of the token left at most
+        let refresh = ttl.map_or(vault.refresh, |ttl| vault.refresh.min(ttl * 2 / 3));
+        Ok(Client {
+            address,
+            token,
+            namespace,
+            agent,
+            secrets: BTreeMap::new(),
+            refresh_at: SystemTime::now() + refresh,
+        })
+    }
+
+    /// When the config has to be loaded again, to read the secrets with a fresh token.
+    pub fn refresh_at(&self) -> SystemTime {
+        self.refresh_at
+    }
+
+    /// Reads the key of a reference (without the `vault:` prefix).
+    pub fn get(&mut self, reference: &str) -> anyhow::Result<String> {
+        let (path, key) = reference
+            .rsplit_once('#')
+            .with_context(|| format!("Expected <mount>/<path>#<key>, got 'vault:{reference}'"))?;
+        let Some((mount, path)) = path.split_once('/') else {
+            bail!("Expected <mount>/<path>#<key>, got 'vault:{reference}'");
+        };
+        let secret = format!("{mount}/{path}");
+        if !self.secrets.contains_key(&secret) {
+            let mut request = self
+                .agent
+                .get(&format!("{}/v1/{mount}/data/{path}", self.address))
+                .set("X-Vault-Token", &self.token);
+            if let Some(namespace) = &self.namespace {
+                request = request.set("X-Vault-Namespace", namespace);
+            }
+            let response: serde_json::Value = serde_json::from_str(
+                &request
+                    .call()
+                    .with_context(|| format!("Failed to read 'vault:{mount}/{path}'"))?
+                    .into_string()?,
+            )?;
+            let serde_json::Value::Object(data) = response["data"]["data"].clone() else {
+                bail!("No KV v2 secret at 'vault:{mount}/{path}'");
+            };
+            self.secrets.insert(secret.clone(), data);
+        }
+        match self.secrets[&secret].get(key) {
+            Some(serde_json::Value::String(secret)) => Ok(secret.clone()),
+            Some(value) => Ok(value.to_string()),
+            None => bail!("No key '{key}' in 'vault:{mount}/{path}'"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{serve, Response};
+
+    #[test]
+    fn test_get() {
+        let addr = serve("127.0.0.1:0".parse().unwrap(), |request| {
+            match request.path.as_str() {
+                "/v1/auth/token/renew-self" => Response::new(
+                    "application/json",
+                    r#"{"auth": {"client_token": "root", "lease_duration": 60}}"#,
+                ),
+                "/v1/secret/data/solar/influx" => Response::new(
+                    "application/json",
+                    r#"{"data": {"data": {"token": "s3cret"}, "metadata": {"version": 2}}}"#,
+                ),
+                _ => Response::not_found(),
+            }
+        })
+        .unwrap();
+        let mut client = Client::login(&Vault {
+            address: Some(format!("http://{addr}")),
+            token: Some("root".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        // Before the renewed token expires
+        let refresh_in = client
+            .refresh_at()
+            .duration_since(SystemTime::now())
+            .unwrap();
+        assert!(refresh_in <= Duration::from_secs(40));
+        assert_eq!(client.get("secret/solar/influx#token").unwrap(), "s3cret");
+        assert!(client.get("secret/solar/influx#user").is_err());
+        assert!(client.get("secret/missing#token").is_err());
+        assert!(client.get("secret#token").is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! Devices computed from the readings of other sources in the same cycle, e.g. the total
+//! production of several inverters.
+use crate::expr::Expr;
+use crate::{Field, PublishData};
+use anyhow::{bail, Context};
+use std::collections::BTreeMap;
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+pub struct VirtualDevice {
+    pub device_name: String,
+    /// Names of the sources to aggregate
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// How to combine each field of the sources
+    #[serde(default)]
+    pub aggregate: BTreeMap<String, Aggregation>,
+    /// Self-consumption and autarky from a PV source and a grid meter
+    #[serde(default, rename = "selfConsumption")]
+    pub self_consumption: Option<SelfConsumption>,
+    /// Computed fields; `source.field` refers to a field of any source, plain names to the
+    /// aggregated fields
+    #[serde(default)]
+    pub derived: BTreeMap<String, Expr>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Inputs for computing self-consumption, as expressions like `inverter.currentPower`.
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfConsumption {
+    /// PV production
+    pub production: Expr,
+    /// Power drawn from the grid, negative when exporting
+    pub grid: Expr,
+    /// The grid meter reports exported power as positive values instead
+    #[serde(default)]
+    pub export_positive: bool,
+}
+
+impl SelfConsumption {
+    /// Adds `production`, `gridImport`, `gridExport`, `consumption`, `selfConsumption` and,
+    /// if defined, the ratios `selfConsumptionRate` and `autarky`.
+    fn apply(
+        &self,
+        data: &mut PublishData,
+        lookup: &dyn Fn(&str) -> Option<f64>,
+    ) -> anyhow::Result<()> {
+        let production = self.production.eval(lookup)?.max(0.0);
+        let mut grid = self.grid.eval(lookup)?;
+        if self.export_positive {
+            grid = -grid;
+        }
+        let export = (-grid).max(0.0);
+        let self_consumption = (production - export).max(0.0);
+        let consumption = (production + grid).max(0.0);
+        data.field("production", production);
+        data.field("gridImport", grid.max(0.0));
+        data.field("gridExport", export);
+        data.field("consumption", consumption);
+        data.field("selfConsumption", self_consumption);
+        if production > 0.0 {
+            data.field("selfConsumptionRate", self_consumption / production);
+        }
+        if consumption > 0.0 {
+            data.field("autarky", self_consumption / consumption);
+        }
+        Ok(())
+    }
+}
+
+#[derive(
+    serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone, Copy,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Aggregation {
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl VirtualDevice {
+    /// Computes the reading from the `readings` of this cycle (by source id). Fails if any of the
+    /// aggregated sources has no reading.
+    pub fn compute(&self, readings: &[(String, PublishData)]) -> anyhow::Result<PublishData> {
+        let inputs = self
+            .sources
+            .iter()
+            .map(|name| {
+                readings
+                    .iter()
+                    .find(|(id, _)| id == name)
+                    .map(|(_, data)| data)
+                    .with_context(|| format!("No reading from '{name}'"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut data = PublishData::default();
+        data.tag("deviceName", self.device_name.clone());
+        for (name, value) in &self.tags {
+            data.set_tag(name, value.clone());
+        }
+        for (name, aggregation) in &self.aggregate {
+            let values: Vec<_> = inputs.iter().filter_map(|d| d.number(name)).collect();
+            if values.is_empty() {
+                continue;
+            }
+            let value = match aggregation {
+                Aggregation::Sum => values.iter().sum(),
+                Aggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+                Aggregation::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+                Aggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            };
+            data.field(name, value);
+        }
+        let source_field = |name: &str| {
+            readings.iter().find_map(|(id, reading)| {
+                let field = name.strip_prefix(id.as_str())?.strip_prefix('.')?;
+                reading.number(field)
+            })
+        };
+        if let Some(self_consumption) = &self.self_consumption {
+            self_consumption
+                .apply(&mut data, &source_field)
+                .with_context(|| "Failed to compute self-consumption")?;
+        }
+        let lookup = |name: &str| data.number(name).or_else(|| source_field(name));
+        let derived = self
+            .derived
+            .iter()
+            .map(|(name, expr)| {
+                expr.eval(&lookup)
+                    .map(|value| Field::Field(name.clone(), value.into()))
+                    .with_context(|| format!("Failed to compute '{name}'"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        data.fields.extend(derived);
+        if !data.has_fields() {
+            bail!("None of the aggregated fields were found");
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    #[test]
+    fn test_compute() {
+        let device: VirtualDevice = serde_json::from_str(
+            r#"{
+                "device_name": "balcony",
+                "sources": ["inverter A", "inverter B"],
+                "aggregate": {"currentPower": "sum", "temperature": "avg"},
+                "derived": {"shareA": "`inverter A`.currentPower / currentPower"}
+            }"#,
+        )
+        .unwrap();
+        let reading = |power: f64, temperature: f64| {
+            let mut data = PublishData::default();
+            data.field("currentPower", power);
+            data.field("temperature", temperature);
+            data
+        };
+        let mut readings = vec![
+            ("inverter A".to_string(), reading(300.0, 40.0)),
+            ("inverter B".to_string(), reading(100.0, 30.0)),
+        ];
+        let data = device.compute(&readings).unwrap();
+        assert_eq!(data["deviceName"], Value::String("balcony".to_string()));
+        assert_eq!(data.number("currentPower"), Some(400.0));
+        assert_eq!(data.number("temperature"), Some(35.0));
+        assert_eq!(data.number("shareA"), Some(0.75));
+
+        readings.pop();
+        assert!(device.compute(&readings).is_err());
+    }
+
+    #[test]
+    fn test_self_consumption() {
+        let device: VirtualDevice = serde_json::from_str(
+            r#"{
+                "device_name": "home",
+                "selfConsumption": {"production": "inverter.currentPower", "grid": "meter.power"}
+            }"#,
+        )
+        .unwrap();
+        let reading = |name: &str, value: f64| {
+            let mut data = PublishData::default();
+            data.field(name, value);
+            data
+        };
+        let mut readings = vec![
+            ("inverter".to_string(), reading("currentPower", 600.0)),
+            ("meter".to_string(), reading("power", -200.0)),
+        ];
+        let data = device.compute(&readings).unwrap();
+        assert_eq!(data.number("gridExport"), Some(200.0));
+        assert_eq!(data.number("consumption"), Some(400.0));
+        assert_eq!(data.number("selfConsumption"), Some(400.0));
+        assert_eq!(data.number("autarky"), Some(1.0));
+
+        readings[1].1 = reading("power", 400.0);
+        let data = device.compute(&readings).unwrap();
+        assert_eq!(data.number("consumption"), Some(1000.0));
+        assert_eq!(data.number("selfConsumptionRate"), Some(1.0));
+        assert_eq!(data.number("autarky"), Some(0.6));
+    }
+}
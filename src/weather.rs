@@ -0,0 +1,226 @@
+//! Weather and irradiance from [open-meteo](https://open-meteo.com/), for comparing the production
+//! against the expected yield. The global weather is published as a point of its own every cycle,
+//! the one of a source is added to its readings.
+use crate::backoff::{Backoff, BackoffState};
+use crate::PublishData;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+/// Delay after the first failed fetch, doubled up to `refresh`.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq, Clone)]
+pub struct Weather {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// open-meteo variables, and the fields they are published as
+    #[serde(default = "Weather::default_variables")]
+    pub variables: BTreeMap<String, String>,
+    /// How long fetched values are used
+    #[serde(default = "Weather::default_refresh", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub refresh: Duration,
+    #[serde(default = "Weather::default_url")]
+    pub url: String,
+    #[serde(default = "Weather::default_timeout", with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub timeout: Duration,
+    /// Device name of the points of the global weather
+    #[serde(default = "Weather::default_device_name")]
+    pub device_name: String,
+    /// Tags of the points of the global weather
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Last fetched weather, and the failures fetching it since.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct WeatherState {
+    fetched: Option<(BTreeMap<String, f64>, SystemTime)>,
+    backoff: BackoffState,
+}
+
+impl Weather {
+    fn default_variables() -> BTreeMap<String, String> {
+        [
+            ("temperature_2m", "outdoorTemperature"),
+            ("cloud_cover", "cloudCover"),
+            ("shortwave_radiation", "irradiance"),
+        ]
+        .into_iter()
+        .map(|(variable, field)| (variable.to_string(), field.to_string()))
+        .collect()
+    }
+
+    fn default_refresh() -> Duration {
+        Duration::from_secs(15 * 60)
+    }
+
+    fn default_url() -> String {
+        "https://api.open-meteo.com/v1/forecast".to_string()
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn default_device_name() -> String {
+        "weather".to_string()
+    }
+
+    /// Current values of the configured variables.
+    fn fetch(&self) -> anyhow::Result<BTreeMap<String, f64>> {
+        let variables = self
+            .variables
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(",");
+        let body = ureq::get(&self.url)
+            .query("latitude", &self.latitude.to_string())
+            .query("longitude", &self.longitude.to_string())
+            .query("current", &variables)
+            .timeout(self.timeout)
+            .call()?
+            .into_string()?;
+        Self::parse(&body)
+    }
+
+    fn parse(body: &str) -> anyhow::Result<BTreeMap<String, f64>> {
+        #[derive(serde::Deserialize)]
+        struct Response {
+            current: BTreeMap<String, serde_json::Value>,
+        }
+        let response: Response = serde_json::from_str(body)?;
+        Ok(response
+            .current
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value.as_f64()?)))
+            .collect())
+    }
+
+    /// The current values, fetched if the last ones are older than `refresh`. `None` while
+    /// backing off after failed fetches.
+    fn current<'a>(
+        &self,
+        state: &'a mut WeatherState,
+        now: SystemTime,
+    ) -> anyhow::Result<Option<&'a BTreeMap<String, f64>>> {
+        let fresh = state
+            .fetched
+            .as_ref()
+            .is_some_and(|(_, at)| now.duration_since(*at).unwrap_or_default() < self.refresh);
+        if !fresh {
+            if !state.backoff.is_due(now) {
+                return Ok(None);
+            }
+            let result = self.fetch();
+            self.backoff()
+                .polled(&mut state.backoff, result.is_ok(), now);
+            state.fetched = Some((result.context("Failed to fetch weather")?, now));
+        }
+        Ok(state.fetched.as_ref().map(|(values, _)| values))
+    }
+
+    /// Retries failed fetches with a growing delay, up to `refresh`.
+    fn backoff(&self) -> Backoff {
+        Backoff {
+            initial: RETRY_DELAY.min(self.refresh),
+            max: self.refresh,
+            quarantine_after: u64::MAX,
+            probe_interval: self.refresh,
+        }
+    }
+
+    fn add_fields(&self, data: &mut PublishData, values: &BTreeMap<String, f64>) {
+        for (variable, field) in &self.variables {
+            if let Some(value) = values.get(variable) {
+                data.field(field, *value);
+            }
+        }
+    }
+
+    /// Adds the current weather as fields of a reading.
+    pub fn apply(
+        &self,
+        data: &mut PublishData,
+        state: &mut WeatherState,
+        now: SystemTime,
+    ) -> anyhow::Result<()> {
+        if let Some(values) = self.current(state, now)? {
+            self.add_fields(data, values);
+        }
+        Ok(())
+    }
+
+    /// The current weather as a point of its own, tagged with `device_name` and `tags`.
+    pub fn point(
+        &self,
+        state: &mut WeatherState,
+        now: SystemTime,
+    ) -> anyhow::Result<Option<PublishData>> {
+        let Some(values) = self.current(state, now)? else {
+            return Ok(None);
+        };
+        let mut data = PublishData::default();
+        data.tag("deviceName", self.device_name.clone());
+        for (name, value) in &self.tags {
+            data.set_tag(name, value.clone());
+        }
+        self.add_fields(&mut data, values);
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_weather() {
+        let weather: Weather = serde_json::from_str(
+            r#"{"latitude": 52.5, "longitude": 13.4, "url": "http://invalid"}"#,
+        )
+        .unwrap();
+        let now = SystemTime::now();
+        let values = Weather::parse(
+            r#"{"current": {"time": "2024-06-01T12:00", "interval": 900, "temperature_2m": 21.5,
+                "cloud_cover": 40, "shortwave_radiation": 612.0}}"#,
+        )
+        .unwrap();
+        let mut state = WeatherState {
+            fetched: Some((values, now - Duration::from_secs(60))),
+            ..Default::default()
+        };
+        let mut data = PublishData::default();
+        weather.apply(&mut data, &mut state, now).unwrap();
+        assert_eq!(data.number("outdoorTemperature"), Some(21.5));
+        assert_eq!(data.number("cloudCover"), Some(40.0));
+        assert_eq!(data.number("irradiance"), Some(612.0));
+        let data = weather.point(&mut state, now).unwrap().unwrap();
+        assert_eq!(
+            data["deviceName"],
+            crate::Value::String("weather".to_string())
+        );
+        assert_eq!(data.number("irradiance"), Some(612.0));
+    }
+
+    #[test]
+    fn test_fetch_backoff() {
+        let weather: Weather = serde_json::from_str(
+            r#"{"latitude": 52.5, "longitude": 13.4, "url": "http://127.0.0.1:1/v1/forecast"}"#,
+        )
+        .unwrap();
+        let mut state = WeatherState::default();
+        let now = SystemTime::now();
+        assert!(weather.point(&mut state, now).is_err());
+        // Not fetched again until the delay passed
+        let later = now + Duration::from_secs(30);
+        assert!(weather.point(&mut state, later).unwrap().is_none());
+        assert_eq!(state.backoff.retry_at, Some(now + RETRY_DELAY));
+        assert!(weather.point(&mut state, now + RETRY_DELAY).is_err());
+        assert_eq!(state.backoff.failures, 2);
+    }
+}
@@ -0,0 +1,132 @@
+//! Downsampling of readings: polling fast, but publishing the min/avg/max over a window.
+use crate::{Field, PublishData, Value};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+pub struct Window {
+    /// Length of the windows, aligned to multiples of it since the epoch
+    #[serde(with = "crate::duration")]
+    #[schemars(with = "crate::duration::Schema")]
+    pub duration: Duration,
+    /// Fields to aggregate, all numeric fields if empty. Other fields keep their last value.
+    #[serde(default)]
+    pub fields: Vec<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct Stats {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u32,
+}
+
+/// Readings of the current window.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+pub struct WindowState {
+    window: Option<u128>,
+    stats: BTreeMap<String, Stats>,
+    last: Vec<Field>,
+}
+
+impl Window {
+    fn index(&self, time: SystemTime) -> u128 {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        since.as_millis() / self.duration.as_millis().max(1)
+    }
+
+    /// Adds the reading to the current window. Once a new window starts, the reading is replaced
+    /// with the aggregate of the previous window, publishing the average of each field along with
+    /// `<field>Min` and `<field>Max`, timestamped with the start of the window. Otherwise nothing is
+    /// published.
+    pub fn apply(&self, data: &mut PublishData, state: &mut WindowState, now: SystemTime) {
+        let index = self.index(now);
+        let finished = match state.window {
+            Some(window) if window != index => Some((window, self.aggregate(state))),
+            _ => None,
+        };
+        state.window = Some(index);
+        let mut last = vec![];
+        for f in data.fields.drain(..) {
+            match &f {
+                Field::Field(name, value)
+                    if self.fields.is_empty() || self.fields.contains(name) =>
+                {
+                    if let Some(value) = value.as_f64() {
+                        state
+                            .stats
+                            .entry(name.clone())
+                            .and_modify(|stats| {
+                                stats.min = stats.min.min(value);
+                                stats.max = stats.max.max(value);
+                                stats.sum += value;
+                                stats.count += 1;
+                            })
+                            .or_insert(Stats {
+                                min: value,
+                                max: value,
+                                sum: value,
+                                count: 1,
+                            });
+                        continue;
+                    }
+                    last.push(f);
+                }
+                _ => last.push(f),
+            }
+        }
+        state.last = last;
+        match finished {
+            Some((window, fields)) => {
+                data.fields = fields;
+                let start = self.duration.as_millis().max(1) * window;
+                data.timestamp = Some(UNIX_EPOCH + Duration::from_millis(start as u64));
+            }
+            None => data.timestamp = None,
+        }
+    }
+
+    fn aggregate(&self, state: &mut WindowState) -> Vec<Field> {
+        let mut fields = std::mem::take(&mut state.last);
+        for (name, stats) in std::mem::take(&mut state.stats) {
+            fields.push(Field::Field(
+                name.clone(),
+                Value::F64(stats.sum / stats.count as f64),
+            ));
+            fields.push(Field::Field(format!("{name}Min"), Value::F64(stats.min)));
+            fields.push(Field::Field(format!("{name}Max"), Value::F64(stats.max)));
+        }
+        fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let window = Window {
+            duration: Duration::from_secs(60),
+            fields: vec![],
+        };
+        let mut state = WindowState::default();
+        let start = UNIX_EPOCH + Duration::from_secs(600);
+        for (secs, power) in [(0, 100.0), (5, 300.0), (10, 200.0)] {
+            let mut data = PublishData::default();
+            data.tag("deviceName", "inverter".to_string());
+            data.field("currentPower", power);
+            window.apply(&mut data, &mut state, start + Duration::from_secs(secs));
+            assert!(!data.has_fields());
+        }
+        let mut data = PublishData::default();
+        data.field("currentPower", 50.0);
+        window.apply(&mut data, &mut state, start + Duration::from_secs(61));
+        assert_eq!(data["deviceName"], Value::String("inverter".to_string()));
+        assert_eq!(data.number("currentPower"), Some(200.0));
+        assert_eq!(data.number("currentPowerMin"), Some(100.0));
+        assert_eq!(data.number("currentPowerMax"), Some(300.0));
+        assert_eq!(data.timestamp(), Some(start));
+    }
+}
@@ -0,0 +1,161 @@
+//! Interactive creation of a config file.
+use anyhow::{bail, Context};
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+use sun_status_grabber::config::Format;
+use sun_status_grabber::{Config, PublishData, Source, SourceConfig};
+
+struct Prompt<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Prompt<R, W> {
+    /// Asks for a value, `default` is used for empty answers.
+    fn ask(&mut self, question: &str, default: Option<&str>) -> anyhow::Result<String> {
+        loop {
+            match default {
+                Some(default) => write!(self.output, "{question} [{default}]: ")?,
+                None => write!(self.output, "{question}: ")?,
+            }
+            self.output.flush()?;
+            let mut answer = String::new();
+            if self.input.read_line(&mut answer)? == 0 {
+                bail!("Aborted");
+            }
+            let answer = answer.trim();
+            match (answer, default) {
+                ("", Some(default)) => return Ok(default.to_string()),
+                ("", None) => continue,
+                (answer, _) => return Ok(answer.to_string()),
+            }
+        }
+    }
+
+    fn confirm(&mut self, question: &str) -> anyhow::Result<bool> {
+        let answer = self.ask(question, Some("y"))?;
+        Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+    }
+
+    fn source(&mut self, kind: &str) -> anyhow::Result<Value> {
+        Ok(match kind {
+            "inverter" => json!({
+                "type": "Inverter",
+                "statusPageUrl": self.ask("Status page URL", Some("http://192.168.1.20/status.html"))?,
+                "user": self.ask("User", Some("admin"))?,
+                "password": self.ask("Password", Some("admin"))?,
+                "device_name": self.ask("Device name", Some("inverter"))?,
+            }),
+            "tasmota" => json!({
+                "type": "Tasmota",
+                "host": self.ask("Host name or IP address", None)?,
+                "device_name": self.ask("Device name", Some("plug"))?,
+            }),
+            kind => bail!("Unknown device type '{kind}'"),
+        })
+    }
+
+    fn target(&mut self) -> anyhow::Result<Value> {
+        Ok(json!({
+            "influxUrl": self.ask("InfluxDB URL", Some("http://localhost:8086"))?,
+            "org": self.ask("Organization", None)?,
+            "bucket": self.ask("Bucket", None)?,
+            "token": self.ask("API token", None)?,
+            "measurement": self.ask("Measurement", Some("power_generation"))?,
+        }))
+    }
+}
+
+fn print_fields(output: &mut impl Write, data: &PublishData) -> std::io::Result<()> {
+    for field in data.fields() {
+        writeln!(output, "  {} = {:?}", field.name(), field.value())?;
+    }
+    Ok(())
+}
+
+/// Asks for the sources and the target, optionally test-polling each source. Returns the config
+/// as JSON.
+pub fn run(input: impl BufRead, output: impl Write, test_poll: bool) -> anyhow::Result<String> {
+    let mut prompt = Prompt { input, output };
+    let mut sources = vec![];
+    loop {
+        let kind = prompt.ask(
+            "Device type (inverter, tasmota, or done)",
+            Some(if sources.is_empty() {
+                "inverter"
+            } else {
+                "done"
+            }),
+        )?;
+        if kind == "done" {
+            break;
+        }
+        let source = match prompt.source(&kind) {
+            Ok(source) => source,
+            Err(err) => {
+                writeln!(prompt.output, "{err}")?;
+                continue;
+            }
+        };
+        if test_poll && prompt.confirm("Test-poll the device now?")? {
+            let mut polled: SourceConfig = serde_json::from_value(source.clone())?;
+            match polled.poll_data() {
+                Ok(data) => print_fields(&mut prompt.output, &data)?,
+                Err(err) => writeln!(prompt.output, "Polling failed: {err:#}")?,
+            }
+        }
+        sources.push(source);
+    }
+    writeln!(prompt.output, "InfluxDB to publish to:")?;
+    let config = json!({"sources": sources, "targets": [prompt.target()?]});
+    let config = serde_json::to_string_pretty(&config)?;
+    let problems = Format::Json
+        .parse(&config)
+        .context("Generated an invalid config")?
+        .validate(false);
+    for problem in &problems {
+        writeln!(prompt.output, "Warning: {problem}")?;
+    }
+    Ok(config)
+}
+
+/// Writes the config created by [`run`], unless the file already exists.
+pub fn init(path: &std::path::Path, force: bool) -> anyhow::Result<()> {
+    if path.exists() && !force {
+        bail!("'{}' already exists", path.display());
+    }
+    let stdin = std::io::stdin();
+    let config = run(stdin.lock(), std::io::stdout(), true)?;
+    std::fs::write(path, config + "\n")
+        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    // Loading it again makes sure the file is found and valid
+    Config::load(path)?;
+    println!("Wrote '{}'", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let input = "tasmota\n192.168.1.23\nheat pump\nbogus\n\n\nmy-org\nsolar\nsecret\n\n";
+        let mut output = vec![];
+        let config = run(input.as_bytes(), &mut output, false).unwrap();
+        let config = Format::Json.parse(&config).unwrap();
+        assert_eq!(config.sources.len(), 1);
+        assert_eq!(config.sources[0].id(), "heat pump");
+        assert_eq!(
+            config.targets[0].backend.influxdb().unwrap().bucket,
+            "solar"
+        );
+        assert_eq!(
+            config.targets[0].backend.influxdb().unwrap().measurement,
+            "power_generation"
+        );
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("Unknown device type 'bogus'"));
+    }
+}